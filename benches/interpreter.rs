@@ -0,0 +1,232 @@
+//! Measures how fast the interpreter runs a few workloads with each of the
+//! chip's engines, and compares that with a saved baseline.
+//!
+//! Speeds in instructions per second depend on the machine and on whatever
+//! else it is doing, so the baseline holds ratios measured within a single
+//! run instead: the speed of the instruction cache and of the recompiler
+//! relative to the plain interpreter, and the speed of the plain interpreter
+//! relative to a calibration loop that doesn't touch the chip, which stands
+//! in for how fast the machine is.  Samples are interleaved, so that a busy
+//! moment slows them all alike, and the median of the samples is compared
+//! with the baseline, alongside the spread of the middle half of the
+//! samples.  Instructions per second are only printed, as they can only be
+//! compared with runs on the same machine.
+//!
+//! ```text
+//! cargo bench                                # compare with benches/baseline.json
+//! cargo bench -- --save-baseline             # overwrite the baseline
+//! cargo bench -- draw                        # only run workloads named draw
+//! ```
+//!
+//! The baseline is only saved in commits of its own, never alongside the
+//! changes being measured.
+
+extern crate chip8;
+extern crate serde_json;
+
+use chip8::{Chip8, Engine};
+use serde_json::{Map, Value};
+use std::env;
+use std::fs::File;
+use std::hint::black_box;
+use std::io::prelude::*;
+use std::time::Instant;
+
+/// Where the baseline is kept, relative to the crate's root.
+const BASELINE: &str = "benches/baseline.json";
+
+/// How many instructions each sample runs.
+const INSTRUCTIONS: u32 = 2_000_000;
+
+/// How many instructions to run in each frame of workloads that don't update
+/// the timers every instruction, so that the engines can run whole blocks.
+const FRAME: u32 = 1000;
+
+/// How many steps of the calibration loop each sample runs.
+const CALIBRATION: u32 = 20_000_000;
+
+/// How many samples to take of each workload.
+const SAMPLES: usize = 15;
+
+/// How much slower than the baseline a workload has to be to be reported as
+/// a regression, as a fraction.  Slowdowns within the spread of the middle
+/// half of the samples are put down to noise rather than reported.
+const REGRESSION: f64 = 0.10;
+
+/// A program to measure, and how to run it.
+struct Workload {
+    name: &'static str,
+    program: &'static [u8],
+    /// Whether to update the timers after every instruction, as a whole frame
+    /// of one instruction, rather than running long frames.
+    frames: bool,
+}
+
+static WORKLOADS: [Workload; 5] = [
+    // The bundled game, playing itself
+    Workload {
+        name: "pong",
+        program: include_bytes!("../PONG"),
+        frames: false,
+    },
+    // Arithmetic and jumps, which is most of what games do
+    Workload {
+        name: "alu",
+        program: &[0x60, 0x01, 0x61, 0x02, 0x80, 0x14, 0x81, 0x25,
+                   0x82, 0x03, 0x80, 0x16, 0x71, 0x01, 0x12, 0x04],
+        frames: false,
+    },
+    // Tall sprites drawn across the screen, one after another
+    Workload {
+        name: "draw",
+        program: &[0xA2, 0x10, 0x60, 0x00, 0x61, 0x00, 0x62, 0x3F,
+                   0xD0, 0x1F, 0x70, 0x03, 0x80, 0x22, 0x12, 0x08,
+                   0xAA, 0x55, 0xAA, 0x55, 0xAA, 0x55, 0xAA, 0x55,
+                   0xAA, 0x55, 0xAA, 0x55, 0xAA, 0x55, 0xAA],
+        frames: false,
+    },
+    // Rewriting one of its own opcodes every time around a loop of
+    // arithmetic that is left alone
+    Workload {
+        name: "selfmod",
+        program: &[0xA2, 0x03, 0x60, 0x00, 0x70, 0x01, 0xF0, 0x55,
+                   0x81, 0x04, 0x82, 0x14, 0x83, 0x24, 0x84, 0x34,
+                   0x85, 0x44, 0x86, 0x54, 0x12, 0x02],
+        frames: false,
+    },
+    // Reading and writing the timers while they count down every frame
+    Workload {
+        name: "timers",
+        program: &[0x60, 0x0A, 0xF0, 0x15, 0xF1, 0x07, 0xF1, 0x18, 0x12, 0x02],
+        frames: true,
+    },
+];
+
+fn main() {
+    let mut save = false;
+    let mut filter = None;
+    for arg in env::args().skip(1) {
+        match arg.as_str() {
+            "--save-baseline" => save = true,
+            // Cargo passes this to every benchmark
+            "--bench" => {}
+            _ => filter = Some(arg),
+        }
+    }
+
+    let baseline = load_baseline();
+    let mut results = baseline.clone();
+    for workload in WORKLOADS.iter() {
+        if let Some(ref filter) = filter {
+            if !workload.name.contains(filter.as_str()) {
+                continue;
+            }
+        }
+
+        let mut plain = Vec::new();
+        let mut calibrated = Vec::new();
+        let mut cached = Vec::new();
+        let mut recompiled = Vec::new();
+        for _ in 0..SAMPLES {
+            let interpreter = measure(workload, Engine::Interpreter);
+            plain.push(interpreter);
+            calibrated.push(interpreter / calibrate());
+            cached.push(measure(workload, Engine::Cached) / interpreter);
+            recompiled.push(measure(workload, Engine::Recompiler) / interpreter);
+        }
+
+        let (speed, spread) = summarize(&plain);
+        println!("{:20} {:6.2}M instructions/s ±{:4.1}% with the plain interpreter",
+                 workload.name, speed / 1e6, spread * 100.0);
+        let ratios = vec![
+            (format!("{}-interpreter", workload.name), calibrated, "the calibration loop's"),
+            (format!("{}-cached", workload.name), cached, "the plain interpreter's"),
+            (format!("{}-recompiled", workload.name), recompiled, "the plain interpreter's"),
+        ];
+        for (name, samples, against) in ratios {
+            let (median, spread) = summarize(&samples);
+            let comparison = match baseline.get(name.as_str()).and_then(Value::as_f64) {
+                Some(old) => {
+                    let change = median / old - 1.0;
+                    let note = if change < -REGRESSION && -change > spread {
+                        "  REGRESSION"
+                    } else {
+                        ""
+                    };
+                    format!("(baseline {:.2}, {:+.1}%){}", old, change * 100.0, note)
+                }
+                None => "(no baseline)".to_string(),
+            };
+            println!("{:20} {:6.2}x {} speed ±{:4.1}%  {}",
+                     name, median, against, spread * 100.0, comparison);
+            results.insert(name, Value::from((median * 1000.0).round() / 1000.0));
+        }
+    }
+
+    if save {
+        let json = serde_json::to_string_pretty(&Value::Object(results)).unwrap();
+        File::create(BASELINE)
+            .and_then(|mut file| writeln!(file, "{}", json))
+            .unwrap_or_else(|why| panic!("Couldn't write {}: {}", BASELINE, why));
+        println!("Saved the results to {}", BASELINE);
+    }
+}
+
+/// Runs the workload once with the given engine, returning its instructions
+/// per second.
+fn measure(workload: &Workload, engine: Engine) -> f64 {
+    let mut chip = Chip8::with_engine(engine);
+    chip.set_seed(1);
+    chip.load_hex(workload.program);
+
+    let start = Instant::now();
+    if workload.frames {
+        for _ in 0..INSTRUCTIONS {
+            chip.emulate_frame(1);
+        }
+    } else {
+        for _ in 0..INSTRUCTIONS / FRAME {
+            chip.emulate_frame(FRAME);
+        }
+    }
+    INSTRUCTIONS as f64 / start.elapsed().as_secs_f64()
+}
+
+/// Runs a loop of shifts, XORs and table updates that doesn't depend on the
+/// chip, returning its steps per second, as a measure of the machine's speed.
+fn calibrate() -> f64 {
+    let mut table = [0u8; 4096];
+    let mut state: u32 = 1;
+    let start = Instant::now();
+    for _ in 0..CALIBRATION {
+        state ^= state << 13;
+        state ^= state >> 17;
+        state ^= state << 5;
+        let i = state as usize % table.len();
+        table[i] = table[i].wrapping_add(state as u8);
+    }
+    black_box(&table);
+    CALIBRATION as f64 / start.elapsed().as_secs_f64()
+}
+
+/// The median of the samples, and how far the middle half of the samples
+/// spread, as a fraction of the median, which a few outliers don't sway.
+fn summarize(samples: &[f64]) -> (f64, f64) {
+    let mut sorted = samples.to_vec();
+    sorted.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    let quartile = |n: usize| sorted[(sorted.len() - 1) * n / 4];
+    let median = quartile(2);
+    (median, (quartile(3) - quartile(1)) / median)
+}
+
+/// Reads the saved ratios of each workload, if there are any.
+fn load_baseline() -> Map<String, Value> {
+    let mut text = String::new();
+    if File::open(BASELINE).and_then(|mut file| file.read_to_string(&mut text)).is_err() {
+        return Map::new();
+    }
+    match serde_json::from_str(&text) {
+        Ok(Value::Object(map)) => map,
+        _ => panic!("{} isn't a JSON object of workload names to numbers", BASELINE),
+    }
+}
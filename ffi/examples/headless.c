@@ -0,0 +1,92 @@
+/*
+ * Runs a ROM headlessly through the C interface and prints its display.
+ *
+ *     cc headless.c -I../include -L../../target/debug -lchip8_ffi -o headless
+ *     ./headless ../../PONG 120
+ *
+ * After the given number of frames, the chip is saved, run on for a while
+ * and loaded again, which should show the same display as before.
+ */
+
+#include <stdio.h>
+#include <stdlib.h>
+
+#include "chip8.h"
+
+static int fail(Chip8 *chip, const char *what) {
+    fprintf(stderr, "Couldn't %s: %s\n", what, chip8_last_error(chip));
+    chip8_free(chip);
+    return 1;
+}
+
+static int print_display(Chip8 *chip) {
+    uint32_t width = chip8_display_width(chip);
+    uint32_t height = chip8_display_height(chip);
+    uint8_t *pixels = malloc(width * height);
+    if (chip8_read_display(chip, pixels, width * height) != 0) {
+        free(pixels);
+        return -1;
+    }
+    for (uint32_t y = 0; y < height; y++) {
+        for (uint32_t x = 0; x < width; x++) {
+            putchar(pixels[y * width + x] ? '#' : '.');
+        }
+        putchar('\n');
+    }
+    free(pixels);
+    return 0;
+}
+
+int main(int argc, char **argv) {
+    if (argc != 3) {
+        fprintf(stderr, "Usage: %s ROM FRAMES\n", argv[0]);
+        return 2;
+    }
+
+    FILE *file = fopen(argv[1], "rb");
+    if (!file) {
+        perror(argv[1]);
+        return 1;
+    }
+    uint8_t rom[4096];
+    size_t len = fread(rom, 1, sizeof rom, file);
+    fclose(file);
+
+    Chip8 *chip = chip8_new();
+    printf("chip8 %s\n", chip8_version());
+    chip8_set_seed(chip, 1);
+    if (chip8_load_rom(chip, rom, len) != 0) {
+        return fail(chip, "load the ROM");
+    }
+
+    int frames = atoi(argv[2]);
+    for (int frame = 0; frame < frames; frame++) {
+        /* Hold key 1, which moves the left paddle up in PONG */
+        chip8_set_keys(chip, frame % 20 < 10 ? 1 << 1 : 0);
+        if (chip8_run_frame(chip, 10) != 0) {
+            return fail(chip, "run a frame");
+        }
+    }
+
+    size_t size = chip8_state_size(chip);
+    uint8_t *state = malloc(size);
+    if (chip8_save_state(chip, state, size) != 0) {
+        free(state);
+        return fail(chip, "save the state");
+    }
+    for (int frame = 0; frame < 60; frame++) {
+        chip8_run_frame(chip, 10);
+    }
+    int loaded = chip8_load_state(chip, state, size);
+    free(state);
+    if (loaded != 0) {
+        return fail(chip, "load the state");
+    }
+
+    if (print_display(chip) != 0) {
+        return fail(chip, "read the display");
+    }
+    printf("Sound: %s\n", chip8_sound_on(chip) ? "on" : "off");
+    chip8_free(chip);
+    return 0;
+}
@@ -0,0 +1,126 @@
+/* Generated from src/lib.rs by cbindgen; edit that instead. */
+
+#ifndef CHIP8_H
+#define CHIP8_H
+
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+
+/**
+ * A chip and the last error it reported.
+ */
+typedef struct Chip8 Chip8;
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+/**
+ * The version of the emulator, such as "0.1.1".
+ */
+const char *chip8_version(void);
+
+/**
+ * Makes a new chip with the original CHIP-8's behaviour and its random
+ * numbers seeded differently every time, unless chip8_set_seed is called.
+ * Free it with chip8_free.
+ */
+Chip8 *chip8_new(void);
+
+/**
+ * Frees a chip made with chip8_new.  Freeing NULL does nothing.
+ */
+void chip8_free(Chip8 *chip);
+
+/**
+ * Describes the last call on the chip that failed, or returns NULL if the
+ * last call that could fail succeeded.  The string belongs to the chip and
+ * lasts until the next call on it.  For a NULL chip, it says so.
+ */
+const char *chip8_last_error(const Chip8 *chip);
+
+/**
+ * Imitates the quirks and memory size of an interpreter, such as "chip8",
+ * "schip" or "xochip".  Call it before loading the ROM, since changing the
+ * memory size can drop what is loaded.
+ */
+int chip8_set_platform(Chip8 *chip, const char *name);
+
+/**
+ * Loads `len` bytes of ROM at 0x200 and starts running from there.  Fails
+ * if the ROM doesn't fit in memory.
+ */
+int chip8_load_rom(Chip8 *chip, const uint8_t *rom, size_t len);
+
+/**
+ * Seeds the random numbers, so that runs repeat.
+ */
+void chip8_set_seed(Chip8 *chip, uint64_t seed);
+
+/**
+ * Runs a 60Hz frame of `ipf` instructions, counting the timers down
+ * afterwards.  Fails if the program runs a bad opcode.
+ */
+int chip8_run_frame(Chip8 *chip, uint32_t ipf);
+
+/**
+ * Presses or releases one of the chip's keys, from 0x0 to 0xF.
+ */
+void chip8_set_key(Chip8 *chip, uint8_t key, bool pressed);
+
+/**
+ * Sets which of the chip's keys are held, with bit N set for key N.
+ */
+void chip8_set_keys(Chip8 *chip, uint16_t keys);
+
+/**
+ * The width of the display (in pixels), which is 64.
+ */
+uint32_t chip8_display_width(const Chip8 *chip);
+
+/**
+ * The height of the display (in pixels), which is 32.
+ */
+uint32_t chip8_display_height(const Chip8 *chip);
+
+/**
+ * Copies the display into `out`, a byte per pixel row by row, which is 1
+ * for pixels that are on and 0 for those that are off.  Fails if `len` is
+ * less than the width times the height.
+ */
+int chip8_read_display(Chip8 *chip, uint8_t *out, size_t len);
+
+/**
+ * Whether the display has changed since the last call.
+ */
+bool chip8_display_changed(Chip8 *chip);
+
+/**
+ * Whether the beeper is sounding.
+ */
+bool chip8_sound_on(const Chip8 *chip);
+
+/**
+ * The size of a save state (in bytes), which only changes with the
+ * platform.
+ */
+size_t chip8_state_size(const Chip8 *chip);
+
+/**
+ * Saves the chip's state into `out`, which must hold at least
+ * chip8_state_size bytes.
+ */
+int chip8_save_state(Chip8 *chip, uint8_t *out, size_t len);
+
+/**
+ * Puts the chip back to a state saved with chip8_save_state.  Fails
+ * without changing the chip if the state is damaged.
+ */
+int chip8_load_state(Chip8 *chip, const uint8_t *state, size_t len);
+
+#ifdef __cplusplus
+} // extern "C"
+#endif // __cplusplus
+
+#endif /* CHIP8_H */
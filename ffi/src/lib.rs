@@ -0,0 +1,423 @@
+//! A C interface to the emulator, for hosting it in C and C++ programs.  The
+//! library is built as both a shared and a static library, and the matching
+//! header, `include/chip8.h`, is generated by cbindgen and checked by
+//! `tests/header.rs`.
+//!
+//! Every function takes the chip it works on, which must come from
+//! `chip8_new` and must not be used after `chip8_free`.  Functions that can
+//! fail return 0 on success and -1 on failure, when `chip8_last_error`
+//! describes what went wrong.  Panics, such as from bad opcodes, are caught
+//! and reported the same way rather than crossing into C.
+//!
+//! Every function accepts a NULL chip without touching it: those that can
+//! fail return -1, for which `chip8_last_error(NULL)` gives the reason, and
+//! the rest do nothing and return 0 or false.  Other NULL pointers fail the
+//! same way, unless they come with a length of 0.
+
+// The rules for the pointers every function takes are above, rather than
+// repeated for each function
+#![allow(clippy::missing_safety_doc)]
+// Fields are written `name: name`, like the emulator's
+#![allow(clippy::redundant_field_names)]
+
+extern crate chip8;
+
+use chip8::platform::Platform;
+use std::ffi::{CStr, CString};
+use std::os::raw::{c_char, c_int};
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+use std::slice;
+
+/// A chip and the last error it reported.
+pub struct Chip8 {
+    chip: chip8::Chip8,
+    error: Option<CString>,
+}
+
+impl Chip8 {
+    /// Remembers the given error for `chip8_last_error`, returning -1.
+    fn fail<E: ToString>(&mut self, error: E) -> c_int {
+        // Messages never contain NULs, but drop any rather than lose the error
+        let message = error.to_string().replace('\0', "");
+        self.error = Some(CString::new(message).unwrap());
+        -1
+    }
+
+    /// Forgets the last error, returning 0.
+    fn succeed(&mut self) -> c_int {
+        self.error = None;
+        0
+    }
+
+    /// Turns the result of something that can fail into 0 or -1.
+    fn check<E: ToString>(&mut self, result: Result<(), E>) -> c_int {
+        match result {
+            Ok(()) => self.succeed(),
+            Err(why) => self.fail(why),
+        }
+    }
+}
+
+/// What `chip8_last_error` says about a NULL chip.
+const NULL_CHIP: &[u8] = b"The chip is NULL\0";
+
+/// Runs `f` on the chip behind a pointer from C, or returns `null` if the
+/// pointer is NULL.
+unsafe fn with_chip<T, F>(chip: *mut Chip8, null: T, f: F) -> T
+    where F: FnOnce(&mut Chip8) -> T
+{
+    match chip.as_mut() {
+        Some(chip) => f(chip),
+        None => null,
+    }
+}
+
+/// Borrows `len` bytes from C, which may be NULL when there are none.
+/// Returns `None` for NULL with a length.
+unsafe fn bytes<'a>(data: *const u8, len: usize) -> Option<&'a [u8]> {
+    if len == 0 {
+        Some(&[])
+    } else if data.is_null() {
+        None
+    } else {
+        Some(slice::from_raw_parts(data, len))
+    }
+}
+
+/// Borrows `len` bytes from C to write to, like `bytes`.
+unsafe fn bytes_mut<'a>(data: *mut u8, len: usize) -> Option<&'a mut [u8]> {
+    if len == 0 {
+        Some(&mut [])
+    } else if data.is_null() {
+        None
+    } else {
+        Some(slice::from_raw_parts_mut(data, len))
+    }
+}
+
+/// The message a panic was started with.
+fn panic_message(cause: &(dyn std::any::Any + Send)) -> String {
+    if let Some(message) = cause.downcast_ref::<String>() {
+        message.clone()
+    } else if let Some(message) = cause.downcast_ref::<&str>() {
+        message.to_string()
+    } else {
+        "Unknown panic".to_string()
+    }
+}
+
+/// The version of the emulator, such as "0.1.1".
+#[no_mangle]
+pub extern "C" fn chip8_version() -> *const c_char {
+    concat!(env!("CARGO_PKG_VERSION"), "\0").as_ptr() as *const c_char
+}
+
+/// Makes a new chip with the original CHIP-8's behaviour and its random
+/// numbers seeded differently every time, unless chip8_set_seed is called.
+/// Free it with chip8_free.
+#[no_mangle]
+pub extern "C" fn chip8_new() -> *mut Chip8 {
+    let mut chip = chip8::Chip8::default();
+    chip.set_seed(chip8::random_seed());
+    Box::into_raw(Box::new(Chip8 {
+        chip: chip,
+        error: None,
+    }))
+}
+
+/// Frees a chip made with chip8_new.  Freeing NULL does nothing.
+#[no_mangle]
+pub unsafe extern "C" fn chip8_free(chip: *mut Chip8) {
+    if !chip.is_null() {
+        drop(Box::from_raw(chip));
+    }
+}
+
+/// Describes the last call on the chip that failed, or returns NULL if the
+/// last call that could fail succeeded.  The string belongs to the chip and
+/// lasts until the next call on it.  For a NULL chip, it says so.
+#[no_mangle]
+pub unsafe extern "C" fn chip8_last_error(chip: *const Chip8) -> *const c_char {
+    match chip.as_ref() {
+        Some(&Chip8 { error: Some(ref error), .. }) => error.as_ptr(),
+        Some(_) => ptr::null(),
+        None => NULL_CHIP.as_ptr() as *const c_char,
+    }
+}
+
+/// Imitates the quirks and memory size of an interpreter, such as "chip8",
+/// "schip" or "xochip".  Call it before loading the ROM, since changing the
+/// memory size can drop what is loaded.
+#[no_mangle]
+pub unsafe extern "C" fn chip8_set_platform(chip: *mut Chip8, name: *const c_char) -> c_int {
+    with_chip(chip, -1, |chip| {
+        if name.is_null() {
+            return chip.fail("The platform's name is NULL");
+        }
+        let name = CStr::from_ptr(name).to_string_lossy();
+        match Platform::from_name(&name) {
+            Some(platform) => {
+                chip.chip.set_memory_size(platform.memory_size());
+                chip.chip.quirks = platform.quirks();
+                chip.succeed()
+            }
+            None => chip.fail(format!("Unknown platform {}", name)),
+        }
+    })
+}
+
+/// Loads `len` bytes of ROM at 0x200 and starts running from there.  Fails
+/// if the ROM doesn't fit in memory.
+#[no_mangle]
+pub unsafe extern "C" fn chip8_load_rom(chip: *mut Chip8, rom: *const u8, len: usize) -> c_int {
+    with_chip(chip, -1, |chip| {
+        let rom = match bytes(rom, len) {
+            Some(rom) => rom,
+            None => return chip.fail("The ROM is NULL"),
+        };
+        let result = chip.chip.load_rom(rom, chip8::PROGRAM_START);
+        chip.check(result)
+    })
+}
+
+/// Seeds the random numbers, so that runs repeat.
+#[no_mangle]
+pub unsafe extern "C" fn chip8_set_seed(chip: *mut Chip8, seed: u64) {
+    with_chip(chip, (), |chip| chip.chip.set_seed(seed));
+}
+
+/// Runs a 60Hz frame of `ipf` instructions, counting the timers down
+/// afterwards.  Fails if the program runs a bad opcode.
+#[no_mangle]
+pub unsafe extern "C" fn chip8_run_frame(chip: *mut Chip8, ipf: u32) -> c_int {
+    with_chip(chip, -1, |chip| {
+        let result = panic::catch_unwind(AssertUnwindSafe(|| chip.chip.emulate_frame(ipf)));
+        chip.check(result.map_err(|cause| panic_message(&*cause)))
+    })
+}
+
+/// Presses or releases one of the chip's keys, from 0x0 to 0xF.
+#[no_mangle]
+pub unsafe extern "C" fn chip8_set_key(chip: *mut Chip8, key: u8, pressed: bool) {
+    with_chip(chip, (), |chip| chip.chip.key[key as usize & 0xF] = pressed as u8);
+}
+
+/// Sets which of the chip's keys are held, with bit N set for key N.
+#[no_mangle]
+pub unsafe extern "C" fn chip8_set_keys(chip: *mut Chip8, keys: u16) {
+    with_chip(chip, (), |chip| {
+        for (i, key) in chip.chip.key.iter_mut().enumerate() {
+            *key = (keys >> i & 1) as u8;
+        }
+    })
+}
+
+/// The width of the display (in pixels), which is 64.
+#[no_mangle]
+pub unsafe extern "C" fn chip8_display_width(chip: *const Chip8) -> u32 {
+    chip.as_ref().map_or(0, |chip| chip.chip.graphics.width())
+}
+
+/// The height of the display (in pixels), which is 32.
+#[no_mangle]
+pub unsafe extern "C" fn chip8_display_height(chip: *const Chip8) -> u32 {
+    chip.as_ref().map_or(0, |chip| chip.chip.graphics.height())
+}
+
+/// Copies the display into `out`, a byte per pixel row by row, which is 1
+/// for pixels that are on and 0 for those that are off.  Fails if `len` is
+/// less than the width times the height.
+#[no_mangle]
+pub unsafe extern "C" fn chip8_read_display(chip: *mut Chip8, out: *mut u8, len: usize) -> c_int {
+    with_chip(chip, -1, |chip| {
+        let needed = (chip.chip.graphics.width() * chip.chip.graphics.height()) as usize;
+        if len < needed {
+            return chip.fail(chip8::Error::BufferTooSmall { size: len, needed: needed });
+        }
+        let out = match bytes_mut(out, needed) {
+            Some(out) => out,
+            None => return chip.fail("The display's buffer is NULL"),
+        };
+        for (pixel, value) in out.iter_mut().zip(chip.chip.graphics.pixels()) {
+            *pixel = value;
+        }
+        chip.succeed()
+    })
+}
+
+/// Whether the display has changed since the last call.
+#[no_mangle]
+pub unsafe extern "C" fn chip8_display_changed(chip: *mut Chip8) -> bool {
+    with_chip(chip, false, |chip| {
+        let changed = chip.chip.draw_flag;
+        chip.chip.draw_flag = false;
+        changed
+    })
+}
+
+/// Whether the beeper is sounding.
+#[no_mangle]
+pub unsafe extern "C" fn chip8_sound_on(chip: *const Chip8) -> bool {
+    chip.as_ref().is_some_and(|chip| chip.chip.make_sound)
+}
+
+/// The size of a save state (in bytes), which only changes with the
+/// platform.
+#[no_mangle]
+pub unsafe extern "C" fn chip8_state_size(chip: *const Chip8) -> usize {
+    chip.as_ref().map_or(0, |chip| chip.chip.state_size())
+}
+
+/// Saves the chip's state into `out`, which must hold at least
+/// chip8_state_size bytes.
+#[no_mangle]
+pub unsafe extern "C" fn chip8_save_state(chip: *mut Chip8, out: *mut u8, len: usize) -> c_int {
+    with_chip(chip, -1, |chip| {
+        let out = match bytes_mut(out, len) {
+            Some(out) => out,
+            None => return chip.fail("The state's buffer is NULL"),
+        };
+        let result = chip.chip.save_state(out).map(|_| ());
+        chip.check(result)
+    })
+}
+
+/// Puts the chip back to a state saved with chip8_save_state.  Fails
+/// without changing the chip if the state is damaged.
+#[no_mangle]
+pub unsafe extern "C" fn chip8_load_state(chip: *mut Chip8,
+                                          state: *const u8,
+                                          len: usize) -> c_int {
+    with_chip(chip, -1, |chip| {
+        let state = match bytes(state, len) {
+            Some(state) => state,
+            None => return chip.fail("The state is NULL"),
+        };
+        let result = chip.chip.load_state(state);
+        chip.check(result)
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::ffi::CStr;
+
+    unsafe fn last_error(chip: *const Chip8) -> Option<String> {
+        let error = chip8_last_error(chip);
+        if error.is_null() {
+            None
+        } else {
+            Some(CStr::from_ptr(error).to_string_lossy().into_owned())
+        }
+    }
+
+    #[test]
+    fn runs_a_rom() {
+        unsafe {
+            let chip = chip8_new();
+            // Draws the font's 0 at the top left, then waits for key 5
+            let rom = [0x60, 0x00, 0xF0, 0x29, 0xD0, 0x05, 0xF1, 0x0A, 0x12, 0x06];
+            assert_eq!(chip8_load_rom(chip, rom.as_ptr(), rom.len()), 0);
+            assert_eq!(chip8_run_frame(chip, 10), 0);
+            assert!(chip8_display_changed(chip));
+            assert!(!chip8_display_changed(chip));
+
+            let mut display = vec![0; 64 * 32];
+            assert_eq!(chip8_read_display(chip, display.as_mut_ptr(), display.len()), 0);
+            assert_eq!(&display[0..5], &[1, 1, 1, 1, 0]);
+            assert_eq!(&display[64..69], &[1, 0, 0, 1, 0]);
+
+            chip8_set_keys(chip, 1 << 5);
+            assert_eq!(chip8_run_frame(chip, 10), 0);
+            assert_eq!((*chip).chip.registers()[1], 5);
+            chip8_free(chip);
+        }
+    }
+
+    #[test]
+    fn reports_errors() {
+        unsafe {
+            let chip = chip8_new();
+            let rom = vec![0; 4000];
+            assert_eq!(chip8_load_rom(chip, rom.as_ptr(), rom.len()), -1);
+            assert_eq!(last_error(chip),
+                       Some("A 4000 byte ROM loaded at 0x200 doesn't fit in 4096 bytes of memory"
+                           .to_string()));
+
+            let name = CString::new("xochip").unwrap();
+            assert_eq!(chip8_set_platform(chip, name.as_ptr()), 0);
+            assert_eq!(last_error(chip), None);
+            assert_eq!(chip8_load_rom(chip, rom.as_ptr(), rom.len()), 0);
+
+            let bad = [0xFF, 0xFF];
+            assert_eq!(chip8_load_rom(chip, bad.as_ptr(), bad.len()), 0);
+            assert_eq!(chip8_run_frame(chip, 10), -1);
+            assert_eq!(last_error(chip), Some("Opcode 0xFFFF is bad".to_string()));
+
+            let mut display = [0; 10];
+            assert_eq!(chip8_read_display(chip, display.as_mut_ptr(), display.len()), -1);
+            chip8_free(chip);
+        }
+    }
+
+    #[test]
+    fn null_pointers() {
+        unsafe {
+            let null = ptr::null_mut();
+            let name = CString::new("chip8").unwrap();
+            let mut buffer = [0; 64 * 32];
+            assert_eq!(last_error(null), Some("The chip is NULL".to_string()));
+            assert_eq!(chip8_set_platform(null, name.as_ptr()), -1);
+            assert_eq!(chip8_load_rom(null, buffer.as_ptr(), 2), -1);
+            assert_eq!(chip8_run_frame(null, 10), -1);
+            assert_eq!(chip8_read_display(null, buffer.as_mut_ptr(), buffer.len()), -1);
+            assert_eq!(chip8_save_state(null, buffer.as_mut_ptr(), buffer.len()), -1);
+            assert_eq!(chip8_load_state(null, buffer.as_ptr(), buffer.len()), -1);
+            chip8_set_seed(null, 1);
+            chip8_set_key(null, 1, true);
+            chip8_set_keys(null, 0xFFFF);
+            assert_eq!(chip8_display_width(null), 0);
+            assert_eq!(chip8_display_height(null), 0);
+            assert!(!chip8_display_changed(null));
+            assert!(!chip8_sound_on(null));
+            assert_eq!(chip8_state_size(null), 0);
+            chip8_free(null);
+
+            // Other pointers fail on the chip, unless there's nothing to read
+            let chip = chip8_new();
+            let none = ptr::null();
+            assert_eq!(chip8_set_platform(chip, none as *const c_char), -1);
+            assert_eq!(last_error(chip), Some("The platform's name is NULL".to_string()));
+            assert_eq!(chip8_load_rom(chip, none, 2), -1);
+            assert_eq!(last_error(chip), Some("The ROM is NULL".to_string()));
+            assert_eq!(chip8_load_rom(chip, none, 0), 0);
+            assert_eq!(chip8_read_display(chip, ptr::null_mut(), buffer.len()), -1);
+            assert_eq!(chip8_save_state(chip, ptr::null_mut(), chip8_state_size(chip)), -1);
+            assert_eq!(chip8_load_state(chip, none, 10), -1);
+            assert_eq!(last_error(chip), Some("The state is NULL".to_string()));
+            chip8_free(chip);
+        }
+    }
+
+    #[test]
+    fn saves_and_loads_states() {
+        unsafe {
+            let chip = chip8_new();
+            let rom = [0x70, 0x01, 0x12, 0x00];
+            chip8_load_rom(chip, rom.as_ptr(), rom.len());
+            chip8_run_frame(chip, 10);
+            let mut state = vec![0; chip8_state_size(chip)];
+            assert_eq!(chip8_save_state(chip, state.as_mut_ptr(), state.len()), 0);
+            chip8_run_frame(chip, 10);
+            assert_eq!((*chip).chip.registers()[0], 10);
+
+            assert_eq!(chip8_load_state(chip, state.as_ptr(), state.len()), 0);
+            assert_eq!((*chip).chip.registers()[0], 5);
+            assert_eq!(chip8_load_state(chip, state.as_ptr(), 10), -1);
+            assert!(last_error(chip).is_some());
+            chip8_free(chip);
+        }
+    }
+}
@@ -0,0 +1,77 @@
+//! Builds `examples/headless.c` against the generated header and the shared
+//! library, runs it on `PONG`, and checks it shows the same display as the
+//! emulator does from Rust.  It is skipped when there's no C compiler.
+
+extern crate chip8;
+
+use std::env;
+use std::fs::File;
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// How many frames the example runs before printing the display.
+const FRAMES: u32 = 120;
+
+/// The display after running `PONG` the way the example does.
+fn expected_display(rom: &[u8]) -> String {
+    let mut chip = chip8::Chip8::default();
+    chip.set_seed(1);
+    chip.load_rom(rom, chip8::PROGRAM_START).unwrap();
+    for frame in 0..FRAMES {
+        chip.key[1] = (frame % 20 < 10) as u8;
+        chip.emulate_frame(10);
+    }
+
+    let mut display = String::new();
+    for y in 0..chip.graphics.height() {
+        for pixel in chip.graphics.row(y) {
+            display.push(if pixel != 0 { '#' } else { '.' });
+        }
+        display.push('\n');
+    }
+    display
+}
+
+/// The directory the test and the libraries are built in.
+fn build_dir() -> PathBuf {
+    env::current_exe().unwrap().parent().unwrap().to_path_buf()
+}
+
+#[test]
+#[cfg(unix)]
+fn runs_the_c_example() {
+    let manifest = Path::new(env!("CARGO_MANIFEST_DIR"));
+    let libraries = build_dir();
+    let example = libraries.join("headless");
+    let compiler = env::var("CC").unwrap_or_else(|_| "cc".to_string());
+    let compiled = Command::new(&compiler)
+        .arg("-std=c99")
+        .arg(manifest.join("examples").join("headless.c"))
+        .arg("-I")
+        .arg(manifest.join("include"))
+        .arg("-L")
+        .arg(&libraries)
+        .arg(format!("-Wl,-rpath,{}", libraries.display()))
+        .arg("-lchip8_ffi")
+        .arg("-o")
+        .arg(&example)
+        .status();
+    match compiled {
+        Ok(status) => assert!(status.success(), "The example didn't compile"),
+        Err(why) => {
+            println!("Couldn't run {}, so the example wasn't built: {}", compiler, why);
+            return;
+        }
+    }
+
+    let rom = manifest.join("..").join("PONG");
+    let output = Command::new(&example).arg(&rom).arg(FRAMES.to_string()).output().unwrap();
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let output = String::from_utf8(output.stdout).unwrap();
+
+    let mut game = Vec::new();
+    File::open(&rom).and_then(|mut file| file.read_to_end(&mut game)).unwrap();
+    let expected = format!("chip8 {}\n{}Sound: off\n", chip8::version(), expected_display(&game));
+    assert_eq!(output, expected);
+}
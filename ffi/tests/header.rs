@@ -0,0 +1,32 @@
+//! Checks that `include/chip8.h` matches the functions in `src/lib.rs`, by
+//! generating the header again with cbindgen.  Run the test with
+//! `CHIP8_WRITE_HEADER=1` to write the new header after changing them.
+
+extern crate cbindgen;
+
+use std::env;
+use std::fs;
+use std::path::Path;
+
+#[test]
+fn header_is_up_to_date() {
+    let manifest = Path::new(env!("CARGO_MANIFEST_DIR"));
+    let config = cbindgen::Config::from_file(manifest.join("cbindgen.toml")).unwrap();
+    let bindings = cbindgen::Builder::new()
+        .with_crate(manifest)
+        .with_config(config)
+        .generate()
+        .expect("cbindgen couldn't read src/lib.rs");
+    let mut generated = Vec::new();
+    bindings.write(&mut generated);
+    let generated = String::from_utf8(generated).unwrap();
+
+    let path = manifest.join("include").join("chip8.h");
+    if env::var_os("CHIP8_WRITE_HEADER").is_some() {
+        fs::write(&path, &generated).unwrap();
+    }
+    let header = fs::read_to_string(&path).unwrap_or_default();
+    assert!(header == generated,
+            "include/chip8.h is out of date with src/lib.rs; write it again with \
+             `CHIP8_WRITE_HEADER=1 cargo test -p chip8-ffi --test header`");
+}
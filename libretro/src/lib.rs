@@ -0,0 +1,590 @@
+//! A libretro core, so that the emulator can run in RetroArch and other
+//! libretro frontends.  The library is built as `libchip8_libretro`, which
+//! frontends expect to be named `chip8_libretro` with the platform's shared
+//! library extension.
+//!
+//! The chip's 16 keys are spread over the RetroPad's 16 buttons, with the
+//! D-pad on 2, 8, 4 and 6 and A on 5, since most games move with those.  The
+//! display is sent as XRGB8888 at its current resolution, and the beeper as
+//! a square wave.  Save states are the chip's own, and core options choose
+//! the platform, each quirk, the speed and the palette.
+//!
+//! Libretro cores are global, so the core and the frontend's callbacks are
+//! kept in statics.
+
+#![allow(clippy::missing_safety_doc)]
+// Fields are written `name: name`, like the emulator's
+#![allow(clippy::redundant_field_names)]
+
+extern crate chip8;
+
+pub mod sys;
+
+use chip8::platform::{Platform, Quirks, QUIRK_NAMES};
+use chip8::render::Palette;
+use chip8::Chip8;
+use std::ffi::CStr;
+use std::mem;
+use std::os::raw::{c_char, c_uint, c_void};
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+use std::slice;
+use std::sync::{Mutex, MutexGuard};
+use sys::*;
+
+/// The rate the beeper is sampled at (in Hz).
+const SAMPLE_RATE: u32 = 44100;
+
+/// The number of audio samples in each 60Hz frame.
+const SAMPLES_PER_FRAME: usize = (SAMPLE_RATE / chip8::TIMER_RATE) as usize;
+
+/// The pitch of the beep (in Hz), the same as the SDL window's.
+const BEEP_PITCH: u32 = 440;
+
+/// The loudness of the beep, a quarter of the most a sample can be.
+const BEEP_VOLUME: i16 = i16::MAX / 4;
+
+/// The RetroPad button for each of the chip's keys, and how the button is
+/// described to the frontend.
+const BUTTONS: [(c_uint, u8, &str); 16] = [
+    (RETRO_DEVICE_ID_JOYPAD_UP, 0x2, "Key 2\0"),
+    (RETRO_DEVICE_ID_JOYPAD_DOWN, 0x8, "Key 8\0"),
+    (RETRO_DEVICE_ID_JOYPAD_LEFT, 0x4, "Key 4\0"),
+    (RETRO_DEVICE_ID_JOYPAD_RIGHT, 0x6, "Key 6\0"),
+    (RETRO_DEVICE_ID_JOYPAD_A, 0x5, "Key 5\0"),
+    (RETRO_DEVICE_ID_JOYPAD_B, 0x0, "Key 0\0"),
+    (RETRO_DEVICE_ID_JOYPAD_X, 0x1, "Key 1\0"),
+    (RETRO_DEVICE_ID_JOYPAD_Y, 0x3, "Key 3\0"),
+    (RETRO_DEVICE_ID_JOYPAD_L, 0x7, "Key 7\0"),
+    (RETRO_DEVICE_ID_JOYPAD_R, 0x9, "Key 9\0"),
+    (RETRO_DEVICE_ID_JOYPAD_L2, 0xA, "Key A\0"),
+    (RETRO_DEVICE_ID_JOYPAD_R2, 0xB, "Key B\0"),
+    (RETRO_DEVICE_ID_JOYPAD_L3, 0xC, "Key C\0"),
+    (RETRO_DEVICE_ID_JOYPAD_R3, 0xD, "Key D\0"),
+    (RETRO_DEVICE_ID_JOYPAD_SELECT, 0xE, "Key E\0"),
+    (RETRO_DEVICE_ID_JOYPAD_START, 0xF, "Key F\0"),
+];
+
+/// The core options, as keys and descriptions listing the choices with the
+/// default first.
+const VARIABLES: [(&str, &str); 9] = [
+    ("chip8_platform\0", "Platform (restarts the game); default|chip8|schip|xochip\0"),
+    ("chip8_ipf\0", "Instructions per frame; 10|15|20|30|50|100|200|500|1000|5\0"),
+    ("chip8_palette\0", "Palette; yellow|white|green|amber|inverted\0"),
+    ("chip8_quirk_shift\0", "Shifts use VY; platform|on|off\0"),
+    ("chip8_quirk_load_store\0", "FX55/FX65 move the index; platform|on|off\0"),
+    ("chip8_quirk_jump\0", "BNNN jumps to NNN + VX; platform|on|off\0"),
+    ("chip8_quirk_vf_reset\0", "Logic opcodes reset VF; platform|on|off\0"),
+    ("chip8_quirk_wrap\0", "Sprites wrap around the edges; platform|on|off\0"),
+    ("chip8_quirk_display_wait\0", "Drawing waits for the next frame; platform|on|off\0"),
+];
+
+/// The palettes to choose from, by name.
+const PALETTES: [(&str, &str); 5] = [
+    ("yellow", "000000,ffff00"),
+    ("white", "000000,ffffff"),
+    ("green", "0f380f,9bbc0f"),
+    ("amber", "000000,ffb000"),
+    ("inverted", "ffffff,000000"),
+];
+
+/// The frontend's callbacks, which it gives the core before anything else.
+struct Frontend {
+    environment: Option<retro_environment_t>,
+    video: Option<retro_video_refresh_t>,
+    audio_batch: Option<retro_audio_sample_batch_t>,
+    input_poll: Option<retro_input_poll_t>,
+    input_state: Option<retro_input_state_t>,
+}
+
+static FRONTEND: Mutex<Frontend> = Mutex::new(Frontend {
+    environment: None,
+    video: None,
+    audio_batch: None,
+    input_poll: None,
+    input_state: None,
+});
+
+/// The settings chosen in the core options.
+#[derive(Clone, Debug, PartialEq)]
+struct Options {
+    platform: Option<Platform>,
+    ipf: u32,
+    palette: Palette,
+    /// Quirk overrides for `Quirks::apply`, such as `wrap=on`.
+    quirks: Vec<String>,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            platform: None,
+            ipf: 10,
+            palette: PALETTES[0].1.parse().unwrap(),
+            quirks: Vec::new(),
+        }
+    }
+}
+
+impl Options {
+    /// Reads the options from the frontend, keeping the defaults for any it
+    /// doesn't know or that have unknown values.
+    fn read(environment: retro_environment_t) -> Self {
+        let mut options = Options::default();
+        for &(key, _) in VARIABLES.iter() {
+            let mut variable = retro_variable {
+                key: key.as_ptr() as *const c_char,
+                value: ptr::null(),
+            };
+            let found = environment(RETRO_ENVIRONMENT_GET_VARIABLE,
+                                    &mut variable as *mut _ as *mut c_void);
+            if !found || variable.value.is_null() {
+                continue;
+            }
+            let value = unsafe { CStr::from_ptr(variable.value) }.to_string_lossy();
+            options.set(&key[..key.len() - 1], &value);
+        }
+        options
+    }
+
+    /// Changes the option with the given key, ignoring unknown keys and
+    /// values.
+    fn set(&mut self, key: &str, value: &str) {
+        match key {
+            "chip8_platform" => self.platform = Platform::from_name(value),
+            "chip8_ipf" => self.ipf = value.parse().unwrap_or(self.ipf),
+            "chip8_palette" => {
+                if let Some(&(_, colors)) = PALETTES.iter().find(|&&(name, _)| name == value) {
+                    self.palette = colors.parse().unwrap();
+                }
+            }
+            _ => {
+                let quirk = key.trim_start_matches("chip8_quirk_").replace('_', "-");
+                let known = QUIRK_NAMES.contains(&quirk.as_str());
+                if known && (value == "on" || value == "off") {
+                    self.quirks.push(format!("{}={}", quirk, value));
+                }
+            }
+        }
+    }
+
+    /// The quirks to run with, which are the platform's with any overrides.
+    fn quirks(&self) -> Quirks {
+        let mut quirks = self.platform.map(|p| p.quirks()).unwrap_or_default();
+        for overrides in &self.quirks {
+            quirks.apply(overrides).unwrap();
+        }
+        quirks
+    }
+}
+
+/// A loaded game.
+struct Core {
+    chip: Chip8,
+    game: Vec<u8>,
+    options: Options,
+    /// The display as XRGB8888, row by row.
+    video: Vec<u32>,
+    /// How far through a cycle of the beep's square wave the audio is, in
+    /// samples.
+    phase: u32,
+    /// The audio for a frame, as interleaved left and right samples.
+    audio: Vec<i16>,
+    /// Whether the game has run a bad opcode, after which it is stopped.
+    crashed: bool,
+}
+
+impl Core {
+    /// Boots the game with the given options.
+    fn new(game: Vec<u8>, options: Options) -> Result<Self, chip8::Error> {
+        let mut chip = Chip8::default();
+        // Resizing memory for another platform or a save state then never
+        // moves it
+        chip.memory_bus().reserve_capacity();
+        let mut core = Core {
+            chip: chip,
+            game: game,
+            options: options,
+            video: Vec::new(),
+            phase: 0,
+            audio: vec![0; SAMPLES_PER_FRAME * 2],
+            crashed: false,
+        };
+        core.boot()?;
+        Ok(core)
+    }
+
+    /// Loads the game into a fresh chip.  The old chip's memory is kept,
+    /// with the new chip's bytes, so that the frontend's pointer to it from
+    /// `retro_get_memory_data` stays good.
+    fn boot(&mut self) -> Result<(), chip8::Error> {
+        let mut chip = Chip8::default();
+        if let Some(platform) = self.options.platform {
+            chip.set_memory_size(platform.memory_size());
+        }
+        chip.quirks = self.options.quirks();
+        chip.set_seed(chip8::random_seed());
+        chip.load_rom(&self.game, chip8::PROGRAM_START)?;
+
+        let bytes = chip.memory().to_vec();
+        mem::swap(chip.memory_bus(), self.chip.memory_bus());
+        let memory = chip.memory_bus();
+        memory.resize(bytes.len());
+        memory.bytes_mut().copy_from_slice(&bytes);
+        self.chip = chip;
+        self.crashed = false;
+        Ok(())
+    }
+
+    /// Switches to new options, restarting the game if the platform changed.
+    fn set_options(&mut self, options: Options) {
+        let restart = options.platform != self.options.platform;
+        self.options = options;
+        self.chip.quirks = self.options.quirks();
+        if restart {
+            // The game fitted before, so it can only fail to fit in a smaller
+            // memory, which the old chip is kept for
+            let _ = self.boot();
+        }
+    }
+
+    /// Runs a frame, unless the game has crashed.
+    fn run_frame(&mut self) {
+        if self.crashed {
+            return;
+        }
+        let chip = &mut self.chip;
+        let ipf = self.options.ipf;
+        if panic::catch_unwind(AssertUnwindSafe(|| chip.emulate_frame(ipf))).is_err() {
+            self.crashed = true;
+        }
+    }
+
+    /// Draws the display into `video`.
+    fn render(&mut self) {
+        let graphics = &self.chip.graphics;
+        let palette = &self.options.palette;
+        self.video.clear();
+        self.video.extend(graphics.pixels().map(|pixel| {
+            let [red, green, blue] = palette.color(pixel);
+            (red as u32) << 16 | (green as u32) << 8 | blue as u32
+        }));
+    }
+
+    /// Fills `audio` with a frame of the beep, or silence.
+    fn make_audio(&mut self) {
+        let period = SAMPLE_RATE / BEEP_PITCH;
+        for frame in self.audio.chunks_mut(2) {
+            let sample = if !self.chip.make_sound {
+                0
+            } else if self.phase < period / 2 {
+                BEEP_VOLUME
+            } else {
+                -BEEP_VOLUME
+            };
+            frame[0] = sample;
+            frame[1] = sample;
+            self.phase = (self.phase + 1) % period;
+        }
+    }
+}
+
+static CORE: Mutex<Option<Core>> = Mutex::new(None);
+
+/// Locks a static, carrying on even if a callback panicked while it was held.
+fn lock<T>(mutex: &'static Mutex<T>) -> MutexGuard<'static, T> {
+    mutex.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+#[no_mangle]
+pub extern "C" fn retro_api_version() -> c_uint {
+    RETRO_API_VERSION
+}
+
+#[no_mangle]
+pub extern "C" fn retro_set_environment(environment: retro_environment_t) {
+    lock(&FRONTEND).environment = Some(environment);
+    let mut variables: Vec<retro_variable> = VARIABLES.iter()
+        .map(|&(key, value)| {
+            retro_variable {
+                key: key.as_ptr() as *const c_char,
+                value: value.as_ptr() as *const c_char,
+            }
+        })
+        .collect();
+    variables.push(retro_variable { key: ptr::null(), value: ptr::null() });
+    environment(RETRO_ENVIRONMENT_SET_VARIABLES, variables.as_mut_ptr() as *mut c_void);
+}
+
+#[no_mangle]
+pub extern "C" fn retro_set_video_refresh(video: retro_video_refresh_t) {
+    lock(&FRONTEND).video = Some(video);
+}
+
+#[no_mangle]
+pub extern "C" fn retro_set_audio_sample(_: retro_audio_sample_t) {}
+
+#[no_mangle]
+pub extern "C" fn retro_set_audio_sample_batch(audio_batch: retro_audio_sample_batch_t) {
+    lock(&FRONTEND).audio_batch = Some(audio_batch);
+}
+
+#[no_mangle]
+pub extern "C" fn retro_set_input_poll(input_poll: retro_input_poll_t) {
+    lock(&FRONTEND).input_poll = Some(input_poll);
+}
+
+#[no_mangle]
+pub extern "C" fn retro_set_input_state(input_state: retro_input_state_t) {
+    lock(&FRONTEND).input_state = Some(input_state);
+}
+
+#[no_mangle]
+pub extern "C" fn retro_init() {}
+
+#[no_mangle]
+pub extern "C" fn retro_deinit() {
+    *lock(&CORE) = None;
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn retro_get_system_info(info: *mut retro_system_info) {
+    *info = retro_system_info {
+        library_name: "chip8\0".as_ptr() as *const c_char,
+        library_version: concat!(env!("CARGO_PKG_VERSION"), "\0").as_ptr() as *const c_char,
+        valid_extensions: "ch8|c8|sc8|xo8|rom|bin\0".as_ptr() as *const c_char,
+        need_fullpath: false,
+        block_extract: false,
+    };
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn retro_get_system_av_info(info: *mut retro_system_av_info) {
+    use chip8::framebuffer::{MAX_HEIGHT, MAX_WIDTH};
+    *info = retro_system_av_info {
+        geometry: retro_game_geometry {
+            base_width: chip8::WIDTH,
+            base_height: chip8::HEIGHT,
+            max_width: MAX_WIDTH,
+            max_height: MAX_HEIGHT,
+            aspect_ratio: 2.0,
+        },
+        timing: retro_system_timing {
+            fps: chip8::TIMER_RATE as f64,
+            sample_rate: SAMPLE_RATE as f64,
+        },
+    };
+}
+
+#[no_mangle]
+pub extern "C" fn retro_set_controller_port_device(_: c_uint, _: c_uint) {}
+
+#[no_mangle]
+pub extern "C" fn retro_reset() {
+    if let Some(ref mut core) = *lock(&CORE) {
+        // The game loaded before with these options, so it still does
+        let _ = core.boot();
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn retro_run() {
+    let (environment, video, audio_batch, input_poll, input_state) = {
+        let frontend = lock(&FRONTEND);
+        (frontend.environment,
+         frontend.video,
+         frontend.audio_batch,
+         frontend.input_poll,
+         frontend.input_state)
+    };
+    let mut core = lock(&CORE);
+    let core = match *core {
+        Some(ref mut core) => core,
+        None => return,
+    };
+
+    if let Some(environment) = environment {
+        let mut updated = false;
+        let asked = environment(RETRO_ENVIRONMENT_GET_VARIABLE_UPDATE,
+                                &mut updated as *mut bool as *mut c_void);
+        if asked && updated {
+            core.set_options(Options::read(environment));
+        }
+    }
+
+    if let (Some(input_poll), Some(input_state)) = (input_poll, input_state) {
+        input_poll();
+        for &(button, key, _) in BUTTONS.iter() {
+            let pressed = input_state(0, RETRO_DEVICE_JOYPAD, 0, button) != 0;
+            core.chip.key[key as usize] = pressed as u8;
+        }
+    }
+
+    core.run_frame();
+
+    if let Some(video) = video {
+        core.render();
+        let width = core.chip.graphics.width();
+        video(core.video.as_ptr() as *const c_void,
+              width,
+              core.chip.graphics.height(),
+              width as usize * 4);
+    }
+    if let Some(audio_batch) = audio_batch {
+        core.make_audio();
+        audio_batch(core.audio.as_ptr(), SAMPLES_PER_FRAME);
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn retro_serialize_size() -> usize {
+    match *lock(&CORE) {
+        Some(ref core) => core.chip.state_size(),
+        None => 0,
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn retro_serialize(data: *mut c_void, size: usize) -> bool {
+    match *lock(&CORE) {
+        Some(ref core) => {
+            let out = slice::from_raw_parts_mut(data as *mut u8, size);
+            core.chip.save_state(out).is_ok()
+        }
+        None => false,
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn retro_unserialize(data: *const c_void, size: usize) -> bool {
+    match *lock(&CORE) {
+        Some(ref mut core) => {
+            let state = slice::from_raw_parts(data as *const u8, size);
+            let loaded = core.chip.load_state(state).is_ok();
+            if loaded {
+                core.crashed = false;
+            }
+            loaded
+        }
+        None => false,
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn retro_cheat_reset() {}
+
+#[no_mangle]
+pub extern "C" fn retro_cheat_set(_: c_uint, _: bool, _: *const c_char) {}
+
+#[no_mangle]
+pub unsafe extern "C" fn retro_load_game(game: *const retro_game_info) -> bool {
+    let environment = match lock(&FRONTEND).environment {
+        Some(environment) => environment,
+        None => return false,
+    };
+    if game.is_null() || (*game).data.is_null() || (*game).size == 0 {
+        return false;
+    }
+
+    let mut format = RETRO_PIXEL_FORMAT_XRGB8888;
+    if !environment(RETRO_ENVIRONMENT_SET_PIXEL_FORMAT, &mut format as *mut c_uint as *mut c_void) {
+        return false;
+    }
+    let mut descriptors: Vec<retro_input_descriptor> = BUTTONS.iter()
+        .map(|&(button, _, description)| {
+            retro_input_descriptor {
+                port: 0,
+                device: RETRO_DEVICE_JOYPAD,
+                index: 0,
+                id: button,
+                description: description.as_ptr() as *const c_char,
+            }
+        })
+        .collect();
+    descriptors.push(retro_input_descriptor {
+        port: 0,
+        device: 0,
+        index: 0,
+        id: 0,
+        description: ptr::null(),
+    });
+    environment(RETRO_ENVIRONMENT_SET_INPUT_DESCRIPTORS,
+                descriptors.as_mut_ptr() as *mut c_void);
+
+    let data = slice::from_raw_parts((*game).data as *const u8, (*game).size).to_vec();
+    match Core::new(data, Options::read(environment)) {
+        Ok(core) => {
+            *lock(&CORE) = Some(core);
+            true
+        }
+        Err(_) => false,
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn retro_load_game_special(_: c_uint, _: *const retro_game_info, _: usize) -> bool {
+    false
+}
+
+#[no_mangle]
+pub extern "C" fn retro_unload_game() {
+    *lock(&CORE) = None;
+}
+
+#[no_mangle]
+pub extern "C" fn retro_get_region() -> c_uint {
+    RETRO_REGION_NTSC
+}
+
+#[no_mangle]
+pub extern "C" fn retro_get_memory_data(id: c_uint) -> *mut c_void {
+    match *lock(&CORE) {
+        // The memory stays put through resets, platform changes and save
+        // states until the game is unloaded, so the pointer lasts as long as
+        // the frontend is allowed to keep it
+        Some(ref mut core) if id == RETRO_MEMORY_SYSTEM_RAM => {
+            core.chip.memory_bus().bytes_mut().as_mut_ptr() as *mut c_void
+        }
+        _ => ptr::null_mut(),
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn retro_get_memory_size(id: c_uint) -> usize {
+    match *lock(&CORE) {
+        Some(ref core) if id == RETRO_MEMORY_SYSTEM_RAM => core.chip.memory().len(),
+        _ => 0,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Options, BUTTONS};
+
+    #[test]
+    fn every_key_has_a_button() {
+        let mut keys: Vec<u8> = BUTTONS.iter().map(|&(_, key, _)| key).collect();
+        keys.sort();
+        assert_eq!(keys, (0..16).collect::<Vec<u8>>());
+    }
+
+    #[test]
+    fn reads_options() {
+        let mut options = Options::default();
+        options.set("chip8_platform", "schip");
+        options.set("chip8_ipf", "30");
+        options.set("chip8_palette", "inverted");
+        options.set("chip8_quirk_display_wait", "on");
+        options.set("chip8_quirk_vf_reset", "platform");
+        assert_eq!(options.ipf, 30);
+        assert_eq!(options.palette.background, [0xFF, 0xFF, 0xFF]);
+        let quirks = options.quirks();
+        assert!(quirks.display_wait);
+        assert_eq!(quirks.logic_resets_vf, chip8::platform::Platform::SuperChip.quirks().logic_resets_vf);
+
+        options.set("chip8_ipf", "fast");
+        options.set("chip8_palette", "plaid");
+        assert_eq!(options.ipf, 30);
+        assert_eq!(options.palette.background, [0xFF, 0xFF, 0xFF]);
+    }
+}
@@ -0,0 +1,339 @@
+//! Drives the core the way a libretro frontend would, through a stub that
+//! records the video and audio it is sent and answers questions about the
+//! core options from a list the test controls.
+
+extern crate chip8;
+extern crate chip8_libretro;
+
+use chip8_libretro::sys::*;
+use chip8_libretro::*;
+use std::ffi::{CStr, CString};
+use std::os::raw::{c_uint, c_void};
+use std::ptr;
+use std::sync::{Mutex, MutexGuard};
+
+/// What the stub frontend has been told, and what it will answer.
+struct Stub {
+    /// The core options the core declared, as keys and descriptions.
+    declared: Vec<(String, String)>,
+    /// The values the frontend gives for core options.
+    options: Vec<(CString, CString)>,
+    /// Whether the options have changed since the core last asked.
+    updated: bool,
+    pixel_format: Option<c_uint>,
+    descriptors: usize,
+    /// The last frame, in XRGB8888.
+    frame: Vec<u32>,
+    width: u32,
+    height: u32,
+    /// The last frame's audio, as interleaved left and right samples.
+    audio: Vec<i16>,
+    /// The RetroPad buttons held, with a bit per button.
+    buttons: u16,
+}
+
+static STUB: Mutex<Stub> = Mutex::new(Stub {
+    declared: Vec::new(),
+    options: Vec::new(),
+    updated: false,
+    pixel_format: None,
+    descriptors: 0,
+    frame: Vec::new(),
+    width: 0,
+    height: 0,
+    audio: Vec::new(),
+    buttons: 0,
+});
+
+/// Keeps tests from sharing the core, which is global, at the same time.
+static SERIAL: Mutex<()> = Mutex::new(());
+
+fn stub() -> MutexGuard<'static, Stub> {
+    STUB.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+extern "C" fn environment(cmd: c_uint, data: *mut c_void) -> bool {
+    let mut stub = stub();
+    unsafe {
+        match cmd {
+            RETRO_ENVIRONMENT_SET_PIXEL_FORMAT => {
+                stub.pixel_format = Some(*(data as *const c_uint));
+                true
+            }
+            RETRO_ENVIRONMENT_SET_INPUT_DESCRIPTORS => {
+                let mut descriptor = data as *const retro_input_descriptor;
+                while !(*descriptor).description.is_null() {
+                    stub.descriptors += 1;
+                    descriptor = descriptor.offset(1);
+                }
+                true
+            }
+            RETRO_ENVIRONMENT_SET_VARIABLES => {
+                let mut variable = data as *const retro_variable;
+                while !(*variable).key.is_null() {
+                    let key = CStr::from_ptr((*variable).key).to_string_lossy().into_owned();
+                    let value = CStr::from_ptr((*variable).value).to_string_lossy().into_owned();
+                    stub.declared.push((key, value));
+                    variable = variable.offset(1);
+                }
+                true
+            }
+            RETRO_ENVIRONMENT_GET_VARIABLE => {
+                let variable = &mut *(data as *mut retro_variable);
+                let key = CStr::from_ptr(variable.key);
+                match stub.options.iter().find(|(k, _)| k.as_c_str() == key) {
+                    Some((_, value)) => {
+                        variable.value = value.as_ptr();
+                        true
+                    }
+                    None => false,
+                }
+            }
+            RETRO_ENVIRONMENT_GET_VARIABLE_UPDATE => {
+                *(data as *mut bool) = stub.updated;
+                stub.updated = false;
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+extern "C" fn video(data: *const c_void, width: c_uint, height: c_uint, pitch: usize) {
+    assert_eq!(pitch, width as usize * 4);
+    let pixels = unsafe { std::slice::from_raw_parts(data as *const u32, (width * height) as usize) };
+    let mut stub = stub();
+    stub.frame = pixels.to_vec();
+    stub.width = width;
+    stub.height = height;
+}
+
+extern "C" fn audio_sample(_: i16, _: i16) {
+    panic!("The core should send audio in batches");
+}
+
+extern "C" fn audio_batch(data: *const i16, frames: usize) -> usize {
+    let samples = unsafe { std::slice::from_raw_parts(data, frames * 2) };
+    stub().audio = samples.to_vec();
+    frames
+}
+
+extern "C" fn input_poll() {}
+
+extern "C" fn input_state(port: c_uint, device: c_uint, _: c_uint, id: c_uint) -> i16 {
+    assert_eq!((port, device), (0, RETRO_DEVICE_JOYPAD));
+    (stub().buttons >> id & 1) as i16
+}
+
+/// Starts the core with the given game and core options, the way a frontend
+/// would, returning whether the game loaded.
+fn start(game: &[u8], options: &[(&str, &str)]) -> bool {
+    {
+        let mut stub = stub();
+        stub.declared.clear();
+        stub.options = options.iter()
+            .map(|&(key, value)| (CString::new(key).unwrap(), CString::new(value).unwrap()))
+            .collect();
+        stub.updated = false;
+        stub.pixel_format = None;
+        stub.descriptors = 0;
+        stub.buttons = 0;
+    }
+    retro_set_environment(environment);
+    retro_set_video_refresh(video);
+    retro_set_audio_sample(audio_sample);
+    retro_set_audio_sample_batch(audio_batch);
+    retro_set_input_poll(input_poll);
+    retro_set_input_state(input_state);
+    retro_init();
+    let info = retro_game_info {
+        path: ptr::null(),
+        data: game.as_ptr() as *const c_void,
+        size: game.len(),
+        meta: ptr::null(),
+    };
+    unsafe { retro_load_game(&info) }
+}
+
+/// Changes a core option while the game is running.
+fn set_option(key: &str, value: &str) {
+    let mut stub = stub();
+    let key = CString::new(key).unwrap();
+    stub.options.retain(|(k, _)| *k != key);
+    stub.options.push((key, CString::new(value).unwrap()));
+    stub.updated = true;
+}
+
+fn stop() {
+    retro_unload_game();
+    retro_deinit();
+}
+
+#[test]
+fn describes_itself() {
+    let _serial = SERIAL.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    assert_eq!(retro_api_version(), RETRO_API_VERSION);
+    unsafe {
+        let mut info: retro_system_info = std::mem::zeroed();
+        retro_get_system_info(&mut info);
+        assert_eq!(CStr::from_ptr(info.library_name).to_str(), Ok("chip8"));
+        assert_eq!(CStr::from_ptr(info.library_version).to_str(), Ok(chip8::version()));
+        assert!(!info.need_fullpath);
+
+        let mut av: retro_system_av_info = std::mem::zeroed();
+        retro_get_system_av_info(&mut av);
+        assert_eq!((av.geometry.base_width, av.geometry.base_height), (64, 32));
+        assert_eq!((av.geometry.max_width, av.geometry.max_height), (128, 64));
+        assert_eq!(av.timing.fps, 60.0);
+        assert_eq!(av.timing.sample_rate, 44100.0);
+    }
+    assert_eq!(retro_serialize_size(), 0);
+    assert!(!unsafe { retro_load_game(ptr::null()) });
+}
+
+#[test]
+fn plays_like_the_emulator() {
+    let _serial = SERIAL.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    let pong = include_bytes!("../../PONG");
+    assert!(start(pong, &[]));
+    {
+        let stub = stub();
+        assert_eq!(stub.pixel_format, Some(RETRO_PIXEL_FORMAT_XRGB8888));
+        assert_eq!(stub.descriptors, 16);
+        assert!(stub.declared.iter().any(|(key, _)| key == "chip8_palette"));
+    }
+
+    let mut chip = chip8::Chip8::default();
+    chip.load_rom(pong, chip8::PROGRAM_START).unwrap();
+    for frame in 0..90 {
+        // X is key 1, which moves PONG's left paddle up
+        let held = frame % 30 < 15;
+        stub().buttons = (held as u16) << RETRO_DEVICE_ID_JOYPAD_X;
+        chip.key[1] = held as u8;
+        retro_run();
+        chip.emulate_frame(10);
+    }
+
+    let stub = stub();
+    assert_eq!((stub.width, stub.height), (64, 32));
+    let expected: Vec<u32> = chip.graphics
+        .pixels()
+        .map(|pixel| if pixel != 0 { 0xFFFF00 } else { 0 })
+        .collect();
+    assert_eq!(stub.frame, expected);
+    assert_eq!(stub.audio.len(), 735 * 2);
+    drop(stub);
+    stop();
+}
+
+#[test]
+fn saves_and_loads_states() {
+    let _serial = SERIAL.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    assert!(start(include_bytes!("../../PONG"), &[]));
+    for _ in 0..30 {
+        retro_run();
+    }
+    let mut state = vec![0u8; retro_serialize_size()];
+    assert!(unsafe { retro_serialize(state.as_mut_ptr() as *mut c_void, state.len()) });
+    for _ in 0..30 {
+        retro_run();
+    }
+    let later = stub().frame.clone();
+
+    for _ in 0..45 {
+        retro_run();
+    }
+    assert!(unsafe { retro_unserialize(state.as_ptr() as *const c_void, state.len()) });
+    for _ in 0..30 {
+        retro_run();
+    }
+    assert_eq!(stub().frame, later);
+    assert!(!unsafe { retro_unserialize(state.as_ptr() as *const c_void, 10) });
+    stop();
+}
+
+#[test]
+fn follows_core_options() {
+    let _serial = SERIAL.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    // Sets VF to 1, then ORs it with V0, which resets VF with the vf-reset
+    // quirk, then stops
+    let program = [0x6F, 0x01, 0x80, 0x01, 0x12, 0x04];
+    assert!(start(&program, &[("chip8_palette", "inverted"), ("chip8_quirk_vf_reset", "on")]));
+    retro_run();
+    assert!(stub().frame.iter().all(|&pixel| pixel == 0xFFFFFF));
+    assert_eq!(retro_get_memory_size(RETRO_MEMORY_SYSTEM_RAM), 4096);
+
+    set_option("chip8_palette", "green");
+    set_option("chip8_platform", "xochip");
+    retro_run();
+    assert!(stub().frame.iter().all(|&pixel| pixel == 0x0F380F));
+    assert_eq!(retro_get_memory_size(RETRO_MEMORY_SYSTEM_RAM), 0x10000);
+    let memory = retro_get_memory_data(RETRO_MEMORY_SYSTEM_RAM) as *const u8;
+    assert_eq!(unsafe { *memory.offset(0x200) }, 0x6F);
+    stop();
+}
+
+#[test]
+fn keeps_memory_in_place() {
+    let _serial = SERIAL.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    // Counts up in V0 and stores it at 0x300, forever
+    let program = [0xA3, 0x00, 0x70, 0x01, 0xF0, 0x55, 0x12, 0x02];
+    assert!(start(&program, &[]));
+    let memory = retro_get_memory_data(RETRO_MEMORY_SYSTEM_RAM) as *const u8;
+    retro_run();
+    assert_ne!(unsafe { *memory.offset(0x300) }, 0);
+    let mut state = vec![0u8; retro_serialize_size()];
+    assert!(unsafe { retro_serialize(state.as_mut_ptr() as *mut c_void, state.len()) });
+
+    // A reset starts the count again in the same memory
+    retro_reset();
+    assert_eq!(retro_get_memory_data(RETRO_MEMORY_SYSTEM_RAM) as *const u8, memory);
+    assert_eq!(unsafe { *memory.offset(0x300) }, 0);
+    retro_run();
+    assert_eq!(unsafe { *memory.offset(0x202) }, 0x70);
+    assert_ne!(unsafe { *memory.offset(0x300) }, 0);
+
+    // So do switching to a bigger memory and loading a smaller one's state
+    set_option("chip8_platform", "xochip");
+    retro_run();
+    assert_eq!(retro_get_memory_size(RETRO_MEMORY_SYSTEM_RAM), 0x10000);
+    assert_eq!(retro_get_memory_data(RETRO_MEMORY_SYSTEM_RAM) as *const u8, memory);
+    assert_eq!(unsafe { *memory.offset(0x200) }, 0xA3);
+    assert!(unsafe { retro_unserialize(state.as_ptr() as *const c_void, state.len()) });
+    assert_eq!(retro_get_memory_size(RETRO_MEMORY_SYSTEM_RAM), 4096);
+    assert_eq!(retro_get_memory_data(RETRO_MEMORY_SYSTEM_RAM) as *const u8, memory);
+    assert_eq!(unsafe { *memory.offset(0x202) }, 0x70);
+    stop();
+}
+
+#[test]
+fn beeps() {
+    let _serial = SERIAL.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    // Sets the sound timer to 4, then stops, so the beep is heard when the
+    // timer runs out
+    let program = [0x60, 0x04, 0xF0, 0x18, 0x12, 0x04];
+    assert!(start(&program, &[]));
+    let mut beeps = Vec::new();
+    for _ in 0..8 {
+        retro_run();
+        let stub = stub();
+        assert_eq!(stub.audio.len(), 735 * 2);
+        assert!(stub.audio.chunks(2).all(|frame| frame[0] == frame[1]));
+        let beeping = stub.audio.iter().any(|&sample| sample > 0);
+        assert_eq!(beeping, stub.audio.iter().any(|&sample| sample < 0));
+        beeps.push(beeping);
+    }
+    assert_eq!(beeps, [false, false, false, true, false, false, false, false]);
+    stop();
+}
+
+#[test]
+fn stops_on_bad_opcodes() {
+    let _serial = SERIAL.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    assert!(start(&[0x60, 0x01, 0xFF, 0xFF], &[]));
+    retro_run();
+    retro_run();
+    retro_reset();
+    retro_run();
+    stop();
+}
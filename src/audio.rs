@@ -16,7 +16,7 @@ impl AudioCallback for SquareWave {
         // Generate a square wave
         for x in out.iter_mut() {
             *x = match self.phase {
-                0.0...0.5 => self.volume,
+                0.0..=0.5 => self.volume,
                 _ => -self.volume
             };
             self.phase = (self.phase + self.phase_inc) % 1.0;
@@ -32,8 +32,9 @@ pub struct Beeper {
 }
 
 impl Beeper {
-    /// Constructs a Beeper using the give SDL context.
-    pub fn new(context: &Sdl, duration: Duration) -> Self {
+    /// Constructs a Beeper using the give SDL context, which beeps for at
+    /// least the given duration at the given volume (from 0.0 to 1.0).
+    pub fn new(context: &Sdl, duration: Duration, volume: f32) -> Self {
         let desired_spec = AudioSpecDesired {
             freq: Some(44100),
             channels: Some(1),
@@ -48,7 +49,7 @@ impl Beeper {
             SquareWave {
                 phase_inc: 440.0 / spec.freq as f32,
                 phase: 0.0,
-                volume: 0.25
+                volume: volume
             }
         }).unwrap();
 
@@ -0,0 +1,289 @@
+//! Runs many chips at once on a pool of worker threads, such as to try a set
+//! of test ROMs under every platform's quirks.  Each job is a chip that has
+//! already been set up with its ROM, quirks and seed, along with how many
+//! frames to run it for and the keys to press along the way.
+
+use framebuffer::Framebuffer;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use {Chip8, CpuState};
+
+/// A key being pressed or released at the start of a frame.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct KeyEvent {
+    /// The frame the key changes on, counting from 0.
+    pub frame: u64,
+    /// The chip's key, from 0x0 to 0xF.
+    pub key: u8,
+    /// Whether the key is pressed, rather than released.
+    pub pressed: bool,
+}
+
+/// The keys to press and release during a run.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct InputScript {
+    events: Vec<KeyEvent>,
+}
+
+impl InputScript {
+    /// Constructs a script from the given events, in any order.
+    pub fn new(mut events: Vec<KeyEvent>) -> Self {
+        events.sort_by_key(|event| event.frame);
+        InputScript { events: events }
+    }
+
+    /// Parses a script with an event per line, made of the frame, the key in
+    /// hex and `down` or `up`, such as `120 5 down`.  Blank lines and lines
+    /// starting with `#` are skipped.
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut events = Vec::new();
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let parts: Vec<&str> = line.split_whitespace().collect();
+            let event = match parts[..] {
+                [frame, key, state] => {
+                    let frame = frame.parse().map_err(|_| format!("Bad frame {}", frame));
+                    let key = u8::from_str_radix(key, 16)
+                        .ok()
+                        .filter(|&key| key < 16)
+                        .ok_or_else(|| format!("Bad key {}", key));
+                    let pressed = match state {
+                        "down" => Ok(true),
+                        "up" => Ok(false),
+                        _ => Err(format!("Expected down or up, not {}", state)),
+                    };
+                    frame.and_then(|frame| {
+                        Ok(KeyEvent {
+                            frame: frame,
+                            key: key?,
+                            pressed: pressed?,
+                        })
+                    })
+                }
+                _ => Err("Expected a frame, a key and down or up".to_string()),
+            };
+            events.push(event.map_err(|why| format!("Line {}: {}", i + 1, why))?);
+        }
+        Ok(InputScript::new(events))
+    }
+
+    /// The events, in the order they happen.
+    pub fn events(&self) -> &[KeyEvent] {
+        &self.events
+    }
+}
+
+/// A chip to run, and how to run it.
+#[derive(Clone, Debug)]
+pub struct Job {
+    /// A name for the job, to tell the outcomes apart.
+    pub name: String,
+    /// The chip, with its ROM loaded and its quirks set.
+    pub chip: Chip8,
+    /// The number of instructions run per 60Hz frame.
+    pub ipf: u32,
+    /// The most frames to run.
+    pub frames: u64,
+    /// The keys to press along the way.
+    pub input: InputScript,
+}
+
+/// Why a job stopped.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Exit {
+    /// It ran every frame it was given.
+    Finished,
+    /// It reached a jump to itself, which is how most programs stop.
+    Halted,
+    /// It panicked, usually on a bad opcode, with the given message.
+    Crashed(String),
+}
+
+/// Measurements of a job's run.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Stats {
+    /// The number of frames run.
+    pub frames: u64,
+    /// The number of opcodes run.
+    pub cycles: u64,
+    /// How long the run took.
+    pub elapsed: Duration,
+}
+
+/// What happened to a job.
+#[derive(Clone, Debug)]
+pub struct Outcome {
+    /// The job's name.
+    pub name: String,
+    /// Why the job stopped.
+    pub exit: Exit,
+    /// The display when the job stopped.
+    pub framebuffer: Framebuffer,
+    /// The chip's registers when the job stopped.
+    pub cpu: CpuState,
+    /// Measurements of the run.
+    pub stats: Stats,
+}
+
+impl Outcome {
+    /// Whether the job panicked.
+    pub fn crashed(&self) -> bool {
+        if let Exit::Crashed(_) = self.exit {
+            return true;
+        }
+        false
+    }
+}
+
+/// Runs a single job on this thread.
+pub fn run_job(job: Job) -> Outcome {
+    let Job { name, mut chip, ipf, frames, input } = job;
+    let start = Instant::now();
+    let cycles = chip.cycles();
+    let mut events = input.events().iter().peekable();
+    let mut frame = 0;
+    let mut exit = Exit::Finished;
+    while frame < frames {
+        while let Some(event) = events.peek().filter(|event| event.frame <= frame).cloned() {
+            chip.key[event.key as usize] = event.pressed as u8;
+            events.next();
+        }
+
+        let result = panic::catch_unwind(AssertUnwindSafe(|| chip.emulate_frame(ipf)));
+        frame += 1;
+        if let Err(cause) = result {
+            exit = Exit::Crashed(panic_message(&*cause));
+            break;
+        }
+        if halted(&chip) {
+            exit = Exit::Halted;
+            break;
+        }
+    }
+
+    Outcome {
+        name: name,
+        exit: exit,
+        framebuffer: chip.graphics.clone(),
+        cpu: chip.cpu_state(),
+        stats: Stats {
+            frames: frame,
+            cycles: chip.cycles() - cycles,
+            elapsed: start.elapsed(),
+        },
+    }
+}
+
+/// Runs the jobs on the given number of worker threads, returning their
+/// outcomes in the same order as the jobs.
+pub fn run(jobs: Vec<Job>, workers: usize) -> Vec<Outcome> {
+    let count = jobs.len();
+    let queue = Arc::new(Mutex::new(jobs.into_iter().enumerate()));
+    let (sender, receiver) = mpsc::channel();
+    let threads: Vec<_> = (0..workers.max(1).min(count))
+        .map(|_| {
+            let queue = queue.clone();
+            let sender = sender.clone();
+            thread::spawn(move || loop {
+                // Jobs catch their own panics, so the queue is never poisoned
+                let next = queue.lock().unwrap().next();
+                match next {
+                    Some((i, job)) => sender.send((i, run_job(job))).unwrap(),
+                    None => break,
+                }
+            })
+        })
+        .collect();
+    drop(sender);
+
+    let mut outcomes: Vec<Option<Outcome>> = (0..count).map(|_| None).collect();
+    for (i, outcome) in receiver {
+        outcomes[i] = Some(outcome);
+    }
+    for thread in threads {
+        thread.join().unwrap();
+    }
+    outcomes.into_iter().map(Option::unwrap).collect()
+}
+
+/// Whether the chip is at a jump to itself, which it can never leave.
+pub fn halted(chip: &Chip8) -> bool {
+    let pc = chip.pc() as usize;
+    let memory = chip.memory();
+    let opcode = (memory[pc % memory.len()] as u16) << 8 | memory[(pc + 1) % memory.len()] as u16;
+    opcode == 0x1000 | chip.pc()
+}
+
+/// The message a panic was started with.
+pub fn panic_message(cause: &(dyn std::any::Any + Send)) -> String {
+    if let Some(message) = cause.downcast_ref::<String>() {
+        message.clone()
+    } else if let Some(message) = cause.downcast_ref::<&str>() {
+        message.to_string()
+    } else {
+        "Unknown panic".to_string()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{run, Exit, InputScript, Job, KeyEvent};
+    use Chip8;
+
+    fn job(name: &str, program: &[u8], frames: u64) -> Job {
+        let mut chip = Chip8::default();
+        chip.load_hex(program);
+        Job {
+            name: name.to_string(),
+            chip: chip,
+            ipf: 10,
+            frames: frames,
+            input: InputScript::default(),
+        }
+    }
+
+    #[test]
+    fn parses_input_scripts() {
+        let script = InputScript::parse("# Start\n30 5 up\n10 5 down\n").unwrap();
+        assert_eq!(script.events(),
+                   &[KeyEvent { frame: 10, key: 5, pressed: true },
+                     KeyEvent { frame: 30, key: 5, pressed: false }]);
+        assert_eq!(InputScript::parse("10 G down"), Err("Line 1: Bad key G".to_string()));
+        assert_eq!(InputScript::parse("\n10 5"),
+                   Err("Line 2: Expected a frame, a key and down or up".to_string()));
+    }
+
+    #[test]
+    fn runs_jobs_in_parallel() {
+        // Waits for key 5, then counts up in V0 until it halts
+        let mut waiting = job("waiting", &[0xF1, 0x0A, 0x70, 0x01, 0x30, 0x03, 0x12, 0x02,
+                                           0x12, 0x08], 100);
+        waiting.input = InputScript::new(vec![KeyEvent { frame: 20, key: 5, pressed: true }]);
+        let jobs = vec![
+            job("forever", &[0x70, 0x01, 0x12, 0x00], 50),
+            waiting,
+            job("bad", &[0x60, 0x01, 0xFF, 0xFF], 50),
+        ];
+
+        let outcomes = run(jobs, 2);
+        let names: Vec<&str> = outcomes.iter().map(|outcome| outcome.name.as_str()).collect();
+        assert_eq!(names, ["forever", "waiting", "bad"]);
+
+        assert_eq!(outcomes[0].exit, Exit::Finished);
+        assert_eq!(outcomes[0].stats.frames, 50);
+        assert_eq!(outcomes[0].stats.cycles, 500);
+
+        assert_eq!(outcomes[1].exit, Exit::Halted);
+        assert_eq!(outcomes[1].stats.frames, 21);
+        assert_eq!(outcomes[1].cpu.registers[0], 3);
+
+        assert_eq!(outcomes[2].exit, Exit::Crashed("Opcode 0xFFFF is bad".to_string()));
+        assert!(outcomes[2].crashed());
+        assert_eq!(outcomes[2].stats.frames, 1);
+    }
+}
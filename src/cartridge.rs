@@ -0,0 +1,328 @@
+//! Octo cartridges, which are GIF images carrying a program and the options
+//! needed to run it.
+//!
+//! A cartridge's payload is a JSON object holding the program's Octo source
+//! (`program`) and Octo's options (`options`).  The payload is prefixed with
+//! its length as a 32 bit big endian number, and the whole thing is stored
+//! two bits at a time, high bits first, in the low two bits of each palette
+//! index of the image's first frame.  The high bits of each index pick one
+//! of four colors, which draw the cartridge's label.
+//!
+//! Octo stores source code rather than assembled bytes, so programs are
+//! assembled with `octo::assemble` to turn them back into ROMs.
+
+use gif;
+use octo;
+use platform::{Platform, Quirks};
+use render::Palette;
+use serde_json::{self, Map, Value};
+use std::fs::File;
+use std::io::{self, BufWriter, Read, Write};
+use std::path::Path;
+use super::{HEIGHT, NPIXELS, WIDTH};
+
+/// The width of a cartridge image (in pixels).
+const CARTRIDGE_WIDTH: usize = 160;
+
+/// The smallest height of a cartridge image (in pixels).  Larger payloads
+/// make taller cartridges.
+const CARTRIDGE_HEIGHT: usize = 128;
+
+/// Where the label sits on the cartridge, and how much it is scaled up.
+const LABEL_X: usize = 16;
+const LABEL_Y: usize = 24;
+const LABEL_SCALE: usize = 2;
+
+/// The RGB colors of the cartridge's shell and its edges, which are the
+/// first two of the four label colors.  The last two are the background and
+/// foreground of the program's palette.
+const SHELL: [u8; 3] = [0x99, 0x99, 0x99];
+const EDGE: [u8; 3] = [0x44, 0x44, 0x44];
+
+/// The sizes Octo uses for each platform's memory, which cartridges record
+/// in the `maxSize` option.
+const MAX_SIZES: [(Platform, u64); 3] = [
+    (Platform::Chip8, 3216),
+    (Platform::SuperChip, 3583),
+    (Platform::XoChip, 65024),
+];
+
+/// The options stored in a cartridge that this emulator understands.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Options {
+    /// The number of instructions to run per 60Hz frame, if given.
+    pub ipf: Option<u32>,
+    /// The platform the program was written for, judged by its memory size.
+    pub platform: Option<Platform>,
+    /// The quirks the program needs.
+    pub quirks: Quirks,
+    /// The colors of the display.
+    pub palette: Palette,
+}
+
+/// A program and the options needed to run it, as stored in a cartridge.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Cartridge {
+    /// The program's Octo source.
+    pub source: String,
+    /// The options for running the program.
+    pub options: Options,
+}
+
+impl Cartridge {
+    /// Constructs a cartridge for the given ROM, writing it as Octo source
+    /// that assembles back into the same bytes.
+    pub fn new(rom: &[u8], options: Options) -> Self {
+        let mut source = String::from(": main\n");
+        for line in rom.chunks(16) {
+            let bytes: Vec<String> = line.iter().map(|b| format!("0x{:02X}", b)).collect();
+            source.push('\t');
+            source.push_str(&bytes.join(" "));
+            source.push('\n');
+        }
+        Cartridge {
+            source: source,
+            options: options,
+        }
+    }
+
+    /// Reads a cartridge from the given GIF file.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, String> {
+        let file = File::open(path).map_err(|why| why.to_string())?;
+        Cartridge::read(file)
+    }
+
+    /// Reads a cartridge from the given GIF image.
+    pub fn read<R: Read>(r: R) -> Result<Self, String> {
+        let mut options = gif::DecodeOptions::new();
+        options.set_color_output(gif::ColorOutput::Indexed);
+        let mut decoder = options.read_info(r).map_err(|why| why.to_string())?;
+        let frame = decoder.read_next_frame()
+            .map_err(|why| why.to_string())?
+            .ok_or("The GIF has no frames")?;
+
+        // Gather the low two bits of each pixel into bytes
+        let bytes: Vec<u8> = frame.buffer.chunks(4)
+            .filter(|quad| quad.len() == 4)
+            .map(|quad| quad.iter().fold(0, |byte, index| byte << 2 | index & 0x3))
+            .collect();
+        if bytes.len() < 4 {
+            return Err("The GIF is too small to be a cartridge".to_string());
+        }
+        let size = bytes[..4].iter().fold(0usize, |size, b| size << 8 | *b as usize);
+        if size > bytes.len() - 4 {
+            return Err("The GIF isn't a cartridge".to_string());
+        }
+
+        let payload: Value = serde_json::from_slice(&bytes[4..4 + size])
+            .map_err(|why| format!("The cartridge's payload is broken: {}", why))?;
+        let source = payload["program"].as_str()
+            .ok_or("The cartridge has no program")?
+            .to_string();
+        Ok(Cartridge {
+            source: source,
+            options: parse_options(&payload["options"])?,
+        })
+    }
+
+    /// Assembles the program into a ROM.
+    pub fn rom(&self) -> Result<Vec<u8>, String> {
+        octo::assemble(&self.source)
+            .map_err(|why| format!("The cartridge's program doesn't assemble: {}", why))
+    }
+
+    /// Encodes the cartridge as a GIF and saves it to the given path, with
+    /// the given display as its label.
+    pub fn save<P: AsRef<Path>>(&self, path: P, label: &[u8; NPIXELS]) -> io::Result<()> {
+        let file = File::create(path)?;
+        self.write(BufWriter::new(file), label)
+    }
+
+    /// Encodes the cartridge as a GIF to the given writer, with the given
+    /// display as its label.
+    pub fn write<W: Write>(&self, w: W, label: &[u8; NPIXELS]) -> io::Result<()> {
+        let mut payload = Map::new();
+        payload.insert("options".to_string(), options_json(&self.options));
+        payload.insert("program".to_string(), Value::String(self.source.clone()));
+        let json = Value::Object(payload).to_string();
+
+        let mut bytes = Vec::with_capacity(json.len() + 4);
+        bytes.extend_from_slice(&[(json.len() >> 24) as u8,
+                                  (json.len() >> 16) as u8,
+                                  (json.len() >> 8) as u8,
+                                  json.len() as u8]);
+        bytes.extend_from_slice(json.as_bytes());
+
+        let height = (bytes.len() * 4).div_ceil(CARTRIDGE_WIDTH).max(CARTRIDGE_HEIGHT);
+        let mut pixels = draw_label(label, height);
+        for (i, byte) in bytes.iter().enumerate() {
+            for j in 0..4 {
+                let bits = byte >> (6 - 2 * j) & 0x3;
+                pixels[i * 4 + j] = pixels[i * 4 + j] << 2 | bits;
+            }
+        }
+        // Pixels past the payload keep the label's color with no data
+        for pixel in pixels.iter_mut().skip(bytes.len() * 4) {
+            *pixel <<= 2;
+        }
+
+        let mut palette = Vec::with_capacity(16 * 3);
+        for color in &[SHELL, EDGE, self.options.palette.background, self.options.palette.foreground] {
+            for _ in 0..4 {
+                palette.extend_from_slice(color);
+            }
+        }
+
+        let mut encoder = gif::Encoder::new(w, CARTRIDGE_WIDTH as u16, height as u16, &palette)
+            .map_err(io::Error::other)?;
+        let frame = gif::Frame::from_indexed_pixels(CARTRIDGE_WIDTH as u16,
+                                                    height as u16,
+                                                    pixels,
+                                                    None);
+        encoder.write_frame(&frame).map_err(io::Error::other)
+    }
+}
+
+/// Draws the cartridge's label, returning which of the four label colors
+/// each pixel is.  The given display is drawn scaled up and framed in the
+/// middle of the cartridge's shell.
+fn draw_label(label: &[u8; NPIXELS], height: usize) -> Vec<u8> {
+    let mut pixels = vec![0; CARTRIDGE_WIDTH * height];
+    let width = WIDTH as usize;
+    let label_width = width * LABEL_SCALE;
+    let label_height = HEIGHT as usize * LABEL_SCALE;
+    for y in LABEL_Y - 2..LABEL_Y + label_height + 2 {
+        for x in LABEL_X - 2..LABEL_X + label_width + 2 {
+            let (lx, ly) = (x.wrapping_sub(LABEL_X), y.wrapping_sub(LABEL_Y));
+            pixels[y * CARTRIDGE_WIDTH + x] = if lx < label_width && ly < label_height {
+                let on = label[ly / LABEL_SCALE * width + lx / LABEL_SCALE] != 0;
+                if on { 3 } else { 2 }
+            } else {
+                1
+            };
+        }
+    }
+    pixels
+}
+
+/// Reads the options this emulator understands from Octo's options.  Octo's
+/// quirks describe what differs from the original CHIP-8, so some are the
+/// opposite of ours.
+fn parse_options(options: &Value) -> Result<Options, String> {
+    let flag = |name: &str| options[name].as_bool().unwrap_or(false);
+    let color = |name: &str, default: [u8; 3]| -> Result<[u8; 3], String> {
+        match options[name].as_str() {
+            Some(color) => format!("{},{}", color, color).parse::<Palette>().map(|p| p.background),
+            None => Ok(default),
+        }
+    };
+
+    let ipf = match options["tickrate"].as_u64() {
+        Some(0) | None => None,
+        Some(ipf) => Some(ipf as u32),
+    };
+    let max_size = options["maxSize"].as_u64();
+    let platform = MAX_SIZES.iter().find(|&&(_, size)| Some(size) == max_size).map(|&(p, _)| p);
+    let default = Palette::default();
+
+    Ok(Options {
+        ipf: ipf,
+        platform: platform,
+        quirks: Quirks {
+            shift_uses_vy: !flag("shiftQuirks"),
+            load_store_increments_index: !flag("loadStoreQuirks"),
+            jump_uses_vx: flag("jumpQuirks"),
+            logic_resets_vf: flag("logicQuirks"),
+            wrap_sprites: !flag("clipQuirks"),
+            display_wait: flag("vBlankQuirks"),
+        },
+        palette: Palette {
+            background: color("backgroundColor", default.background)?,
+            foreground: color("fillColor", default.foreground)?,
+        },
+    })
+}
+
+/// Writes the given options the way Octo stores them.
+fn options_json(options: &Options) -> Value {
+    let color = |rgb: [u8; 3]| Value::String(format!("#{:02X}{:02X}{:02X}", rgb[0], rgb[1], rgb[2]));
+    let quirks = &options.quirks;
+    let platform = options.platform.unwrap_or(Platform::SuperChip);
+    let max_size = MAX_SIZES.iter().find(|&&(p, _)| p == platform).unwrap().1;
+
+    let mut json = Map::new();
+    json.insert("tickrate".to_string(), Value::from(options.ipf.unwrap_or(10)));
+    json.insert("maxSize".to_string(), Value::from(max_size));
+    json.insert("backgroundColor".to_string(), color(options.palette.background));
+    json.insert("fillColor".to_string(), color(options.palette.foreground));
+    json.insert("shiftQuirks".to_string(), Value::Bool(!quirks.shift_uses_vy));
+    json.insert("loadStoreQuirks".to_string(), Value::Bool(!quirks.load_store_increments_index));
+    json.insert("jumpQuirks".to_string(), Value::Bool(quirks.jump_uses_vx));
+    json.insert("logicQuirks".to_string(), Value::Bool(quirks.logic_resets_vf));
+    json.insert("clipQuirks".to_string(), Value::Bool(!quirks.wrap_sprites));
+    json.insert("vBlankQuirks".to_string(), Value::Bool(quirks.display_wait));
+    Value::Object(json)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Cartridge, Options};
+    use platform::Platform;
+    use render::Palette;
+    use NPIXELS;
+
+    fn options() -> Options {
+        Options {
+            ipf: Some(20),
+            platform: Some(Platform::XoChip),
+            quirks: Platform::XoChip.quirks(),
+            palette: Palette {
+                background: [0x10, 0x20, 0x30],
+                foreground: [0xF0, 0xE0, 0xD0],
+            },
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let rom: Vec<u8> = (0..1000).map(|i| (i * 7) as u8).collect();
+        let mut label = [0; NPIXELS];
+        label[100] = 1;
+
+        let mut gif = Vec::new();
+        Cartridge::new(&rom, options()).write(&mut gif, &label).unwrap();
+        assert_eq!(&gif[0..6], b"GIF89a");
+
+        let cartridge = Cartridge::read(&gif[..]).unwrap();
+        assert_eq!(cartridge.options, options());
+        assert_eq!(cartridge.rom().unwrap(), rom);
+    }
+
+    #[test]
+    fn assembles() {
+        let cartridge = Cartridge {
+            source: ": main # start here\n  0x6A 0b101 -1\n\n255 # done".to_string(),
+            options: options(),
+        };
+        assert_eq!(cartridge.rom().unwrap(), vec![0x6A, 5, 0xFF, 0xFF]);
+
+        let cartridge = Cartridge {
+            source: ": main\n  v0 := 5\n  jump nowhere\n".to_string(),
+            options: options(),
+        };
+        assert_eq!(cartridge.rom().unwrap_err(),
+                   "The cartridge's program doesn't assemble: Line 3: Unknown name nowhere");
+    }
+
+    #[test]
+    fn rejects_plain_gifs() {
+        let mut gif = Vec::new();
+        {
+            let palette = [0, 0, 0, 0xFF, 0xFF, 0xFF];
+            let mut encoder = ::gif::Encoder::new(&mut gif, 8, 8, &palette).unwrap();
+            let frame = ::gif::Frame::from_indexed_pixels(8, 8, vec![1; 64], None);
+            encoder.write_frame(&frame).unwrap();
+        }
+        assert!(Cartridge::read(&gif[..]).is_err());
+    }
+}
@@ -0,0 +1,489 @@
+use chip8::cartridge;
+use chip8::database::{Database, Entry};
+use chip8::filter::Filter;
+use chip8::platform::{Platform, Quirks};
+use chip8::render::Palette;
+#[cfg(feature = "sdl-frontend")]
+use input::Keymap;
+use std::env;
+use std::fs::File;
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+
+/// The names of every setting, which are the same in config files and on the
+/// command line.
+pub const KEYS: [&str; 16] = [
+    "ipf",
+    "platform",
+    "quirks",
+    "palette",
+    "scale",
+    "filter",
+    "scaling",
+    "fullscreen",
+    "keymap",
+    "volume",
+    "mute",
+    "seed",
+    "paused",
+    "load-address",
+    "entry-point",
+    "beep",
+];
+
+/// How the display is stretched to fill a window of any size.  Either way the
+/// display keeps its 2:1 aspect ratio, with black bars filling the rest of
+/// the window.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Scaling {
+    /// Scale by the largest whole number that fits, keeping pixels square
+    /// and sharp.
+    Integer,
+    /// Scale to fill as much of the window as possible, smoothing pixels
+    /// with linear filtering.
+    Smooth,
+}
+
+/// Every setting for running a game.
+///
+/// Settings start out with their defaults, and are then overridden by the
+/// global config file, the ROM's config file, and finally the command line.
+#[derive(Clone, Debug)]
+pub struct Settings {
+    /// The number of instructions run per 60Hz frame.
+    pub ipf: u32,
+    /// The interpreter whose quirks are imitated, if any.
+    pub platform: Option<Platform>,
+    /// The quirks found for the ROM in the ROM database or its cartridge,
+    /// used instead of the platform's.
+    pub preset_quirks: Option<Quirks>,
+    /// Quirk overrides applied on top of the platform's, in the order given.
+    pub quirk_overrides: Vec<String>,
+    /// The colors of the display.
+    pub palette: Palette,
+    /// How many times bigger the window, screenshots and recordings are than
+    /// the chip's display.
+    pub scale: u32,
+    /// The filter applied when scaling the display up.
+    pub filter: Filter,
+    /// How the display is stretched to fill the window.
+    pub scaling: Scaling,
+    /// Whether the window starts out fullscreen.
+    pub fullscreen: bool,
+    /// The keyboard keys standing in for the chip's keys: `hex`, `qwerty`,
+    /// or 16 comma separated SDL key names.
+    pub keymap: String,
+    /// Extra keys bound to the chip's keys by the ROM database, by the name
+    /// of the input, such as `up` or `player2A`.
+    pub key_bindings: Vec<(String, u8)>,
+    /// The beep's volume, from 0 to 100.
+    pub volume: u32,
+    /// Whether the beep is silenced.
+    pub mute: bool,
+    /// The seed for the chip's random numbers, if runs should repeat exactly.
+    pub seed: Option<u64>,
+    /// Whether emulation starts out paused.
+    pub paused: bool,
+    /// The address the ROM is loaded at.
+    pub load_address: u16,
+    /// The address the ROM is started from, if it isn't the load address.
+    pub entry_point: Option<u16>,
+    /// The shortest a beep lasts (in milliseconds).
+    pub beep: u64,
+    /// The game's title and authors from the ROM database, if it is known.
+    pub title: Option<String>,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            ipf: 10,
+            platform: None,
+            preset_quirks: None,
+            quirk_overrides: Vec::new(),
+            palette: Palette::default(),
+            scale: 8,
+            filter: Filter::None,
+            scaling: Scaling::Integer,
+            fullscreen: false,
+            keymap: "hex".to_string(),
+            key_bindings: Vec::new(),
+            volume: 25,
+            mute: false,
+            seed: None,
+            paused: false,
+            load_address: chip8::PROGRAM_START,
+            entry_point: None,
+            beep: 250,
+            title: None,
+        }
+    }
+}
+
+impl Settings {
+    /// Changes the setting with the given name to the given value.
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+        let value = value.trim();
+        match key {
+            "ipf" => self.ipf = parse_number(key, value)?,
+            "platform" => {
+                self.platform = Some(Platform::from_name(value)
+                    .ok_or_else(|| format!("Unknown platform {}", value))?);
+                self.preset_quirks = None;
+            }
+            "quirks" => {
+                // Check the overrides now, so mistakes are reported with
+                // where they came from
+                Quirks::default().apply(value)?;
+                self.quirk_overrides.push(value.to_string());
+            }
+            "palette" => self.palette = value.parse()?,
+            "scale" => {
+                let scale = parse_number(key, value)?;
+                if scale == 0 {
+                    return Err("The scale must be at least 1".to_string());
+                }
+                self.scale = scale;
+            }
+            "filter" => {
+                self.filter = Filter::from_name(value)
+                    .ok_or_else(|| format!("Unknown filter {}", value))?;
+            }
+            "scaling" => {
+                self.scaling = match value {
+                    "integer" => Scaling::Integer,
+                    "smooth" => Scaling::Smooth,
+                    _ => return Err(format!("Unknown scaling {}", value)),
+                };
+            }
+            "fullscreen" => self.fullscreen = parse_bool(key, value)?,
+            "keymap" => {
+                check_keymap(value)?;
+                self.keymap = value.to_string();
+            }
+            "volume" => {
+                let volume = parse_number(key, value)?;
+                if volume > 100 {
+                    return Err("The volume must be from 0 to 100".to_string());
+                }
+                self.volume = volume;
+            }
+            "mute" => self.mute = parse_bool(key, value)?,
+            "seed" => self.seed = Some(parse_number(key, value)?),
+            "paused" => self.paused = parse_bool(key, value)?,
+            "load-address" => self.load_address = parse_address(key, value)?,
+            "entry-point" => self.entry_point = Some(parse_address(key, value)?),
+            "beep" => self.beep = parse_number(key, value)?,
+            _ => return Err(format!("Unknown setting {}", key)),
+        }
+        Ok(())
+    }
+
+    /// Changes every setting found in the given config file.
+    pub fn load<P: AsRef<Path>>(&mut self, path: P) -> Result<(), String> {
+        let path = path.as_ref();
+        let mut text = String::new();
+        File::open(path)
+            .and_then(|mut file| file.read_to_string(&mut text))
+            .map_err(|why| format!("Couldn't read {}: {}", path.display(), why))?;
+
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut parts = line.splitn(2, '=');
+            let key = parts.next().unwrap().trim();
+            let value = match parts.next() {
+                Some(value) => value,
+                None => {
+                    return Err(format!("{}:{}: expected key = value", path.display(), i + 1))
+                }
+            };
+            self.set(key, value)
+                .map_err(|why| format!("{}:{}: {}", path.display(), i + 1, why))?;
+        }
+        Ok(())
+    }
+
+    /// Changes the settings to suit a game found in the ROM database.  Only
+    /// what the entry says is changed.
+    pub fn apply_entry(&mut self, entry: &Entry) {
+        // The entry's quirks are only known when its platform is
+        if entry.platform.is_some() {
+            self.platform = entry.platform;
+            self.preset_quirks = Some(entry.quirks);
+        }
+        if let Some(ipf) = entry.ipf {
+            self.ipf = ipf;
+        }
+        if !entry.keys.is_empty() {
+            self.key_bindings = entry.keys.clone();
+        }
+        if let Some(palette) = entry.palette {
+            self.palette = palette;
+        }
+        self.title = Some(entry.description());
+    }
+
+    /// Changes the settings to the options a ROM's cartridge was made with.
+    pub fn apply_cartridge(&mut self, options: &cartridge::Options) {
+        self.platform = options.platform;
+        self.preset_quirks = Some(options.quirks);
+        if let Some(ipf) = options.ipf {
+            self.ipf = ipf;
+        }
+        self.palette = options.palette;
+    }
+
+    /// The cartridge options for writing a ROM with these settings.
+    pub fn cartridge_options(&self) -> cartridge::Options {
+        cartridge::Options {
+            ipf: Some(self.ipf),
+            platform: self.platform,
+            quirks: self.quirks(),
+            palette: self.palette,
+        }
+    }
+
+    /// The chip's quirks, which are the preset or platform's quirks
+    /// with every override applied.
+    pub fn quirks(&self) -> Quirks {
+        let mut quirks = self.preset_quirks
+            .or_else(|| self.platform.map(|p| p.quirks()))
+            .unwrap_or_default();
+        for overrides in &self.quirk_overrides {
+            quirks.apply(overrides).unwrap();
+        }
+        quirks
+    }
+
+    /// The keymap for the SDL window, with the ROM database's keys bound.
+    #[cfg(feature = "sdl-frontend")]
+    pub fn sdl_keymap(&self) -> Keymap {
+        // The keymap was checked when it was set
+        let mut keymap: Keymap = self.keymap.parse().unwrap();
+        for &(ref input, key) in &self.key_bindings {
+            keymap.bind(input, key);
+        }
+        keymap
+    }
+
+    /// The beep's volume from 0.0 to 1.0, taking muting into account.
+    #[cfg(feature = "sdl-frontend")]
+    pub fn volume(&self) -> f32 {
+        if self.mute { 0.0 } else { self.volume as f32 / 100.0 }
+    }
+}
+
+/// The global config file, which is `chip8/config` in the user's config
+/// directory.
+pub fn global_path() -> Option<PathBuf> {
+    env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".config")))
+        .map(|dir| dir.join("chip8").join("config"))
+}
+
+/// The ROM database, which is `chip8/programs.json` in the user's config
+/// directory.
+pub fn database_path() -> Option<PathBuf> {
+    global_path().map(|path| path.with_file_name("programs.json"))
+}
+
+/// Reads the ROM database from the given file, or from the usual place if
+/// there is one there, warning about any ROMs in it that are skipped.
+pub fn load_database(path: Option<&str>) -> Result<Option<Database>, String> {
+    let database = match path {
+        Some(path) => Database::load(path)?,
+        None => match database_path() {
+            Some(ref path) if path.exists() => Database::load(path)?,
+            _ => return Ok(None),
+        },
+    };
+    for why in database.skipped() {
+        warn!("Skipped a ROM in the database: {}", why);
+    }
+    Ok(Some(database))
+}
+
+/// The config file for the given ROM, which sits next to it with a `.cfg`
+/// extension.
+pub fn rom_path(rom: &str) -> PathBuf {
+    Path::new(rom).with_extension("cfg")
+}
+
+/// Checks that a keymap can be used.  Without the SDL window, only the
+/// `hex` and `qwerty` layouts are known.
+#[cfg(feature = "sdl-frontend")]
+fn check_keymap(value: &str) -> Result<(), String> {
+    value.parse::<Keymap>().map(|_| ())
+}
+
+#[cfg(not(feature = "sdl-frontend"))]
+fn check_keymap(value: &str) -> Result<(), String> {
+    match value {
+        "hex" | "qwerty" => Ok(()),
+        _ => Err(format!("Expected hex or qwerty, got {}", value)),
+    }
+}
+
+fn parse_number<T: ::std::str::FromStr>(key: &str, value: &str) -> Result<T, String> {
+    value.parse().map_err(|_| format!("Bad {} {}", key, value))
+}
+
+/// Parses a memory address, written in decimal or in hex starting with `0x`.
+/// Whether the address is in the chip's memory is only known once the
+/// platform is, so it is checked when the ROM is loaded.
+fn parse_address(key: &str, value: &str) -> Result<u16, String> {
+    let address = if value.starts_with("0x") || value.starts_with("0X") {
+        u16::from_str_radix(&value[2..], 16).ok()
+    } else {
+        value.parse().ok()
+    };
+    match address {
+        Some(address) => Ok(address),
+        None => Err(format!("Bad {} {}", key, value)),
+    }
+}
+
+fn parse_bool(key: &str, value: &str) -> Result<bool, String> {
+    match value {
+        "true" | "on" | "yes" | "1" => Ok(true),
+        "false" | "off" | "no" | "0" => Ok(false),
+        _ => Err(format!("Bad {} {} (expected true or false)", key, value)),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Settings;
+    use chip8::database::Entry;
+    use chip8::platform::{Platform, Quirks};
+    use std::env;
+    use std::fs;
+    use std::path::PathBuf;
+    use std::process;
+
+    /// Writes a config file to a temporary file, returning its path.
+    fn config_file(name: &str, text: &str) -> PathBuf {
+        let path = env::temp_dir().join(format!("chip8-config-{}-{}.cfg", process::id(), name));
+        fs::write(&path, text).unwrap();
+        path
+    }
+
+    #[test]
+    fn later_layers_win() {
+        let global = config_file("global", "ipf = 20\nscale = 4\nplatform = schip\n");
+        let rom = config_file("rom", "# Faster for this game\nipf = 30\n\nmute = on\n");
+
+        // The defaults, then the global file, the ROM's file and the command line
+        let mut settings = Settings::default();
+        assert_eq!(settings.ipf, 10);
+        settings.load(&global).unwrap();
+        settings.load(&rom).unwrap();
+        settings.set("scale", "2").unwrap();
+        fs::remove_file(global).unwrap();
+        fs::remove_file(rom).unwrap();
+
+        assert_eq!(settings.ipf, 30);
+        assert_eq!(settings.scale, 2);
+        assert_eq!(settings.platform, Some(Platform::SuperChip));
+        assert!(settings.mute);
+        assert_eq!(settings.volume, 25);
+    }
+
+    #[test]
+    fn entries_only_change_what_they_say() {
+        let mut settings = Settings::default();
+        settings.set("platform", "schip").unwrap();
+        settings.key_bindings = vec![("up".to_string(), 5)];
+        let entry = Entry {
+            title: "Pong".to_string(),
+            authors: Vec::new(),
+            platform: None,
+            quirks: Quirks::default(),
+            ipf: Some(15),
+            keys: Vec::new(),
+            palette: None,
+            unsupported_quirks: Vec::new(),
+        };
+        settings.apply_entry(&entry);
+        assert_eq!(settings.platform, Some(Platform::SuperChip));
+        assert_eq!(settings.preset_quirks, None);
+        assert_eq!(settings.key_bindings, vec![("up".to_string(), 5)]);
+        assert_eq!(settings.ipf, 15);
+        assert_eq!(settings.title, Some("Pong".to_string()));
+    }
+
+    #[test]
+    fn set() {
+        let mut settings = Settings::default();
+        settings.set("load-address", "0x600").unwrap();
+        settings.set("entry-point", "1538").unwrap();
+        settings.set("paused", "yes").unwrap();
+        settings.set("seed", " 7 ").unwrap();
+        assert_eq!(settings.load_address, 0x600);
+        assert_eq!(settings.entry_point, Some(0x602));
+        // XO-CHIP programs can be anywhere in its 64K of memory
+        settings.set("load-address", "0xF000").unwrap();
+        assert_eq!(settings.load_address, 0xF000);
+        assert!(settings.paused);
+        assert_eq!(settings.seed, Some(7));
+    }
+
+    #[test]
+    fn rejects_bad_keys_and_values() {
+        let mut settings = Settings::default();
+        assert_eq!(settings.set("speed", "10"), Err("Unknown setting speed".to_string()));
+        assert_eq!(settings.set("ipf", "fast"), Err("Bad ipf fast".to_string()));
+        assert!(settings.set("scale", "0").is_err());
+        assert!(settings.set("volume", "101").is_err());
+        assert!(settings.set("mute", "maybe").is_err());
+        assert!(settings.set("platform", "cosmac").is_err());
+        assert!(settings.set("quirks", "shift,warp").is_err());
+        assert!(settings.set("load-address", "0x10000").is_err());
+        assert!(settings.set("keymap", "dvorak").is_err());
+
+        // Nothing was changed by the mistakes
+        assert_eq!(settings.ipf, 10);
+        assert_eq!(settings.scale, 8);
+        assert!(settings.quirk_overrides.is_empty());
+    }
+
+    #[test]
+    fn load_reports_where_mistakes_are() {
+        let path = config_file("bad", "ipf = 15\nscale\n");
+        let mut settings = Settings::default();
+        let why = settings.load(&path).unwrap_err();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(why, format!("{}:2: expected key = value", path.display()));
+        assert_eq!(settings.ipf, 15);
+
+        let path = config_file("missing", "");
+        fs::remove_file(&path).unwrap();
+        assert!(Settings::default().load(&path).unwrap_err().starts_with("Couldn't read"));
+    }
+
+    #[test]
+    fn quirks() {
+        let mut settings = Settings::default();
+        assert_eq!(settings.quirks(), Default::default());
+
+        // Overrides apply on top of the platform's quirks, in order
+        settings.set("platform", "chip8").unwrap();
+        settings.set("quirks", "shift=off").unwrap();
+        settings.set("quirks", "wrap,shift").unwrap();
+        let quirks = settings.quirks();
+        assert!(quirks.shift_uses_vy);
+        assert!(quirks.wrap_sprites);
+        assert_eq!(quirks.display_wait, Platform::Chip8.quirks().display_wait);
+
+        // A preset from the database replaces the platform's quirks, until
+        // another platform is picked
+        settings.preset_quirks = Some(Platform::SuperChip.quirks());
+        assert!(settings.quirks().jump_uses_vx);
+        settings.set("platform", "chip8").unwrap();
+        assert!(!settings.quirks().jump_uses_vx);
+    }
+}
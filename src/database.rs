@@ -0,0 +1,340 @@
+use platform::{Platform, Quirks};
+use render::Palette;
+use serde_json::{self, Map, Value};
+use sha1_smol::Sha1;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::prelude::*;
+use std::path::Path;
+
+/// What the ROM database knows about a single ROM.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Entry {
+    /// The name of the game.
+    pub title: String,
+    /// The people who wrote the game.
+    pub authors: Vec<String>,
+    /// The interpreter the ROM was written for, if it is one that can be
+    /// imitated.
+    pub platform: Option<Platform>,
+    /// The quirks the ROM needs, which are its platform's quirks with any
+    /// quirks listed for the ROM applied.
+    pub quirks: Quirks,
+    /// The number of instructions to run per 60Hz frame, if the ROM needs a
+    /// particular speed.
+    pub ipf: Option<u32>,
+    /// The chip keys used for each of the ROM's inputs, named as in the
+    /// database, such as `up` or `player2A`.
+    pub keys: Vec<(String, u8)>,
+    /// The colors the ROM was meant to be shown in, if it has any.
+    pub palette: Option<Palette>,
+    /// The quirks the ROM needs that can't be imitated, named as in the
+    /// database, such as `memoryIncrementByX`.
+    pub unsupported_quirks: Vec<String>,
+}
+
+impl Entry {
+    /// Describes the game for the window title, such as `Pong by Paul
+    /// Vervalin`.
+    pub fn description(&self) -> String {
+        if self.authors.is_empty() {
+            self.title.clone()
+        } else {
+            format!("{} by {}", self.title, self.authors.join(", "))
+        }
+    }
+}
+
+/// A database of known ROMs, keyed by the SHA-1 hash of their bytes.
+///
+/// The database is read from the `programs.json` file of the community
+/// [CHIP-8 database](https://github.com/chip-8/chip-8-database).
+#[derive(Clone, Debug, Default)]
+pub struct Database {
+    entries: HashMap<String, Entry>,
+    /// Why each ROM that couldn't be read was skipped.
+    skipped: Vec<String>,
+}
+
+impl Database {
+    /// Reads the database from the given `programs.json` file.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, String> {
+        let path = path.as_ref();
+        let mut text = String::new();
+        File::open(path)
+            .and_then(|mut file| file.read_to_string(&mut text))
+            .map_err(|why| format!("Couldn't read {}: {}", path.display(), why))?;
+        Database::parse(&text).map_err(|why| format!("{}: {}", path.display(), why))
+    }
+
+    /// Parses the database from the contents of a `programs.json` file.
+    /// ROMs whose entries can't be read are skipped, and listed by
+    /// `skipped`.
+    pub fn parse(json: &str) -> Result<Self, String> {
+        let programs: Value = serde_json::from_str(json).map_err(|why| why.to_string())?;
+        let programs = programs.as_array().ok_or("Expected a list of programs")?;
+
+        let mut entries = HashMap::new();
+        let mut skipped = Vec::new();
+        for program in programs {
+            let title = program["title"].as_str().unwrap_or("Untitled");
+            let authors: Vec<String> = program["authors"]
+                .as_array()
+                .map_or(Vec::new(), |authors| {
+                    authors.iter().filter_map(Value::as_str).map(String::from).collect()
+                });
+            let roms = match program["roms"].as_object() {
+                Some(roms) => roms,
+                None => continue,
+            };
+            for (hash, rom) in roms {
+                match parse_rom(title, &authors, rom) {
+                    Ok(entry) => {
+                        entries.insert(hash.to_lowercase(), entry);
+                    }
+                    Err(why) => skipped.push(format!("{} ({}): {}", title, hash, why)),
+                }
+            }
+        }
+        Ok(Database {
+            entries: entries,
+            skipped: skipped,
+        })
+    }
+
+    /// Finds the entry for the given ROM, if it is known.
+    pub fn lookup(&self, game: &[u8]) -> Option<&Entry> {
+        self.entries.get(&sha1(game))
+    }
+
+    /// The number of ROMs in the database.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Why each ROM that couldn't be read was skipped.
+    pub fn skipped(&self) -> &[String] {
+        &self.skipped
+    }
+
+    /// Whether the database has no ROMs in it.
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+/// The SHA-1 hash of the given bytes, as lowercase hex.
+pub fn sha1(bytes: &[u8]) -> String {
+    Sha1::from(bytes).digest().to_string()
+}
+
+/// Parses the database's description of a single ROM.
+fn parse_rom(title: &str, authors: &[String], rom: &Value) -> Result<Entry, String> {
+    // Use the first listed platform that can be imitated
+    let names: Vec<&str> = rom["platforms"]
+        .as_array()
+        .map_or(Vec::new(), |names| names.iter().filter_map(Value::as_str).collect());
+    let known = names.iter().cloned().find(|name| platform_quirks(name).is_some());
+
+    let (platform, mut quirks) = match known {
+        Some(name) => platform_quirks(name).unwrap(),
+        None => (None, Quirks::default()),
+    };
+    let mut unsupported_quirks = Vec::new();
+    if let Some(overrides) = known.and_then(|name| rom["quirkyPlatforms"][name].as_object()) {
+        unsupported_quirks = apply_quirks(&mut quirks, overrides)?;
+    }
+
+    let ipf = match rom["tickrate"].as_u64() {
+        Some(0) => return Err("The tickrate must be at least 1".to_string()),
+        Some(ipf) => Some(ipf as u32),
+        None => None,
+    };
+
+    let mut keys = Vec::new();
+    if let Some(inputs) = rom["keys"].as_object() {
+        for (input, key) in inputs {
+            match key.as_u64() {
+                Some(key) if key < 16 => keys.push((input.clone(), key as u8)),
+                _ => return Err(format!("Bad key for {}: {}", input, key)),
+            }
+        }
+    }
+
+    let palette = match rom["colors"]["pixels"].as_array() {
+        Some(colors) if colors.len() >= 2 => {
+            // Only the first two colors are used, for the single plane
+            let background = colors[0].as_str().unwrap_or("");
+            let foreground = colors[1].as_str().unwrap_or("");
+            Some(format!("{},{}", background, foreground).parse::<Palette>()?)
+        }
+        _ => None,
+    };
+
+    Ok(Entry {
+        title: title.to_string(),
+        authors: authors.to_vec(),
+        platform: platform,
+        quirks: quirks,
+        ipf: ipf,
+        keys: keys,
+        palette: palette,
+        unsupported_quirks: unsupported_quirks,
+    })
+}
+
+/// The platform and quirks for one of the database's platform names, if it
+/// is one that can be imitated.  Modern CHIP-8 has no platform of its own:
+/// it is the original's shifts and loads and stores, without its other
+/// quirks.
+fn platform_quirks(name: &str) -> Option<(Option<Platform>, Quirks)> {
+    let platform = match name {
+        "originalChip8" | "hybridVIP" => Platform::Chip8,
+        "chip48" | "superchip1" | "superchip" => Platform::SuperChip,
+        "xochip" => Platform::XoChip,
+        "modernChip8" => {
+            let quirks = Quirks {
+                shift_uses_vy: true,
+                load_store_increments_index: true,
+                ..Default::default()
+            };
+            return Some((None, quirks));
+        }
+        _ => return None,
+    };
+    Some((Some(platform), platform.quirks()))
+}
+
+/// Applies the database's quirk settings for a ROM to the given quirks,
+/// returning the names of the quirks that are switched on but can't be
+/// imitated.  The database's quirks describe what differs from modern
+/// CHIP-8, so some are the opposite of ours.
+fn apply_quirks(quirks: &mut Quirks,
+                overrides: &Map<String, Value>) -> Result<Vec<String>, String> {
+    let mut unsupported = Vec::new();
+    for (name, value) in overrides {
+        let on = value.as_bool().ok_or_else(|| format!("Bad value for quirk {}", name))?;
+        match name.as_str() {
+            "shift" => quirks.shift_uses_vy = !on,
+            "memoryLeaveIUnchanged" => quirks.load_store_increments_index = !on,
+            "jump" => quirks.jump_uses_vx = on,
+            "logic" => quirks.logic_resets_vf = on,
+            "wrap" => quirks.wrap_sprites = on,
+            "vblank" => quirks.display_wait = on,
+            // Such as memoryIncrementByX, where FX55 and FX65 add X to I
+            // rather than X + 1
+            _ if on => unsupported.push(name.clone()),
+            _ => {}
+        }
+    }
+    Ok(unsupported)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{sha1, Database};
+    use platform::{Platform, Quirks};
+
+    static PROGRAMS: &str = r##"[
+        {
+            "title": "Test Game",
+            "authors": ["Someone", "Someone Else"],
+            "roms": {
+                "A9993E364706816ABA3E25717850C26C9CD0D89D": {
+                    "file": "test.ch8",
+                    "platforms": ["megachip8", "superchip"],
+                    "tickrate": 30,
+                    "quirkyPlatforms": {
+                        "superchip": { "shift": false, "vblank": true, "memoryIncrementByX": true }
+                    },
+                    "keys": { "up": 5, "a": 6 },
+                    "colors": { "pixels": ["#101010", "#f0f0f0"] }
+                }
+            }
+        },
+        {
+            "title": "Untouched",
+            "roms": {
+                "da39a3ee5e6b4b0d3255bfef95601890afd80709": {
+                    "platforms": ["modernChip8"]
+                }
+            }
+        },
+        {
+            "title": "Modern",
+            "roms": {
+                "81fe8bfe87576c3ecb22426f8e57847382917acf": {
+                    "platforms": ["modernChip8", "superchip"],
+                    "quirkyPlatforms": {
+                        "modernChip8": { "logic": true, "memoryIncrementByX": false }
+                    }
+                }
+            }
+        }
+    ]"##;
+
+    #[test]
+    fn hashes() {
+        assert_eq!(sha1(b"abc"), "a9993e364706816aba3e25717850c26c9cd0d89d");
+    }
+
+    #[test]
+    fn lookup() {
+        let database = Database::parse(PROGRAMS).unwrap();
+
+        let entry = database.lookup(b"abc").unwrap();
+        assert_eq!(entry.description(), "Test Game by Someone, Someone Else");
+        assert_eq!(entry.platform, Some(Platform::SuperChip));
+        assert!(entry.quirks.shift_uses_vy);
+        assert!(entry.quirks.display_wait);
+        assert!(entry.quirks.jump_uses_vx);
+        assert_eq!(entry.ipf, Some(30));
+        assert!(entry.keys.contains(&("up".to_string(), 5)));
+        assert_eq!(entry.palette.unwrap().foreground, [0xF0, 0xF0, 0xF0]);
+        assert_eq!(entry.unsupported_quirks, vec!["memoryIncrementByX".to_string()]);
+
+        let entry = database.lookup(b"").unwrap();
+        assert_eq!(entry.description(), "Untouched");
+        assert_eq!(entry.platform, None);
+        assert_eq!(entry.ipf, None);
+        assert_eq!(entry.palette, None);
+
+        assert!(database.lookup(b"abcde").is_none());
+    }
+
+    #[test]
+    fn modern_chip8() {
+        let database = Database::parse(PROGRAMS).unwrap();
+        assert_eq!(database.len(), 3);
+
+        // Modern CHIP-8 shifts VY and moves I on loads and stores, like the
+        // original, but has none of its other quirks
+        let entry = database.lookup(b"").unwrap();
+        assert_eq!(entry.platform, None);
+        assert_eq!(entry.quirks, Quirks {
+            shift_uses_vy: true,
+            load_store_increments_index: true,
+            ..Default::default()
+        });
+
+        // It comes before platforms listed after it, and its quirks can be
+        // changed too
+        let entry = database.lookup(b"abcd").unwrap();
+        assert_eq!(entry.platform, None);
+        assert!(entry.quirks.shift_uses_vy);
+        assert!(entry.quirks.logic_resets_vf);
+        assert!(entry.unsupported_quirks.is_empty());
+    }
+
+    #[test]
+    fn skips_bad_entries() {
+        assert!(Database::parse("{}").is_err());
+        let database = Database::parse(r#"[
+            {"title": "x", "roms": {"00": {"keys": {"up": 16}}}},
+            {"title": "y", "roms": {"01": {"platforms": ["originalChip8"]}}}
+        ]"#).unwrap();
+        assert_eq!(database.len(), 1);
+        assert_eq!(database.skipped().len(), 1);
+        assert!(database.skipped()[0].starts_with("x (00): "));
+    }
+}
@@ -0,0 +1,443 @@
+//! Decodes opcodes ahead of time into a handler and its operands, so that
+//! the chip's instruction cache can run an opcode without taking it apart
+//! again.  The handlers do exactly what `Chip8::execute_opcode` does.
+
+use super::Chip8;
+use std::fmt;
+
+/// Runs a decoded opcode on the chip.
+pub type Handler = fn(&mut Chip8, &Decoded);
+
+/// An opcode taken apart into the handler that runs it and its operands.
+#[derive(Clone, Copy)]
+pub struct Decoded {
+    /// Runs the opcode.
+    pub handler: Handler,
+    /// The opcode itself.
+    pub opcode: u16,
+    /// The register in the second nibble.
+    pub x: u8,
+    /// The register in the third nibble.
+    pub y: u8,
+    /// The last nibble.
+    pub n: u8,
+    /// The last byte.
+    pub nn: u8,
+    /// The last three nibbles, which are an address.
+    pub nnn: u16,
+}
+
+impl Decoded {
+    /// Stands in for an opcode that hasn't been decoded yet.  Running it
+    /// decodes the opcode at the program counter, caches it and runs it.
+    pub fn undecoded() -> Self {
+        Decoded {
+            handler: op_undecoded,
+            opcode: 0,
+            x: 0,
+            y: 0,
+            n: 0,
+            nn: 0,
+            nnn: 0,
+        }
+    }
+}
+
+impl fmt::Debug for Decoded {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Decoded({:#06X})", self.opcode)
+    }
+}
+
+/// The handlers for opcodes chosen by their first nibble alone.
+static HANDLERS: [Handler; 16] = [
+    op_0nnn, op_1nnn, op_2nnn, op_3xnn, op_4xnn, op_5xy0, op_6xnn, op_7xnn,
+    op_8xyn, op_9xy0, op_annn, op_bnnn, op_cxnn, op_dxyn, op_exnn, op_fxnn,
+];
+
+/// The handlers for the 0x8XYN opcodes, chosen by their last nibble.
+static ALU_HANDLERS: [Handler; 16] = [
+    op_8xy0, op_8xy1, op_8xy2, op_8xy3, op_8xy4, op_8xy5, op_8xy6, op_8xy7,
+    op_bad, op_bad, op_bad, op_bad, op_bad, op_bad, op_8xye, op_bad,
+];
+
+/// Takes the given opcode apart.  Opcodes that aren't valid decode to a
+/// handler that panics when run, like the interpreter does.
+pub fn decode(opcode: u16) -> Decoded {
+    let nn = (opcode & 0x00FF) as u8;
+    let handler = match opcode & 0xF000 {
+        0x0000 => match opcode {
+            0x00E0 => op_00e0,
+            0x00EE => op_00ee,
+            _ => op_bad,
+        },
+        0x8000 => ALU_HANDLERS[(opcode & 0x000F) as usize],
+        0xE000 => match nn {
+            0x9E => op_ex9e,
+            0xA1 => op_exa1,
+            _ => op_bad,
+        },
+        0xF000 => match nn {
+            0x07 => op_fx07,
+            0x0A => op_fx0a,
+            0x15 => op_fx15,
+            0x18 => op_fx18,
+            0x1E => op_fx1e,
+            0x29 => op_fx29,
+            0x33 => op_fx33,
+            0x55 => op_fx55,
+            0x65 => op_fx65,
+            _ => op_bad,
+        },
+        _ => HANDLERS[(opcode >> 12) as usize],
+    };
+    Decoded {
+        handler: handler,
+        opcode: opcode,
+        x: ((opcode & 0x0F00) >> 8) as u8,
+        y: ((opcode & 0x00F0) >> 4) as u8,
+        n: (opcode & 0x000F) as u8,
+        nn: nn,
+        nnn: opcode & 0x0FFF,
+    }
+}
+
+// The first nibble alone never picks these, since the rest of the opcode
+// does, so they are only placeholders in the table.
+fn op_0nnn(chip: &mut Chip8, d: &Decoded) { op_bad(chip, d) }
+fn op_8xyn(chip: &mut Chip8, d: &Decoded) { op_bad(chip, d) }
+fn op_exnn(chip: &mut Chip8, d: &Decoded) { op_bad(chip, d) }
+fn op_fxnn(chip: &mut Chip8, d: &Decoded) { op_bad(chip, d) }
+
+fn op_undecoded(chip: &mut Chip8, _: &Decoded) {
+    let pc = chip.pc;
+    let decoded = decode(chip.memory.read_word(pc));
+    let mask = chip.cache.len() - 1;
+    chip.cache[pc as usize & mask] = decoded;
+    chip.opcode = decoded.opcode;
+    (decoded.handler)(chip, &decoded);
+}
+
+fn op_bad(_: &mut Chip8, d: &Decoded) {
+    panic!("Opcode {:#X} is bad", d.opcode);
+}
+
+fn op_00e0(chip: &mut Chip8, _: &Decoded) {
+    chip.graphics.clear();
+    chip.draw_flag = true;
+    chip.pc += 2;
+}
+
+fn op_00ee(chip: &mut Chip8, _: &Decoded) {
+    chip.sp -= 1;
+    chip.pc = chip.stack[chip.sp as usize];
+    chip.pc += 2;
+}
+
+fn op_1nnn(chip: &mut Chip8, d: &Decoded) {
+    chip.pc = d.nnn;
+}
+
+fn op_2nnn(chip: &mut Chip8, d: &Decoded) {
+    chip.stack[chip.sp as usize] = chip.pc;
+    chip.sp += 1;
+    chip.pc = d.nnn;
+}
+
+fn op_3xnn(chip: &mut Chip8, d: &Decoded) {
+    chip.pc += if chip.reg[d.x as usize] == d.nn { 4 } else { 2 };
+}
+
+fn op_4xnn(chip: &mut Chip8, d: &Decoded) {
+    chip.pc += if chip.reg[d.x as usize] != d.nn { 4 } else { 2 };
+}
+
+fn op_5xy0(chip: &mut Chip8, d: &Decoded) {
+    chip.pc += if chip.reg[d.x as usize] == chip.reg[d.y as usize] { 4 } else { 2 };
+}
+
+fn op_6xnn(chip: &mut Chip8, d: &Decoded) {
+    chip.reg[d.x as usize] = d.nn;
+    chip.pc += 2;
+}
+
+fn op_7xnn(chip: &mut Chip8, d: &Decoded) {
+    let x = d.x as usize;
+    chip.reg[x] = chip.reg[x].wrapping_add(d.nn);
+    chip.pc += 2;
+}
+
+fn op_8xy0(chip: &mut Chip8, d: &Decoded) {
+    chip.reg[d.x as usize] = chip.reg[d.y as usize];
+    chip.pc += 2;
+}
+
+fn op_8xy1(chip: &mut Chip8, d: &Decoded) {
+    chip.reg[d.x as usize] |= chip.reg[d.y as usize];
+    if chip.quirks.logic_resets_vf {
+        chip.reg[0xF] = 0;
+    }
+    chip.pc += 2;
+}
+
+fn op_8xy2(chip: &mut Chip8, d: &Decoded) {
+    chip.reg[d.x as usize] &= chip.reg[d.y as usize];
+    if chip.quirks.logic_resets_vf {
+        chip.reg[0xF] = 0;
+    }
+    chip.pc += 2;
+}
+
+fn op_8xy3(chip: &mut Chip8, d: &Decoded) {
+    chip.reg[d.x as usize] ^= chip.reg[d.y as usize];
+    if chip.quirks.logic_resets_vf {
+        chip.reg[0xF] = 0;
+    }
+    chip.pc += 2;
+}
+
+fn op_8xy4(chip: &mut Chip8, d: &Decoded) {
+    // The flag is set last, so that it wins when X is F
+    let (x, y) = (d.x as usize, d.y as usize);
+    let (sum, carry) = chip.reg[x].overflowing_add(chip.reg[y]);
+    chip.reg[x] = sum;
+    chip.reg[0xF] = carry as u8;
+    chip.pc += 2;
+}
+
+fn op_8xy5(chip: &mut Chip8, d: &Decoded) {
+    let (x, y) = (d.x as usize, d.y as usize);
+    let (difference, borrow) = chip.reg[x].overflowing_sub(chip.reg[y]);
+    chip.reg[x] = difference;
+    chip.reg[0xF] = !borrow as u8;
+    chip.pc += 2;
+}
+
+fn op_8xy6(chip: &mut Chip8, d: &Decoded) {
+    let x = d.x as usize;
+    if chip.quirks.shift_uses_vy {
+        chip.reg[x] = chip.reg[d.y as usize];
+    }
+    let lsb = chip.reg[x] & 0x01;
+    chip.reg[x] >>= 1;
+    chip.reg[0xF] = lsb;
+    chip.pc += 2;
+}
+
+fn op_8xy7(chip: &mut Chip8, d: &Decoded) {
+    let (x, y) = (d.x as usize, d.y as usize);
+    let (difference, borrow) = chip.reg[y].overflowing_sub(chip.reg[x]);
+    chip.reg[x] = difference;
+    chip.reg[0xF] = !borrow as u8;
+    chip.pc += 2;
+}
+
+fn op_8xye(chip: &mut Chip8, d: &Decoded) {
+    let x = d.x as usize;
+    if chip.quirks.shift_uses_vy {
+        chip.reg[x] = chip.reg[d.y as usize];
+    }
+    let msb = chip.reg[x] >> 7;
+    chip.reg[x] <<= 1;
+    chip.reg[0xF] = msb;
+    chip.pc += 2;
+}
+
+fn op_9xy0(chip: &mut Chip8, d: &Decoded) {
+    chip.pc += if chip.reg[d.x as usize] != chip.reg[d.y as usize] { 4 } else { 2 };
+}
+
+fn op_annn(chip: &mut Chip8, d: &Decoded) {
+    chip.index = d.nnn;
+    chip.pc += 2;
+}
+
+fn op_bnnn(chip: &mut Chip8, d: &Decoded) {
+    let x = if chip.quirks.jump_uses_vx { d.x as usize } else { 0 };
+    chip.pc = d.nnn + chip.reg[x] as u16;
+}
+
+fn op_cxnn(chip: &mut Chip8, d: &Decoded) {
+    chip.reg[d.x as usize] = d.nn & chip.random();
+    chip.pc += 2;
+}
+
+fn op_dxyn(chip: &mut Chip8, d: &Decoded) {
+    chip.draw(d.x as usize, d.y as usize, d.n as usize);
+    chip.pc += 2;
+}
+
+fn op_ex9e(chip: &mut Chip8, d: &Decoded) {
+    chip.pc += if chip.key[chip.reg[d.x as usize] as usize] != 0 { 4 } else { 2 };
+}
+
+fn op_exa1(chip: &mut Chip8, d: &Decoded) {
+    chip.pc += if chip.key[chip.reg[d.x as usize] as usize] == 0 { 4 } else { 2 };
+}
+
+fn op_fx07(chip: &mut Chip8, d: &Decoded) {
+    chip.reg[d.x as usize] = chip.timer_delay;
+    chip.pc += 2;
+}
+
+fn op_fx0a(chip: &mut Chip8, d: &Decoded) {
+    chip.wait_for_key(d.x as usize);
+}
+
+fn op_fx15(chip: &mut Chip8, d: &Decoded) {
+    chip.timer_delay = chip.reg[d.x as usize];
+    chip.pc += 2;
+}
+
+fn op_fx18(chip: &mut Chip8, d: &Decoded) {
+    chip.timer_sound = chip.reg[d.x as usize];
+    chip.pc += 2;
+}
+
+fn op_fx1e(chip: &mut Chip8, d: &Decoded) {
+    chip.index += chip.reg[d.x as usize] as u16;
+    chip.pc += 2;
+}
+
+fn op_fx29(chip: &mut Chip8, d: &Decoded) {
+    chip.index = 5 * chip.reg[d.x as usize] as u16;
+    chip.pc += 2;
+}
+
+fn op_fx33(chip: &mut Chip8, d: &Decoded) {
+    chip.store_bcd(d.x as usize);
+    chip.pc += 2;
+}
+
+fn op_fx55(chip: &mut Chip8, d: &Decoded) {
+    chip.store_registers(d.x as usize);
+    chip.pc += 2;
+}
+
+fn op_fx65(chip: &mut Chip8, d: &Decoded) {
+    chip.load_registers(d.x as usize);
+    chip.pc += 2;
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::decode;
+    use platform::Platform;
+    use {Chip8, Engine};
+
+    /// Loops forever through arithmetic, memory, timer, key and drawing
+    /// opcodes, with the registers changing every time around.
+    pub const EVERY_OPCODE: [u8; 66] = [
+        0x00, 0xE0, 0x6A, 0x05, 0x7B, 0x13, 0x8C, 0xB0, 0x8C, 0xA1, 0x8C, 0xA2,
+        0x8C, 0xB3, 0x8C, 0xA4, 0x8D, 0xC5, 0x8D, 0xA6, 0x8E, 0xB7, 0x8E, 0xCE,
+        0x3B, 0x40, 0x4C, 0x10, 0x5A, 0xB0, 0x9A, 0xC0, 0xC9, 0xFF, 0xFD, 0x15,
+        0xF8, 0x07, 0xFB, 0x18, 0xFA, 0x29, 0xDB, 0xC5, 0xA3, 0x00, 0xFB, 0x33,
+        0xF5, 0x55, 0xF5, 0x65, 0xEA, 0x9E, 0xEA, 0xA1, 0x22, 0x3C, 0x12, 0x02,
+        0xFA, 0x1E, 0x7F, 0x01, 0x00, 0xEE,
+    ];
+
+    /// Runs 6205 at 0x20A once, then overwrites it with 6207 using FX55 and
+    /// runs it again.
+    pub const SELF_MODIFYING: [u8; 24] = [
+        0xA2, 0x0A, 0x60, 0x62, 0x61, 0x07, 0x63, 0x00, 0x12, 0x0A,
+        0x62, 0x05, 0x33, 0x01, 0x12, 0x12, 0x12, 0x10,
+        0x73, 0x01, 0xF1, 0x55, 0x12, 0x0A,
+    ];
+
+    /// Runs the program on an interpreting chip and one with the given
+    /// engine side by side, for the given number of frames of the given
+    /// number of cycles, checking that they agree after every frame.
+    pub fn run_both(engine: Engine, program: &[u8], platform: Option<Platform>,
+                    frames: u32, cycles: u32) -> Chip8 {
+        let mut plain = Chip8::default();
+        let mut other = Chip8::with_engine(engine);
+        for chip in [&mut plain, &mut other].iter_mut() {
+            if let Some(platform) = platform {
+                chip.quirks = platform.quirks();
+            }
+            chip.set_seed(7);
+            chip.load_hex(program);
+        }
+
+        for frame in 0..frames {
+            // Press a few keys now and then, so key opcodes go both ways
+            let keys = [(frame / 7 % 16) as usize, (frame / 3 % 16) as usize];
+            for chip in [&mut plain, &mut other].iter_mut() {
+                chip.key = [0; 16];
+                for &key in keys.iter() {
+                    chip.key[key] = (frame % 5 != 0) as u8;
+                }
+                chip.emulate_frame(cycles);
+            }
+            assert_eq!(plain.cpu_state(), other.cpu_state(), "frame {}", frame);
+            assert_eq!(plain.memory(), other.memory(), "frame {}", frame);
+            assert_eq!(plain.graphics, other.graphics, "frame {}", frame);
+        }
+        other
+    }
+
+    #[test]
+    fn decodes() {
+        let decoded = decode(0xD12F);
+        assert_eq!((decoded.x, decoded.y, decoded.n, decoded.nn, decoded.nnn),
+                   (0x1, 0x2, 0xF, 0x2F, 0x12F));
+    }
+
+    #[test]
+    #[should_panic(expected = "Opcode 0x8128 is bad")]
+    fn bad_opcodes_panic() {
+        let mut chip = Chip8::with_engine(Engine::Cached);
+        chip.load_hex(&[0x81, 0x28]);
+        chip.emulate_cycle();
+    }
+
+    #[test]
+    fn matches_interpreter_on_pong() {
+        let pong = include_bytes!("../PONG");
+        run_both(Engine::Cached, pong, None, 600, 15);
+        run_both(Engine::Cached, pong, Some(Platform::Chip8), 600, 15);
+    }
+
+    #[test]
+    fn matches_interpreter_on_every_opcode() {
+        run_both(Engine::Cached, &EVERY_OPCODE, None, 300, 15);
+        run_both(Engine::Cached, &EVERY_OPCODE, Some(Platform::Chip8), 300, 15);
+        run_both(Engine::Cached, &EVERY_OPCODE, Some(Platform::SuperChip), 300, 15);
+    }
+
+    #[test]
+    fn sees_self_modifying_code() {
+        let chip = run_both(Engine::Cached, &SELF_MODIFYING, None, 2, 15);
+        assert_eq!(chip.registers()[2], 7);
+        assert_eq!(chip.pc(), 0x210);
+    }
+
+    #[test]
+    fn sees_outside_writes() {
+        let mut chip = Chip8::with_engine(Engine::Cached);
+        chip.load_hex(&[0x60, 0x01, 0x12, 0x00]);
+        chip.emulate_frame(2);
+        chip.write_memory(0x200, &[0x60, 0x02]).unwrap();
+        chip.emulate_frame(2);
+        assert_eq!(chip.registers()[0], 2);
+
+        chip.memory_bus().bytes_mut()[0x201] = 0x03;
+        chip.emulate_frame(2);
+        assert_eq!(chip.registers()[0], 3);
+
+        // The cache is emptied in place, once, however often memory is handed
+        // out between frames
+        let cache = chip.cache.as_ptr();
+        for value in 5..8 {
+            chip.memory_bus().bytes_mut()[0x201] = value;
+        }
+        chip.emulate_frame(2);
+        assert_eq!(chip.registers()[0], 7);
+        assert_eq!(chip.cache.as_ptr(), cache);
+
+        // Read hooks bypass the cache
+        chip.memory_bus().add_read_hook(0x201, 0x201, |_, _| 0x04);
+        chip.emulate_frame(2);
+        assert_eq!(chip.registers()[0], 4);
+    }
+}
@@ -0,0 +1,399 @@
+use render::{Image, Palette};
+use std::cmp;
+use super::{HEIGHT, NPIXELS, WIDTH};
+
+/// A post-processing filter used when scaling the display up into an image.
+///
+/// Filters only depend on the bitmap, palette and scale, so the same filter
+/// looks the same in the SDL window, in screenshots and in recordings.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Filter {
+    /// Plain nearest neighbor scaling.
+    #[default]
+    None,
+    /// Darkens every other line, like the gaps between a TV's scanlines.
+    Scanlines,
+    /// Imitates a CRT monitor with glowing pixels, an RGB shadow mask and
+    /// scanlines.
+    Crt,
+    /// Darkens the edges of each pixel, showing a grid between them.
+    Grid,
+    /// Smooths diagonal edges by doubling the resolution with AdvMAME2x.
+    Scale2x,
+    /// Smooths diagonal edges by tripling the resolution with AdvMAME3x.
+    Scale3x,
+    /// Smooths diagonal edges by doubling the resolution with Eric
+    /// Johnston's original EPX rules.
+    Epx,
+}
+
+/// Every filter, in the order they are cycled through.
+pub const FILTERS: [Filter; 7] = [
+    Filter::None,
+    Filter::Scanlines,
+    Filter::Crt,
+    Filter::Grid,
+    Filter::Scale2x,
+    Filter::Scale3x,
+    Filter::Epx,
+];
+
+impl Filter {
+    /// The filter's name, as used on the command line.
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Filter::None => "none",
+            Filter::Scanlines => "scanlines",
+            Filter::Crt => "crt",
+            Filter::Grid => "grid",
+            Filter::Scale2x => "scale2x",
+            Filter::Scale3x => "scale3x",
+            Filter::Epx => "epx",
+        }
+    }
+
+    /// Finds the filter with the given name, ignoring case.
+    pub fn from_name(name: &str) -> Option<Filter> {
+        FILTERS.iter().cloned().find(|f| f.name().eq_ignore_ascii_case(name))
+    }
+
+    /// The filter after this one, wrapping around to the first.
+    pub fn next(&self) -> Filter {
+        let i = FILTERS.iter().position(|f| f == self).unwrap();
+        FILTERS[(i + 1) % FILTERS.len()]
+    }
+
+    /// Renders the given bitmap with the given palette, scaled up by the
+    /// given integer factor and then filtered.
+    pub fn render(&self, bitmap: &[u8; NPIXELS], palette: &Palette, scale: u32) -> Image {
+        match *self {
+            Filter::None => Image::from_bitmap(bitmap, palette, scale),
+            Filter::Scanlines => {
+                let mut image = Image::from_bitmap(bitmap, palette, scale);
+                scanlines(&mut image, 128);
+                image
+            }
+            Filter::Crt => {
+                let mut image = Image::from_bitmap(bitmap, palette, scale);
+                bloom(&mut image, cmp::max(1, scale / 2));
+                shadow_mask(&mut image);
+                scanlines(&mut image, 192);
+                image
+            }
+            Filter::Grid => {
+                let mut image = Image::from_bitmap(bitmap, palette, scale);
+                grid(&mut image, scale);
+                image
+            }
+            Filter::Scale2x => smooth(bitmap, palette, scale, 2, scale2x),
+            Filter::Scale3x => smooth(bitmap, palette, scale, 3, scale3x),
+            Filter::Epx => smooth(bitmap, palette, scale, 2, epx),
+        }
+    }
+}
+
+/// Multiplies each of the pixel's color channels by the given factor, where
+/// 256 leaves the pixel unchanged.
+fn dim(pixel: &mut [u8], factor: [u32; 3]) {
+    for c in 0..3 {
+        pixel[c] = (pixel[c] as u32 * factor[c] / 256) as u8;
+    }
+}
+
+/// Dims every other line of the image by the given factor out of 256.
+fn scanlines(image: &mut Image, factor: u32) {
+    let row = image.width as usize * 4;
+    for line in image.pixels.chunks_mut(row).skip(1).step_by(2) {
+        for pixel in line.chunks_mut(4) {
+            dim(pixel, [factor; 3]);
+        }
+    }
+}
+
+/// Dims the last column and row of every scaled pixel.
+fn grid(image: &mut Image, scale: u32) {
+    if scale < 3 {
+        return;
+    }
+    let width = image.width as usize;
+    let scale = scale as usize;
+    for (i, pixel) in image.pixels.chunks_mut(4).enumerate() {
+        let (x, y) = (i % width, i / width);
+        if x % scale == scale - 1 || y % scale == scale - 1 {
+            dim(pixel, [160; 3]);
+        }
+    }
+}
+
+/// Lets bright pixels glow into their neighbors by adding a blurred copy of
+/// the image, blurred by the given radius, on top of it.
+fn bloom(image: &mut Image, radius: u32) {
+    let width = image.width as usize;
+    let height = image.height as usize;
+    let radius = radius as isize;
+    let size = (2 * radius + 1) as u32;
+
+    // Blur horizontally, then vertically, clamping at the edges
+    let mut across = vec![0u32; width * height * 3];
+    for y in 0..height {
+        for x in 0..width {
+            for dx in -radius..radius + 1 {
+                let sx = cmp::min(cmp::max(x as isize + dx, 0), width as isize - 1) as usize;
+                for c in 0..3 {
+                    across[(y * width + x) * 3 + c] += image.pixels[(y * width + sx) * 4 + c] as u32;
+                }
+            }
+        }
+    }
+    for y in 0..height {
+        for x in 0..width {
+            for c in 0..3 {
+                let mut sum = 0;
+                for dy in -radius..radius + 1 {
+                    let sy = cmp::min(cmp::max(y as isize + dy, 0), height as isize - 1) as usize;
+                    sum += across[(sy * width + x) * 3 + c];
+                }
+                let glow = sum / (size * size) / 2;
+                let value = &mut image.pixels[(y * width + x) * 4 + c];
+                *value = cmp::min(255, *value as u32 + glow) as u8;
+            }
+        }
+    }
+}
+
+/// Imitates a CRT's aperture grille by favoring red, green and blue in turn
+/// on each column.
+fn shadow_mask(image: &mut Image) {
+    let width = image.width as usize;
+    for (i, pixel) in image.pixels.chunks_mut(4).enumerate() {
+        let factor = match (i % width) % 3 {
+            0 => [256, 192, 192],
+            1 => [192, 256, 192],
+            _ => [192, 192, 256],
+        };
+        dim(pixel, factor);
+    }
+}
+
+/// Enlarges the bitmap by the given factor with the given edge smoothing
+/// algorithm, then scales the result to the size asked for with nearest
+/// neighbor sampling and colors it with the palette.
+fn smooth(bitmap: &[u8; NPIXELS],
+          palette: &Palette,
+          scale: u32,
+          factor: usize,
+          algorithm: fn(&Neighbors) -> [u8; 9]) -> Image {
+    let (width, height) = (WIDTH as usize, HEIGHT as usize);
+    let big_width = width * factor;
+    let mut big = vec![0; NPIXELS * factor * factor];
+    for y in 0..height {
+        for x in 0..width {
+            let out = algorithm(&Neighbors::around(bitmap, x, y));
+            for j in 0..factor {
+                for i in 0..factor {
+                    big[(y * factor + j) * big_width + x * factor + i] = out[j * factor + i];
+                }
+            }
+        }
+    }
+
+    // Scale the enlarged bitmap to the final size and color it
+    let out_width = (WIDTH * scale) as usize;
+    let out_height = (HEIGHT * scale) as usize;
+    let mut pixels = Vec::with_capacity(out_width * out_height * 4);
+    for y in 0..out_height {
+        let sy = y * height * factor / out_height;
+        for x in 0..out_width {
+            let sx = x * big_width / out_width;
+            let index = big[sy * big_width + sx];
+            pixels.extend_from_slice(&palette.color(index));
+            pixels.push(0xFF);
+        }
+    }
+    Image {
+        width: out_width as u32,
+        height: out_height as u32,
+        pixels: pixels,
+    }
+}
+
+/// A pixel and its eight neighbors, named like so, with pixels past the edge
+/// of the display repeating the nearest edge pixel:
+///
+/// ```text
+/// A B C
+/// D E F
+/// G H I
+/// ```
+struct Neighbors {
+    a: u8, b: u8, c: u8,
+    d: u8, e: u8, f: u8,
+    g: u8, h: u8, i: u8,
+}
+
+impl Neighbors {
+    fn around(bitmap: &[u8; NPIXELS], x: usize, y: usize) -> Self {
+        let (width, height) = (WIDTH as usize, HEIGHT as usize);
+        let left = if x == 0 { 0 } else { x - 1 };
+        let right = cmp::min(x + 1, width - 1);
+        let up = if y == 0 { 0 } else { y - 1 };
+        let down = cmp::min(y + 1, height - 1);
+        let at = |x: usize, y: usize| if 0 == bitmap[y * width + x] { 0 } else { 1 };
+        Neighbors {
+            a: at(left, up), b: at(x, up), c: at(right, up),
+            d: at(left, y), e: at(x, y), f: at(right, y),
+            g: at(left, down), h: at(x, down), i: at(right, down),
+        }
+    }
+}
+
+/// AdvMAME2x, filling the first four entries of the result row by row.
+fn scale2x(n: &Neighbors) -> [u8; 9] {
+    let (b, d, e, f, h) = (n.b, n.d, n.e, n.f, n.h);
+    let mut out = [e; 9];
+    if b != h && d != f {
+        out[0] = if d == b { d } else { e };
+        out[1] = if b == f { f } else { e };
+        out[2] = if d == h { d } else { e };
+        out[3] = if h == f { f } else { e };
+    }
+    out
+}
+
+/// AdvMAME3x, filling all nine entries of the result row by row.
+fn scale3x(n: &Neighbors) -> [u8; 9] {
+    let (a, b, c, d, e, f, g, h, i) = (n.a, n.b, n.c, n.d, n.e, n.f, n.g, n.h, n.i);
+    let mut out = [e; 9];
+    if b != h && d != f {
+        out[0] = if d == b { d } else { e };
+        out[1] = if (d == b && e != c) || (b == f && e != a) { b } else { e };
+        out[2] = if b == f { f } else { e };
+        out[3] = if (d == b && e != g) || (d == h && e != a) { d } else { e };
+        out[5] = if (b == f && e != i) || (h == f && e != c) { f } else { e };
+        out[6] = if d == h { d } else { e };
+        out[7] = if (d == h && e != i) || (h == f && e != g) { h } else { e };
+        out[8] = if h == f { f } else { e };
+    }
+    out
+}
+
+/// EPX, filling the first four entries of the result row by row.
+fn epx(n: &Neighbors) -> [u8; 9] {
+    // EPX names the pixels above, right, left and below P as A, B, C and D
+    let (top, right, left, bottom, p) = (n.b, n.f, n.d, n.h, n.e);
+    let mut out = [p; 9];
+    let matching = [(top, right), (top, left), (top, bottom),
+                    (right, left), (right, bottom), (left, bottom)];
+    // Three or more matching neighbors means P is inside a shape
+    if matching.iter().filter(|&&(x, y)| x == y).count() >= 3 {
+        return out;
+    }
+    if left == top { out[0] = top; }
+    if top == right { out[1] = right; }
+    if bottom == left { out[2] = left; }
+    if right == bottom { out[3] = bottom; }
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Filter, FILTERS};
+    use render::{Image, Palette};
+    use NPIXELS;
+
+    /// A bitmap with a single diagonal line from the top left corner.
+    fn diagonal() -> [u8; NPIXELS] {
+        let mut bitmap = [0; NPIXELS];
+        for i in 0..8 {
+            bitmap[i * 64 + i] = 1;
+        }
+        bitmap
+    }
+
+    /// Whether the pixel at the given position of the image is the palette's
+    /// foreground color.
+    fn lit(image: &Image, x: u32, y: u32) -> bool {
+        let i = ((y * image.width + x) * 4) as usize;
+        image.pixels[i..i + 3] == Palette::default().foreground
+    }
+
+    #[test]
+    fn names() {
+        for filter in FILTERS.iter() {
+            assert_eq!(Filter::from_name(filter.name()), Some(*filter));
+        }
+        assert_eq!(Filter::from_name("CRT"), Some(Filter::Crt));
+        assert_eq!(Filter::from_name("blur"), None);
+    }
+
+    #[test]
+    fn next_wraps_around() {
+        let mut filter = Filter::None;
+        for _ in 0..FILTERS.len() {
+            filter = filter.next();
+        }
+        assert_eq!(filter, Filter::None);
+        assert_eq!(Filter::None.next(), Filter::Scanlines);
+    }
+
+    #[test]
+    fn every_filter_keeps_the_size() {
+        for filter in FILTERS.iter() {
+            for scale in 1..5 {
+                let image = filter.render(&diagonal(), &Palette::default(), scale);
+                assert_eq!(image.width, 64 * scale);
+                assert_eq!(image.height, 32 * scale);
+                assert_eq!(image.pixels.len(), (64 * 32 * 4 * scale * scale) as usize);
+            }
+        }
+    }
+
+    #[test]
+    fn none_matches_plain_rendering() {
+        let palette = Palette::default();
+        assert_eq!(Filter::None.render(&diagonal(), &palette, 3),
+                   Image::from_bitmap(&diagonal(), &palette, 3));
+    }
+
+    #[test]
+    fn scanlines_dim_odd_lines() {
+        let mut bitmap = [0; NPIXELS];
+        bitmap[0] = 1;
+        let image = Filter::Scanlines.render(&bitmap, &Palette::default(), 2);
+        assert!(lit(&image, 0, 0));
+        assert!(!lit(&image, 0, 1));
+        assert_eq!(&image.pixels[128 * 4..128 * 4 + 4], &[0x7F, 0x7F, 0x00, 0xFF]);
+    }
+
+    #[test]
+    fn scale2x_fills_in_diagonals() {
+        let palette = Palette::default();
+        let plain = Filter::None.render(&diagonal(), &palette, 2);
+        let image = Filter::Scale2x.render(&diagonal(), &palette, 2);
+        assert!(!lit(&plain, 2, 1));
+        assert!(!lit(&plain, 1, 2));
+        assert!(lit(&image, 2, 1));
+        assert!(lit(&image, 1, 2));
+        assert!(!lit(&image, 3, 0));
+    }
+
+    #[test]
+    fn epx_matches_scale2x() {
+        let palette = Palette::default();
+        assert_eq!(Filter::Epx.render(&diagonal(), &palette, 4),
+                   Filter::Scale2x.render(&diagonal(), &palette, 4));
+    }
+
+    #[test]
+    fn scale3x_keeps_lone_pixels() {
+        let mut bitmap = [0; NPIXELS];
+        bitmap[64 + 1] = 1;
+        let image = Filter::Scale3x.render(&bitmap, &Palette::default(), 3);
+        for y in 3..6 {
+            for x in 3..6 {
+                assert!(lit(&image, x, y));
+            }
+        }
+        assert!(!lit(&image, 2, 3));
+    }
+}
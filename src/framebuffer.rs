@@ -0,0 +1,367 @@
+//! The chip's display, stored as packed rows of bits so that sprites can be
+//! drawn a whole row at a time.
+
+use super::{HEIGHT, NPIXELS, WIDTH};
+
+/// The widest the display can be (in pixels).
+pub const MAX_WIDTH: u32 = 128;
+
+/// The tallest the display can be (in pixels).
+pub const MAX_HEIGHT: u32 = 64;
+
+/// The number of bit planes, which XO-CHIP draws in separately to show up to
+/// four colors.
+pub const PLANES: usize = 2;
+
+/// The display's resolution.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Resolution {
+    /// The original 64x32 display.
+    Low,
+    /// SUPER-CHIP's 128x64 display.
+    High,
+}
+
+impl Resolution {
+    /// The width of the display (in pixels).
+    pub fn width(&self) -> u32 {
+        match *self {
+            Resolution::Low => WIDTH,
+            Resolution::High => MAX_WIDTH,
+        }
+    }
+
+    /// The height of the display (in pixels).
+    pub fn height(&self) -> u32 {
+        match *self {
+            Resolution::Low => HEIGHT,
+            Resolution::High => MAX_HEIGHT,
+        }
+    }
+}
+
+/// A rectangle of pixels on the display.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Rect {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Rect {
+    /// The smallest rectangle covering both this one and the given one.
+    pub fn union(&self, other: &Rect) -> Rect {
+        let x = self.x.min(other.x);
+        let y = self.y.min(other.y);
+        Rect {
+            x: x,
+            y: y,
+            width: (self.x + self.width).max(other.x + other.width) - x,
+            height: (self.y + self.height).max(other.y + other.height) - y,
+        }
+    }
+}
+
+/// The chip's display.
+///
+/// Each row of each plane is a `u128`, with the leftmost pixel in the
+/// highest bit the resolution uses, so a sprite row is drawn with a shift
+/// and an XOR.  A pixel's value has a bit for each plane that is on there,
+/// so it is 0 for off and 1 for on when only the first plane is used.
+///
+/// The display remembers the rectangle that has changed since it was last
+/// asked, so frontends can redraw only that part.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Framebuffer {
+    resolution: Resolution,
+    selected: u8,
+    rows: [[u128; MAX_HEIGHT as usize]; PLANES],
+    dirty: Option<Rect>,
+}
+
+impl Default for Framebuffer {
+    /// Constructs a blank low resolution display drawing in the first
+    /// plane, which counts as changed all over.
+    fn default() -> Self {
+        let mut framebuffer = Framebuffer {
+            resolution: Resolution::Low,
+            selected: 1,
+            rows: [[0; MAX_HEIGHT as usize]; PLANES],
+            dirty: None,
+        };
+        framebuffer.mark_all_dirty();
+        framebuffer
+    }
+}
+
+impl Framebuffer {
+    /// The display's resolution.
+    pub fn resolution(&self) -> Resolution {
+        self.resolution
+    }
+
+    /// Changes the display's resolution, which clears it in every plane.
+    pub fn set_resolution(&mut self, resolution: Resolution) {
+        self.resolution = resolution;
+        self.rows = [[0; MAX_HEIGHT as usize]; PLANES];
+        self.mark_all_dirty();
+    }
+
+    /// The width of the display (in pixels).
+    pub fn width(&self) -> u32 {
+        self.resolution.width()
+    }
+
+    /// The height of the display (in pixels).
+    pub fn height(&self) -> u32 {
+        self.resolution.height()
+    }
+
+    /// The planes that clearing and drawing affect, one bit per plane.
+    pub fn selected_planes(&self) -> u8 {
+        self.selected
+    }
+
+    /// Picks the planes that clearing and drawing affect, one bit per plane.
+    /// Bits for planes that don't exist are ignored.
+    pub fn select_planes(&mut self, planes: u8) {
+        self.selected = planes & ((1 << PLANES) - 1);
+    }
+
+    /// Turns off every pixel in the selected planes.
+    pub fn clear(&mut self) {
+        for plane in 0..PLANES {
+            if self.selected & (1 << plane) != 0 {
+                self.rows[plane] = [0; MAX_HEIGHT as usize];
+            }
+        }
+        self.mark_all_dirty();
+    }
+
+    /// The value of the pixel at the given position, with a bit for each
+    /// plane it is on in.  Pixels off the display are 0.
+    pub fn pixel(&self, x: u32, y: u32) -> u8 {
+        if x >= self.width() || y >= self.height() {
+            return 0;
+        }
+        let bit = self.column_bit(x);
+        (0..PLANES).fold(0, |value, plane| {
+            value | (((self.rows[plane][y as usize] & bit) != 0) as u8) << plane
+        })
+    }
+
+    /// Sets the pixel at the given position to the given value, with a bit
+    /// for each plane to turn it on in.  Pixels off the display are ignored.
+    pub fn set_pixel(&mut self, x: u32, y: u32, value: u8) {
+        if x >= self.width() || y >= self.height() {
+            return;
+        }
+        let bit = self.column_bit(x);
+        for plane in 0..PLANES {
+            let row = &mut self.rows[plane][y as usize];
+            if value & (1 << plane) != 0 {
+                *row |= bit;
+            } else {
+                *row &= !bit;
+            }
+        }
+        self.mark_dirty(Rect { x: x, y: y, width: 1, height: 1 });
+    }
+
+    /// XORs an 8 pixel wide sprite onto the selected planes with its top
+    /// left corner at the given position.  Pixels past the edges wrap around
+    /// if `wrap` is set, and are clipped otherwise.  Returns whether any
+    /// pixel was turned off.
+    pub fn draw_sprite(&mut self, x: u32, y: u32, sprite: &[u8], wrap: bool) -> bool {
+        self.draw(x, y, 8, sprite.iter().map(|&row| row as u128), wrap)
+    }
+
+    /// Draws a 16 pixel wide sprite, like SUPER-CHIP's 16x16 sprites, in the
+    /// same way as `draw_sprite`.
+    pub fn draw_wide_sprite(&mut self, x: u32, y: u32, sprite: &[u16], wrap: bool) -> bool {
+        self.draw(x, y, 16, sprite.iter().map(|&row| row as u128), wrap)
+    }
+
+    /// The rectangle that has changed since the last call, if any, which is
+    /// then forgotten.
+    pub fn take_dirty(&mut self) -> Option<Rect> {
+        self.dirty.take()
+    }
+
+    /// The rectangle that has changed since the last call to `take_dirty`,
+    /// if any.
+    pub fn dirty(&self) -> Option<Rect> {
+        self.dirty
+    }
+
+    /// The values of the pixels in the given row, from left to right.
+    pub fn row<'a>(&'a self, y: u32) -> impl Iterator<Item = u8> + 'a {
+        (0..self.width()).map(move |x| self.pixel(x, y))
+    }
+
+    /// The values of all of the pixels, row by row.
+    pub fn pixels<'a>(&'a self) -> impl Iterator<Item = u8> + 'a {
+        (0..self.height()).flat_map(move |y| self.row(y))
+    }
+
+    /// The display as a 64x32 bitmap with one byte per pixel, for the
+    /// renderers.  At high resolution every other pixel is used.
+    pub fn bitmap(&self) -> [u8; NPIXELS] {
+        let step = self.width() / WIDTH;
+        let mut bitmap = [0; NPIXELS];
+        for y in 0..HEIGHT {
+            for x in 0..WIDTH {
+                bitmap[(y * WIDTH + x) as usize] = self.pixel(x * step, y * step);
+            }
+        }
+        bitmap
+    }
+
+    /// The bit for the given column in a row.
+    fn column_bit(&self, x: u32) -> u128 {
+        1 << (self.width() - 1 - x)
+    }
+
+    /// XORs sprite rows that are the given number of pixels wide onto the
+    /// selected planes.
+    fn draw<I>(&mut self, x: u32, y: u32, sprite_width: u32, sprite: I, wrap: bool) -> bool
+        where I: Iterator<Item = u128> + Clone
+    {
+        let width = self.width();
+        let height = self.height();
+        let mask = if width == 128 { !0 } else { (1 << width) - 1 };
+        let (x, y) = if wrap { (x % width, y % height) } else { (x, y) };
+        if x >= width || y >= height {
+            return false;
+        }
+
+        let mut collided = false;
+        for plane in 0..PLANES {
+            if self.selected & (1 << plane) == 0 {
+                continue;
+            }
+            for (i, bits) in sprite.clone().enumerate() {
+                let row_y = y + i as u32;
+                let row_y = if wrap {
+                    row_y % height
+                } else if row_y < height {
+                    row_y
+                } else {
+                    break;
+                };
+
+                // Line the sprite row up with the left edge, then move it over
+                let aligned = bits << (width - sprite_width);
+                let placed = if wrap && x > 0 {
+                    (aligned >> x | aligned << (width - x)) & mask
+                } else {
+                    aligned >> x
+                };
+                if placed == 0 {
+                    continue;
+                }
+
+                let row = &mut self.rows[plane][row_y as usize];
+                collided |= *row & placed != 0;
+                *row ^= placed;
+
+                let left = placed.leading_zeros() - (MAX_WIDTH - width);
+                let right = width - placed.trailing_zeros();
+                self.mark_dirty(Rect { x: left, y: row_y, width: right - left, height: 1 });
+            }
+        }
+        collided
+    }
+
+    fn mark_dirty(&mut self, rect: Rect) {
+        self.dirty = Some(match self.dirty {
+            Some(dirty) => dirty.union(&rect),
+            None => rect,
+        });
+    }
+
+    fn mark_all_dirty(&mut self) {
+        let (width, height) = (self.width(), self.height());
+        self.mark_dirty(Rect { x: 0, y: 0, width: width, height: height });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Framebuffer, Rect, Resolution};
+
+    #[test]
+    fn draws_and_collides() {
+        let mut framebuffer = Framebuffer::default();
+        framebuffer.take_dirty();
+
+        assert!(!framebuffer.draw_sprite(4, 2, &[0b1000_0001, 0xFF], false));
+        assert_eq!(framebuffer.pixel(4, 2), 1);
+        assert_eq!(framebuffer.pixel(5, 2), 0);
+        assert_eq!(framebuffer.pixel(11, 2), 1);
+        assert_eq!(framebuffer.pixel(7, 3), 1);
+        assert_eq!(framebuffer.take_dirty(), Some(Rect { x: 4, y: 2, width: 8, height: 2 }));
+        assert_eq!(framebuffer.take_dirty(), None);
+
+        assert!(framebuffer.draw_sprite(4, 3, &[0x01], false));
+        assert_eq!(framebuffer.pixel(11, 3), 0);
+        assert_eq!(framebuffer.take_dirty(), Some(Rect { x: 11, y: 3, width: 1, height: 1 }));
+    }
+
+    #[test]
+    fn clips_and_wraps() {
+        let mut framebuffer = Framebuffer::default();
+        framebuffer.draw_sprite(60, 31, &[0xFF, 0xFF], false);
+        assert_eq!(framebuffer.pixels().filter(|&pixel| pixel != 0).count(), 4);
+
+        let mut framebuffer = Framebuffer::default();
+        framebuffer.draw_sprite(60, 31, &[0xFF, 0xFF], true);
+        assert_eq!(framebuffer.pixel(63, 31), 1);
+        assert_eq!(framebuffer.pixel(3, 31), 1);
+        assert_eq!(framebuffer.pixel(4, 31), 0);
+        assert_eq!(framebuffer.pixel(0, 0), 1);
+        assert_eq!(framebuffer.pixels().filter(|&pixel| pixel != 0).count(), 16);
+
+        // Sprites starting off the display are clipped entirely
+        let mut framebuffer = Framebuffer::default();
+        assert!(!framebuffer.draw_sprite(64, 0, &[0xFF], false));
+        assert_eq!(framebuffer.pixels().filter(|&pixel| pixel != 0).count(), 0);
+    }
+
+    #[test]
+    fn high_resolution_and_planes() {
+        let mut framebuffer = Framebuffer::default();
+        framebuffer.set_resolution(Resolution::High);
+        framebuffer.select_planes(0b11);
+        framebuffer.draw_wide_sprite(120, 63, &[0xFFFF], true);
+        assert_eq!(framebuffer.pixel(127, 63), 3);
+        assert_eq!(framebuffer.pixel(7, 63), 3);
+        assert_eq!(framebuffer.pixel(8, 63), 0);
+
+        framebuffer.select_planes(0b10);
+        framebuffer.clear();
+        assert_eq!(framebuffer.pixel(127, 63), 1);
+        assert_eq!(framebuffer.row(63).filter(|&pixel| pixel != 0).count(), 16);
+
+        // The renderers see every other pixel
+        framebuffer.set_pixel(2, 2, 1);
+        assert_eq!(framebuffer.bitmap()[64 + 1], 1);
+    }
+
+    #[test]
+    fn bitmaps() {
+        let mut framebuffer = Framebuffer::default();
+        framebuffer.set_pixel(2, 1, 1);
+        framebuffer.set_pixel(63, 31, 1);
+        let bitmap = framebuffer.bitmap();
+        assert_eq!(bitmap[64 + 2], 1);
+        assert_eq!(bitmap[64 * 32 - 1], 1);
+        assert_eq!(bitmap.iter().filter(|&&pixel| pixel != 0).count(), 2);
+
+        framebuffer.take_dirty();
+        framebuffer.clear();
+        assert_eq!(framebuffer.take_dirty(), Some(Rect { x: 0, y: 0, width: 64, height: 32 }));
+        assert!(framebuffer.pixels().all(|pixel| pixel == 0));
+    }
+}
@@ -1,26 +1,33 @@
 extern crate chip8;
 extern crate sdl2;
 
+use chip8::render::Image;
+use config::Scaling;
 use sdl2::pixels::PixelFormatEnum;
 use sdl2::rect::Rect;
+use sdl2::video::FullscreenType;
 use sdl2::Sdl;
+use std::cmp;
 use std::time::{Duration, Instant};
 use std::thread::sleep;
 
 /// Represents a display.  In this case, it represents an SDL window.
 pub struct Display<'a> {
-    width: u32,
-    height: u32,
     renderer: sdl2::render::Renderer<'a>,
     texture: sdl2::render::Texture,
+    texture_size: (u32, u32),
+    scaling: Scaling,
+    fullscreen: bool,
     frame_duration: Duration,
     frame_last: Instant,
 }
 
 impl<'a> Display<'a> {
-    /// Constructs a new SDL window with the given SDL context,
+    /// Constructs a new resizable SDL window with the given SDL context,
     /// the given title, the given width (in pixels, the given height
-    /// (in pixels), and the given frame duration.
+    /// (in pixels), and the given frame duration.  The window starts out
+    /// fullscreen if asked to, and stretches the display using the given
+    /// scaling.
     ///
     /// The frame duration is used to set how long each frame should last.
     /// Higher values lead to slower emulator performance but also help
@@ -29,32 +36,66 @@ impl<'a> Display<'a> {
                title: &str,
                width: u32,
                height: u32,
-               duration: Duration) -> Self {
+               duration: Duration,
+               fullscreen: bool,
+               scaling: Scaling) -> Self {
         let video_subsystem = context.video().unwrap();
-        let window = video_subsystem.window(title, width, height)
-            .position_centered()
+        let mut builder = video_subsystem.window(title, width, height);
+        builder.position_centered()
             .opengl()
-            .build()
-            .unwrap();
+            .resizable();
+        if fullscreen {
+            builder.fullscreen_desktop();
+        }
+        let mut window = builder.build().unwrap();
+        window.set_minimum_size(chip8::WIDTH, chip8::HEIGHT).unwrap();
+
+        // The texture's filtering is picked when it is created
+        sdl2::hint::set("SDL_RENDER_SCALE_QUALITY", match scaling {
+            Scaling::Integer => "nearest",
+            Scaling::Smooth => "linear",
+        });
         let renderer = window.renderer().build().unwrap();
         let texture = renderer.create_texture_streaming(
-            PixelFormatEnum::RGB24, 64, 32).unwrap();
+            PixelFormatEnum::RGB24, chip8::WIDTH, chip8::HEIGHT).unwrap();
 
         Display {
-            width: width,
-            height: height,
             renderer: renderer,
             texture: texture,
+            texture_size: (chip8::WIDTH, chip8::HEIGHT),
+            scaling: scaling,
+            fullscreen: fullscreen,
             frame_duration: duration,
             frame_last: Instant::now(),
         }
     }
 
-    /// The window draws the give bitmap image on the Display's frame duration.
+    /// Switches the window between fullscreen and windowed mode.
+    pub fn toggle_fullscreen(&mut self) {
+        self.fullscreen = !self.fullscreen;
+        let mode = if self.fullscreen {
+            FullscreenType::Desktop
+        } else {
+            FullscreenType::Off
+        };
+        if let Err(why) = self.renderer.window_mut().unwrap().set_fullscreen(mode) {
+            println!("Couldn't change fullscreen mode: {}", why);
+        }
+        self.redraw();
+    }
+
+    /// Changes the window's title.
+    pub fn set_title(&mut self, title: &str) {
+        if let Err(why) = self.renderer.window_mut().unwrap().set_title(title) {
+            println!("Couldn't set the window title: {}", why);
+        }
+    }
+
+    /// Waits out the rest of the Display's frame duration.
     ///
-    /// If there is time left over, that time is spent sleeping.
-    pub fn draw_frame(&mut self, bitmap: &[u8; chip8::NPIXELS]) {
-        // Keep timing okay
+    /// If there is time left over since the last call, that time is spent
+    /// sleeping.
+    pub fn sync(&mut self) {
         let prev_duration = Instant::now().duration_since(self.frame_last);
         debug!("Last frame duration: {:?}", prev_duration);
         if prev_duration < self.frame_duration {
@@ -63,24 +104,60 @@ impl<'a> Display<'a> {
             sleep(diff);
         }
         self.frame_last = Instant::now();
+    }
 
+    /// The window draws the given image, stretching it to fit the window.
+    pub fn draw_frame(&mut self, image: &Image) {
+        // Filters can change the image's size, which needs a new texture
+        if (image.width, image.height) != self.texture_size {
+            self.texture = self.renderer.create_texture_streaming(
+                PixelFormatEnum::RGB24, image.width, image.height).unwrap();
+            self.texture_size = (image.width, image.height);
+        }
+
+        let width = image.width as usize;
         self.texture.with_lock(None, |buffer: &mut [u8], pitch: usize| {
-            for y in 0..32 {
-                for x in 0..64 {
+            for (y, row) in image.pixels.chunks(width * 4).enumerate() {
+                for (x, pixel) in row.chunks(4).enumerate() {
                     let offset = y*pitch + x*3;
-                    let value = if 0 != bitmap[y * 64 + x] { 255 } else { 0 };
-                    buffer[offset + 0] = value as u8;
-                    buffer[offset + 1] = value as u8;
-                    buffer[offset + 2] = 0;
+                    buffer[offset..offset + 3].copy_from_slice(&pixel[0..3]);
                 }
             }
-
         }).unwrap();
+        self.redraw();
+    }
+
+    /// The window draws the last image again, such as after it has
+    /// been resized.
+    pub fn redraw(&mut self) {
+        let viewport = self.viewport();
         self.renderer.clear();
-        self.renderer.copy(&self.texture,
-                           None,
-                           Some(Rect::new(0, 0, self.width, self.height)));
+        self.renderer.copy(&self.texture, None, Some(viewport));
         self.renderer.present();
     }
+
+    /// Finds where in the window the display should be drawn, keeping the
+    /// display's aspect ratio and centering it.
+    fn viewport(&self) -> Rect {
+        let (width, height) = self.renderer.output_size().unwrap();
+        let (w, h) = match self.scaling {
+            Scaling::Integer => {
+                let scale = cmp::max(1, cmp::min(width / chip8::WIDTH,
+                                                 height / chip8::HEIGHT));
+                (chip8::WIDTH * scale, chip8::HEIGHT * scale)
+            }
+            Scaling::Smooth => {
+                if width * chip8::HEIGHT > height * chip8::WIDTH {
+                    (height * chip8::WIDTH / chip8::HEIGHT, height)
+                } else {
+                    (width, width * chip8::HEIGHT / chip8::WIDTH)
+                }
+            }
+        };
+        Rect::new((width as i32 - w as i32) / 2,
+                  (height as i32 - h as i32) / 2,
+                  w,
+                  h)
+    }
 }
 
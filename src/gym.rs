@@ -0,0 +1,579 @@
+//! An environment for training agents to play games, in the style of OpenAI
+//! Gym.  An agent picks one of a few actions each step, each of which holds
+//! down a set of keys for a number of frames, and is given the display, a
+//! reward and whether the game is over.
+//!
+//! Games don't say what their score is, so each ROM needs a spec, which says
+//! what the actions are and which registers or memory hold the score and
+//! tell when the game is over.  Specs are written with a setting per line:
+//!
+//! ```text
+//! # Pong: the left paddle moves with 1 and 4, and the score is in memory
+//! ipf = 10
+//! frame-skip = 4
+//! action = none
+//! action = 1
+//! action = 4
+//! reward = 1 per [0x2F0]
+//! reward = -1 per [0x2F1]
+//! done = [0x2F1] == 9
+//! max-steps = 10000
+//! ```
+//!
+//! Nothing is drawn or played, so environments run as fast as the
+//! interpreter, and one can be cloned for each worker thread.
+
+use batch::{halted, panic_message};
+use std::fs::File;
+use std::io::prelude::*;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
+use std::str::FromStr;
+use {Chip8, NPIXELS};
+
+/// What the agent sees: the display as a 64x32 bitmap, row by row, with a
+/// byte per pixel.  High resolution displays are scaled down by keeping every
+/// other pixel.
+pub type Observation = [u8; NPIXELS];
+
+/// A value in the chip, or a number to compare it with.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Operand {
+    /// A register, from V0 to VF, written `V3`.
+    Register(u8),
+    /// The index register, written `I`.
+    Index,
+    /// The byte at an address in memory, written `[0x2F0]`.
+    Memory(u16),
+    /// A number, in decimal or in hex with `0x`.
+    Number(u16),
+}
+
+impl Operand {
+    /// The operand's value in the given chip.
+    pub fn value(&self, chip: &Chip8) -> u16 {
+        match *self {
+            Operand::Register(x) => chip.registers()[x as usize] as u16,
+            Operand::Index => chip.index(),
+            Operand::Memory(address) => {
+                let memory = chip.memory();
+                memory[address as usize % memory.len()] as u16
+            }
+            Operand::Number(number) => number,
+        }
+    }
+}
+
+impl FromStr for Operand {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Self, String> {
+        let text = text.trim();
+        let bad = || format!("Expected a register, I, [address] or a number, not {}", text);
+        if text.eq_ignore_ascii_case("I") {
+            return Ok(Operand::Index);
+        }
+        if text.len() == 2 && (text.starts_with('V') || text.starts_with('v')) {
+            return u8::from_str_radix(&text[1..], 16).map(Operand::Register).map_err(|_| bad());
+        }
+        if text.starts_with('[') && text.ends_with(']') {
+            return parse_number(&text[1..text.len() - 1]).map(Operand::Memory).ok_or_else(bad);
+        }
+        parse_number(text).map(Operand::Number).ok_or_else(bad)
+    }
+}
+
+/// How a condition compares its two operands.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Comparison {
+    Equal,
+    NotEqual,
+    Less,
+    LessOrEqual,
+    Greater,
+    GreaterOrEqual,
+}
+
+/// The comparisons, as they are written, with the longest first so that
+/// `<=` isn't taken for `<`.
+const COMPARISONS: [(&str, Comparison); 6] = [
+    ("==", Comparison::Equal),
+    ("!=", Comparison::NotEqual),
+    ("<=", Comparison::LessOrEqual),
+    (">=", Comparison::GreaterOrEqual),
+    ("<", Comparison::Less),
+    (">", Comparison::Greater),
+];
+
+/// A comparison of two values in the chip, such as `VE == 0`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Condition {
+    pub left: Operand,
+    pub comparison: Comparison,
+    pub right: Operand,
+}
+
+impl Condition {
+    /// Whether the condition holds in the given chip.
+    pub fn holds(&self, chip: &Chip8) -> bool {
+        let left = self.left.value(chip);
+        let right = self.right.value(chip);
+        match self.comparison {
+            Comparison::Equal => left == right,
+            Comparison::NotEqual => left != right,
+            Comparison::Less => left < right,
+            Comparison::LessOrEqual => left <= right,
+            Comparison::Greater => left > right,
+            Comparison::GreaterOrEqual => left >= right,
+        }
+    }
+}
+
+impl FromStr for Condition {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Self, String> {
+        for &(symbol, comparison) in &COMPARISONS {
+            if let Some((left, right)) = text.split_once(symbol) {
+                return Ok(Condition {
+                    left: left.parse()?,
+                    comparison: comparison,
+                    right: right.parse()?,
+                });
+            }
+        }
+        Err(format!("Expected a comparison such as V0 == 1, not {}", text.trim()))
+    }
+}
+
+/// A way of earning rewards.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Reward {
+    /// The amount is given on each step that the condition becomes true,
+    /// written `reward = 10 when VE == 0`.
+    When(Condition, f32),
+    /// The amount is given for each step of a value going up, and taken for
+    /// each step of it going down, written `reward = 1 per [0x2F0]`.
+    Per(Operand, f32),
+}
+
+impl FromStr for Reward {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Self, String> {
+        let text = text.trim();
+        let (amount, rest) = text.split_once(' ').unwrap_or((text, ""));
+        let amount: f32 = amount.parse().map_err(|_| format!("Bad reward {}", amount))?;
+        let rest = rest.trim_start();
+        if let Some(condition) = rest.strip_prefix("when ") {
+            Ok(Reward::When(condition.parse()?, amount))
+        } else if let Some(operand) = rest.strip_prefix("per ") {
+            Ok(Reward::Per(operand.parse()?, amount))
+        } else {
+            Err("Expected an amount, then when and a condition or per and a value".to_string())
+        }
+    }
+}
+
+/// How to play a ROM: its speed, the actions an agent can take, and how it
+/// is rewarded.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Spec {
+    /// The number of instructions run per 60Hz frame.
+    pub ipf: u32,
+    /// The number of frames each action is held for.
+    pub frame_skip: u32,
+    /// The keys held down for each action.
+    pub actions: Vec<Vec<u8>>,
+    /// The ways of earning rewards, which are added up each step.
+    pub rewards: Vec<Reward>,
+    /// The conditions that end the game when any of them holds.
+    pub done: Vec<Condition>,
+    /// The most steps in a game, after which it is cut short.
+    pub max_steps: Option<u64>,
+}
+
+impl Default for Spec {
+    /// Plays at the usual speed, holding actions for 4 frames as is usual
+    /// for Atari games, with an action for no keys and one for each key.
+    fn default() -> Self {
+        let mut actions = vec![Vec::new()];
+        actions.extend((0..16).map(|key| vec![key]));
+        Spec {
+            ipf: 10,
+            frame_skip: 4,
+            actions: actions,
+            rewards: Vec::new(),
+            done: Vec::new(),
+            max_steps: None,
+        }
+    }
+}
+
+impl Spec {
+    /// Reads a spec from the given file.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, String> {
+        let path = path.as_ref();
+        let mut text = String::new();
+        File::open(path)
+            .and_then(|mut file| file.read_to_string(&mut text))
+            .map_err(|why| format!("Couldn't read {}: {}", path.display(), why))?;
+        Spec::parse(&text).map_err(|why| format!("{}:{}", path.display(), why))
+    }
+
+    /// Parses a spec with a `key = value` setting per line.  Blank lines and
+    /// lines starting with `#` are skipped, settings that aren't given keep
+    /// their defaults, and any actions replace the default ones.
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut spec = Spec::default();
+        let mut actions = Vec::new();
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let set = match line.split_once('=') {
+                Some((key, value)) => spec.set(key.trim(), value.trim(), &mut actions),
+                None => Err("Expected key = value".to_string()),
+            };
+            set.map_err(|why| format!("Line {}: {}", i + 1, why))?;
+        }
+        if !actions.is_empty() {
+            spec.actions = actions;
+        }
+        Ok(spec)
+    }
+
+    /// Changes the setting with the given name, adding actions to `actions`.
+    fn set(&mut self, key: &str, value: &str, actions: &mut Vec<Vec<u8>>) -> Result<(), String> {
+        match key {
+            "ipf" => self.ipf = parse_setting(key, value)?,
+            "frame-skip" => {
+                self.frame_skip = parse_setting(key, value)?;
+                if self.frame_skip == 0 {
+                    return Err("The frame skip must be at least 1".to_string());
+                }
+            }
+            "max-steps" => self.max_steps = Some(parse_setting(key, value)?),
+            "action" => {
+                let mut keys = Vec::new();
+                if value != "none" {
+                    for key in value.split_whitespace() {
+                        keys.push(u8::from_str_radix(key, 16)
+                            .ok()
+                            .filter(|&key| key < 16)
+                            .ok_or_else(|| format!("Bad key {}", key))?);
+                    }
+                }
+                actions.push(keys);
+            }
+            "reward" => self.rewards.push(value.parse()?),
+            "done" => self.done.push(value.parse()?),
+            _ => return Err(format!("Unknown setting {}", key)),
+        }
+        Ok(())
+    }
+}
+
+/// Why a game ended.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum End {
+    /// The spec's done condition with the given index held.
+    Done(usize),
+    /// The chip reached a jump to itself, which is how most games stop.
+    Halted,
+    /// The chip panicked, usually on a bad opcode, with the given message.
+    Crashed(String),
+    /// The game ran for the spec's most steps and was cut short.
+    OutOfSteps,
+}
+
+/// What happened during a step, beyond the reward.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Info {
+    /// The number of steps since the game was reset.
+    pub steps: u64,
+    /// The number of frames run since the game was reset.
+    pub frames: u64,
+    /// Why the game ended, if it has.
+    pub end: Option<End>,
+}
+
+/// A game being played by an agent.
+#[derive(Clone, Debug)]
+pub struct Env {
+    /// The chip being played.
+    pub chip: Chip8,
+    spec: Spec,
+    /// The chip as it was given, which games start again from.
+    start: Chip8,
+    /// The value of each `Per` reward's operand after the last step.
+    values: Vec<u16>,
+    /// Whether each `When` reward's condition held after the last step.
+    held: Vec<bool>,
+    steps: u64,
+    frames: u64,
+    end: Option<End>,
+}
+
+impl Env {
+    /// Constructs an environment for the given chip, which should have its
+    /// ROM loaded and its quirks set, played as the spec says.
+    pub fn new(chip: Chip8, spec: Spec) -> Self {
+        let mut env = Env {
+            chip: chip.clone(),
+            spec: spec,
+            start: chip,
+            values: Vec::new(),
+            held: Vec::new(),
+            steps: 0,
+            frames: 0,
+            end: None,
+        };
+        env.reset();
+        env
+    }
+
+    /// How the game is played.
+    pub fn spec(&self) -> &Spec {
+        &self.spec
+    }
+
+    /// The number of actions, which are numbered from 0.
+    pub fn actions(&self) -> usize {
+        self.spec.actions.len()
+    }
+
+    /// Seeds the chip's random numbers for the games after the next reset,
+    /// so that they can differ from one another.
+    pub fn set_seed(&mut self, seed: u64) {
+        self.start.set_seed(seed);
+    }
+
+    /// Starts the game again from the beginning, returning the first
+    /// observation.
+    pub fn reset(&mut self) -> Observation {
+        self.chip = self.start.clone();
+        self.steps = 0;
+        self.frames = 0;
+        self.end = None;
+        let chip = &self.chip;
+        self.values = self.spec.rewards.iter()
+            .filter_map(|reward| match *reward {
+                Reward::Per(operand, _) => Some(operand.value(chip)),
+                Reward::When(..) => None,
+            })
+            .collect();
+        self.held = self.spec.rewards.iter()
+            .filter_map(|reward| match *reward {
+                Reward::When(condition, _) => Some(condition.holds(chip)),
+                Reward::Per(..) => None,
+            })
+            .collect();
+        self.observation()
+    }
+
+    /// Holds the action's keys for the spec's frame skip, returning the
+    /// observation, the reward, whether the game is over and what else
+    /// happened.  Once the game is over, steps do nothing until it is reset.
+    ///
+    /// Panics if there is no such action.
+    pub fn step(&mut self, action: usize) -> (Observation, f32, bool, Info) {
+        assert!(action < self.actions(), "Action {} is out of range", action);
+        if self.end.is_some() {
+            return (self.observation(), 0.0, true, self.info());
+        }
+
+        self.chip.key = [0; 16];
+        for &key in &self.spec.actions[action] {
+            self.chip.key[key as usize] = 1;
+        }
+        for _ in 0..self.spec.frame_skip {
+            let chip = &mut self.chip;
+            let ipf = self.spec.ipf;
+            let result = panic::catch_unwind(AssertUnwindSafe(|| chip.emulate_frame(ipf)));
+            self.frames += 1;
+            if let Err(cause) = result {
+                self.end = Some(End::Crashed(panic_message(&*cause)));
+                break;
+            }
+            if halted(&self.chip) {
+                self.end = Some(End::Halted);
+                break;
+            }
+        }
+        self.steps += 1;
+
+        let reward = self.reward();
+        if self.end.is_none() {
+            self.end = self.spec.done.iter()
+                .position(|condition| condition.holds(&self.chip))
+                .map(End::Done);
+        }
+        if self.end.is_none() && self.spec.max_steps.is_some_and(|most| self.steps >= most) {
+            self.end = Some(End::OutOfSteps);
+        }
+        (self.observation(), reward, self.end.is_some(), self.info())
+    }
+
+    /// What the agent sees now.
+    pub fn observation(&self) -> Observation {
+        self.chip.graphics.bitmap()
+    }
+
+    /// The steps and frames so far, and why the game ended.
+    pub fn info(&self) -> Info {
+        Info {
+            steps: self.steps,
+            frames: self.frames,
+            end: self.end.clone(),
+        }
+    }
+
+    /// Adds up the rewards earned since the last step.
+    fn reward(&mut self) -> f32 {
+        let chip = &self.chip;
+        let mut values = self.values.iter_mut();
+        let mut held = self.held.iter_mut();
+        let mut reward = 0.0;
+        for &kind in &self.spec.rewards {
+            match kind {
+                Reward::Per(operand, amount) => {
+                    let last = values.next().unwrap();
+                    let value = operand.value(chip);
+                    reward += amount * (value as i32 - *last as i32) as f32;
+                    *last = value;
+                }
+                Reward::When(condition, amount) => {
+                    let last = held.next().unwrap();
+                    let holds = condition.holds(chip);
+                    if holds && !*last {
+                        reward += amount;
+                    }
+                    *last = holds;
+                }
+            }
+        }
+        reward
+    }
+}
+
+/// Parses the number for a setting.
+fn parse_setting<T: FromStr>(key: &str, value: &str) -> Result<T, String> {
+    value.parse().map_err(|_| format!("Bad {} {}", key, value))
+}
+
+/// Parses a number in decimal, or in hex with `0x`.
+fn parse_number(text: &str) -> Option<u16> {
+    let text = text.trim();
+    match text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")) {
+        Some(hex) => u16::from_str_radix(hex, 16).ok(),
+        None => text.parse().ok(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Comparison, Condition, End, Env, Operand, Reward, Spec};
+    use Chip8;
+
+    fn chip(program: &[u8]) -> Chip8 {
+        let mut chip = Chip8::default();
+        chip.load_hex(program);
+        chip
+    }
+
+    #[test]
+    fn parses_specs() {
+        let spec = Spec::parse("# Test\nframe-skip = 2\naction = none\naction = 1 c\n\
+                                reward = 1 per [0x2F0]\nreward = -2.5 when vE >= 3\n\
+                                done = I == 0x300\nmax-steps = 50\n").unwrap();
+        assert_eq!(spec.ipf, 10);
+        assert_eq!(spec.frame_skip, 2);
+        assert_eq!(spec.actions, vec![vec![], vec![0x1, 0xC]]);
+        assert_eq!(spec.rewards,
+                   vec![Reward::Per(Operand::Memory(0x2F0), 1.0),
+                        Reward::When(Condition {
+                                         left: Operand::Register(0xE),
+                                         comparison: Comparison::GreaterOrEqual,
+                                         right: Operand::Number(3),
+                                     },
+                                     -2.5)]);
+        assert_eq!(spec.done,
+                   vec![Condition {
+                            left: Operand::Index,
+                            comparison: Comparison::Equal,
+                            right: Operand::Number(0x300),
+                        }]);
+        assert_eq!(spec.max_steps, Some(50));
+        assert_eq!(Spec::default().actions.len(), 17);
+
+        assert_eq!(Spec::parse("action = 1\naction = G"), Err("Line 2: Bad key G".to_string()));
+        assert_eq!(Spec::parse("done = V0"),
+                   Err("Line 1: Expected a comparison such as V0 == 1, not V0".to_string()));
+        assert_eq!(Spec::parse("done = VG == 1"),
+                   Err("Line 1: Expected a register, I, [address] or a number, not VG"
+                       .to_string()));
+        assert_eq!(Spec::parse("reward = per V0"), Err("Line 1: Bad reward per".to_string()));
+        assert_eq!(Spec::parse("speed = 3"), Err("Line 1: Unknown setting speed".to_string()));
+    }
+
+    #[test]
+    fn plays_games() {
+        // Adds 1 to V0 each frame key 5 is held, and 1 to V1 each frame it
+        // isn't
+        let program = [0x6E, 0x05, 0x63, 0x01, 0xF3, 0x15, 0xEE, 0x9E, 0x12, 0x0E,
+                       0x70, 0x01, 0x12, 0x10, 0x71, 0x01, 0xF4, 0x07, 0x34, 0x00,
+                       0x12, 0x10, 0x12, 0x04];
+        let spec = Spec::parse("frame-skip = 3\naction = none\naction = 5\n\
+                                reward = 1 per V0\nreward = -1 per V1\n\
+                                reward = 10 when V0 >= 4\ndone = V0 == 9\n").unwrap();
+        let mut env = Env::new(chip(&program), spec);
+        assert_eq!(env.actions(), 2);
+        let (_, reward, done, info) = env.step(1);
+        assert_eq!((reward, done, info.steps, info.frames), (3.0, false, 1, 3));
+        let (_, reward, done, _) = env.step(0);
+        assert_eq!((reward, done), (-3.0, false));
+        // V0 reaches 4 during this step, which is rewarded once
+        let (_, reward, _, _) = env.step(1);
+        assert_eq!(reward, 13.0);
+        let (_, reward, done, info) = env.step(1);
+        assert_eq!((reward, done, info.end), (3.0, true, Some(End::Done(0))));
+        assert_eq!(env.step(1).1, 0.0);
+
+        env.reset();
+        assert_eq!(env.chip.registers()[0], 0);
+        assert_eq!(env.info().steps, 0);
+        assert_eq!(env.step(1).1, 3.0);
+    }
+
+    #[test]
+    fn ends_games() {
+        let spec = Spec { max_steps: Some(3), ..Spec::default() };
+        let mut env = Env::new(chip(&[0x70, 0x01, 0x12, 0x00]), spec.clone());
+        assert!(!env.step(0).2);
+        assert!(!env.step(0).2);
+        assert_eq!(env.step(0).3.end, Some(End::OutOfSteps));
+
+        let mut env = Env::new(chip(&[0x60, 0x01, 0x12, 0x02]), spec.clone());
+        assert_eq!(env.step(0).3.end, Some(End::Halted));
+
+        let mut env = Env::new(chip(&[0x60, 0x01, 0xFF, 0xFF]), spec);
+        let (_, _, done, info) = env.step(0);
+        assert!(done);
+        assert_eq!(info.end, Some(End::Crashed("Opcode 0xFFFF is bad".to_string())));
+        assert_eq!(info.frames, 1);
+    }
+
+    #[test]
+    fn observes_the_display() {
+        // Draws the 0 glyph in the top left corner
+        let mut env = Env::new(chip(&[0x60, 0x00, 0xF0, 0x29, 0xD0, 0x05, 0x12, 0x06]),
+                               Spec::default());
+        assert!(env.observation().iter().all(|&pixel| pixel == 0));
+        let (observation, _, _, _) = env.step(0);
+        assert_eq!(&observation[..4], &[1, 1, 1, 1]);
+        assert_eq!(&observation[64..68], &[1, 0, 0, 1]);
+        assert_eq!(observation.iter().filter(|&&pixel| pixel != 0).count(), 14);
+    }
+}
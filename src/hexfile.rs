@@ -0,0 +1,217 @@
+//! Text formats for ROMs: Intel HEX files, and hex listings like the ones
+//! printed in magazines.
+
+/// A program read from a text format, which says where it belongs in
+/// memory.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Program {
+    /// The address of the program's first byte, if the text says where the
+    /// program goes.
+    pub address: Option<u16>,
+    /// The program's bytes, with any gaps between records filled with 0.
+    pub bytes: Vec<u8>,
+    /// The address to start running from, if the file gives one.
+    pub entry_point: Option<u16>,
+}
+
+/// Parses a program from either format, picking Intel HEX if the first
+/// line starts with a colon.  Programs with no addresses are placed at the
+/// given address.
+pub fn parse(text: &str, address: u16) -> Result<Program, String> {
+    if text.trim_start().starts_with(':') {
+        parse_intel_hex(text)
+    } else {
+        parse_listing(text, address)
+    }
+}
+
+/// Parses an Intel HEX file, placing its data at the addresses given by its
+/// records.
+pub fn parse_intel_hex(text: &str) -> Result<Program, String> {
+    let mut data: Vec<(u32, u8)> = Vec::new();
+    let mut base = 0u32;
+    let mut entry_point = None;
+    let mut ended = false;
+
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let error = |msg: &str| format!("Line {}: {}", i + 1, msg);
+        if !line.starts_with(':') {
+            return Err(error("Expected a record starting with ':'"));
+        }
+        let record = decode_hex(&line[1..]).ok_or_else(|| error("Expected hex digits"))?;
+        if record.len() < 5 || record.len() != record[0] as usize + 5 {
+            return Err(error("The record's length is wrong"));
+        }
+        if record.iter().fold(0u8, |sum, b| sum.wrapping_add(*b)) != 0 {
+            return Err(error("The record's checksum is wrong"));
+        }
+
+        let offset = (record[1] as u32) << 8 | record[2] as u32;
+        let payload = &record[4..record.len() - 1];
+        let word = |i: usize| (payload[i] as u32) << 8 | payload[i + 1] as u32;
+        match (record[3], payload.len()) {
+            (0x00, _) => {
+                for (j, byte) in payload.iter().enumerate() {
+                    data.push((base + offset + j as u32, *byte));
+                }
+            }
+            (0x01, _) => {
+                ended = true;
+                break;
+            }
+            (0x02, 2) => base = word(0) << 4,
+            (0x03, 4) => entry_point = Some((word(0) << 4) + word(2)),
+            (0x04, 2) => base = word(0) << 16,
+            (0x05, 4) => entry_point = Some(word(0) << 16 | word(2)),
+            (0x02, _) | (0x03, _) | (0x04, _) | (0x05, _) => {
+                return Err(error("The record's length is wrong"))
+            }
+            (kind, _) => return Err(error(&format!("Unknown record type {:02X}", kind))),
+        }
+    }
+
+    if !ended {
+        return Err("The end of file record is missing".to_string());
+    }
+    let entry_point = match entry_point {
+        Some(address) if address > 0xFFFF => {
+            return Err(format!("The start address {:#X} is past the end of memory", address))
+        }
+        entry_point => entry_point.map(|address| address as u16),
+    };
+    build(&data).map(|(address, bytes)| {
+        Program {
+            address: Some(address),
+            bytes: bytes,
+            entry_point: entry_point,
+        }
+    })
+}
+
+/// Parses a hex listing, which is hex bytes separated by whitespace.  Bytes
+/// may be grouped into words of four digits, such as `6A02`, and a line may
+/// start with an address followed by a colon, such as `0200:`, which places
+/// the rest of the line there.  Everything after a `#` or `;` is a comment.
+/// Listings with no addresses are placed at the given address.
+pub fn parse_listing(text: &str, address: u16) -> Result<Program, String> {
+    let mut data: Vec<(u32, u8)> = Vec::new();
+    let mut next = address as u32;
+    let mut placed = false;
+
+    for (i, line) in text.lines().enumerate() {
+        let code = line.split(&['#', ';'][..]).next().unwrap();
+        for token in code.split_whitespace() {
+            let error = || format!("Line {}: Expected hex bytes, got {}", i + 1, token);
+            if let Some(digits) = token.strip_suffix(':') {
+                next = decode_hex(digits)
+                    .filter(|bytes| !bytes.is_empty() && bytes.len() <= 2)
+                    .map(|bytes| bytes.iter().fold(0, |n, b| n << 8 | *b as u32))
+                    .ok_or_else(error)?;
+                placed = true;
+                continue;
+            }
+            let digits = if token.starts_with("0x") || token.starts_with("0X") {
+                &token[2..]
+            } else {
+                token
+            };
+            let bytes = decode_hex(digits).filter(|bytes| !bytes.is_empty()).ok_or_else(error)?;
+            for byte in bytes {
+                data.push((next, byte));
+                next += 1;
+            }
+        }
+    }
+
+    build(&data).map(|(address, bytes)| {
+        Program {
+            address: if placed { Some(address) } else { None },
+            bytes: bytes,
+            entry_point: None,
+        }
+    })
+}
+
+/// Lays out the given bytes by address, starting at the lowest address.
+fn build(data: &[(u32, u8)]) -> Result<(u16, Vec<u8>), String> {
+    let start = data.iter().map(|&(address, _)| address).min().ok_or("There is no data")?;
+    let end = data.iter().map(|&(address, _)| address).max().unwrap() + 1;
+    if end > 0x10000 {
+        return Err(format!("Data at {:#X} is past the end of memory", end - 1));
+    }
+
+    let mut bytes = vec![0; (end - start) as usize];
+    for &(address, byte) in data {
+        bytes[(address - start) as usize] = byte;
+    }
+    Ok((start as u16, bytes))
+}
+
+/// Decodes pairs of hex digits into bytes.
+fn decode_hex(digits: &str) -> Option<Vec<u8>> {
+    if digits.len() % 2 != 0 || !digits.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+    Some((0..digits.len())
+         .step_by(2)
+         .map(|i| u8::from_str_radix(&digits[i..i + 2], 16).unwrap())
+         .collect())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{parse, parse_intel_hex, parse_listing};
+
+    #[test]
+    fn intel_hex() {
+        let text = ":0406000000E0A22A4A\n\
+                    :02060A006A0282\n\
+                    :0400000500000602EF\n\
+                    :00000001FF\n";
+        let program = parse_intel_hex(text).unwrap();
+        assert_eq!(program.address, Some(0x600));
+        assert_eq!(program.bytes, vec![0x00, 0xE0, 0xA2, 0x2A, 0, 0, 0, 0, 0, 0, 0x6A, 0x02]);
+        assert_eq!(program.entry_point, Some(0x602));
+    }
+
+    #[test]
+    fn intel_hex_errors() {
+        assert!(parse_intel_hex(":0406000000E0A22A4B\n:00000001FF").unwrap_err()
+                .contains("checksum"));
+        assert!(parse_intel_hex(":0406000000E0A22A4A\n").unwrap_err().contains("end of file"));
+        assert!(parse_intel_hex(":0406000000E0A22A\n:00000001FF").unwrap_err()
+                .contains("Line 1"));
+        assert!(parse_intel_hex(":0406000000E0A22A4A\nhello\n:00000001FF").unwrap_err()
+                .contains("Line 2"));
+        assert!(parse_intel_hex(":02000004000FEB\n:0100000000FF\n:00000001FF").is_err());
+    }
+
+    #[test]
+    fn listing() {
+        let text = "# A magazine listing\n\
+                    00E0 A22A  ; clear the screen\n\
+                    6a 02\n";
+        let program = parse_listing(text, 0x200).unwrap();
+        assert_eq!(program.address, None);
+        assert_eq!(program.bytes, vec![0x00, 0xE0, 0xA2, 0x2A, 0x6A, 0x02]);
+        assert_eq!(program.entry_point, None);
+
+        let program = parse("0300: 12 34\n0x56\n0200: 00E0", 0x200).unwrap();
+        assert_eq!(program.address, Some(0x200));
+        assert_eq!(program.bytes.len(), 0x103);
+        assert_eq!(&program.bytes[0..2], &[0x00, 0xE0]);
+        assert_eq!(&program.bytes[0x100..], &[0x12, 0x34, 0x56]);
+    }
+
+    #[test]
+    fn listing_errors() {
+        assert!(parse_listing("00E0 A22\n", 0x200).unwrap_err().contains("A22"));
+        assert!(parse_listing("00E0\n6A0G\n", 0x200).unwrap_err().contains("Line 2"));
+        assert!(parse_listing("# nothing\n", 0x200).is_err());
+    }
+
+}
@@ -1,64 +1,223 @@
 extern crate sdl2;
 
-use sdl2::event::Event;
+use sdl2::event::{Event, WindowEventId};
 use sdl2::EventPump;
 use sdl2::Sdl;
 use sdl2::keyboard::{Keycode, KeyboardState, Scancode};
+use std::str::FromStr;
+
+/// Says which keyboard key stands in for each of the chip's 16 keys.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Keymap {
+    keys: [Scancode; 16],
+    alternates: [Option<Scancode>; 16],
+}
+
+impl Keymap {
+    /// Maps each of the chip's keys to the keyboard key with the same hex
+    /// digit.
+    pub fn hex() -> Self {
+        Keymap {
+            keys: [Scancode::Num0, Scancode::Num1, Scancode::Num2, Scancode::Num3,
+                   Scancode::Num4, Scancode::Num5, Scancode::Num6, Scancode::Num7,
+                   Scancode::Num8, Scancode::Num9, Scancode::A, Scancode::B,
+                   Scancode::C, Scancode::D, Scancode::E, Scancode::F],
+            alternates: [None; 16],
+        }
+    }
+
+    /// Maps the COSMAC VIP's 4x4 keypad onto the same shape of keys on the
+    /// left side of a QWERTY keyboard.
+    ///
+    /// ```text
+    /// 1 2 3 C      1 2 3 4
+    /// 4 5 6 D  =>  Q W E R
+    /// 7 8 9 E      A S D F
+    /// A 0 B F      Z X C V
+    /// ```
+    pub fn qwerty() -> Self {
+        Keymap {
+            keys: [Scancode::X, Scancode::Num1, Scancode::Num2, Scancode::Num3,
+                   Scancode::Q, Scancode::W, Scancode::E, Scancode::A,
+                   Scancode::S, Scancode::D, Scancode::Z, Scancode::C,
+                   Scancode::Num4, Scancode::R, Scancode::F, Scancode::V],
+            alternates: [None; 16],
+        }
+    }
+
+    /// Binds a second keyboard key to the given chip key, for one of the
+    /// inputs named in the ROM database, such as `up` or `player2A`.  Player
+    /// one uses the arrow keys, space and left shift, while player two uses
+    /// IJKL, right control and right shift.  Unknown inputs are ignored.
+    pub fn bind(&mut self, input: &str, key: u8) {
+        let scancode = match input {
+            "up" => Scancode::Up,
+            "down" => Scancode::Down,
+            "left" => Scancode::Left,
+            "right" => Scancode::Right,
+            "a" => Scancode::Space,
+            "b" => Scancode::LShift,
+            "player2Up" => Scancode::I,
+            "player2Down" => Scancode::K,
+            "player2Left" => Scancode::J,
+            "player2Right" => Scancode::L,
+            "player2A" => Scancode::RCtrl,
+            "player2B" => Scancode::RShift,
+            _ => return,
+        };
+        self.alternates[key as usize & 0xF] = Some(scancode);
+    }
+}
+
+impl FromStr for Keymap {
+    type Err = String;
+
+    /// Parses a keymap, which is either `hex`, `qwerty`, or 16 comma
+    /// separated SDL key names for the chip's keys 0 through F.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "hex" => return Ok(Keymap::hex()),
+            "qwerty" => return Ok(Keymap::qwerty()),
+            _ => {}
+        }
+
+        let names: Vec<&str> = s.split(',').map(str::trim).collect();
+        if names.len() != 16 {
+            return Err(format!("Expected hex, qwerty or 16 comma separated keys, got {}", s));
+        }
+        let mut keymap = Keymap::hex();
+        for (i, name) in names.iter().enumerate() {
+            keymap.keys[i] = Scancode::from_name(name)
+                .ok_or_else(|| format!("Unknown key {}", name))?;
+        }
+        Ok(keymap)
+    }
+}
 
 /// This struct keeps track of the SDL EventPump, which is used for scanning
 /// the keyboard for key presses.
 pub struct Keyboard {
     event_pump: EventPump,
+    keymap: Keymap,
+    fast_forward_held: bool,
 }
 
-/// This enum says whether or not the user is trying to quit.
+/// This enum says what the user is asking the caller to do.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum Command {
     /// The caller should quit.
     Quit,
+    /// The caller should pause or resume emulation.
+    Pause,
+    /// The caller should run a single frame, pausing if it isn't already.
+    FrameAdvance,
+    /// The caller should switch fast-forwarding on or off.
+    FastForward,
+    /// The caller should switch fast-forwarding between a capped speed and
+    /// running as fast as possible.
+    Uncapped,
+    /// The caller should switch slow motion on or off.
+    SlowMotion,
+    /// The caller should restart the game, keeping the chip's memory.
+    SoftReset,
+    /// The caller should reload the game into a fresh chip.
+    HardReset,
+    /// The caller should save a screenshot and continue running.
+    Screenshot,
+    /// The caller should switch between fullscreen and windowed mode.
+    ToggleFullscreen,
+    /// The caller should switch to the next display filter.
+    NextFilter,
+    /// The caller should redraw the window, which was resized or uncovered.
+    Redraw,
     /// The caller should continue running.
     Continue,
 }
 
 impl Keyboard {
-    /// Constructs a new Keyboard from the given SDL context.
-    pub fn new(context: &Sdl) -> Self {
+    /// Constructs a new Keyboard from the given SDL context, reading the
+    /// chip's keys through the given keymap.
+    pub fn new(context: &Sdl, keymap: Keymap) -> Self {
         Keyboard {
             event_pump: context.event_pump().unwrap(),
+            keymap: keymap,
+            fast_forward_held: false,
         }
     }
 
-    /// Checks the keyboard's keys, looking for quit events and which keys
-    /// should be marked as pressed in the given key state array.
+    /// Reads the chip's keys through the given keymap from now on.
+    pub fn set_keymap(&mut self, keymap: Keymap) {
+        self.keymap = keymap;
+    }
+
+    /// Checks the keyboard's keys, looking for quit and hotkey events and
+    /// which keys should be marked as pressed in the given key state array.
+    ///
+    /// Escape quits, F1 pauses, F2 advances a frame, F3 toggles
+    /// fast-forward, F4 toggles uncapped fast-forward, F5 toggles slow
+    /// motion, F6 soft resets, F7 hard resets, F10 cycles through the
+    /// display filters, F11 toggles fullscreen and F12 takes a screenshot.
     pub fn check(&mut self, keys: &mut [u8; 16]) -> Command {
-        // Handle quit event
+        // Handle quit and hotkey events
+        let mut command = Command::Continue;
         for event in self.event_pump.poll_iter() {
             match event {
                 Event::Quit {..} | Event::KeyDown { keycode: Some(Keycode::Escape), .. } => {
                     return Command::Quit;
                 },
+                Event::KeyDown { keycode: Some(Keycode::F1), repeat: false, .. } => {
+                    command = Command::Pause;
+                },
+                Event::KeyDown { keycode: Some(Keycode::F2), .. } => {
+                    command = Command::FrameAdvance;
+                },
+                Event::KeyDown { keycode: Some(Keycode::F3), repeat: false, .. } => {
+                    command = Command::FastForward;
+                },
+                Event::KeyDown { keycode: Some(Keycode::F4), repeat: false, .. } => {
+                    command = Command::Uncapped;
+                },
+                Event::KeyDown { keycode: Some(Keycode::F5), repeat: false, .. } => {
+                    command = Command::SlowMotion;
+                },
+                Event::KeyDown { keycode: Some(Keycode::F6), repeat: false, .. } => {
+                    command = Command::SoftReset;
+                },
+                Event::KeyDown { keycode: Some(Keycode::F7), repeat: false, .. } => {
+                    command = Command::HardReset;
+                },
+                Event::KeyDown { keycode: Some(Keycode::F10), repeat: false, .. } => {
+                    command = Command::NextFilter;
+                },
+                Event::KeyDown { keycode: Some(Keycode::F11), repeat: false, .. } => {
+                    command = Command::ToggleFullscreen;
+                },
+                Event::KeyDown { keycode: Some(Keycode::F12), repeat: false, .. } => {
+                    command = Command::Screenshot;
+                },
+                Event::Window { win_event_id: WindowEventId::SizeChanged, .. } |
+                Event::Window { win_event_id: WindowEventId::Exposed, .. }
+                    if command == Command::Continue => {
+                    command = Command::Redraw;
+                },
                 _ => {}
             }
         }
 
         // Record the keyboard state
         let kb = KeyboardState::new(&self.event_pump);
-        keys[0x0] = kb.is_scancode_pressed(Scancode::Num0) as u8;
-        keys[0x1] = kb.is_scancode_pressed(Scancode::Num1) as u8;
-        keys[0x2] = kb.is_scancode_pressed(Scancode::Num2) as u8;
-        keys[0x3] = kb.is_scancode_pressed(Scancode::Num3) as u8;
-        keys[0x4] = kb.is_scancode_pressed(Scancode::Num4) as u8;
-        keys[0x5] = kb.is_scancode_pressed(Scancode::Num5) as u8;
-        keys[0x6] = kb.is_scancode_pressed(Scancode::Num6) as u8;
-        keys[0x7] = kb.is_scancode_pressed(Scancode::Num7) as u8;
-        keys[0x8] = kb.is_scancode_pressed(Scancode::Num8) as u8;
-        keys[0x9] = kb.is_scancode_pressed(Scancode::Num9) as u8;
-        keys[0xA] = kb.is_scancode_pressed(Scancode::A) as u8;
-        keys[0xB] = kb.is_scancode_pressed(Scancode::B) as u8;
-        keys[0xC] = kb.is_scancode_pressed(Scancode::C) as u8;
-        keys[0xD] = kb.is_scancode_pressed(Scancode::D) as u8;
-        keys[0xE] = kb.is_scancode_pressed(Scancode::E) as u8;
-        keys[0xF] = kb.is_scancode_pressed(Scancode::F) as u8;
-
-        Command::Continue
+        for (i, key) in keys.iter_mut().enumerate() {
+            let alternate = self.keymap.alternates[i].map(|scancode| kb.is_scancode_pressed(scancode));
+            *key = (kb.is_scancode_pressed(self.keymap.keys[i]) || alternate == Some(true)) as u8;
+        }
+        self.fast_forward_held = kb.is_scancode_pressed(Scancode::Tab);
+
+        command
+    }
+
+    /// Whether the fast-forward key (Tab) was held down during the last
+    /// check.
+    pub fn fast_forward_held(&self) -> bool {
+        self.fast_forward_held
     }
 }
@@ -0,0 +1,192 @@
+//! Reads batch files, which list ROMs to run headlessly with `--batch`, and
+//! describes how the runs went.
+//!
+//! A batch file has a ROM per line, followed by settings for running it as
+//! `name=value` pairs separated by spaces, such as
+//! `games/PONG platform=chip8 frames=600 input=pong.keys`.  The settings are
+//! the same as in config files, along with `frames`, the most frames to run,
+//! and `input`, an input script of keys to press.  Paths are relative to the
+//! batch file, and blank lines and lines starting with `#` are skipped.
+
+use chip8::batch::{Exit, Outcome};
+use serde_json::{Map, Value};
+use std::path::{Path, PathBuf};
+
+/// A ROM to run from a batch file.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Entry {
+    /// The line of the batch file the ROM is on, counting from 1.
+    pub line: usize,
+    /// The ROM's path.
+    pub rom: PathBuf,
+    /// The settings to run the ROM with, in order.
+    pub settings: Vec<(String, String)>,
+}
+
+/// Parses a batch file, with paths relative to the given directory.
+pub fn parse(text: &str, base: &Path) -> Result<Vec<Entry>, String> {
+    let mut entries = Vec::new();
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let mut words = line.split_whitespace();
+        let rom = base.join(words.next().unwrap());
+        let mut settings = Vec::new();
+        for word in words {
+            let mut parts = word.splitn(2, '=');
+            let name = parts.next().unwrap();
+            match parts.next() {
+                Some(value) => settings.push((name.to_string(), value.to_string())),
+                None => return Err(format!("Line {}: expected name=value, not {}", i + 1, word)),
+            }
+        }
+        entries.push(Entry {
+            line: i + 1,
+            rom: rom,
+            settings: settings,
+        });
+    }
+    Ok(entries)
+}
+
+/// Describes an outcome in a line, such as
+/// `PONG: halted after 30 frames (300 cycles in 2 ms)`.
+pub fn summary(outcome: &Outcome) -> String {
+    let exit = match outcome.exit {
+        Exit::Finished => "finished".to_string(),
+        Exit::Halted => "halted".to_string(),
+        Exit::Crashed(ref why) => format!("crashed ({})", why),
+    };
+    format!("{}: {} after {} frames ({} cycles in {} ms)",
+            outcome.name,
+            exit,
+            outcome.stats.frames,
+            outcome.stats.cycles,
+            millis(outcome))
+}
+
+/// Describes an outcome as JSON, for reports.  The display is a list of rows,
+/// each a string with a digit per pixel.
+pub fn report(outcome: &Outcome) -> Value {
+    let mut object = Map::new();
+    let (exit, message) = match outcome.exit {
+        Exit::Finished => ("finished", None),
+        Exit::Halted => ("halted", None),
+        Exit::Crashed(ref why) => ("crashed", Some(why.clone())),
+    };
+    object.insert("name".to_string(), Value::from(outcome.name.clone()));
+    object.insert("exit".to_string(), Value::from(exit));
+    if let Some(message) = message {
+        object.insert("message".to_string(), Value::from(message));
+    }
+    object.insert("frames".to_string(), Value::from(outcome.stats.frames));
+    object.insert("cycles".to_string(), Value::from(outcome.stats.cycles));
+    object.insert("milliseconds".to_string(), Value::from(millis(outcome)));
+    object.insert("pc".to_string(), Value::from(outcome.cpu.pc));
+    object.insert("registers".to_string(), Value::from(outcome.cpu.registers.to_vec()));
+
+    let framebuffer = &outcome.framebuffer;
+    let rows: Vec<Value> = (0..framebuffer.height())
+        .map(|y| {
+            let row: String = framebuffer.row(y).map(|pixel| (b'0' + pixel) as char).collect();
+            Value::from(row)
+        })
+        .collect();
+    object.insert("display".to_string(), Value::from(rows));
+    Value::Object(object)
+}
+
+/// How long an outcome's run took (in milliseconds).
+fn millis(outcome: &Outcome) -> u64 {
+    let elapsed = outcome.stats.elapsed;
+    elapsed.as_secs() * 1000 + elapsed.subsec_millis() as u64
+}
+
+#[cfg(test)]
+mod test {
+    use super::{parse, report, summary, Entry};
+    use chip8::batch::{Exit, Outcome, Stats};
+    use chip8::framebuffer::Framebuffer;
+    use chip8::Chip8;
+    use std::path::{Path, PathBuf};
+    use std::time::Duration;
+
+    /// An outcome of a short run that drew a pixel and halted.
+    fn outcome(exit: Exit) -> Outcome {
+        let mut chip = Chip8::default();
+        chip.set_register(3, 7);
+        let mut framebuffer = Framebuffer::default();
+        framebuffer.set_pixel(1, 0, 1);
+        Outcome {
+            name: "PONG".to_string(),
+            exit: exit,
+            framebuffer: framebuffer,
+            cpu: chip.cpu_state(),
+            stats: Stats {
+                frames: 30,
+                cycles: 300,
+                elapsed: Duration::from_millis(2),
+            },
+        }
+    }
+
+    #[test]
+    fn parses() {
+        let text = "# Smoke tests\n\ngames/PONG platform=chip8 frames=600\n  /roms/TETRIS  \n";
+        let entries = parse(text, Path::new("batches")).unwrap();
+        assert_eq!(entries, vec![
+            Entry {
+                line: 3,
+                rom: PathBuf::from("batches/games/PONG"),
+                settings: vec![("platform".to_string(), "chip8".to_string()),
+                               ("frames".to_string(), "600".to_string())],
+            },
+            // Absolute paths are kept as they are
+            Entry {
+                line: 4,
+                rom: PathBuf::from("/roms/TETRIS"),
+                settings: Vec::new(),
+            },
+        ]);
+    }
+
+    #[test]
+    fn parse_errors() {
+        assert_eq!(parse("PONG\nBLITZ ipf=15 fast\n", Path::new("")),
+                   Err("Line 2: expected name=value, not fast".to_string()));
+        assert_eq!(parse("", Path::new("")), Ok(Vec::new()));
+    }
+
+    #[test]
+    fn summaries() {
+        assert_eq!(summary(&outcome(Exit::Halted)),
+                   "PONG: halted after 30 frames (300 cycles in 2 ms)");
+        assert_eq!(summary(&outcome(Exit::Crashed("Opcode 0x8128 is bad".to_string()))),
+                   "PONG: crashed (Opcode 0x8128 is bad) after 30 frames (300 cycles in 2 ms)");
+    }
+
+    #[test]
+    fn reports() {
+        let report = report(&outcome(Exit::Crashed("Opcode 0x8128 is bad".to_string())));
+        let object = report.as_object().unwrap();
+        let mut keys: Vec<_> = object.keys().map(String::as_str).collect();
+        keys.sort();
+        assert_eq!(keys, ["cycles", "display", "exit", "frames", "message", "milliseconds",
+                          "name", "pc", "registers"]);
+        assert_eq!(object["exit"], "crashed");
+        assert_eq!(object["message"], "Opcode 0x8128 is bad");
+        assert_eq!(object["frames"], 30);
+        assert_eq!(object["pc"], 0x200);
+        assert_eq!(object["registers"][3], 7);
+        assert_eq!(object["registers"].as_array().unwrap().len(), 16);
+
+        let display = object["display"].as_array().unwrap();
+        assert_eq!(display.len(), 32);
+        assert_eq!(display[0].as_str().unwrap(), format!("01{}", "0".repeat(62)));
+
+        // Only crashes have messages
+        assert!(super::report(&outcome(Exit::Finished)).get("message").is_none());
+    }
+}
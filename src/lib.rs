@@ -1,14 +1,73 @@
-extern crate bit_vec;
-extern crate rand;
-
-use bit_vec::BitVec;
-use rand::Rng;
+#![cfg_attr(not(feature = "std"), no_std)]
+// Fields are written `name: name`, from before the shorthand existed
+#![allow(clippy::redundant_field_names)]
+// `%` keeps building on compilers from before `is_multiple_of`
+#![allow(clippy::manual_is_multiple_of)]
+// The tests pass programs as `&vec![..]`
+#![cfg_attr(test, allow(clippy::useless_vec))]
+
+// Without the standard library, the parts of it the core uses come from core
+#[cfg(not(feature = "std"))]
+extern crate core as std;
+#[cfg(feature = "gif")]
+extern crate gif;
+#[cfg(feature = "png")]
+extern crate png;
+#[cfg(feature = "json")]
+extern crate serde_json;
+#[cfg(feature = "json")]
+extern crate sha1_smol;
+
+#[cfg(feature = "std")]
+pub mod batch;
+#[cfg(all(feature = "gif", feature = "json"))]
+pub mod cartridge;
+#[cfg(feature = "json")]
+pub mod database;
+#[cfg(feature = "std")]
+pub mod decode;
+#[cfg(feature = "std")]
+pub mod filter;
+pub mod framebuffer;
+#[cfg(feature = "std")]
+pub mod gym;
+#[cfg(feature = "std")]
+pub mod hexfile;
+pub mod memory;
+#[cfg(feature = "std")]
+pub mod netplay;
+#[cfg(feature = "std")]
+pub mod octo;
+pub mod platform;
+#[cfg(feature = "std")]
+mod recompile;
+#[cfg(all(feature = "gif", feature = "png"))]
+pub mod record;
+#[cfg(feature = "std")]
+pub mod render;
+pub mod state;
+
+use platform::Quirks;
+#[cfg(feature = "std")]
+use decode::Decoded;
+#[cfg(feature = "std")]
+use filter::Filter;
+use framebuffer::Framebuffer;
+use memory::Memory;
+#[cfg(feature = "std")]
+use recompile::Blocks;
+#[cfg(feature = "std")]
+use render::{Image, Palette};
 use std::fmt;
 use std::num::Wrapping;
 
-/// The size of the chip's memory (RAM and ROM storage).
+/// The size of the chip's memory (RAM and ROM storage), unless the platform
+/// needs more.
 const NMEM: usize = 4096;
 
+/// The address programs are normally loaded at and started from.
+pub const PROGRAM_START: u16 = 0x200;
+
 /// The number of registers.
 const NREG: usize = 16;
 
@@ -21,6 +80,10 @@ pub const HEIGHT: u32 = 32;
 /// The total number of pixels.
 pub const NPIXELS: usize = (WIDTH * HEIGHT) as usize;
 
+/// The rate (in Hz) at which the delay and sound timers count down.  This is
+/// also the rate at which frames are shown.
+pub const TIMER_RATE: u32 = 60;
+
 /*
  * From http://www.multigesture.net/articles/how-to-write-an-emulator-chip-8-interpreter/
  * MEMORY MAP:
@@ -58,22 +121,164 @@ pub fn version() -> &'static str {
     )
 }
 
+/// Something the chip was asked to do that it can't.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Error {
+    /// A ROM of the given size loaded at the given address doesn't fit in
+    /// the given amount of memory.
+    RomTooBig { size: usize, address: u16, memory: usize },
+    /// The given number of bytes written at the given address don't fit in
+    /// the given amount of memory.
+    WriteTooBig { size: usize, address: u16, memory: usize },
+    /// The given stack pointer is past the end of the stack.
+    StackOverflow(u16),
+    /// A buffer of the given size is too small for the given number of
+    /// bytes.
+    BufferTooSmall { size: usize, needed: usize },
+    /// A save state is damaged or was saved by another version.
+    BadState,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::RomTooBig { size, address, memory } => {
+                write!(f, "A {} byte ROM loaded at {:#X} doesn't fit in {} bytes of memory",
+                       size, address, memory)
+            }
+            Error::WriteTooBig { size, address, memory } => {
+                write!(f, "{} bytes written at {:#X} don't fit in {} bytes of memory",
+                       size, address, memory)
+            }
+            Error::StackOverflow(sp) => {
+                write!(f, "The stack pointer {} is past the end of the stack", sp)
+            }
+            Error::BufferTooSmall { size, needed } => {
+                write!(f, "A {} byte buffer is too small for {} bytes", size, needed)
+            }
+            Error::BadState => {
+                f.write_str("The save state is damaged or was saved by another version")
+            }
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for Error {}
+
+#[cfg(feature = "std")]
+impl From<Error> for String {
+    fn from(error: Error) -> String {
+        error.to_string()
+    }
+}
+
+/// Makes a random byte for 0xCXNN, given a state that the chip keeps for it
+/// and that `Chip8::set_seed` sets.  A generator can ignore the state, such as
+/// to read a hardware random number generator instead.
+pub type Rng = fn(&mut u64) -> u8;
+
+/// The random number generator chips start out with, SplitMix64, which gives
+/// good numbers from any state.
+pub fn splitmix(state: &mut u64) -> u8 {
+    *state = state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+    let mut z = *state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    (z ^ (z >> 31)) as u8
+}
+
+/// A seed that is different every time, for chips that shouldn't make the
+/// same random numbers on every run.  It mixes the standard library's own
+/// random hashing keys, which come from the operating system, with the time.
+#[cfg(feature = "std")]
+pub fn random_seed() -> u64 {
+    use std::collections::hash_map::RandomState;
+    use std::hash::{BuildHasher, Hasher};
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    let mut hasher = RandomState::new().build_hasher();
+    if let Ok(since_epoch) = SystemTime::now().duration_since(UNIX_EPOCH) {
+        hasher.write_u64(since_epoch.as_secs());
+        hasher.write_u32(since_epoch.subsec_nanos());
+    }
+    hasher.finish()
+}
+
+/// A snapshot of the chip's registers, stack and timers, for debuggers,
+/// frontends and tests.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CpuState {
+    /// The opcode that was run last.
+    pub opcode: u16,
+    /// The registers V0 through VF.
+    pub registers: [u8; NREG],
+    /// The index register I.
+    pub index: u16,
+    /// The address of the next opcode to run.
+    pub pc: u16,
+    /// The number of return addresses on the stack.
+    pub sp: u16,
+    /// The stack of return addresses, of which the first `sp` are in use.
+    pub stack: [u16; 16],
+    /// The delay timer.
+    pub delay_timer: u8,
+    /// The sound timer.
+    pub sound_timer: u8,
+}
+
+/// How the chip runs opcodes.
+#[cfg(feature = "std")]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Engine {
+    /// Fetches and decodes each opcode as it is run.
+    Interpreter,
+    /// Runs opcodes from a cache of decoded instructions, which uses more
+    /// memory and is somewhat faster, by about a tenth on games and half
+    /// again on straight arithmetic.
+    Cached,
+    /// Runs whole basic blocks of decoded instructions at a time, which is
+    /// faster still for programs that do a lot of arithmetic, but no faster
+    /// than the cache for games that mostly draw and jump.
+    Recompiler,
+}
+
 /// The Chip8 emulator.  This can load vectors of `u8` representations of ROMs
 /// and play them.
+///
+/// A chip holds everything it needs, so it can be sent to another thread, and
+/// cloning it copies its whole state, such as to run a game several ways from
+/// the same point.
+#[derive(Clone)]
 pub struct Chip8 {
     pub draw_flag: bool,
     opcode: u16,
-    memory: [u8; NMEM],
+    memory: Memory,
     reg: [u8; NREG],
     index: u16,
     pc: u16,
-    pub graphics: [u8; NPIXELS],
+    start: u16,
+    pub graphics: Framebuffer,
     timer_delay: u8,
     timer_sound: u8,
     stack: [u16; 16],
     sp: u16,
     pub key: [u8; 16],
     pub make_sound: bool,
+    pub quirks: Quirks,
+    rng: Rng,
+    rng_state: u64,
+    cycles: u64,
+    #[cfg(feature = "std")]
+    engine: Engine,
+    #[cfg(feature = "std")]
+    cache: Vec<Decoded>,
+    #[cfg(feature = "std")]
+    blocks: Blocks,
+    /// Whether memory may have changed from outside the chip since the
+    /// cache or blocks were last emptied.
+    #[cfg(feature = "std")]
+    stale: bool,
 }
 
 impl fmt::Debug for Chip8 {
@@ -115,18 +320,17 @@ impl fmt::Debug for Chip8 {
                self.reg[15],
                self.sp,
                self.stack,
-               ).unwrap();
-        write!(f, "  graphics:\n").unwrap();
-        let mut row = String::with_capacity(64);
-        write!(f, "+----------------------------------------------------------------+\n").unwrap();
+               )?;
+        writeln!(f, "  graphics:")?;
+        writeln!(f, "+----------------------------------------------------------------+")?;
         for i in 0..32 {
+            f.write_str("|")?;
             for j in 0..64 {
-                row.push(if 0 == self.graphics[i * 64 + j] { ' ' } else { '#' });
+                f.write_str(if 0 == self.graphics.pixel(j, i) { " " } else { "#" })?;
             }
-            write!(f, "|{}|\n", row).unwrap();
-            row.clear();
+            f.write_str("|\n")?;
         }
-        write!(f, "+----------------------------------------------------------------+\n")
+        writeln!(f, "+----------------------------------------------------------------+")
     }
 }
 
@@ -136,17 +340,30 @@ impl Default for Chip8 {
         let mut chip = Chip8 {
             draw_flag: true,
             opcode: 0,
-            memory: [0; NMEM],
+            memory: Memory::new(NMEM),
             reg: [0; NREG],
             index: 0,
             pc: 0x200,
-            graphics: [0; NPIXELS],
+            start: PROGRAM_START,
+            graphics: Framebuffer::default(),
             timer_delay: 0,
             timer_sound: 0,
             stack: [0; 16],
             sp: 0,
             key: [0; 16],
             make_sound: false,
+            quirks: Quirks::default(),
+            rng: splitmix,
+            rng_state: 0,
+            cycles: 0,
+            #[cfg(feature = "std")]
+            engine: Engine::Interpreter,
+            #[cfg(feature = "std")]
+            cache: Vec::new(),
+            #[cfg(feature = "std")]
+            blocks: Blocks::default(),
+            #[cfg(feature = "std")]
+            stale: false,
         };
 
         // Initialize the font set
@@ -161,30 +378,426 @@ impl Default for Chip8 {
 impl Chip8 {
     /// Loads the given bytes into the chip's memory.
     pub fn load_hex(&mut self, game: &[u8]) {
-        for (i, byte) in game.iter().enumerate() {
-            self.memory[i + 512] = *byte;
+        self.load_hex_at(game, PROGRAM_START);
+    }
+
+    /// Loads the given bytes into the chip's memory at the given address, and
+    /// starts running from there.
+    /// # Panics
+    /// If the bytes don't fit in memory, this panics with a description of
+    /// the error.
+    pub fn load_hex_at(&mut self, game: &[u8], address: u16) {
+        if let Err(why) = self.load_rom(game, address) {
+            panic!("{}", why);
         }
     }
 
+    /// Loads the given bytes into the chip's memory at the given address, and
+    /// starts running from there.  Fails without changing the chip if the
+    /// bytes don't fit in memory.
+    pub fn load_rom(&mut self, game: &[u8], address: u16) -> Result<(), Error> {
+        if address as usize + game.len() > self.memory.len() {
+            return Err(Error::RomTooBig {
+                size: game.len(),
+                address: address,
+                memory: self.memory.len(),
+            });
+        }
+        self.write_memory(address, game)?;
+        self.set_entry_point(address);
+        Ok(())
+    }
+
+    /// A snapshot of the chip's registers, stack and timers.
+    pub fn cpu_state(&self) -> CpuState {
+        CpuState {
+            opcode: self.opcode,
+            registers: self.reg,
+            index: self.index,
+            pc: self.pc,
+            sp: self.sp,
+            stack: self.stack,
+            delay_timer: self.timer_delay,
+            sound_timer: self.timer_sound,
+        }
+    }
+
+    /// Puts the chip's registers, stack and timers back to a snapshot.  Fails
+    /// without changing the chip if the stack pointer is past the end of
+    /// the stack.
+    pub fn set_cpu_state(&mut self, state: &CpuState) -> Result<(), Error> {
+        if state.sp as usize > self.stack.len() {
+            return Err(Error::StackOverflow(state.sp));
+        }
+        self.opcode = state.opcode;
+        self.reg = state.registers;
+        self.index = state.index;
+        self.pc = state.pc;
+        self.sp = state.sp;
+        self.stack = state.stack;
+        self.timer_delay = state.delay_timer;
+        self.timer_sound = state.sound_timer;
+        Ok(())
+    }
+
+    /// The size of the chip's save states (in bytes), which only changes
+    /// when the size of its memory does.
+    pub fn state_size(&self) -> usize {
+        state::size(self.memory.len())
+    }
+
+    /// Saves the chip's whole state at the start of the given buffer, which
+    /// must be at least `state_size` bytes.  Returns the number of bytes
+    /// written.
+    pub fn save_state(&self, out: &mut [u8]) -> Result<usize, Error> {
+        state::save(self, out)
+    }
+
+    /// Puts the chip back to a state saved with `save_state`, keeping its
+    /// quirks, engine and memory hooks.  Fails without changing the chip if
+    /// the state is damaged.
+    pub fn load_state(&mut self, state: &[u8]) -> Result<(), Error> {
+        state::load(self, state)
+    }
+
+    /// The chip's memory, as it is stored, ignoring any hooks.
+    pub fn memory(&self) -> &[u8] {
+        self.memory.bytes()
+    }
+
+    /// The return addresses on the stack, oldest first.
+    pub fn stack(&self) -> &[u16] {
+        &self.stack[..self.sp as usize]
+    }
+
+    /// The registers V0 through VF.
+    pub fn registers(&self) -> &[u8; NREG] {
+        &self.reg
+    }
+
+    /// The index register I.
+    pub fn index(&self) -> u16 {
+        self.index
+    }
+
+    /// The address of the next opcode to run.
+    pub fn pc(&self) -> u16 {
+        self.pc
+    }
+
+    /// Sets register VX, for X from 0 to F.
+    /// # Panics
+    /// If the register is past VF.
+    pub fn set_register(&mut self, x: usize, value: u8) {
+        self.reg[x] = value;
+    }
+
+    /// Sets the index register I.
+    pub fn set_index(&mut self, index: u16) {
+        self.index = index;
+    }
+
+    /// Sets the address of the next opcode to run, without changing where a
+    /// reset starts from.
+    pub fn set_pc(&mut self, pc: u16) {
+        self.pc = pc;
+    }
+
+    /// Sets the delay and sound timers.
+    pub fn set_timers(&mut self, delay: u8, sound: u8) {
+        self.timer_delay = delay;
+        self.timer_sound = sound;
+    }
+
+    /// Writes the given bytes into memory at the given address, skipping any
+    /// hooks.  Fails without changing the chip if they don't fit.
+    pub fn write_memory(&mut self, address: u16, bytes: &[u8]) -> Result<(), Error> {
+        let start = address as usize;
+        if start + bytes.len() > self.memory.len() {
+            return Err(Error::WriteTooBig {
+                size: bytes.len(),
+                address: address,
+                memory: self.memory.len(),
+            });
+        }
+        self.memory.bytes_mut()[start..start + bytes.len()].copy_from_slice(bytes);
+        self.invalidate(address, bytes.len());
+        Ok(())
+    }
+
+    /// Changes the size of the chip's memory, such as to the size a platform
+    /// needs, keeping the bytes that still fit.
+    /// # Panics
+    /// If the size isn't a power of two, or is more than `memory::CAPACITY`,
+    /// such as XO-CHIP's 64K without std or the `xo-chip-memory` feature.
+    pub fn set_memory_size(&mut self, size: usize) {
+        self.memory.resize(size);
+        self.flush_cache();
+    }
+
+    /// The chip's memory bus, for adding hooks on reads and writes.  Since
+    /// memory could be changed through it, the instruction cache is emptied
+    /// before the chip next runs.
+    pub fn memory_bus(&mut self) -> &mut Memory {
+        self.flush_cache();
+        &mut self.memory
+    }
+
+    /// Starts running from the given address, both now and after a reset.
+    pub fn set_entry_point(&mut self, address: u16) {
+        self.pc = address;
+        self.start = address;
+    }
+
+    /// Resets the chip like its reset switch would, clearing the display,
+    /// registers, stack and timers and starting the loaded program again.
+    /// Memory is left alone, so the program isn't reloaded.
+    pub fn reset(&mut self) {
+        self.draw_flag = true;
+        self.opcode = 0;
+        self.reg = [0; NREG];
+        self.index = 0;
+        self.pc = self.start;
+        self.graphics = Framebuffer::default();
+        self.timer_delay = 0;
+        self.timer_sound = 0;
+        self.stack = [0; 16];
+        self.sp = 0;
+        self.make_sound = false;
+    }
+
+    /// Seeds the random number generator used by 0xCXNN.  Every chip starts
+    /// out seeded with 0, so that tests and batch runs repeat exactly, and
+    /// frontends seed it with `random_seed` unless asked to repeat a run.
+    pub fn set_seed(&mut self, seed: u64) {
+        self.rng_state = seed;
+    }
+
+    /// Changes the random number generator used by 0xCXNN, which starts out
+    /// as `splitmix`.
+    pub fn set_rng(&mut self, rng: Rng) {
+        self.rng = rng;
+    }
+
+    /// Makes a random byte for 0xCXNN.
+    fn random(&mut self) -> u8 {
+        (self.rng)(&mut self.rng_state)
+    }
+
+    /// The number of opcodes the chip has run since it was constructed.
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    /// Renders the current display to an image with the given palette, scaled
+    /// up by the given integer factor and then filtered.
+    #[cfg(feature = "std")]
+    pub fn screenshot(&self, palette: &Palette, scale: u32, filter: Filter) -> Image {
+        filter.render(&self.graphics.bitmap(), palette, scale)
+    }
+
     /// Run the emulator through a single cycle.
     /// # Panics
     /// If the emulator comes across an invalid opcode, it will panic with a
     /// description of the error (including the invalid opcode).
     pub fn emulate_cycle(&mut self) {
-        // Fetch opcode
-        self.fetch_opcode();
-
-        // Decode and Execute opcode
-        self.execute_opcode();
+        // Fetch, decode and execute opcode
+        self.run(1);
 
         // Update timers
         self.update_timers();
     }
 
+    /// Run the emulator through a single 60Hz frame, which is the given
+    /// number of cycles followed by a single update of the timers.  With the
+    /// display wait quirk, the frame ends early after drawing a sprite.
+    /// # Panics
+    /// If the emulator comes across an invalid opcode, it will panic with a
+    /// description of the error (including the invalid opcode).
+    pub fn emulate_frame(&mut self, cycles: u32) {
+        self.run(cycles);
+        self.update_timers();
+    }
+
+    /// Runs the rest of a 60Hz frame like `emulate_frame`, but stops before
+    /// running an opcode at any of the breakpoints, for debuggers.  `cycles`
+    /// is the number of opcodes left in the frame, and is counted down as
+    /// they run.  Returns whether the frame finished, in which case the
+    /// timers have been updated, rather than stopping at a breakpoint.  With
+    /// `resume`, the chip doesn't stop at a breakpoint it starts at, so that
+    /// it can go on from one.
+    /// # Panics
+    /// If the emulator comes across an invalid opcode, it will panic with a
+    /// description of the error (including the invalid opcode).
+    pub fn emulate_frame_until(&mut self, cycles: &mut u32, breakpoints: &[u16], resume: bool) -> bool {
+        self.refresh_cache();
+        let mut skip = resume;
+        while *cycles > 0 {
+            if !skip && breakpoints.contains(&self.pc) {
+                return false;
+            }
+            skip = false;
+            self.step();
+            self.cycles += 1;
+            *cycles -= 1;
+            if self.quirks.display_wait && self.opcode & 0xF000 == 0xD000 {
+                break;
+            }
+        }
+        *cycles = 0;
+        self.update_timers();
+        true
+    }
+
+    /// Constructs a new Chip8 emulator that runs opcodes with the given
+    /// engine.
+    #[cfg(feature = "std")]
+    pub fn with_engine(engine: Engine) -> Self {
+        let mut chip = Chip8::default();
+        chip.set_engine(engine);
+        chip
+    }
+
+    /// Changes how the chip runs opcodes.  Whichever engine is used, the
+    /// chip behaves the same: caches and blocks are kept up to date as the
+    /// program writes to memory, are emptied when memory is changed from
+    /// outside the chip, and aren't used while there are read hooks, since
+    /// they could change what the program sees.
+    #[cfg(feature = "std")]
+    pub fn set_engine(&mut self, engine: Engine) {
+        self.engine = engine;
+        self.cache = Vec::new();
+        self.blocks = Blocks::default();
+        self.flush_cache();
+        self.refresh_cache();
+    }
+
+    /// How the chip runs opcodes.
+    #[cfg(feature = "std")]
+    pub fn engine(&self) -> Engine {
+        self.engine
+    }
+
+    /// Marks the instruction cache and the compiled blocks to be emptied
+    /// before the chip next runs, so that handing out memory several times
+    /// between frames costs nothing.
+    #[cfg(feature = "std")]
+    fn flush_cache(&mut self) {
+        self.stale = true;
+    }
+
+    /// Empties the instruction cache or the compiled blocks, if the engine
+    /// uses them and they have been marked by `flush_cache`.  They are
+    /// emptied in place unless the memory has changed size.
+    #[cfg(feature = "std")]
+    fn refresh_cache(&mut self) {
+        if !self.stale {
+            return;
+        }
+        self.stale = false;
+        let size = self.memory.len();
+        match self.engine {
+            Engine::Interpreter => {}
+            Engine::Cached => {
+                self.cache.clear();
+                self.cache.resize(size, Decoded::undecoded());
+            }
+            Engine::Recompiler => self.blocks.clear(size),
+        }
+    }
+
+    /// Drops the cached instructions and compiled blocks that overlap the
+    /// given number of bytes written at the given address.
+    #[cfg(feature = "std")]
+    fn invalidate(&mut self, address: u16, count: usize) {
+        self.blocks.invalidate(address, count);
+        if self.cache.is_empty() {
+            return;
+        }
+        // The instruction starting just before the address overlaps it too
+        let mask = self.cache.len() - 1;
+        let first = address as usize + mask;
+        for i in 0..count + 1 {
+            self.cache[(first + i) & mask] = Decoded::undecoded();
+        }
+    }
+
+    /// Without the standard library, only the interpreter is built, so there
+    /// is nothing to empty.
+    #[cfg(not(feature = "std"))]
+    fn flush_cache(&mut self) {}
+
+    #[cfg(not(feature = "std"))]
+    fn refresh_cache(&mut self) {}
+
+    #[cfg(not(feature = "std"))]
+    fn invalidate(&mut self, _address: u16, _count: usize) {}
+
+    /// Runs the given number of opcodes with the chip's engine, stopping
+    /// early after drawing a sprite with the display wait quirk.
+    fn run(&mut self, cycles: u32) {
+        self.refresh_cache();
+        #[cfg(feature = "std")]
+        {
+            if !self.memory.has_read_hooks() {
+                match self.engine {
+                    Engine::Interpreter => {}
+                    Engine::Cached => {
+                        self.run_cached(cycles);
+                        return;
+                    }
+                    Engine::Recompiler => {
+                        self.cycles += recompile::run(self, cycles) as u64;
+                        return;
+                    }
+                }
+            }
+        }
+        for _ in 0..cycles {
+            self.step();
+            self.cycles += 1;
+            if self.quirks.display_wait && self.opcode & 0xF000 == 0xD000 {
+                break;
+            }
+        }
+    }
+
+    /// Runs the given number of opcodes from the instruction cache, like
+    /// `run`.  Addresses that haven't been decoded yet hold a handler that
+    /// decodes the opcode there and caches it.
+    #[cfg(feature = "std")]
+    fn run_cached(&mut self, cycles: u32) {
+        let mask = self.cache.len() - 1;
+        for _ in 0..cycles {
+            let decoded = self.cache[self.pc as usize & mask];
+            self.opcode = decoded.opcode;
+            (decoded.handler)(self, &decoded);
+            self.cycles += 1;
+            if self.quirks.display_wait && self.opcode & 0xF000 == 0xD000 {
+                break;
+            }
+        }
+    }
+
+    /// Runs the next opcode, from the instruction cache if it is on.
+    #[inline]
+    fn step(&mut self) {
+        #[cfg(feature = "std")]
+        {
+            if !self.cache.is_empty() && !self.memory.has_read_hooks() {
+                let decoded = self.cache[self.pc as usize & (self.cache.len() - 1)];
+                self.opcode = decoded.opcode;
+                (decoded.handler)(self, &decoded);
+                return;
+            }
+        }
+        self.fetch_opcode();
+        self.execute_opcode();
+    }
+
     /// Read the next opcode from memory.
     fn fetch_opcode(&mut self) {
-        self.opcode = (self.memory[self.pc as usize] as u16) << 8 |
-            self.memory[(self.pc + 1) as usize] as u16;
+        self.opcode = self.memory.read_word(self.pc);
     }
 
     /// Run the current opcode, storing the results in the chip.
@@ -195,9 +808,7 @@ impl Chip8 {
                 match self.opcode {
                     0x00E0 => {
                         // 0x00E0: Clears the screen
-                        for i in 0..NPIXELS {
-                            self.graphics[i] = 0;
-                        }
+                        self.graphics.clear();
                         self.draw_flag = true;
                         self.pc += 2;
                     }
@@ -276,6 +887,9 @@ impl Chip8 {
                         let x = (self.opcode & 0x0F00) >> 8;
                         let y = (self.opcode & 0x00F0) >> 4;
                         self.reg[x as usize] |= self.reg[y as usize];
+                        if self.quirks.logic_resets_vf {
+                            self.reg[0xF] = 0;
+                        }
                         self.pc += 2;
                     }
                     0x0002 => {
@@ -283,6 +897,9 @@ impl Chip8 {
                         let x = (self.opcode & 0x0F00) >> 8;
                         let y = (self.opcode & 0x00F0) >> 4;
                         self.reg[x as usize] &= self.reg[y as usize];
+                        if self.quirks.logic_resets_vf {
+                            self.reg[0xF] = 0;
+                        }
                         self.pc += 2;
                     }
                     0x0003 => {
@@ -290,63 +907,61 @@ impl Chip8 {
                         let x = (self.opcode & 0x0F00) >> 8;
                         let y = (self.opcode & 0x00F0) >> 4;
                         self.reg[x as usize] ^= self.reg[y as usize];
+                        if self.quirks.logic_resets_vf {
+                            self.reg[0xF] = 0;
+                        }
                         self.pc += 2;
                     }
                     0x0004 => {
-                        // 0x8XY4: Add regY to regX, set carry if needed
-                        if self.reg[((self.opcode & 0x00F0) >> 4) as usize] >
-                            (0xFF - self.reg[((self.opcode & 0x0F00) >> 8) as usize]) {
-                            self.reg[0xF] = 1;
-                        } else {
-                            self.reg[0xF] = 0;
-                        }
-                        let x = Wrapping(self.reg[((self.opcode & 0x0F00) >> 8) as usize]);
-                        let y = Wrapping(self.reg[((self.opcode & 0x00F0) >> 4) as usize]);
-                        self.reg[((self.opcode & 0x0F00) >> 8) as usize] = (x + y).0;
+                        // 0x8XY4: Add regY to regX, set carry if needed.  The
+                        // flag is set last, so it wins when X is F
+                        let x_index = ((self.opcode & 0x0F00) >> 8) as usize;
+                        let y_index = ((self.opcode & 0x00F0) >> 4) as usize;
+                        let (sum, carry) = self.reg[x_index].overflowing_add(self.reg[y_index]);
+                        self.reg[x_index] = sum;
+                        self.reg[0xF] = carry as u8;
                         self.pc += 2;
                     }
                     0x0005 => {
                         // 0x8XY5: regX -= regY, regF = 0 if borrow, else 1
                         let x_index = ((self.opcode & 0x0F00) >> 8) as usize;
                         let y_index = ((self.opcode & 0x00F0) >> 4) as usize;
-                        if self.reg[y_index] > self.reg[x_index] {
-                            self.reg[0xF] = 0;
-                        } else {
-                            self.reg[0xF] = 1;
-                        }
-                        let x = Wrapping(self.reg[x_index]);
-                        let y = Wrapping(self.reg[y_index]);
-                        self.reg[x_index] = (x - y).0;
+                        let (difference, borrow) = self.reg[x_index].overflowing_sub(self.reg[y_index]);
+                        self.reg[x_index] = difference;
+                        self.reg[0xF] = !borrow as u8;
                         self.pc += 2;
                     }
                     0x0006 => {
                         // 0x8X_6: Shifts regX right by one, setting regF to lsb of regX before
                         let x = (self.opcode & 0x0F00) >> 8;
-                        let lsb = (self.reg[x as usize] & 0x0001) as u8;
-                        self.reg[0xF] = lsb;
+                        if self.quirks.shift_uses_vy {
+                            let y = (self.opcode & 0x00F0) >> 4;
+                            self.reg[x as usize] = self.reg[y as usize];
+                        }
+                        let lsb = self.reg[x as usize] & 0x0001;
                         self.reg[x as usize] >>= 1;
+                        self.reg[0xF] = lsb;
                         self.pc += 2;
                     }
                     0x0007 => {
                         // 0x8XY7: regX = regY - regX, regF = 0 if borrow, else 1
                         let x_index = ((self.opcode & 0x0F00) >> 8) as usize;
                         let y_index = ((self.opcode & 0x00F0) >> 4) as usize;
-                        if self.reg[x_index] > self.reg[y_index] {
-                            self.reg[0xF] = 0;
-                        } else {
-                            self.reg[0xF] = 1;
-                        }
-                        let x = Wrapping(self.reg[x_index]);
-                        let y = Wrapping(self.reg[y_index]);
-                        self.reg[x_index] = (y - x).0;
+                        let (difference, borrow) = self.reg[y_index].overflowing_sub(self.reg[x_index]);
+                        self.reg[x_index] = difference;
+                        self.reg[0xF] = !borrow as u8;
                         self.pc += 2;
                     }
                     0x000E => {
                         // 0x8X_E: Shifts regX left by one, setting regF to msb of regX before
                         let x = (self.opcode & 0x0F00) >> 8;
-                        let msb = ((self.reg[x as usize] & 0x80) as u8) >> 7;
-                        self.reg[0xF] = msb;
+                        if self.quirks.shift_uses_vy {
+                            let y = (self.opcode & 0x00F0) >> 4;
+                            self.reg[x as usize] = self.reg[y as usize];
+                        }
+                        let msb = (self.reg[x as usize] & 0x80) >> 7;
                         self.reg[x as usize] <<= 1;
+                        self.reg[0xF] = msb;
                         self.pc += 2;
                     }
                     _ => panic!("Opcode {:#X} is bad", self.opcode),
@@ -369,45 +984,26 @@ impl Chip8 {
             0xB000 => {
                 // 0xBNNN: Jump to address NNN + reg0
                 let address = self.opcode & 0x0FFF;
-                self.pc = address + self.reg[0] as u16;
+                let x = if self.quirks.jump_uses_vx {
+                    (self.opcode & 0x0F00) >> 8
+                } else {
+                    0
+                };
+                self.pc = address + self.reg[x as usize] as u16;
             }
             0xC000 => {
                 // 0xCXNN: regX = random number & NN
                 let x = (self.opcode & 0x0F00) >> 8;
                 let nn = (self.opcode & 0x00FF) as u8;
-                self.reg[x as usize] = nn & (rand::thread_rng().gen_range(0,255) as u8);
+                self.reg[x as usize] = nn & self.random();
                 self.pc += 2;
             }
             0xD000 => {
-                // 0xDXYN: Draw sprint at regX,regY with N bytes of data, start at index
-                // TODO: Fix wrapping of stuff
-                let x = self.reg[((self.opcode & 0x0F00) >> 8) as usize] as i32;
-                let y = self.reg[((self.opcode & 0x00F0) >> 4) as usize] as i32;
-                let n = (self.opcode & 0x000F) as u16;
-                let start = self.index;
-
-                self.reg[0xF] = 0;
-                for i in start..(start+n) {
-                    let row_num = (i - start) as u8;
-                    let bits = BitVec::from_bytes(&[self.memory[i as usize]]);
-                    for j in 0..8 {
-                        let x_s = x + j;
-                        let y_s = y + row_num as i32;
-                        if 0 <= x_s &&
-                           x_s < 64 &&
-                           0 <= y_s &&
-                           y_s < 32 {
-                               let address = (64 * y_s) + x_s;
-                               if bits[j as usize] {
-                                   if 1 == self.graphics[address as usize] {
-                                       self.reg[0xF] = 1;
-                                   }
-                                   self.graphics[address as usize] ^= 1;
-                               }
-                           }
-                    }
-                }
-                self.draw_flag = true;
+                // 0xDXYN: Draw sprite at regX,regY with N bytes of data, start at index
+                let x = ((self.opcode & 0x0F00) >> 8) as usize;
+                let y = ((self.opcode & 0x00F0) >> 4) as usize;
+                let n = (self.opcode & 0x000F) as usize;
+                self.draw(x, y, n);
                 self.pc += 2;
             }
             0xE000 => {
@@ -442,13 +1038,7 @@ impl Chip8 {
                     0x000A => {
                         // 0xFX0A: Wait for a keypress, halting operation, store in regX
                         let x = ((self.opcode & 0x0F00) >> 8) as usize;
-                        for k in 0..0xF {
-                            if self.key[k as usize] != 0 {
-                                self.reg[x] = k as u8;
-                                self.pc += 2;
-                                break;
-                            }
-                        }
+                        self.wait_for_key(x);
                     }
                     0x0015 => {
                         // 0xFX15: Sets delay timer to regX
@@ -478,25 +1068,19 @@ impl Chip8 {
                         // 0xFX33: Store binary coded decimal of regX
                         // http://www.multigesture.net/wp-content/uploads/mirror/goldroad/chip8.shtml
                         let x = ((self.opcode & 0x0F00) >> 8) as usize;
-                        self.memory[self.index as usize] = self.reg[x] / 100;
-                        self.memory[(self.index + 1) as usize] = (self.reg[x] / 10) % 10;
-                        self.memory[(self.index + 2) as usize] = (self.reg[x] % 100) % 10;
+                        self.store_bcd(x);
                         self.pc += 2;
                     }
                     0x0055 => {
                         // 0xFX55: Stores reg0 through regX (inclusive) in memory starting at index
                         let x = ((self.opcode & 0x0F00) >> 8) as usize;
-                        for i in 0..(x+1) {
-                            self.memory[self.index as usize + i] = self.reg[i];
-                        }
+                        self.store_registers(x);
                         self.pc += 2;
                     }
                     0x0065 => {
                         // 0xFX65: Fills reg0 through regX (inclusive) from memory starting at index
                         let x = ((self.opcode & 0x0F00) >> 8) as usize;
-                        for i in 0..(x+1) {
-                            self.reg[i] = self.memory[self.index as usize + i];
-                        }
+                        self.load_registers(x);
                         self.pc += 2;
                     }
                     _ => panic!("Opcode {:#X} is bad", self.opcode),
@@ -506,6 +1090,62 @@ impl Chip8 {
         }
     }
 
+    /// Draws the N byte sprite at the index at regX,regY, setting regF if a
+    /// pixel was turned off.
+    fn draw(&mut self, x: usize, y: usize, n: usize) {
+        let mut sprite = [0; 15];
+        for (i, row) in sprite.iter_mut().enumerate().take(n) {
+            *row = self.memory.read(self.index.wrapping_add(i as u16));
+        }
+        let collided = self.graphics.draw_sprite(self.reg[x] as u32, self.reg[y] as u32,
+                                                 &sprite[..n], self.quirks.wrap_sprites);
+        self.reg[0xF] = collided as u8;
+        self.draw_flag = true;
+    }
+
+    /// Stores the first pressed key in regX and moves on, or stays on the
+    /// same opcode if no key is pressed.
+    fn wait_for_key(&mut self, x: usize) {
+        for k in 0..self.key.len() {
+            if self.key[k] != 0 {
+                self.reg[x] = k as u8;
+                self.pc += 2;
+                break;
+            }
+        }
+    }
+
+    /// Stores the binary coded decimal digits of regX at the index.
+    fn store_bcd(&mut self, x: usize) {
+        self.memory.write(self.index, self.reg[x] / 100);
+        self.memory.write(self.index.wrapping_add(1), (self.reg[x] / 10) % 10);
+        self.memory.write(self.index.wrapping_add(2), (self.reg[x] % 100) % 10);
+        let index = self.index;
+        self.invalidate(index, 3);
+    }
+
+    /// Stores reg0 through regX (inclusive) in memory starting at the index.
+    fn store_registers(&mut self, x: usize) {
+        for i in 0..(x+1) {
+            self.memory.write(self.index.wrapping_add(i as u16), self.reg[i]);
+        }
+        let index = self.index;
+        self.invalidate(index, x + 1);
+        if self.quirks.load_store_increments_index {
+            self.index += x as u16 + 1;
+        }
+    }
+
+    /// Fills reg0 through regX (inclusive) from memory starting at the index.
+    fn load_registers(&mut self, x: usize) {
+        for i in 0..(x+1) {
+            self.reg[i] = self.memory.read(self.index.wrapping_add(i as u16));
+        }
+        if self.quirks.load_store_increments_index {
+            self.index += x as u16 + 1;
+        }
+    }
+
     /// Update the chip's internal timers for delay and sound.
     fn update_timers(&mut self) {
         if self.timer_delay > 0 {
@@ -524,18 +1164,19 @@ impl Chip8 {
 
 #[cfg(test)]
 mod test {
-    use super::Chip8;
+    use super::{Chip8, Engine};
+    use platform::Platform;
 
     #[test]
     fn op_00e0() {
         let mut chip = Chip8::default();
         chip.load_hex(&vec![0x00, 0xE0]);
-        chip.graphics[1] = 1;
+        chip.graphics.set_pixel(1, 0, 1);
         assert_eq!(chip.pc, 512);
 
         chip.emulate_cycle();
         assert_eq!(chip.pc, 514);
-        assert_eq!(chip.graphics[1], 0);
+        assert_eq!(chip.graphics.pixel(1, 0), 0);
     }
 
     #[test]
@@ -786,6 +1427,27 @@ mod test {
         assert_eq!(chip.reg[0xF], 0x1);
     }
 
+    #[test]
+    fn flags_win_over_vf() {
+        // With regF as regX, regF ends up holding the flag, not the result
+        let mut chip = Chip8::default();
+        chip.load_hex(&[0x8F, 0x14, 0x8F, 0x15, 0x8F, 0x17, 0x8F, 0x06, 0x8F, 0x0E]);
+        chip.reg[0x1] = 0x05;
+        chip.reg[0xF] = 0xFF;
+        chip.emulate_cycle();
+        assert_eq!(chip.reg[0xF], 1);
+        chip.emulate_cycle();
+        assert_eq!(chip.reg[0xF], 0);
+        chip.emulate_cycle();
+        assert_eq!(chip.reg[0xF], 1);
+        chip.reg[0xF] = 0x02;
+        chip.emulate_cycle();
+        assert_eq!(chip.reg[0xF], 0);
+        chip.reg[0xF] = 0x80;
+        chip.emulate_cycle();
+        assert_eq!(chip.reg[0xF], 1);
+    }
+
     #[test]
     fn op_9xy0() {
         let mut chip = Chip8::default();
@@ -886,6 +1548,18 @@ mod test {
         assert_eq!(chip.reg[1], 1);
     }
 
+    #[test]
+    fn op_fx0a_sees_key_f() {
+        for &engine in [Engine::Interpreter, Engine::Cached, Engine::Recompiler].iter() {
+            let mut chip = Chip8::with_engine(engine);
+            chip.load_hex(&vec![0xF1, 0x0A]);
+            chip.key[0xF] = 1;
+            chip.emulate_frame(1);
+            assert_eq!(chip.pc, 514, "{:?}", engine);
+            assert_eq!(chip.reg[1], 0xF, "{:?}", engine);
+        }
+    }
+
     #[test]
     fn op_fx15() {
         let mut chip = Chip8::default();
@@ -966,4 +1640,294 @@ mod test {
         assert_eq!(chip.reg[0], 0xAB);
         assert_eq!(chip.reg[1], 0xCD);
     }
+
+    #[test]
+    fn emulate_frame() {
+        let mut chip = Chip8::default();
+        chip.load_hex(&vec![0x61, 0x0A, 0xF1, 0x15, 0x71, 0x01]);
+        assert_eq!(chip.pc, 512);
+
+        chip.emulate_frame(3);
+        assert_eq!(chip.pc, 518);
+        assert_eq!(chip.reg[1], 0x0B);
+        assert_eq!(chip.timer_delay, 9);
+    }
+
+    #[test]
+    fn emulate_frame_until() {
+        let mut chip = Chip8::default();
+        chip.load_hex(&vec![0x61, 0x0A, 0xF1, 0x15, 0x71, 0x01, 0x12, 0x04]);
+
+        let mut cycles = 10;
+        assert!(!chip.emulate_frame_until(&mut cycles, &[0x204], false));
+        assert_eq!((chip.pc, cycles, chip.timer_delay), (0x204, 8, 10));
+        assert!(!chip.emulate_frame_until(&mut cycles, &[0x204], false));
+        assert_eq!(cycles, 8);
+
+        // Going on from the breakpoint loops back to it
+        assert!(!chip.emulate_frame_until(&mut cycles, &[0x204], true));
+        assert_eq!((chip.pc, cycles, chip.reg[1]), (0x204, 6, 0x0B));
+        assert!(chip.emulate_frame_until(&mut cycles, &[], true));
+        assert_eq!((cycles, chip.timer_delay), (0, 9));
+    }
+
+    #[test]
+    fn load_hex_at() {
+        let mut chip = Chip8::default();
+        chip.load_hex_at(&vec![0x6A, 0x2F], 0x600);
+        assert_eq!(chip.pc, 0x600);
+        assert_eq!(chip.memory[0x600], 0x6A);
+        assert_eq!(chip.memory[0x200], 0);
+
+        chip.emulate_cycle();
+        assert_eq!(chip.reg[0xA], 0x2F);
+    }
+
+    #[test]
+    fn load_rom_checks_size() {
+        let mut chip = Chip8::default();
+        assert!(chip.load_rom(&vec![0xAA; 3584], 0x200).is_ok());
+        assert!(chip.load_rom(&vec![0xBB; 3585], 0x200).is_err());
+        assert!(chip.load_rom(&vec![0xBB; 2], 0xFFF).is_err());
+        assert_eq!(chip.memory[0xFFF], 0xAA);
+    }
+
+    #[test]
+    fn entry_point() {
+        let mut chip = Chip8::default();
+        chip.load_hex_at(&vec![0x00, 0x00, 0x6A, 0x2F], 0x600);
+        chip.set_entry_point(0x602);
+        chip.emulate_cycle();
+        assert_eq!(chip.reg[0xA], 0x2F);
+
+        chip.reset();
+        assert_eq!(chip.pc, 0x602);
+    }
+
+    #[test]
+    fn reset() {
+        let mut chip = Chip8::default();
+        chip.load_hex_at(&vec![0x6A, 0x2F, 0x22, 0x00, 0xA1, 0x23], 0x600);
+        chip.emulate_cycle();
+        chip.emulate_cycle();
+        chip.graphics.set_pixel(5, 0, 1);
+        chip.memory[0x601] = 0x30;
+
+        chip.reset();
+        assert_eq!(chip.pc, 0x600);
+        assert_eq!(chip.sp, 0);
+        assert_eq!(chip.reg[0xA], 0);
+        assert_eq!(chip.graphics.pixel(5, 0), 0);
+        assert!(chip.draw_flag);
+
+        // Memory is kept, including changes made while running
+        chip.emulate_cycle();
+        assert_eq!(chip.reg[0xA], 0x30);
+    }
+
+    #[test]
+    fn memory_hooks() {
+        let mut chip = Chip8::default();
+        chip.load_hex(&vec![0xF1, 0x55, 0xF1, 0x65]);
+        chip.memory_bus().protect(0x000, 0x1FF);
+        chip.memory_bus().add_read_hook(0x001, 0x001, |_, value| value + 1);
+        chip.reg[0] = 0xAB;
+        chip.reg[1] = 0xCD;
+
+        chip.emulate_cycle();
+        assert_eq!(chip.memory[0], 0xF0);
+        assert_eq!(chip.memory[1], 0x90);
+        chip.emulate_cycle();
+        assert_eq!(chip.reg[0], 0xF0);
+        assert_eq!(chip.reg[1], 0x91);
+
+        // Writes past the end of memory wrap around into the protected font
+        chip.load_hex(&vec![0xAF, 0xFF, 0xF1, 0x55]);
+        chip.reg[0] = 0xAB;
+        chip.emulate_cycle();
+        chip.emulate_cycle();
+        assert_eq!(chip.memory[0xFFF], 0xAB);
+        assert_eq!(chip.memory[0], 0xF0);
+    }
+
+    #[test]
+    fn memory_size() {
+        let mut chip = Chip8::default();
+        assert!(chip.load_rom(&vec![0xAA; 0x1000], 0x1000).is_err());
+        chip.set_memory_size(Platform::XoChip.memory_size());
+        assert!(chip.load_rom(&vec![0xAA; 0x1000], 0x1000).is_ok());
+        assert_eq!(chip.memory[0x1FFF], 0xAA);
+        assert_eq!(chip.memory[0], 0xF0);
+    }
+
+    #[test]
+    fn cpu_state() {
+        let mut chip = Chip8::default();
+        chip.load_hex(&vec![0x6A, 0x2F, 0xA1, 0x23, 0x22, 0x00]);
+        chip.emulate_frame(3);
+
+        let state = chip.cpu_state();
+        assert_eq!(state.opcode, 0x2200);
+        assert_eq!(state.registers[0xA], 0x2F);
+        assert_eq!(state.index, 0x123);
+        assert_eq!(state.pc, 0x200);
+        assert_eq!(chip.stack(), &[0x204]);
+        assert_eq!(&chip.memory()[0x200..0x202], &[0x6A, 0x2F]);
+
+        chip.set_register(0xA, 1);
+        chip.set_index(0x300);
+        chip.set_pc(0x202);
+        chip.set_timers(5, 6);
+        assert_eq!(chip.registers()[0xA], 1);
+        assert_eq!(chip.index(), 0x300);
+        assert_eq!(chip.pc(), 0x202);
+        assert_eq!(chip.cpu_state().sound_timer, 6);
+
+        assert!(chip.set_cpu_state(&state).is_ok());
+        assert_eq!(chip.cpu_state(), state);
+        let mut bad = state;
+        bad.sp = 17;
+        assert!(chip.set_cpu_state(&bad).is_err());
+        assert_eq!(chip.cpu_state(), state);
+    }
+
+    #[test]
+    fn write_memory() {
+        let mut chip = Chip8::default();
+        chip.memory_bus().protect(0x300, 0x3FF);
+        assert!(chip.write_memory(0x300, &[1, 2]).is_ok());
+        assert_eq!(&chip.memory()[0x300..0x302], &[1, 2]);
+        assert!(chip.write_memory(0xFFF, &[1, 2]).is_err());
+        assert_eq!(chip.pc(), 0x200);
+    }
+
+    #[test]
+    fn seeded_rng_repeats() {
+        let program = vec![0xC0, 0xFF, 0xC1, 0xFF, 0xC2, 0xFF];
+        let mut first = Chip8::default();
+        let mut second = Chip8::default();
+        for chip in [&mut first, &mut second].iter_mut() {
+            chip.load_hex(&program);
+            chip.set_seed(42);
+            chip.emulate_frame(3);
+        }
+        assert_eq!(first.reg, second.reg);
+    }
+
+    #[test]
+    fn random_seeds_differ() {
+        assert!(super::random_seed() != super::random_seed());
+    }
+
+    #[test]
+    fn caller_supplied_rng() {
+        fn counter(state: &mut u64) -> u8 {
+            *state += 1;
+            *state as u8
+        }
+        let mut chip = Chip8::default();
+        chip.set_rng(counter);
+        chip.set_seed(9);
+        chip.load_hex(&[0xC0, 0xFF, 0xC1, 0x0F]);
+        chip.emulate_frame(2);
+        assert_eq!(&chip.reg[..2], &[10, 11]);
+    }
+
+    #[test]
+    fn send_and_clone() {
+        fn check<T: Send + Clone>() {}
+        check::<Chip8>();
+
+        let mut chip = Chip8::default();
+        chip.load_hex(&[0x60, 0x01, 0x70, 0x01, 0x12, 0x02]);
+        chip.emulate_frame(3);
+        let mut clone = chip.clone();
+        chip.emulate_frame(2);
+        clone.emulate_frame(2);
+        assert_eq!(chip.cpu_state(), clone.cpu_state());
+        assert_eq!(clone.cycles(), 5);
+    }
+
+    #[test]
+    fn quirk_shift_uses_vy() {
+        let mut chip = Chip8::default();
+        chip.quirks.shift_uses_vy = true;
+        chip.load_hex(&vec![0x81, 0x26, 0x81, 0x2E]);
+        chip.reg[1] = 0xFF;
+        chip.reg[2] = 0b0110;
+
+        chip.emulate_cycle();
+        assert_eq!(chip.reg[1], 0b011);
+        assert_eq!(chip.reg[0xF], 0);
+
+        chip.emulate_cycle();
+        assert_eq!(chip.reg[1], 0b1100);
+    }
+
+    #[test]
+    fn quirk_load_store_increments_index() {
+        let mut chip = Chip8::default();
+        chip.quirks.load_store_increments_index = true;
+        chip.load_hex(&vec![0xF1, 0x55, 0xF2, 0x65]);
+        chip.index = 0x300;
+
+        chip.emulate_cycle();
+        assert_eq!(chip.index, 0x302);
+        chip.emulate_cycle();
+        assert_eq!(chip.index, 0x305);
+    }
+
+    #[test]
+    fn quirk_jump_uses_vx() {
+        let mut chip = Chip8::default();
+        chip.quirks.jump_uses_vx = true;
+        chip.load_hex(&vec![0xB6, 0x66]);
+        chip.reg[0] = 0x1;
+        chip.reg[6] = 0x5;
+
+        chip.emulate_cycle();
+        assert_eq!(chip.pc, 0x666 + 0x5);
+    }
+
+    #[test]
+    fn quirk_logic_resets_vf() {
+        let mut chip = Chip8::default();
+        chip.quirks.logic_resets_vf = true;
+        chip.load_hex(&vec![0x8A, 0x21]);
+        chip.reg[0xF] = 1;
+
+        chip.emulate_cycle();
+        assert_eq!(chip.reg[0xF], 0);
+    }
+
+    #[test]
+    fn quirk_wrap_sprites() {
+        let mut chip = Chip8::default();
+        chip.quirks.wrap_sprites = true;
+        chip.load_hex(&vec![0xD0, 0x11]);
+        chip.memory[0x300] = 0xFF;
+        chip.index = 0x300;
+        chip.reg[0] = 60;
+        chip.reg[1] = 31;
+
+        chip.emulate_cycle();
+        assert_eq!(chip.graphics.pixel(63, 31), 1);
+        assert_eq!(chip.graphics.pixel(0, 31), 1);
+        assert_eq!(chip.graphics.pixel(3, 31), 1);
+        assert_eq!(chip.graphics.pixel(4, 31), 0);
+    }
+
+    #[test]
+    fn quirk_display_wait() {
+        let mut chip = Chip8 {
+            quirks: Platform::Chip8.quirks(),
+            ..Chip8::default()
+        };
+        chip.load_hex(&vec![0xD0, 0x01, 0xD0, 0x01]);
+
+        chip.emulate_frame(10);
+        assert_eq!(chip.pc, 514);
+        chip.emulate_frame(10);
+        assert_eq!(chip.pc, 516);
+    }
 }
@@ -1,24 +1,452 @@
-use std::error::Error;
+use chip8::cartridge::{self, Cartridge};
+use chip8::hexfile;
+use chip8::octo;
+use chip8::PROGRAM_START;
+use flate2::read::GzDecoder;
+#[cfg(any(feature = "sdl-frontend", test))]
+use std::fs;
 use std::fs::File;
+use std::io::{self, Cursor};
 use std::io::prelude::*;
 use std::path::Path;
+use std::str::FromStr;
+#[cfg(any(feature = "sdl-frontend", test))]
+use std::path::PathBuf;
+#[cfg(any(feature = "sdl-frontend", test))]
+use std::time::SystemTime;
+use zip::ZipArchive;
 
-/// Loads the given filename as a vetor of bytes.
-pub fn load_file(path: &str) -> Vec<u8> {
-    // Initialize the emulator and load the game
-    let path = Path::new(path);
-    let display = path.display();
+/// The extensions of files in zip archives that are taken to be ROMs.
+const ROM_EXTENSIONS: [&str; 6] = ["ch8", "c8", "sc8", "xo8", "rom", "bin"];
 
-    let mut file = match File::open(path) {
-        Err(why) => panic!("Couldn't open {}: {}", display, Error::description(&why)),
-        Ok(file) => file,
+/// The extensions of files that are read as hex text.
+const HEX_EXTENSIONS: [&str; 2] = ["hex", "txt"];
+
+/// The extension of Octo source files.
+const OCTO_EXTENSION: &str = "8o";
+
+/// How a ROM file is written.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    /// Machine code, maybe in a gzip file, a zip archive or an Octo
+    /// cartridge.
+    Binary,
+    /// An Intel HEX file or a hex listing.
+    Hex,
+    /// Octo source, to be assembled.
+    Octo,
+}
+
+impl Format {
+    /// The format of a file with the given name, judged by its extension.
+    pub fn from_name(name: &str) -> Format {
+        let extension = Path::new(name).extension()
+            .and_then(|ext| ext.to_str())
+            .map(|ext| ext.to_lowercase());
+        match extension.as_deref() {
+            Some(ext) if HEX_EXTENSIONS.contains(&ext) => Format::Hex,
+            Some(OCTO_EXTENSION) => Format::Octo,
+            _ => Format::Binary,
+        }
+    }
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, String> {
+        match name {
+            "binary" => Ok(Format::Binary),
+            "hex" => Ok(Format::Hex),
+            "octo" => Ok(Format::Octo),
+            _ => Err(format!("Unknown ROM format {}", name)),
+        }
+    }
+}
+
+/// A loaded ROM, along with the options it was packaged with, if any.
+pub struct Rom {
+    /// The ROM's bytes.
+    pub game: Vec<u8>,
+    /// The options from the ROM's Octo cartridge, if it came in one.
+    pub options: Option<cartridge::Options>,
+    /// The address the ROM must be loaded at, if its file says.
+    pub address: Option<u16>,
+    /// The address to start running the ROM from, if its file says.
+    pub entry_point: Option<u16>,
+}
+
+/// Loads a ROM from the given path, or from stdin if the path is `-`.
+///
+/// Gzipped ROMs are unpacked.  ROMs in zip archives are found by the given
+/// member name, or else by being the only ROM in the archive.  Octo
+/// cartridges are unpacked into their options and their program, which is
+/// assembled into the ROM.  Intel HEX files and hex listings are parsed,
+/// keeping the addresses they give, and Octo source is assembled, when the
+/// format says so or the file's extension does.
+pub fn load(path: &str, member: Option<&str>, format: Option<Format>) -> Result<Rom, String> {
+    load_from(path, member, format, &mut io::stdin())
+}
+
+/// Loads a ROM like `load`, reading the given reader in place of stdin.
+fn load_from<R: Read>(path: &str,
+                      member: Option<&str>,
+                      format: Option<Format>,
+                      stdin: &mut R) -> Result<Rom, String> {
+    let bytes = if path == "-" {
+        let mut bytes = Vec::new();
+        stdin.read_to_end(&mut bytes).map(|_| bytes)
+    } else {
+        read_file(path)
+    };
+    let bytes = bytes.map_err(|why| format!("Couldn't read {}: {}", path, why))?;
+
+    // The name of the file holding the ROM, whose extension gives its format
+    let (bytes, name) = if bytes.starts_with(&[0x1F, 0x8B]) {
+        let mut game = Vec::new();
+        GzDecoder::new(&bytes[..])
+            .read_to_end(&mut game)
+            .map_err(|why| format!("Couldn't unpack {}: {}", path, why))?;
+        (game, path.trim_end_matches(".gz").to_string())
+    } else if bytes.starts_with(b"PK\x03\x04") {
+        unzip(bytes, member).map_err(|why| format!("{}: {}", path, why))?
+    } else {
+        (bytes, path.to_string())
     };
+    let format = format.unwrap_or_else(|| Format::from_name(&name));
 
+    if bytes.starts_with(b"GIF8") {
+        let cartridge = Cartridge::read(&bytes[..]).and_then(|cartridge| {
+            cartridge.rom().map(|game| {
+                Rom {
+                    game: game,
+                    options: Some(cartridge.options),
+                    address: None,
+                    entry_point: None,
+                }
+            })
+        });
+        cartridge.map_err(|why| format!("{}: {}", path, why))
+    } else if format == Format::Hex {
+        let text = String::from_utf8_lossy(&bytes);
+        let program = hexfile::parse(&text, PROGRAM_START)
+            .map_err(|why| format!("{}: {}", path, why))?;
+        Ok(Rom {
+            entry_point: program.entry_point.or_else(|| default_entry_point(&program)),
+            game: program.bytes,
+            options: None,
+            address: program.address,
+        })
+    } else if format == Format::Octo {
+        let text = String::from_utf8_lossy(&bytes);
+        Ok(Rom {
+            game: octo::assemble(&text).map_err(|why| format!("{}: {}", path, why))?,
+            options: None,
+            address: None,
+            entry_point: None,
+        })
+    } else {
+        Ok(Rom {
+            game: bytes,
+            options: None,
+            address: None,
+            entry_point: None,
+        })
+    }
+}
+
+/// Where to start a program that doesn't give an entry point: the usual
+/// start of programs if the program covers it, or else its first byte.
+fn default_entry_point(program: &hexfile::Program) -> Option<u16> {
+    let start = program.address?;
+    let end = start as usize + program.bytes.len();
+    if start < PROGRAM_START && end > PROGRAM_START as usize {
+        Some(PROGRAM_START)
+    } else {
+        None
+    }
+}
+
+/// Reads the given file as a vector of bytes.
+pub fn read_file<P: AsRef<Path>>(path: P) -> io::Result<Vec<u8>> {
     let mut game = Vec::new();
-    match file.read_to_end(&mut game) {
-        Err(why) => panic!("Couldn't read {}: {}", display, Error::description(&why)),
-        Ok(_) => (),
+    File::open(path)?.read_to_end(&mut game)?;
+    Ok(game)
+}
+
+/// Finds a ROM in the given zip archive, either the member with the given
+/// name or the only ROM in the archive, returning it and its name.
+fn unzip(archive: Vec<u8>, member: Option<&str>) -> Result<(Vec<u8>, String), String> {
+    let mut archive = ZipArchive::new(Cursor::new(archive))
+        .map_err(|why| format!("Couldn't open the zip archive: {}", why))?;
+
+    let name = match member {
+        Some(name) => name.to_string(),
+        None => {
+            let mut files: Vec<String> = archive.file_names()
+                .filter(|name| !name.ends_with('/'))
+                .map(String::from)
+                .collect();
+            files.sort();
+            let roms: Vec<&String> = files.iter()
+                .filter(|name| {
+                    Path::new(name).extension()
+                        .and_then(|ext| ext.to_str())
+                        .map(|ext| ROM_EXTENSIONS.contains(&ext.to_lowercase().as_str()))
+                        == Some(true)
+                })
+                .collect();
+            match (files.len(), roms.len()) {
+                (1, _) => files[0].clone(),
+                (_, 1) => roms[0].clone(),
+                _ => {
+                    return Err(format!("Pick one of the archive's files with --zip-member: {}",
+                                       files.join(", ")))
+                }
+            }
+        }
     };
 
-    game
+    let mut file = archive.by_name(&name)
+        .map_err(|why| format!("Couldn't find {} in the zip archive: {}", name, why))?;
+    let mut game = Vec::new();
+    file.read_to_end(&mut game)
+        .map_err(|why| format!("Couldn't unpack {}: {}", name, why))?;
+    Ok((game, name))
+}
+
+/// Watches a file for changes by polling its modification time.
+#[cfg(any(feature = "sdl-frontend", test))]
+pub struct Watcher {
+    path: PathBuf,
+    modified: Option<SystemTime>,
+}
+
+#[cfg(any(feature = "sdl-frontend", test))]
+impl Watcher {
+    /// Constructs a Watcher for the given file, which counts as unchanged
+    /// until it is next modified.
+    pub fn new<P: AsRef<Path>>(path: P) -> Self {
+        let path = path.as_ref().to_path_buf();
+        let modified = modified(&path);
+        Watcher {
+            path: path,
+            modified: modified,
+        }
+    }
+
+    /// Checks whether the file has been modified since the last check.  A
+    /// file that is missing, such as while it is being rebuilt, counts as
+    /// unchanged until it comes back.
+    pub fn changed(&mut self) -> bool {
+        match modified(&self.path) {
+            Some(time) if Some(time) != self.modified => {
+                self.modified = Some(time);
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+/// The time the given file was last modified, if it can be found.
+#[cfg(any(feature = "sdl-frontend", test))]
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|meta| meta.modified()).ok()
+}
+
+#[cfg(test)]
+mod test {
+    use super::{load, load_from, Format, Watcher};
+    use flate2::Compression;
+    use flate2::write::GzEncoder;
+    use std::env;
+    use std::fs::{self, File};
+    use std::io::{self, Cursor, Write};
+    use std::path::PathBuf;
+    use std::process;
+    use std::time::{Duration, SystemTime};
+    use zip::ZipWriter;
+    use zip::write::FileOptions;
+
+    const GAME: [u8; 4] = [0x60, 0x01, 0x12, 0x02];
+
+    /// Gzips the given bytes.
+    fn gzip(bytes: &[u8]) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(bytes).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    /// Zips up files with the given names, each holding its name's bytes
+    /// followed by `GAME`.
+    fn zip(names: &[&str]) -> Vec<u8> {
+        let mut writer = ZipWriter::new(Cursor::new(Vec::new()));
+        for name in names {
+            writer.start_file(*name, FileOptions::default()).unwrap();
+            writer.write_all(name.as_bytes()).unwrap();
+            writer.write_all(&GAME).unwrap();
+        }
+        writer.finish().unwrap().into_inner()
+    }
+
+    /// Loads bytes as if they were piped to stdin.
+    fn load_stdin(bytes: Vec<u8>, member: Option<&str>) -> Result<Vec<u8>, String> {
+        load_from("-", member, None, &mut Cursor::new(bytes)).map(|rom| rom.game)
+    }
+
+    #[test]
+    fn plain_files() {
+        let path = temp_path("plain.ch8");
+        fs::write(&path, GAME).unwrap();
+        let rom = load(path.to_str().unwrap(), None, None).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(rom.game, GAME);
+        assert!(rom.options.is_none());
+        assert_eq!(rom.address, None);
+
+        let why = load(path.to_str().unwrap(), None, None).err().unwrap();
+        assert!(why.starts_with(&format!("Couldn't read {}", path.display())), "{}", why);
+    }
+
+    #[test]
+    fn stdin() {
+        assert_eq!(load_stdin(GAME.to_vec(), None).unwrap(), GAME);
+        assert!(load_from("-", None, None, &mut BrokenPipe).err().unwrap().starts_with("Couldn't read -"));
+    }
+
+    #[test]
+    fn gzip_files() {
+        assert_eq!(load_stdin(gzip(&GAME), None).unwrap(), GAME);
+
+        // A gzip header with nothing after it
+        let mut bad = gzip(&GAME);
+        bad.truncate(12);
+        assert!(load_stdin(bad, None).err().unwrap().starts_with("Couldn't unpack -"));
+    }
+
+    #[test]
+    fn zip_members() {
+        let archive = zip(&["readme.txt", "docs/", "pong.ch8", "tetris.CH8"]);
+        let game = load_stdin(archive.clone(), Some("tetris.CH8")).unwrap();
+        assert_eq!(game, b"tetris.CH8\x60\x01\x12\x02");
+        let game = load_stdin(archive.clone(), Some("pong.ch8")).unwrap();
+        assert!(game.starts_with(b"pong.ch8"));
+
+        // Members are read as the format their names give
+        let why = load_stdin(archive.clone(), Some("readme.txt")).err().unwrap();
+        assert!(why.starts_with("-: Line 1: Expected hex bytes"), "{}", why);
+
+        let why = load_stdin(archive, Some("breakout.ch8")).err().unwrap();
+        assert!(why.starts_with("-: Couldn't find breakout.ch8 in the zip archive"), "{}", why);
+    }
+
+    #[test]
+    fn zip_with_one_rom() {
+        // The only ROM is picked out from the other files
+        let game = load_stdin(zip(&["readme.txt", "pong.ch8"]), None).unwrap();
+        assert!(game.starts_with(b"pong.ch8"));
+
+        // As is the only file, whatever it's called
+        let game = load_stdin(zip(&["PONG"]), None).unwrap();
+        assert!(game.starts_with(b"PONG"));
+    }
+
+    #[test]
+    fn ambiguous_zips() {
+        let why = load_stdin(zip(&["readme.txt", "pong.ch8", "tetris.ch8"]), None).err().unwrap();
+        assert_eq!(why,
+                   "-: Pick one of the archive's files with --zip-member: pong.ch8, readme.txt, \
+                    tetris.ch8");
+        let why = load_stdin(zip(&["readme.txt", "PONG"]), None).err().unwrap();
+        assert!(why.contains("--zip-member"), "{}", why);
+
+        let mut broken = zip(&["pong.ch8"]);
+        broken.truncate(30);
+        let why = load_stdin(broken, None).err().unwrap();
+        assert!(why.starts_with("-: Couldn't open the zip archive"), "{}", why);
+    }
+
+    #[test]
+    fn text_formats() {
+        // Hex text and Octo source are known by their extensions
+        let path = temp_path("listing.hex");
+        fs::write(&path, "6001 1202\n").unwrap();
+        let rom = load(path.to_str().unwrap(), None, None).unwrap();
+        assert_eq!(rom.game, GAME);
+
+        // Or by the format given, whatever the file is called
+        let rom = load(path.to_str().unwrap(), None, Some(Format::Binary)).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(rom.game, b"6001 1202\n");
+
+        let path = temp_path("game.8o");
+        fs::write(&path, ": main\n  v0 := 1\n  jump main\n").unwrap();
+        let rom = load(path.to_str().unwrap(), None, None).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(rom.game, [0x60, 0x01, 0x12, 0x00]);
+
+        // ROMs that happen to look like text are still machine code
+        let path = temp_path("text.ch8");
+        fs::write(&path, "ABCD").unwrap();
+        let rom = load(path.to_str().unwrap(), None, None).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(rom.game, b"ABCD");
+
+        // The extension inside a gzip file's name counts
+        let path = temp_path("listing.hex.gz");
+        fs::write(&path, gzip(b"6001 1202")).unwrap();
+        let rom = load(path.to_str().unwrap(), None, None).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(rom.game, GAME);
+
+        let mut stdin = Cursor::new(b": main 0x60 0x01 0x12 0x02".to_vec());
+        assert_eq!(load_from("-", None, Some(Format::Octo), &mut stdin).unwrap().game, GAME);
+        assert_eq!("hex".parse(), Ok(Format::Hex));
+        assert!("elf".parse::<Format>().is_err());
+    }
+
+    /// A reader that always fails, like a closed pipe.
+    struct BrokenPipe;
+
+    impl io::Read for BrokenPipe {
+        fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+            Err(io::Error::new(io::ErrorKind::BrokenPipe, "broken pipe"))
+        }
+    }
+
+    /// A path for a temporary file, which is removed first if it exists.
+    fn temp_path(name: &str) -> PathBuf {
+        let path = env::temp_dir().join(format!("chip8-loader-{}-{}", process::id(), name));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    /// Writes the file, marking it as modified at the given time.
+    fn touch(path: &PathBuf, bytes: &[u8], modified: SystemTime) {
+        fs::write(path, bytes).unwrap();
+        File::options().write(true).open(path).unwrap().set_modified(modified).unwrap();
+    }
+
+    #[test]
+    fn watcher_sees_changes() {
+        let path = temp_path("watched.ch8");
+        let start = SystemTime::now() - Duration::from_secs(60);
+        touch(&path, &[0x12, 0x00], start);
+
+        let mut watcher = Watcher::new(&path);
+        assert!(!watcher.changed());
+
+        touch(&path, &[0x12, 0x02], start + Duration::from_secs(10));
+        assert!(watcher.changed());
+        assert!(!watcher.changed());
+
+        // A missing file, such as one being rebuilt, isn't a change until it
+        // comes back
+        fs::remove_file(&path).unwrap();
+        assert!(!watcher.changed());
+        touch(&path, &[0x12, 0x04], start + Duration::from_secs(20));
+        assert!(watcher.changed());
+        fs::remove_file(&path).unwrap();
+    }
 }
@@ -1,82 +1,1062 @@
+// Fields are written `name: name`, from before the shorthand existed
+#![allow(clippy::redundant_field_names)]
+// `%` keeps building on compilers from before `is_multiple_of`
+#![allow(clippy::manual_is_multiple_of)]
+
 extern crate chip8;
+#[macro_use]
 extern crate clap;
 #[macro_use]
 extern crate log;
 extern crate env_logger;
+extern crate flate2;
+#[cfg(feature = "sdl-frontend")]
 extern crate sdl2;
+extern crate serde_json;
+extern crate zip;
 
+#[cfg(feature = "sdl-frontend")]
 mod audio;
+mod config;
+#[cfg(feature = "sdl-frontend")]
 mod graphics;
+#[cfg(feature = "sdl-frontend")]
 mod input;
+mod jobs;
 mod loader;
+mod rpc;
+#[cfg(feature = "tty-frontend")]
+mod tty;
 
-use chip8::Chip8;
-use clap::{Arg, App};
-use std::time::Duration;
+use chip8::{random_seed, Chip8};
+use chip8::batch::{self, InputScript, Job};
+use chip8::cartridge::Cartridge;
+#[cfg(feature = "sdl-frontend")]
+use chip8::filter::Filter;
+use chip8::filter::FILTERS;
+use chip8::netplay::{self, Options, Session};
+use chip8::platform::{PLATFORMS, QUIRK_NAMES};
+use chip8::record::{Format, Recorder};
+#[cfg(feature = "sdl-frontend")]
+use chip8::render::Image;
+use clap::{Arg, App, ArgMatches, ErrorKind};
+use config::Settings;
+use loader::Rom;
+use std::fs::File;
+use std::io::prelude::*;
+use std::net::{TcpListener, TcpStream};
+use std::path::Path;
+#[cfg(feature = "sdl-frontend")]
+use std::path::PathBuf;
+use std::process;
+use std::thread;
+use std::time::{Duration, Instant};
+#[cfg(feature = "sdl-frontend")]
+use std::time::{SystemTime, UNIX_EPOCH};
 
-/// Adjust the scaling factor of the chip8's display.  The larger the number,
-/// the bigger the display.
-const SCALE : u32 = 8;
+/// The other player in netplay, if there is one.
+type Netplay = Option<Session<TcpStream>>;
 
-/// The scaled width of the display.
-const WIDTH : u32 = chip8::WIDTH * SCALE;
+/// What every frontend runs alongside the game: netplay, the JSON-RPC server
+/// and the recording, each if it was asked for.
+struct Extras<'a> {
+    netplay: Netplay,
+    rpc: Option<rpc::Server>,
+    /// Where to save the recording, and the recording.
+    recorder: Option<(&'a str, Recorder)>,
+}
 
-/// The scaled height of the display.
-const HEIGHT : u32 = chip8::HEIGHT * SCALE;
+impl<'a> Extras<'a> {
+    /// Handles the JSON-RPC calls that have come in, if there's a server.
+    /// Returns whether to keep going.
+    fn poll(&mut self,
+            chip: &mut Chip8,
+            settings: &mut Settings,
+            game: &mut Vec<u8>,
+            matches: &ArgMatches,
+            paused: &mut bool) -> bool {
+        match self.rpc {
+            Some(ref mut server) => {
+                server.poll(chip, settings, game, matches, paused, self.netplay.is_some())
+            }
+            None => true,
+        }
+    }
+
+    /// Whether a JSON-RPC call asked for a frame to be run while paused.
+    fn step(&mut self) -> bool {
+        match self.rpc {
+            Some(ref mut server) => server.take_step(),
+            None => false,
+        }
+    }
+}
+
+/// The window's title.
+#[cfg(feature = "sdl-frontend")]
+const TITLE: &str = "Chip8 Emulator";
+
+/// How often (in displayed frames) the ROM is checked for changes with
+/// `--watch`.
+#[cfg(feature = "sdl-frontend")]
+const WATCH_FRAMES: u64 = 30;
+
+/// How long (in displayed frames) messages stay in the window's title.
+#[cfg(feature = "sdl-frontend")]
+const MESSAGE_FRAMES: u64 = 180;
+
+/// How many frames are run per displayed frame while fast-forwarding.
+#[cfg(feature = "sdl-frontend")]
+const FAST_FORWARD_FRAMES: u32 = 4;
+
+/// How many displayed frames each frame is stretched over in slow motion.
+#[cfg(feature = "sdl-frontend")]
+const SLOW_MOTION_FRAMES: u64 = 4;
 
 fn main() {
     // Init the logger
     env_logger::init().unwrap();
 
     // Setup the commandline flags and usage/help message.
-    let matches = App::new("Chip8 Emulator")
+    let platform_names: Vec<&str> = PLATFORMS.iter().map(|p| p.name()).collect();
+    let filter_names: Vec<&str> = FILTERS.iter().map(|f| f.name()).collect();
+    let quirks_help = format!("Turns quirks on or off, like shift=on,jump=off (quirks: {})",
+                              QUIRK_NAMES.join(", "));
+    let app = App::new("Chip8 Emulator")
         .version(chip8::version())
         .author("Chris Konstad <chriskon149@gmail.com>")
-        .about("Runs Chip8 games.")
+        .about("Runs Chip8 games.\n\n\
+                Settings are read from the global config file (chip8/config in \
+                the user's config directory, or the file given with --config), \
+                then from the ROM database if the ROM is known, \
+                then from a config file next to the ROM with a .cfg extension, \
+                and then from the command line.  Config files have one \
+                \"setting = value\" per line, using the names of the flags \
+                below, such as \"scale = 4\" or \"mute = true\".")
+        .after_help("HOTKEYS:
+    Esc    Quit
+    F1     Pause or resume
+    F2     Advance a single frame
+    Tab    Fast-forward while held
+    F3     Toggle fast-forward
+    F4     Toggle uncapped fast-forward
+    F5     Toggle slow motion
+    F6     Soft reset (restart the game)
+    F7     Hard reset (reload the game)
+    F10    Next display filter
+    F11    Toggle fullscreen
+    F12    Save a screenshot next to the ROM")
         .arg(Arg::with_name("ROM")
-             .help("Sets the path to the ROM to play")
-             .required(true))
-        .get_matches();
+             .help("Sets the path to the ROM to play, which may be gzipped, in a zip \
+                    archive, an Octo cartridge, an Intel HEX file, a hex listing or Octo \
+                    source (see --rom-format), or - to read it from stdin")
+             .required_unless("batch"))
+        .arg(Arg::with_name("config")
+             .long("config")
+             .value_name("FILE")
+             .help("Reads global settings from FILE")
+             .takes_value(true))
+        .arg(Arg::with_name("database")
+             .long("database")
+             .value_name("FILE")
+             .help("Reads the ROM database from FILE, a programs.json from the CHIP-8 database, \
+                    instead of chip8/programs.json in the user's config directory")
+             .takes_value(true))
+        .arg(Arg::with_name("ipf")
+             .long("ipf")
+             .value_name("N")
+             .help("Runs N instructions per 60Hz frame [default: 10]")
+             .takes_value(true))
+        .arg(Arg::with_name("platform")
+             .long("platform")
+             .value_name("NAME")
+             .help("Imitates the quirks of an interpreter")
+             .possible_values(&platform_names)
+             .takes_value(true))
+        .arg(Arg::with_name("quirks")
+             .long("quirks")
+             .value_name("LIST")
+             .help(&quirks_help)
+             .takes_value(true))
+        .arg(Arg::with_name("palette")
+             .long("palette")
+             .value_name("BG,FG")
+             .help("Sets the background and foreground colors [default: 000000,ffff00]")
+             .takes_value(true))
+        .arg(Arg::with_name("scale")
+             .long("scale")
+             .value_name("N")
+             .help("Scales the display up N times for the window, screenshots and recordings [default: 8]")
+             .takes_value(true))
+        .arg(Arg::with_name("filter")
+             .long("filter")
+             .value_name("NAME")
+             .help("Filters the scaled display (cycle with F10) [default: none]")
+             .possible_values(&filter_names)
+             .takes_value(true))
+        .arg(Arg::with_name("scaling")
+             .long("scaling")
+             .value_name("MODE")
+             .help("Fills the window with whole-number or smooth scaling [default: integer]")
+             .possible_values(&["integer", "smooth"])
+             .takes_value(true))
+        .arg(Arg::with_name("fullscreen")
+             .long("fullscreen")
+             .help("Starts in fullscreen mode (toggle with F11)"))
+        .arg(Arg::with_name("keymap")
+             .long("keymap")
+             .value_name("KEYS")
+             .help("Maps the keyboard to the chip's keys: hex, qwerty, or 16 comma separated key names for 0-F [default: hex]")
+             .takes_value(true))
+        .arg(Arg::with_name("volume")
+             .long("volume")
+             .value_name("0-100")
+             .help("Sets the beep's volume [default: 25]")
+             .takes_value(true))
+        .arg(Arg::with_name("mute")
+             .long("mute")
+             .help("Silences the beep"))
+        .arg(Arg::with_name("beep")
+             .long("beep")
+             .value_name("MS")
+             .help("Makes each beep last at least MS milliseconds [default: 250]")
+             .takes_value(true))
+        .arg(Arg::with_name("seed")
+             .long("seed")
+             .value_name("N")
+             .help("Seeds the random numbers so runs can be repeated")
+             .takes_value(true))
+        .arg(Arg::with_name("paused")
+             .long("paused")
+             .help("Starts paused (resume with F1)"))
+        .arg(Arg::with_name("load-address")
+             .long("load-address")
+             .value_name("ADDR")
+             .help("Loads the ROM at ADDR, such as 0x600 for ETI-660 programs [default: 0x200]")
+             .takes_value(true))
+        .arg(Arg::with_name("entry-point")
+             .long("entry-point")
+             .value_name("ADDR")
+             .help("Starts running the ROM from ADDR [default: the load address]")
+             .takes_value(true))
+        .arg(Arg::with_name("zip-member")
+             .long("zip-member")
+             .value_name("NAME")
+             .help("Loads the file called NAME when the ROM is a zip archive")
+             .takes_value(true))
+        .arg(Arg::with_name("rom-format")
+             .long("rom-format")
+             .value_name("FORMAT")
+             .help("Reads the ROM as machine code, hex text (an Intel HEX file or a hex listing) \
+                    or Octo source [default: hex for .hex and .txt files, octo for .8o files, \
+                    and binary otherwise]")
+             .possible_values(&["binary", "hex", "octo"])
+             .takes_value(true))
+        .arg(Arg::with_name("watch")
+             .long("watch")
+             .help("Reloads the ROM whenever it changes, keeping the current quirks and keymap"))
+        .arg(Arg::with_name("watch-settings")
+             .long("watch-settings")
+             .help("Also reads the config files again whenever the ROM is reloaded")
+             .requires("watch"))
+        .arg(Arg::with_name("record-video")
+             .long("record-video")
+             .value_name("FILE")
+             .help("Records the display to an animated .gif or .png file")
+             .takes_value(true))
+        .arg(Arg::with_name("write-cartridge")
+             .long("write-cartridge")
+             .value_name("FILE")
+             .help("Packs the ROM and its settings into an Octo cartridge GIF on exit, \
+                    labeled with the last frame shown")
+             .takes_value(true))
+        .arg(Arg::with_name("headless")
+             .long("headless")
+             .help("Runs without a window, sound, or keyboard input, until --frames have run \
+                    or a JSON-RPC call quits"))
+        .arg(Arg::with_name("frames")
+             .long("frames")
+             .value_name("N")
+             .help("Quits after running N frames")
+             .takes_value(true))
+        .arg(Arg::with_name("input")
+             .long("input")
+             .value_name("FILE")
+             .help("Presses keys from FILE with --headless, which has \"FRAME KEY down|up\" lines")
+             .takes_value(true)
+             .requires("headless"))
+        .arg(Arg::with_name("host")
+             .long("host")
+             .value_name("PORT")
+             .help("Waits for another player to connect on PORT, and plays the game with them \
+                    over the network.  Both players need the same ROM and settings")
+             .takes_value(true))
+        .arg(Arg::with_name("connect")
+             .long("connect")
+             .value_name("ADDR")
+             .help("Plays the game hosted at ADDR, such as 192.168.1.5:7777")
+             .takes_value(true)
+             .conflicts_with("host"))
+        .arg(Arg::with_name("netplay-keys")
+             .long("netplay-keys")
+             .value_name("KEYS")
+             .help("Sets the keys this player presses in netplay, such as 1,4 [default: 0-7 for \
+                    the host, 8-F for the other player]")
+             .takes_value(true))
+        .arg(Arg::with_name("input-delay")
+             .long("input-delay")
+             .value_name("N")
+             .help("Sends keys N frames ahead of when they are used in netplay, which hides up \
+                    to N frames of lag, up to 60 [default: 2]")
+             .takes_value(true)
+             .requires("host"))
+        .arg(Arg::with_name("rpc")
+             .long("rpc")
+             .value_name("ADDR")
+             .help("Takes JSON-RPC calls to control the emulator on ADDR, which is a port on \
+                    localhost or the path of a Unix socket")
+             .takes_value(true))
+        .arg(Arg::with_name("rpc-dir")
+             .long("rpc-dir")
+             .value_name("DIR")
+             .help("Sets the only directory JSON-RPC calls can read and write files in \
+                    [default: the current directory]")
+             .takes_value(true)
+             .requires("rpc"))
+        .arg(Arg::with_name("batch")
+             .long("batch")
+             .value_name("FILE")
+             .help("Runs every ROM listed in FILE headlessly, in parallel, and prints how each \
+                    run ended.  FILE has a ROM per line followed by settings like \
+                    \"frames=600 platform=chip8 input=keys.txt\", where input names a file of \
+                    \"FRAME KEY down|up\" lines.  Other flags apply to every ROM")
+             .takes_value(true)
+             .conflicts_with("ROM"))
+        .arg(Arg::with_name("workers")
+             .long("workers")
+             .value_name("N")
+             .help("Runs N ROMs at a time with --batch [default: the number of CPUs]")
+             .takes_value(true)
+             .requires("batch"))
+        .arg(Arg::with_name("report")
+             .long("report")
+             .value_name("FILE")
+             .help("Writes how each --batch run ended, with its registers and display, to FILE \
+                    as JSON")
+             .takes_value(true)
+             .requires("batch"));
+    let app = if cfg!(feature = "tty-frontend") {
+        let help = if cfg!(feature = "sdl-frontend") {
+            "Plays in the terminal instead of a window, using the hex or qwerty keymap.  \
+             Escape or Ctrl-C quits"
+        } else {
+            "Plays in the terminal, which is the default in this build, using the hex or \
+             qwerty keymap.  Escape or Ctrl-C quits"
+        };
+        app.arg(Arg::with_name("tty")
+                .long("tty")
+                .help(help)
+                .conflicts_with("headless"))
+    } else {
+        app
+    };
+    let matches = app.get_matches();
+    if matches.is_present("headless") && !matches.is_present("frames") &&
+       !matches.is_present("rpc") {
+        clap::Error::with_description("--headless needs --frames, or --rpc to be told when to \
+                                       quit",
+                                      ErrorKind::MissingRequiredArgument)
+            .exit();
+    }
+
+    if let Some(path) = matches.value_of("batch") {
+        let crashed = run_batch(&matches, path).unwrap_or_else(|why| {
+            println!("{}", why);
+            process::exit(1);
+        });
+        process::exit(if crashed { 1 } else { 0 });
+    }
 
     println!("Chip8 emulator starting...");
 
     // Initialize the emulator and load the game
+    let rom = matches.value_of("ROM").unwrap();
+    let (mut settings, mut game, mut chip) = start(&matches, rom).unwrap_or_else(|why| {
+        println!("{}", why);
+        process::exit(1);
+    });
+
+    // Prepare the recorder if asked to record
+    let recorder = matches.value_of("record-video").map(|path| {
+        let format = Format::from_path(path).unwrap_or_else(|| {
+            println!("Can't record to {}: use a .gif or .png file", path);
+            process::exit(1);
+        });
+        let recorder = Recorder::new(format, settings.palette, settings.scale, settings.filter)
+            .unwrap_or_else(|why| {
+                println!("Can't record to {}: {}", path, why);
+                process::exit(1);
+            });
+        (path, recorder)
+    });
+
+    let frames = if matches.is_present("frames") {
+        Some(value_t!(matches, "frames", u64).unwrap_or_else(|e| e.exit()))
+    } else {
+        None
+    };
+
+    let rpc_dir = matches.value_of("rpc-dir").unwrap_or(".");
+    let rpc = matches.value_of("rpc")
+        .map(|address| rpc::Server::listen(address, rpc_dir))
+        .transpose()
+        .unwrap_or_else(|why| {
+            println!("{}", why);
+            process::exit(1);
+        });
+    let netplay = connect(&matches, &settings, &mut chip).unwrap_or_else(|why| {
+        println!("{}", why);
+        process::exit(1);
+    });
+    let mut extras = Extras {
+        netplay: netplay,
+        rpc: rpc,
+        recorder: recorder,
+    };
+
+    let result = if matches.is_present("headless") {
+        run_headless(&mut chip, &mut settings, &matches, &mut game, frames, &mut extras)
+    } else {
+        play(&mut chip, &mut settings, &matches, &mut game, frames, &mut extras)
+    };
+    if let Err(why) = result {
+        println!("{}", why);
+        process::exit(1);
+    }
+
+    // Make sure the other player's game ended the same way
+    if let Some(ref mut session) = extras.netplay {
+        if let Err(why) = session.finish() {
+            println!("{}", why);
+            process::exit(1);
+        }
+        println!("Netplay ended in sync after {} frames, with checksum {:016x}",
+                 session.frame(),
+                 netplay::checksum(&chip));
+    }
+
+    // Pack the game into a cartridge, if asked to
+    if let Some(path) = matches.value_of("write-cartridge") {
+        let cartridge = Cartridge::new(&game, settings.cartridge_options());
+        match cartridge.save(path, &chip.graphics.bitmap()) {
+            Ok(()) => println!("Saved a cartridge to {}", path),
+            Err(why) => println!("Couldn't save {}: {}", path, why),
+        }
+    }
+
+    // Save the recording, if there is one
+    if let Some((path, recorder)) = extras.recorder {
+        match recorder.save(path) {
+            Ok(()) => println!("Saved {} frames to {}", recorder.len(), path),
+            Err(why) => println!("Couldn't save {}: {}", path, why),
+        }
+    }
+}
+
+/// Builds the settings for the given ROM from the defaults, the global config
+/// file, the ROM database, the ROM's config file and the command line, with
+/// later ones taking precedence.
+fn load_settings(matches: &ArgMatches, rom: &str, loaded: &Rom) -> Result<Settings, String> {
+    let mut settings = Settings::default();
+
+    // A missing config file is only a problem if it was asked for
+    if let Some(path) = matches.value_of("config") {
+        settings.load(path)?;
+    } else if let Some(path) = config::global_path() {
+        if path.exists() {
+            settings.load(path)?;
+        }
+    }
+    if let Some(database) = config::load_database(matches.value_of("database"))? {
+        if let Some(entry) = database.lookup(&loaded.game) {
+            println!("Found {} in the ROM database", entry.description());
+            for quirk in &entry.unsupported_quirks {
+                println!("The game needs the {} quirk, which isn't supported", quirk);
+            }
+            settings.apply_entry(entry);
+        }
+    }
+    if let Some(ref options) = loaded.options {
+        settings.apply_cartridge(options);
+    }
+    if let Some(address) = loaded.address {
+        settings.load_address = address;
+    }
+    if let Some(entry_point) = loaded.entry_point {
+        settings.entry_point = Some(entry_point);
+    }
+    let rom_config = config::rom_path(rom);
+    if rom_config.exists() {
+        settings.load(rom_config)?;
+    }
+
+    for key in config::KEYS.iter() {
+        if matches.occurrences_of(key) == 0 {
+            continue;
+        }
+        let value = matches.value_of(key).unwrap_or("true");
+        settings.set(key, value).map_err(|why| format!("--{}: {}", key, why))?;
+    }
+    Ok(settings)
+}
+
+/// The format given for the ROM with --rom-format, if any.
+fn rom_format(matches: &ArgMatches) -> Option<loader::Format> {
+    matches.value_of("rom-format").map(|format| format.parse().unwrap())
+}
+
+/// Loads the given ROM and its settings, and builds a chip to play it.
+fn start(matches: &ArgMatches, rom: &str) -> Result<(Settings, Vec<u8>, Chip8), String> {
+    let loaded = loader::load(rom, matches.value_of("zip-member"), rom_format(matches))?;
+    let settings = load_settings(matches, rom, &loaded)?;
+    let chip = boot(&settings, &loaded.game)?;
+    Ok((settings, loaded.game, chip))
+}
+
+/// Builds a chip set up with the given settings, with the given game loaded.
+/// Fails if the game is empty or doesn't fit on the chip, or if the entry
+/// point is outside its memory.
+fn boot(settings: &Settings, game: &[u8]) -> Result<Chip8, String> {
+    if game.is_empty() {
+        return Err("The ROM is empty".to_string());
+    }
+    if let Some(platform) = settings.platform {
+        platform.check_rom(game.len(), settings.load_address)?;
+    }
+
     let mut chip = Chip8::default();
-    chip.load_hex(&loader::load_file(matches.value_of("ROM").unwrap()));
+    if let Some(platform) = settings.platform {
+        chip.set_memory_size(platform.memory_size());
+    }
+    chip.quirks = settings.quirks();
+
+    // Without a seed, every run is different
+    chip.set_seed(settings.seed.unwrap_or_else(random_seed));
+    chip.load_rom(game, settings.load_address)?;
+    if let Some(entry_point) = settings.entry_point {
+        if entry_point as usize >= chip.memory().len() {
+            return Err(format!("The entry point {:#X} isn't in {} bytes of memory",
+                               entry_point, chip.memory().len()));
+        }
+        chip.set_entry_point(entry_point);
+    }
+    Ok(chip)
+}
+
+/// Connects to the other player if asked to with `--host` or `--connect`,
+/// seeding the chip with the host's seed.
+fn connect(matches: &ArgMatches, settings: &Settings, chip: &mut Chip8) -> Result<Netplay, String> {
+    let keys = |default: &str| {
+        netplay::parse_keys(matches.value_of("netplay-keys").unwrap_or(default))
+            .map_err(|why| format!("--netplay-keys: {}", why))
+    };
+    let session = if matches.is_present("host") {
+        let port = value_t!(matches, "host", u16).unwrap_or_else(|e| e.exit());
+        let mut options = Options {
+            keys: keys("0,1,2,3,4,5,6,7")?,
+            ipf: settings.ipf,
+            ..Options::default()
+        };
+        if matches.is_present("input-delay") {
+            options.delay = value_t!(matches, "input-delay", u32).unwrap_or_else(|e| e.exit());
+        }
+        let listener = TcpListener::bind(("0.0.0.0", port))
+            .map_err(|why| format!("Couldn't listen on port {}: {}", port, why))?;
+        println!("Waiting for the other player on port {}", listener.local_addr().unwrap().port());
+        let (stream, address) = listener.accept()
+            .map_err(|why| format!("The other player couldn't connect: {}", why))?;
+        println!("{} joined", address);
+        stream.set_nodelay(true).map_err(|why| why.to_string())?;
+        let seed = settings.seed.unwrap_or_else(random_seed);
+        Session::host(stream, chip, options, seed)
+    } else if let Some(address) = matches.value_of("connect") {
+        let options = Options {
+            keys: keys("8,9,A,B,C,D,E,F")?,
+            ipf: settings.ipf,
+            ..Options::default()
+        };
+        let stream = TcpStream::connect(address)
+            .map_err(|why| format!("Couldn't connect to {}: {}", address, why))?;
+        println!("Connected to {}", address);
+        stream.set_nodelay(true).map_err(|why| why.to_string())?;
+        Session::join(stream, chip, options)
+    } else {
+        return Ok(None);
+    };
+    session.map(Some).map_err(|why| why.to_string())
+}
+
+/// Plays the game with whichever frontend was asked for, or the best one
+/// this build has.  The SDL window is preferred over the terminal.
+fn play(chip: &mut Chip8,
+        settings: &mut Settings,
+        matches: &ArgMatches,
+        game: &mut Vec<u8>,
+        frames: Option<u64>,
+        extras: &mut Extras) -> Result<(), String> {
+    #[cfg(feature = "tty-frontend")]
+    {
+        if matches.is_present("tty") || !cfg!(feature = "sdl-frontend") {
+            return run_tty(chip, settings, matches, game, frames, extras);
+        }
+    }
+    run_sdl(chip, settings, matches, game, frames, extras)
+}
+
+/// Stands in for the SDL window in builds without a frontend.
+#[cfg(not(feature = "sdl-frontend"))]
+fn run_sdl(_: &mut Chip8,
+           _: &mut Settings,
+           _: &ArgMatches,
+           _: &mut Vec<u8>,
+           _: Option<u64>,
+           _: &mut Extras) -> Result<(), String> {
+    Err("This build can't play games, only run them with --headless or --batch".to_string())
+}
+
+/// Runs the emulator in the terminal until the user quits or the given
+/// number of frames have passed.
+#[cfg(feature = "tty-frontend")]
+fn run_tty(chip: &mut Chip8,
+           settings: &mut Settings,
+           matches: &ArgMatches,
+           game: &mut Vec<u8>,
+           frames: Option<u64>,
+           extras: &mut Extras) -> Result<(), String> {
+    let mut terminal = tty::Terminal::new(&settings.keymap)?;
+    let frame_duration = Duration::new(0, 1_000_000_000 / chip8::TIMER_RATE);
+    let mut next_frame = Instant::now();
+    let mut paused = settings.paused;
+    let mut beeping = false;
+    let mut frame = 0;
+    while frames != Some(frame) && terminal.check(&mut chip.key) {
+        // Any key resumes a game started paused
+        if paused && chip.key.iter().any(|&key| key != 0) {
+            paused = false;
+        }
+        if !extras.poll(chip, settings, game, matches, &mut paused) {
+            break;
+        }
+        if (!paused || extras.step()) && run_frame(chip, settings, extras)? {
+            frame += 1;
+        }
+
+        // Only the lines that changed are drawn again
+        if let Some(dirty) = chip.graphics.take_dirty() {
+            chip.draw_flag = false;
+            terminal.draw(&chip.graphics, &settings.palette, dirty);
+        }
+        if chip.make_sound && !beeping && !settings.mute {
+            terminal.beep();
+        }
+        beeping = chip.make_sound;
+
+        next_frame += frame_duration;
+        let now = Instant::now();
+        if next_frame > now {
+            thread::sleep(next_frame - now);
+        } else {
+            next_frame = now;
+        }
+    }
+    Ok(())
+}
+
+/// Runs the emulator in an SDL window until the user quits or the given
+/// number of frames have passed.  The display is drawn with the configured
+/// filter until the user picks another one, and the given game is reloaded
+/// on a hard reset.  With `--watch`, the game is reloaded whenever the ROM
+/// changes.  Resets and reloads are turned off in netplay, since they would
+/// only happen on this player's side.
+#[cfg(feature = "sdl-frontend")]
+fn run_sdl(chip: &mut Chip8,
+           settings: &mut Settings,
+           matches: &ArgMatches,
+           game: &mut Vec<u8>,
+           frames: Option<u64>,
+           extras: &mut Extras) -> Result<(), String> {
+    let rom = matches.value_of("ROM").unwrap();
+    let mut watcher = if matches.is_present("watch") && extras.netplay.is_some() {
+        println!("--watch is turned off in netplay");
+        None
+    } else if matches.is_present("watch") {
+        Some(loader::Watcher::new(rom))
+    } else {
+        None
+    };
 
     // Prepare SDL for video, audio, and input
+    let mut title = window_title(settings);
     let sdl_context = sdl2::init().unwrap();
     let mut beeper = audio::Beeper::new(&sdl_context,
-                                        Duration::from_millis(250));
-    let mut keyboard = input::Keyboard::new(&sdl_context);
-
-    // TODO TEST AT 60frames a second!
-    // This isn't set to 60Hz because that was too slow
+                                        Duration::from_millis(settings.beep),
+                                        settings.volume());
+    let mut keyboard = input::Keyboard::new(&sdl_context, settings.sdl_keymap());
     let mut window = graphics::Display::new(&sdl_context,
-                                            "Chip8 Emulator",
-                                            WIDTH,
-                                            HEIGHT,
-                                            Duration::from_millis(2));
+                                            &title,
+                                            chip8::WIDTH * settings.scale,
+                                            chip8::HEIGHT * settings.scale,
+                                            Duration::new(0, 1_000_000_000 / chip8::TIMER_RATE),
+                                            settings.fullscreen,
+                                            settings.scaling);
 
     // Emulation loop
+    let mut filter = settings.filter;
+    let mut paused = settings.paused;
+    let mut fast_forward = false;
+    let mut uncapped = false;
+    let mut slow_motion = false;
+    let mut tick: u64 = 0;
+    let mut title_reset = None;
+    let mut frame = 0;
     'running: loop {
         // Check the input and store it on the chip
+        let mut advance = false;
         match keyboard.check(&mut chip.key) {
             input::Command::Quit => break 'running,
+            input::Command::Pause => {
+                paused = !paused;
+                println!("{}", if paused { "Paused" } else { "Resumed" });
+            }
+            input::Command::FrameAdvance => {
+                paused = true;
+                advance = true;
+            }
+            input::Command::FastForward => {
+                fast_forward = !fast_forward;
+                println!("Fast-forward: {}", on_off(fast_forward));
+            }
+            input::Command::Uncapped => {
+                uncapped = !uncapped;
+                println!("Uncapped fast-forward: {}", on_off(uncapped));
+            }
+            input::Command::SlowMotion => {
+                slow_motion = !slow_motion;
+                println!("Slow motion: {}", on_off(slow_motion));
+            }
+            input::Command::SoftReset | input::Command::HardReset if extras.netplay.is_some() => {
+                println!("Resets are turned off in netplay");
+            }
+            input::Command::SoftReset => {
+                chip.reset();
+                println!("Reset");
+            }
+            input::Command::HardReset => {
+                match boot(settings, game) {
+                    Ok(new_chip) => {
+                        *chip = new_chip;
+                        println!("Reloaded {}", rom);
+                    }
+                    Err(why) => println!("Couldn't reload {}: {}", rom, why),
+                }
+            }
+            input::Command::Screenshot => save_screenshot(chip, settings, rom, filter),
+            input::Command::NextFilter => {
+                filter = filter.next();
+                println!("Filter: {}", filter.name());
+                window.draw_frame(&render(chip, settings, filter));
+            }
+            input::Command::ToggleFullscreen => window.toggle_fullscreen(),
+            input::Command::Redraw => window.redraw(),
             input::Command::Continue => {}
         }
+        if !extras.poll(chip, settings, game, matches, &mut paused) {
+            break 'running;
+        }
 
-        // Run a cycle on the chip
-        chip.emulate_cycle();
+        // Reload the game if it has been rebuilt
+        let changed = match watcher {
+            Some(ref mut watcher) => tick % WATCH_FRAMES == 0 && watcher.changed(),
+            None => false,
+        };
+        if changed {
+            let message = match reload(settings, matches) {
+                Ok((new_settings, new_game, new_chip)) => {
+                    keyboard.set_keymap(new_settings.sdl_keymap());
+                    *settings = new_settings;
+                    *game = new_game;
+                    *chip = new_chip;
+                    title = window_title(settings);
+                    title_reset = Some(tick + MESSAGE_FRAMES);
+                    format!("Reloaded {}", rom)
+                }
+                Err(why) => {
+                    title_reset = None;
+                    why
+                }
+            };
+            println!("{}", message);
+            window.set_title(&format!("{} - {}", title, message));
+        }
+        if title_reset == Some(tick) {
+            window.set_title(&title);
+        }
+
+        // Work out how many frames to run before waiting for the next one
+        let fast = fast_forward || keyboard.fast_forward_held();
+        let batch = if advance || extras.step() {
+            1
+        } else if paused {
+            0
+        } else if fast {
+            FAST_FORWARD_FRAMES
+        } else if slow_motion {
+            if tick % SLOW_MOTION_FRAMES == 0 { 1 } else { 0 }
+        } else {
+            1
+        };
+        tick += 1;
+
+        // Run the frames, stopping at the frame limit
+        for _ in 0..batch {
+            if frames == Some(frame) {
+                break 'running;
+            }
+            if !run_frame(chip, settings, extras)? {
+                break;
+            }
+            frame += 1;
+        }
 
         // Render the frame if needed
-        if chip.draw_flag {
+        if chip.graphics.take_dirty().is_some() {
             debug!("{:?}", chip);
             chip.draw_flag = false;
-            window.draw_frame(&chip.graphics);
+            window.draw_frame(&render(chip, settings, filter));
         }
 
         // Make sound if needed
-        beeper.set_beep(chip.make_sound);
+        beeper.set_beep(chip.make_sound && batch > 0);
+
+        if !(fast && uncapped) {
+            window.sync();
+        }
+    }
+    Ok(())
+}
+
+/// The window's title, naming the game if it is in the ROM database.
+#[cfg(feature = "sdl-frontend")]
+fn window_title(settings: &Settings) -> String {
+    match settings.title {
+        Some(ref game) => format!("{} - {}", TITLE, game),
+        None => TITLE.to_string(),
+    }
+}
+
+/// Reads the ROM again for `--watch`, along with the config files if asked
+/// to with `--watch-settings`.  Otherwise the given settings are kept.
+#[cfg(feature = "sdl-frontend")]
+fn reload(settings: &Settings, matches: &ArgMatches) -> Result<(Settings, Vec<u8>, Chip8), String> {
+    let rom = matches.value_of("ROM").unwrap();
+    let loaded = loader::load(rom, matches.value_of("zip-member"), rom_format(matches))?;
+    let settings = if matches.is_present("watch-settings") {
+        load_settings(matches, rom, &loaded)?
+    } else {
+        settings.clone()
+    };
+    let chip = boot(&settings, &loaded.game)?;
+    Ok((settings, loaded.game, chip))
+}
+
+/// Runs the emulator as fast as possible for the given number of frames,
+/// without any video or audio, pressing keys from `--input` if it's given.
+/// With `--rpc`, frames run at 60Hz until the given number have run or a call
+/// quits, so that calls can keep up.
+fn run_headless(chip: &mut Chip8,
+                settings: &mut Settings,
+                matches: &ArgMatches,
+                game: &mut Vec<u8>,
+                frames: Option<u64>,
+                extras: &mut Extras) -> Result<(), String> {
+    let input = match matches.value_of("input") {
+        Some(path) => load_input(Path::new(path))?,
+        None => InputScript::default(),
+    };
+    let mut events = input.events().iter().peekable();
+    let mut keys = [0; 16];
+    let paced = extras.rpc.is_some();
+    let frame_duration = Duration::new(0, 1_000_000_000 / chip8::TIMER_RATE);
+    let mut next_frame = Instant::now();
+    let mut paused = paced && settings.paused;
+    let mut frame = 0;
+    while frames != Some(frame) {
+        while let Some(event) = events.peek().filter(|event| event.frame <= frame).cloned() {
+            keys[event.key as usize] = event.pressed as u8;
+            events.next();
+        }
+        chip.key = keys;
+        if !extras.poll(chip, settings, game, matches, &mut paused) {
+            break;
+        }
+        if (!paused || extras.step()) && run_frame(chip, settings, extras)? {
+            frame += 1;
+        }
+
+        if paced {
+            next_frame += frame_duration;
+            let now = Instant::now();
+            if next_frame > now {
+                thread::sleep(next_frame - now);
+            } else {
+                next_frame = now;
+            }
+        }
+    }
+    debug!("{:?}", chip);
+    Ok(())
+}
+
+/// Reads a script of keys to press, with `FRAME KEY down|up` lines.
+fn load_input(path: &Path) -> Result<InputScript, String> {
+    let mut text = String::new();
+    File::open(path)
+        .and_then(|mut file| file.read_to_string(&mut text))
+        .map_err(|why| format!("Couldn't read {}: {}", path.display(), why))?;
+    InputScript::parse(&text).map_err(|why| format!("{}: {}", path.display(), why))
+}
+
+/// Runs every ROM in the given batch file on a pool of worker threads,
+/// printing how each run ended and writing a report if asked to.  Runs are
+/// seeded with 0 unless given a seed, so that they repeat.  Returns whether
+/// any of them crashed.
+fn run_batch(matches: &ArgMatches, path: &str) -> Result<bool, String> {
+    let mut text = String::new();
+    File::open(path)
+        .and_then(|mut file| file.read_to_string(&mut text))
+        .map_err(|why| format!("Couldn't read {}: {}", path, why))?;
+    let base = Path::new(path).parent().unwrap_or_else(|| Path::new(""));
+    let default_frames = if matches.is_present("frames") {
+        Some(value_t!(matches, "frames", u64).unwrap_or_else(|e| e.exit()))
+    } else {
+        None
+    };
+
+    let mut runs = Vec::new();
+    for entry in jobs::parse(&text, base).map_err(|why| format!("{}: {}", path, why))? {
+        let rom = entry.rom.to_string_lossy().into_owned();
+        let context = |why: String| format!("{}:{}: {}", path, entry.line, why);
+        let loaded = loader::load(&rom, matches.value_of("zip-member"), rom_format(matches))
+            .map_err(&context)?;
+        let mut settings = load_settings(matches, &rom, &loaded).map_err(&context)?;
+        let mut frames = default_frames;
+        let mut input = InputScript::default();
+        for (name, value) in &entry.settings {
+            match name.as_str() {
+                "frames" => {
+                    frames = Some(value.parse()
+                        .map_err(|_| context(format!("Bad number of frames {}", value)))?)
+                }
+                "input" => input = load_input(&base.join(value)).map_err(&context)?,
+                _ => settings.set(name, value).map_err(&context)?,
+            }
+        }
+        let frames = frames.ok_or_else(|| context("Use frames=N or --frames".to_string()))?;
+        settings.seed = Some(settings.seed.unwrap_or(0));
+
+        runs.push(Job {
+            name: rom,
+            chip: boot(&settings, &loaded.game).map_err(&context)?,
+            ipf: settings.ipf,
+            frames: frames,
+            input: input,
+        });
+    }
+
+    let workers = if matches.is_present("workers") {
+        value_t!(matches, "workers", usize).unwrap_or_else(|e| e.exit())
+    } else {
+        thread::available_parallelism().map(|n| n.get()).unwrap_or(1)
+    };
+    let outcomes = batch::run(runs, workers);
+    for outcome in &outcomes {
+        println!("{}", jobs::summary(outcome));
+    }
+
+    if let Some(report) = matches.value_of("report") {
+        let json: Vec<_> = outcomes.iter().map(jobs::report).collect();
+        File::create(report)
+            .and_then(|mut file| {
+                writeln!(file, "{}", serde_json::to_string_pretty(&json).unwrap())
+            })
+            .map_err(|why| format!("Couldn't write {}: {}", report, why))?;
+        println!("Saved a report to {}", report);
+    }
+    Ok(outcomes.iter().any(|outcome| outcome.crashed()))
+}
+
+/// Runs a single 60Hz frame on the chip, in lockstep with the other player
+/// in netplay, or otherwise stopping at any breakpoints set over JSON-RPC,
+/// and records the display afterwards if there is a recording.  Returns
+/// whether the frame finished, which it hasn't if it is stopped at a
+/// breakpoint.
+fn run_frame(chip: &mut Chip8, settings: &Settings, extras: &mut Extras) -> Result<bool, String> {
+    if let Some(ref mut session) = extras.netplay {
+        session.run_frame(chip).map_err(|why| why.to_string())?;
+        if let Some(ref mut server) = extras.rpc {
+            server.finish_frame(chip);
+        }
+    } else if let Some(ref mut server) = extras.rpc {
+        if !server.run_frame(chip, settings.ipf) {
+            return Ok(false);
+        }
+    } else {
+        chip.emulate_frame(settings.ipf);
+    }
+    if let Some((_, ref mut recorder)) = extras.recorder {
+        recorder.capture(&chip.graphics.bitmap());
+    }
+    Ok(true)
+}
+
+/// Describes whether a mode is switched on.
+#[cfg(feature = "sdl-frontend")]
+fn on_off(on: bool) -> &'static str {
+    if on { "on" } else { "off" }
+}
+
+/// Renders the chip's display for the window.  Unfiltered displays are left
+/// unscaled for SDL to stretch, while filters need to work at the configured
+/// scale.
+#[cfg(feature = "sdl-frontend")]
+fn render(chip: &Chip8, settings: &Settings, filter: Filter) -> Image {
+    let scale = if filter == Filter::None { 1 } else { settings.scale };
+    chip.screenshot(&settings.palette, scale, filter)
+}
+
+/// Saves a screenshot of the chip's display next to the given ROM, scaled up
+/// by the configured factor and filtered with the given filter.
+#[cfg(feature = "sdl-frontend")]
+fn save_screenshot(chip: &Chip8, settings: &Settings, rom: &str, filter: Filter) {
+    let path = screenshot_path(rom);
+    match chip.screenshot(&settings.palette, settings.scale, filter).save_png(&path) {
+        Ok(()) => println!("Saved screenshot to {}", path.display()),
+        Err(why) => println!("Couldn't save {}: {}", path.display(), why),
+    }
+}
+
+/// Builds a timestamped screenshot path in the same directory as the given
+/// ROM, such as `games/PONG-1476000000123.png` for `games/PONG`.  A ROM read
+/// from standard input gets `screenshot-1476000000123.png` in the current
+/// directory.
+#[cfg(feature = "sdl-frontend")]
+fn screenshot_path(rom: &str) -> PathBuf {
+    let since_epoch = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+    let millis = since_epoch.as_secs() * 1000 + since_epoch.subsec_millis() as u64;
+    if rom == "-" {
+        return PathBuf::from(format!("screenshot-{}.png", millis));
     }
+    let rom = Path::new(rom);
+    let stem = rom.file_stem().map_or("screenshot".into(), |s| s.to_string_lossy());
+    rom.with_file_name(format!("{}-{}.png", stem, millis))
 }
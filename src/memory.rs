@@ -0,0 +1,459 @@
+//! The chip's memory bus, which lets embedders hook reads and writes to
+//! regions of memory, such as to watch an address, map a test device into
+//! memory or protect the font from being overwritten.  Hooks need the `std`
+//! feature.
+
+use std::ops::{Index, IndexMut};
+
+/// The most memory a chip can have, which is what XO-CHIP uses.
+pub const MAX_SIZE: usize = 0x10000;
+
+/// The most memory a chip can have in this build.  With the standard library,
+/// memory is kept on the heap and sized to the platform.  Without it, memory
+/// is kept in an array this big, so that it needs no heap: the 4K most
+/// platforms use, or XO-CHIP's 64K with the `xo-chip-memory` feature.
+#[cfg(any(feature = "std", feature = "xo-chip-memory"))]
+pub const CAPACITY: usize = MAX_SIZE;
+#[cfg(not(any(feature = "std", feature = "xo-chip-memory")))]
+pub const CAPACITY: usize = 0x1000;
+
+/// Where memory's bytes are kept.
+#[cfg(feature = "std")]
+type Bytes = Vec<u8>;
+#[cfg(not(feature = "std"))]
+type Bytes = [u8; CAPACITY];
+
+/// A read hook, which is given an address and the byte stored there, and
+/// returns the byte the program should see.  Any closure that can be cloned
+/// is one.
+#[cfg(feature = "std")]
+pub trait ReadHook: Send {
+    /// Runs the hook on a read.
+    fn read(&mut self, address: u16, value: u8) -> u8;
+    /// Copies the hook, for a copy of the memory.
+    fn clone_hook(&self) -> Box<dyn ReadHook>;
+}
+
+#[cfg(feature = "std")]
+impl<F> ReadHook for F
+    where F: FnMut(u16, u8) -> u8 + Clone + Send + 'static
+{
+    fn read(&mut self, address: u16, value: u8) -> u8 {
+        self(address, value)
+    }
+
+    fn clone_hook(&self) -> Box<dyn ReadHook> {
+        Box::new(self.clone())
+    }
+}
+
+#[cfg(feature = "std")]
+impl Clone for Box<dyn ReadHook> {
+    fn clone(&self) -> Self {
+        self.clone_hook()
+    }
+}
+
+/// A write hook, which is given an address and the byte the program is
+/// writing there, and returns the byte to store, or `None` to drop the write.
+/// Any closure that can be cloned is one.
+#[cfg(feature = "std")]
+pub trait WriteHook: Send {
+    /// Runs the hook on a write.
+    fn write(&mut self, address: u16, value: u8) -> Option<u8>;
+    /// Copies the hook, for a copy of the memory.
+    fn clone_hook(&self) -> Box<dyn WriteHook>;
+}
+
+#[cfg(feature = "std")]
+impl<F> WriteHook for F
+    where F: FnMut(u16, u8) -> Option<u8> + Clone + Send + 'static
+{
+    fn write(&mut self, address: u16, value: u8) -> Option<u8> {
+        self(address, value)
+    }
+
+    fn clone_hook(&self) -> Box<dyn WriteHook> {
+        Box::new(self.clone())
+    }
+}
+
+#[cfg(feature = "std")]
+impl Clone for Box<dyn WriteHook> {
+    fn clone(&self) -> Self {
+        self.clone_hook()
+    }
+}
+
+/// Names a hook, so that it can be removed later.
+#[cfg(feature = "std")]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct HookId(usize);
+
+/// A hook on the addresses `first` through `last`, inclusive.
+#[cfg(feature = "std")]
+#[derive(Clone)]
+struct Hook<F> {
+    id: HookId,
+    first: u16,
+    last: u16,
+    hook: F,
+}
+
+#[cfg(feature = "std")]
+impl<F> Hook<F> {
+    fn covers(&self, address: u16) -> bool {
+        self.first <= address && address <= self.last
+    }
+}
+
+/// The chip's memory, with any hooks on it.
+///
+/// Programs read and write memory through `read` and `write`, which run the
+/// hooks on the address in the order they were added.  Indexing reads and
+/// writes the bytes directly, skipping the hooks, as a debugger would.
+/// Addresses past the end of memory wrap around to the start, and hooks see
+/// the address after it wraps.
+///
+/// Cloning memory copies its bytes and clones each of its hooks, so a hook's
+/// state, such as a count it keeps, goes separate ways in the copy.  State a
+/// hook shares through an `Arc` stays shared.
+#[derive(Clone)]
+pub struct Memory {
+    bytes: Bytes,
+    mask: usize,
+    #[cfg(feature = "std")]
+    read_hooks: Vec<Hook<Box<dyn ReadHook>>>,
+    #[cfg(feature = "std")]
+    write_hooks: Vec<Hook<Box<dyn WriteHook>>>,
+    #[cfg(feature = "std")]
+    next_id: usize,
+}
+
+impl Memory {
+    /// Constructs a memory of the given size with no hooks, filled with 0.
+    /// # Panics
+    /// If the size isn't a power of two, or is more than `CAPACITY`.
+    pub fn new(size: usize) -> Self {
+        check_size(size);
+        Memory {
+            #[cfg(feature = "std")]
+            bytes: vec![0; size],
+            #[cfg(not(feature = "std"))]
+            bytes: [0; CAPACITY],
+            mask: size - 1,
+            #[cfg(feature = "std")]
+            read_hooks: Vec::new(),
+            #[cfg(feature = "std")]
+            write_hooks: Vec::new(),
+            #[cfg(feature = "std")]
+            next_id: 0,
+        }
+    }
+
+    /// The number of bytes of memory.
+    pub fn len(&self) -> usize {
+        self.mask + 1
+    }
+
+    /// Whether the memory has no bytes, which is never true.
+    pub fn is_empty(&self) -> bool {
+        false
+    }
+
+    /// Changes the size of memory, keeping the bytes that still fit and
+    /// filling any new bytes with 0.  Hooks are kept.
+    /// # Panics
+    /// If the size isn't a power of two, or is more than `CAPACITY`.
+    pub fn resize(&mut self, size: usize) {
+        check_size(size);
+        #[cfg(feature = "std")]
+        self.bytes.resize(size, 0);
+        #[cfg(not(feature = "std"))]
+        {
+            let kept = size.min(self.len());
+            for byte in self.bytes[kept..].iter_mut() {
+                *byte = 0;
+            }
+        }
+        self.mask = size - 1;
+    }
+
+    /// Sets aside room for memory to grow to `CAPACITY`, so that resizing it
+    /// never moves its bytes, for hosts that hand out pointers to them.
+    #[cfg(feature = "std")]
+    pub fn reserve_capacity(&mut self) {
+        let len = self.bytes.len();
+        self.bytes.reserve_exact(CAPACITY - len);
+    }
+
+    /// The bytes of memory, as they are stored, ignoring any hooks.
+    pub fn bytes(&self) -> &[u8] {
+        &self.bytes[..self.mask + 1]
+    }
+
+    /// The bytes of memory, to change without running any hooks.
+    pub fn bytes_mut(&mut self) -> &mut [u8] {
+        &mut self.bytes[..self.mask + 1]
+    }
+
+    /// Reads the byte at the given address as a program would, through any
+    /// read hooks on the address.
+    #[inline]
+    pub fn read(&mut self, address: u16) -> u8 {
+        let address = (address as usize & self.mask) as u16;
+        let value = self.bytes[address as usize];
+        #[cfg(feature = "std")]
+        {
+            if !self.read_hooks.is_empty() {
+                return self.read_hooked(address, value);
+            }
+        }
+        value
+    }
+
+    /// Writes the byte at the given address as a program would, through any
+    /// write hooks on the address.
+    #[inline]
+    pub fn write(&mut self, address: u16, value: u8) {
+        let address = (address as usize & self.mask) as u16;
+        #[cfg(feature = "std")]
+        let value = if self.write_hooks.is_empty() {
+            value
+        } else {
+            match self.write_hooked(address, value) {
+                Some(value) => value,
+                None => return,
+            }
+        };
+        self.bytes[address as usize] = value;
+    }
+
+    /// Reads the big-endian word at the given address, such as an opcode.
+    #[inline]
+    pub fn read_word(&mut self, address: u16) -> u16 {
+        (self.read(address) as u16) << 8 | self.read(address.wrapping_add(1)) as u16
+    }
+
+    /// Runs the given hook on reads from the addresses `first` through
+    /// `last`, inclusive.
+    #[cfg(feature = "std")]
+    pub fn add_read_hook<F>(&mut self, first: u16, last: u16, hook: F) -> HookId
+        where F: FnMut(u16, u8) -> u8 + Clone + Send + 'static
+    {
+        let id = self.next_id();
+        self.read_hooks.push(Hook {
+            id: id,
+            first: first,
+            last: last,
+            hook: Box::new(hook),
+        });
+        id
+    }
+
+    /// Runs the given hook on writes to the addresses `first` through
+    /// `last`, inclusive.
+    #[cfg(feature = "std")]
+    pub fn add_write_hook<F>(&mut self, first: u16, last: u16, hook: F) -> HookId
+        where F: FnMut(u16, u8) -> Option<u8> + Clone + Send + 'static
+    {
+        let id = self.next_id();
+        self.write_hooks.push(Hook {
+            id: id,
+            first: first,
+            last: last,
+            hook: Box::new(hook),
+        });
+        id
+    }
+
+    /// Drops writes to the addresses `first` through `last`, inclusive, such
+    /// as to keep a program from overwriting the font.
+    #[cfg(feature = "std")]
+    pub fn protect(&mut self, first: u16, last: u16) -> HookId {
+        self.add_write_hook(first, last, |_, _| None)
+    }
+
+    /// Whether any read hooks have been added.
+    pub fn has_read_hooks(&self) -> bool {
+        #[cfg(feature = "std")]
+        {
+            !self.read_hooks.is_empty()
+        }
+        #[cfg(not(feature = "std"))]
+        {
+            false
+        }
+    }
+
+    /// Removes the given hook.  Returns whether it was found.
+    #[cfg(feature = "std")]
+    pub fn remove_hook(&mut self, id: HookId) -> bool {
+        let count = self.read_hooks.len() + self.write_hooks.len();
+        self.read_hooks.retain(|hook| hook.id != id);
+        self.write_hooks.retain(|hook| hook.id != id);
+        count != self.read_hooks.len() + self.write_hooks.len()
+    }
+
+    /// Removes all of the hooks.
+    #[cfg(feature = "std")]
+    pub fn clear_hooks(&mut self) {
+        self.read_hooks.clear();
+        self.write_hooks.clear();
+    }
+
+    #[cfg(feature = "std")]
+    fn next_id(&mut self) -> HookId {
+        self.next_id += 1;
+        HookId(self.next_id)
+    }
+
+    /// Passes a read through the hooks on its address.  This is kept out of
+    /// `read` so that reading memory with no hooks stays small enough to
+    /// inline.
+    #[cfg(feature = "std")]
+    #[inline(never)]
+    fn read_hooked(&mut self, address: u16, mut value: u8) -> u8 {
+        for hook in self.read_hooks.iter_mut().filter(|hook| hook.covers(address)) {
+            value = hook.hook.read(address, value);
+        }
+        value
+    }
+
+    /// Passes a write through the hooks on its address, stopping if one of
+    /// them drops it.
+    #[cfg(feature = "std")]
+    #[inline(never)]
+    fn write_hooked(&mut self, address: u16, mut value: u8) -> Option<u8> {
+        for hook in self.write_hooks.iter_mut().filter(|hook| hook.covers(address)) {
+            value = hook.hook.write(address, value)?;
+        }
+        Some(value)
+    }
+}
+
+/// Checks that a memory size is one that memory can be.
+fn check_size(size: usize) {
+    assert!(size.is_power_of_two() && size <= CAPACITY,
+            "Memory size {} isn't a power of two up to {}", size, CAPACITY);
+}
+
+impl Index<usize> for Memory {
+    type Output = u8;
+
+    fn index(&self, address: usize) -> &u8 {
+        &self.bytes()[address]
+    }
+}
+
+impl IndexMut<usize> for Memory {
+    fn index_mut(&mut self, address: usize) -> &mut u8 {
+        &mut self.bytes_mut()[address]
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Memory;
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn reads_and_writes() {
+        let mut memory = Memory::new(4096);
+        memory.write(0x300, 0xAB);
+        assert_eq!(memory.read(0x300), 0xAB);
+        assert_eq!(memory[0x300], 0xAB);
+
+        // Addresses wrap around
+        memory.write(0x1001, 0xCD);
+        assert_eq!(memory[1], 0xCD);
+        memory[0xFFF] = 0x12;
+        memory[0] = 0x34;
+        assert_eq!(memory.read_word(0xFFF), 0x1234);
+    }
+
+    #[test]
+    fn hooks() {
+        let mut memory = Memory::new(4096);
+        let written = Arc::new(Mutex::new(Vec::new()));
+        let log = written.clone();
+        memory.add_write_hook(0x300, 0x30F, move |address, value| {
+            log.lock().unwrap().push((address, value));
+            Some(value + 1)
+        });
+        let device = memory.add_read_hook(0xF00, 0xF00, |_, _| 0x42);
+        memory.protect(0x000, 0x1FF);
+
+        memory.write(0x305, 1);
+        memory.write(0x310, 1);
+        memory.write(0x050, 1);
+        assert_eq!(*written.lock().unwrap(), vec![(0x305, 1)]);
+        assert_eq!(memory[0x305], 2);
+        assert_eq!(memory[0x310], 1);
+        assert_eq!(memory[0x050], 0);
+
+        assert_eq!(memory.read(0xF00), 0x42);
+        assert_eq!(memory[0xF00], 0);
+
+        // Hooks see addresses after they wrap around
+        memory.write(0x1050, 1);
+        memory.write(0x1305, 1);
+        assert_eq!(memory[0x050], 0);
+        assert_eq!(written.lock().unwrap()[1], (0x305, 1));
+        assert_eq!(memory.read(0x1F00), 0x42);
+
+        // Clones get copies of the hooks, which share only what they share
+        // through an Arc
+        let mut clone = memory.clone();
+        clone.write(0x306, 1);
+        assert_eq!(clone[0x306], 2);
+        assert_eq!(memory[0x306], 0);
+        assert_eq!(written.lock().unwrap().len(), 3);
+        assert!(memory.remove_hook(device));
+        assert!(!memory.remove_hook(device));
+        assert_eq!(memory.read(0xF00), 0);
+    }
+
+    #[test]
+    fn clones_hook_state() {
+        let mut memory = Memory::new(4096);
+        let mut reads = 0;
+        memory.add_read_hook(0x300, 0x300, move |_, _| {
+            reads += 1;
+            reads
+        });
+        assert_eq!(memory.read(0x300), 1);
+
+        let mut clone = memory.clone();
+        assert_eq!(clone.read(0x300), 2);
+        assert_eq!(clone.read(0x300), 3);
+        assert_eq!(memory.read(0x300), 2);
+    }
+
+    #[test]
+    fn resizes() {
+        let mut memory = Memory::new(4096);
+        memory[0xFFF] = 1;
+        memory.resize(0x10000);
+        assert_eq!(memory.len(), 0x10000);
+        assert_eq!(memory.bytes().len(), 0x10000);
+        assert_eq!(memory[0xFFF], 1);
+        memory.write(0x1001, 2);
+        assert_eq!(memory[0x1001], 2);
+
+        // Shrinking drops the bytes past the end, so growing again brings
+        // back 0s
+        memory.resize(0x1000);
+        assert_eq!(memory.bytes().len(), 0x1000);
+        memory.resize(0x2000);
+        assert_eq!(memory[0x1001], 0);
+
+        // With room set aside, resizing leaves the bytes where they are
+        let mut memory = Memory::new(4096);
+        memory.reserve_capacity();
+        let bytes = memory.bytes().as_ptr();
+        memory.resize(0x10000);
+        memory.resize(0x1000);
+        assert_eq!(memory.bytes().as_ptr(), bytes);
+    }
+}
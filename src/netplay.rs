@@ -0,0 +1,649 @@
+//! Two players on one keypad, each running the game on their own machine.
+//! The players' chips run in lockstep: each frame, both send the keys they
+//! are holding down to the other, and neither runs a frame until it has the
+//! other's keys for it.  Keys are sent a few frames ahead of when they are
+//! used, so that the network has that long to deliver them before the game
+//! has to wait.
+//!
+//! Each player is given some of the keys, such as 1 and 4 for the left
+//! paddle in `PONG` and C and D for the right one.  Both chips start from the
+//! same state, with the host's seed, and every so often they compare
+//! checksums of their states, so that a desync is found rather than the
+//! games quietly drifting apart.
+//!
+//! Messages are a tag byte followed by big-endian numbers.  The handshake is
+//! a hello from the host, then one from the guest, which each check that the
+//! other runs the same number of instructions per frame with the same
+//! quirks and the same starting state, after which each side
+//! sends its keys for every frame and a checksum every interval, and a
+//! message when it finds a desync or ends the game.
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::io::{self, Read, Write};
+use platform::{Quirks, QUIRK_NAMES};
+use Chip8;
+
+/// The version of the protocol, which both players need to share.
+const VERSION: u8 = 2;
+
+/// The start of a hello.
+const MAGIC: [u8; 4] = *b"C8NP";
+
+/// The tags of the messages sent after the handshake.
+const INPUT: u8 = 1;
+const CHECKSUM: u8 = 2;
+const DONE: u8 = 3;
+const DESYNC: u8 = 4;
+
+/// The most frames of input delay a session can have, which keeps a hello
+/// from asking for huge queues of keys.
+pub const MAX_DELAY: u32 = 60;
+
+/// The size of a hello: the magic, version, delay, interval, seed, keys,
+/// instructions per frame, quirks and checksum.
+const HELLO_SIZE: usize = 4 + 1 + 4 + 4 + 8 + 2 + 4 + 1 + 8;
+
+/// Why netplay stopped.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Error {
+    /// The chips' states were different after the given frame.
+    Desync { frame: u64 },
+    /// The other player left.
+    Disconnected,
+    /// The other player is playing a different game or with different
+    /// settings, or wants some of the same keys.
+    Mismatch(String),
+    /// The connection failed, or sent something that isn't netplay.
+    Connection(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Desync { frame } => {
+                write!(f, "The games desynced: they were different after frame {}", frame)
+            }
+            Error::Disconnected => write!(f, "The other player left"),
+            Error::Mismatch(ref why) => write!(f, "{}", why),
+            Error::Connection(ref why) => write!(f, "Netplay failed: {}", why),
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(why: io::Error) -> Self {
+        match why.kind() {
+            io::ErrorKind::UnexpectedEof |
+            io::ErrorKind::ConnectionReset |
+            io::ErrorKind::ConnectionAborted |
+            io::ErrorKind::BrokenPipe => Error::Disconnected,
+            _ => Error::Connection(why.to_string()),
+        }
+    }
+}
+
+/// How a player plays.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Options {
+    /// The keys this player presses, with bit N set for key N.
+    pub keys: u16,
+    /// How many frames keys are sent ahead of when they are used, up to
+    /// `MAX_DELAY`.  The host's delay is used by both players.
+    pub delay: u32,
+    /// How many frames go by between checksums.  The host's interval is used
+    /// by both players.
+    pub interval: u32,
+    /// How many instructions to run each frame, which both players need to
+    /// agree on.
+    pub ipf: u32,
+}
+
+impl Default for Options {
+    /// Keys 0 to 7, with 2 frames of delay, a checksum every second and 10
+    /// instructions per frame.
+    fn default() -> Self {
+        Options {
+            keys: 0x00FF,
+            delay: 2,
+            interval: 60,
+            ipf: 10,
+        }
+    }
+}
+
+/// A player's side of a game of netplay.
+pub struct Session<S> {
+    stream: S,
+    /// The keys this player presses.
+    keys: u16,
+    delay: u32,
+    interval: u32,
+    ipf: u32,
+    /// The next frame to run.
+    frame: u64,
+    /// This player's keys for the next frames, starting with `frame`.
+    local: VecDeque<u16>,
+    /// The other player's keys for the next frames, starting with `frame`.
+    remote: VecDeque<u16>,
+    /// Checksums after frames, from this player and from the other one,
+    /// which haven't been compared yet.
+    ours: VecDeque<(u64, u64)>,
+    theirs: VecDeque<(u64, u64)>,
+    /// Whether the other player has ended the game.
+    done: bool,
+}
+
+impl<S: Read + Write> Session<S> {
+    /// Starts a game as the host, over a stream to a guest.  The chip should
+    /// have its ROM loaded and its quirks set, and is seeded with the given
+    /// seed, which the guest's chip is also seeded with.
+    pub fn host(mut stream: S, chip: &mut Chip8, options: Options, seed: u64) -> Result<Self, Error> {
+        chip.set_seed(seed);
+        let ours = Hello {
+            delay: options.delay,
+            interval: options.interval.max(1),
+            seed: seed,
+            keys: options.keys,
+            ipf: options.ipf,
+            quirks: quirk_bits(&chip.quirks),
+            checksum: checksum(chip),
+        };
+        ours.write(&mut stream)?;
+        let theirs = Hello::read(&mut stream)?;
+        Session::new(stream, options.keys, &ours, &theirs)
+    }
+
+    /// Joins a game as the guest, over a stream to the host.  The chip should
+    /// have its ROM loaded and its quirks set, and is seeded with the host's
+    /// seed.
+    pub fn join(mut stream: S, chip: &mut Chip8, options: Options) -> Result<Self, Error> {
+        let theirs = Hello::read(&mut stream)?;
+        chip.set_seed(theirs.seed);
+        let ours = Hello {
+            delay: theirs.delay,
+            interval: theirs.interval.max(1),
+            seed: theirs.seed,
+            keys: options.keys,
+            ipf: options.ipf,
+            quirks: quirk_bits(&chip.quirks),
+            checksum: checksum(chip),
+        };
+        ours.write(&mut stream)?;
+        Session::new(stream, options.keys, &ours, &theirs)
+    }
+
+    /// Checks that the players can play together.
+    fn new(stream: S, keys: u16, ours: &Hello, theirs: &Hello) -> Result<Self, Error> {
+        if ours.delay > MAX_DELAY {
+            return Err(Error::Mismatch(format!("An input delay of {} frames is more than the {} \
+                                                allowed",
+                                               ours.delay, MAX_DELAY)));
+        }
+        if ours.ipf != theirs.ipf {
+            return Err(Error::Mismatch(format!("The other player runs {} instructions per \
+                                                frame, not {}",
+                                               theirs.ipf, ours.ipf)));
+        }
+        if ours.quirks != theirs.quirks {
+            return Err(Error::Mismatch(format!("The other player has quirks {}, not {}",
+                                               quirk_names(theirs.quirks),
+                                               quirk_names(ours.quirks))));
+        }
+        if ours.checksum != theirs.checksum {
+            return Err(Error::Mismatch("The other player has a different game or settings"
+                .to_string()));
+        }
+        if ours.keys & theirs.keys != 0 {
+            return Err(Error::Mismatch(format!("Both players want keys {}",
+                                               key_names(ours.keys & theirs.keys))));
+        }
+        // The guest has taken the host's delay and interval by now
+        let delay = ours.delay as usize;
+        Ok(Session {
+            stream: stream,
+            keys: keys,
+            delay: ours.delay,
+            interval: ours.interval,
+            ipf: ours.ipf,
+            frame: 0,
+            local: vec![0; delay].into(),
+            remote: vec![0; delay].into(),
+            ours: VecDeque::new(),
+            theirs: VecDeque::new(),
+            done: false,
+        })
+    }
+
+    /// Runs a frame of the agreed number of instructions, with the chip's
+    /// keys as this player's keys.  The keys are sent to the other player,
+    /// and the frame is run once their keys for it arrive, with the chip's
+    /// keys set to both players' keys for it.  Only the keys this player was
+    /// given are sent.
+    pub fn run_frame(&mut self, chip: &mut Chip8) -> Result<(), Error> {
+        let mut pressed = 0;
+        for (i, &key) in chip.key.iter().enumerate() {
+            pressed |= ((key != 0) as u16) << i;
+        }
+        let keys = pressed & self.keys;
+        self.send(INPUT, self.frame + self.delay as u64, &keys.to_be_bytes())?;
+        self.local.push_back(keys);
+
+        while self.remote.is_empty() {
+            if self.done {
+                return Err(Error::Disconnected);
+            }
+            self.receive()?;
+        }
+        let pressed = self.local.pop_front().unwrap() | self.remote.pop_front().unwrap();
+        for (i, key) in chip.key.iter_mut().enumerate() {
+            *key = (pressed >> i & 1) as u8;
+        }
+        chip.emulate_frame(self.ipf);
+        self.frame += 1;
+
+        if self.frame % self.interval as u64 == 0 {
+            let sum = checksum(chip);
+            self.send(CHECKSUM, self.frame, &sum.to_be_bytes())?;
+            self.ours.push_back((self.frame, sum));
+            self.compare()?;
+        }
+        Ok(())
+    }
+
+    /// Ends the game, waiting for the other player to end it too, so that
+    /// their last checksums are compared and nothing they sent is lost.  It
+    /// is fine for the other player to have left already.
+    pub fn finish(&mut self) -> Result<(), Error> {
+        self.send(DONE, self.frame, &[])?;
+        while !self.done {
+            match self.receive() {
+                Err(Error::Disconnected) => break,
+                result => result?,
+            }
+        }
+        Ok(())
+    }
+
+    /// The number of frames run.
+    pub fn frame(&self) -> u64 {
+        self.frame
+    }
+
+    /// The frames keys are sent ahead of when they are used.
+    pub fn delay(&self) -> u32 {
+        self.delay
+    }
+
+    /// Sends a message.
+    fn send(&mut self, tag: u8, frame: u64, payload: &[u8]) -> Result<(), Error> {
+        let mut message = Vec::with_capacity(9 + payload.len());
+        message.push(tag);
+        message.extend_from_slice(&frame.to_be_bytes());
+        message.extend_from_slice(payload);
+        self.stream.write_all(&message)?;
+        self.stream.flush()?;
+        Ok(())
+    }
+
+    /// Reads a message from the other player, returning its tag, frame and
+    /// payload.
+    fn read_message(&mut self) -> Result<(u8, u64, u64), Error> {
+        let mut header = [0; 9];
+        self.stream.read_exact(&mut header)?;
+        let mut frame = [0; 8];
+        frame.copy_from_slice(&header[1..]);
+        let size = match header[0] {
+            INPUT => 2,
+            CHECKSUM => 8,
+            DONE | DESYNC => 0,
+            tag => return Err(Error::Connection(format!("Unknown message {}", tag))),
+        };
+        let mut payload = [0; 8];
+        self.stream.read_exact(&mut payload[8 - size..])?;
+        Ok((header[0], u64::from_be_bytes(frame), u64::from_be_bytes(payload)))
+    }
+
+    /// Reads a message from the other player and acts on it.
+    fn receive(&mut self) -> Result<(), Error> {
+        let (tag, frame, value) = self.read_message()?;
+        match tag {
+            INPUT => {
+                let expected = self.frame + self.remote.len() as u64;
+                if frame != expected {
+                    return Err(Error::Connection(format!("Expected keys for frame {}, not {}",
+                                                         expected, frame)));
+                }
+                self.remote.push_back(value as u16 & !self.keys);
+            }
+            CHECKSUM => {
+                self.theirs.push_back((frame, value));
+                self.compare()?;
+            }
+            DONE => self.done = true,
+            _ => return Err(Error::Desync { frame: frame }),
+        }
+        Ok(())
+    }
+
+    /// Compares the checksums both players have sent for the same frames.
+    /// On a desync the other player is told, and anything more they send is
+    /// read until they stop, so that closing the connection doesn't lose
+    /// the message.
+    fn compare(&mut self) -> Result<(), Error> {
+        while let (Some(&(ours, our_sum)), Some(&(theirs, their_sum))) =
+                  (self.ours.front(), self.theirs.front()) {
+            if ours == theirs && our_sum != their_sum {
+                self.send(DESYNC, ours, &[])?;
+                while let Ok((tag, _, _)) = self.read_message() {
+                    if tag == DONE || tag == DESYNC {
+                        break;
+                    }
+                }
+                return Err(Error::Desync { frame: ours });
+            }
+            if ours <= theirs {
+                self.ours.pop_front();
+            }
+            if theirs <= ours {
+                self.theirs.pop_front();
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The checksum of the chip's state, which is the same for chips in the same
+/// state.
+pub fn checksum(chip: &Chip8) -> u64 {
+    let mut state = vec![0; chip.state_size()];
+    let size = chip.save_state(&mut state).unwrap();
+    fnv1a(&state[..size])
+}
+
+/// Parses a list of keys in hex, such as `1,4` or `C,D`, into a set of keys
+/// with bit N set for key N.
+pub fn parse_keys(list: &str) -> Result<u16, String> {
+    let mut keys = 0;
+    for key in list.split(',') {
+        let key = key.trim();
+        let bit = u8::from_str_radix(key, 16)
+            .ok()
+            .filter(|&key| key < 16)
+            .ok_or_else(|| format!("Bad key {}", key))?;
+        keys |= 1 << bit;
+    }
+    Ok(keys)
+}
+
+/// Names a set of keys, such as `1, 4`.
+fn key_names(keys: u16) -> String {
+    let names: Vec<String> = (0..16)
+        .filter(|&key| keys >> key & 1 != 0)
+        .map(|key| format!("{:X}", key))
+        .collect();
+    names.join(", ")
+}
+
+/// The 64-bit FNV-1a hash of some bytes.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xCBF29CE484222325;
+    for &byte in bytes {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x100000001B3);
+    }
+    hash
+}
+
+/// The quirks that are on, with a bit for each in the order of
+/// `QUIRK_NAMES`.
+fn quirk_bits(quirks: &Quirks) -> u8 {
+    let quirks = [
+        quirks.shift_uses_vy,
+        quirks.load_store_increments_index,
+        quirks.jump_uses_vx,
+        quirks.logic_resets_vf,
+        quirks.wrap_sprites,
+        quirks.display_wait,
+    ];
+    quirks.iter().enumerate().fold(0, |bits, (i, &on)| bits | (on as u8) << i)
+}
+
+/// The names of the quirks that are on in the given bits, for messages.
+fn quirk_names(bits: u8) -> String {
+    let names: Vec<&str> = QUIRK_NAMES.iter()
+        .enumerate()
+        .filter(|&(i, _)| bits >> i & 1 != 0)
+        .map(|(_, &name)| name)
+        .collect();
+    if names.is_empty() {
+        "none".to_string()
+    } else {
+        names.join(", ")
+    }
+}
+
+/// What each player says when the game starts.
+struct Hello {
+    delay: u32,
+    interval: u32,
+    seed: u64,
+    keys: u16,
+    ipf: u32,
+    quirks: u8,
+    /// The checksum of the chip's state before the first frame.
+    checksum: u64,
+}
+
+impl Hello {
+    fn write<W: Write>(&self, stream: &mut W) -> Result<(), Error> {
+        let mut hello = Vec::with_capacity(HELLO_SIZE);
+        hello.extend_from_slice(&MAGIC);
+        hello.push(VERSION);
+        hello.extend_from_slice(&self.delay.to_be_bytes());
+        hello.extend_from_slice(&self.interval.to_be_bytes());
+        hello.extend_from_slice(&self.seed.to_be_bytes());
+        hello.extend_from_slice(&self.keys.to_be_bytes());
+        hello.extend_from_slice(&self.ipf.to_be_bytes());
+        hello.push(self.quirks);
+        hello.extend_from_slice(&self.checksum.to_be_bytes());
+        stream.write_all(&hello)?;
+        stream.flush()?;
+        Ok(())
+    }
+
+    fn read<R: Read>(stream: &mut R) -> Result<Self, Error> {
+        let mut hello = [0; HELLO_SIZE];
+        stream.read_exact(&mut hello)?;
+        if hello[..4] != MAGIC {
+            return Err(Error::Connection("The other side isn't playing netplay".to_string()));
+        }
+        if hello[4] != VERSION {
+            return Err(Error::Mismatch(format!("The other player has netplay version {}, not {}",
+                                               hello[4], VERSION)));
+        }
+        let number = |start: usize, size: usize| {
+            hello[start..start + size].iter().fold(0u64, |n, &byte| n << 8 | byte as u64)
+        };
+        Ok(Hello {
+            delay: number(5, 4) as u32,
+            interval: number(9, 4) as u32,
+            seed: number(13, 8),
+            keys: number(21, 2) as u16,
+            ipf: number(23, 4) as u32,
+            quirks: hello[27],
+            checksum: number(28, 8),
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{checksum, parse_keys, Error, Options, Session, MAX_DELAY};
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+    use {Chip8, PROGRAM_START};
+
+    const PONG: &[u8] = include_bytes!("../PONG");
+
+    fn pong() -> Chip8 {
+        let mut chip = Chip8::default();
+        chip.load_rom(PONG, PROGRAM_START).unwrap();
+        chip
+    }
+
+    /// The keys a player holds on a frame: their first key for a while, then
+    /// their second, then neither.
+    fn held(first: usize, second: usize, frame: u64) -> [u8; 16] {
+        let mut keys = [0; 16];
+        match frame % 90 {
+            0..=29 => keys[first] = 1,
+            30..=59 => keys[second] = 1,
+            _ => {}
+        }
+        keys
+    }
+
+    /// Plays `PONG` for some frames over a local connection, with the host
+    /// on the left paddle and the guest on the right, and with the guest's
+    /// chip changed by `tamper` before each frame.  Returns each side's
+    /// result, and its chip's checksum at the end.
+    fn play<F>(frames: u64, options: Options, tamper: F) -> [(Result<(), Error>, u64); 2]
+        where F: Fn(u64, &mut Chip8) + Send + 'static
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let guest = thread::spawn(move || {
+            let mut chip = pong();
+            let stream = TcpStream::connect(address).unwrap();
+            let options = Options { keys: 0xF000, ..options };
+            let result = Session::join(stream, &mut chip, options).and_then(|mut session| {
+                for frame in 0..frames {
+                    tamper(frame, &mut chip);
+                    chip.key = held(0xC, 0xD, frame);
+                    session.run_frame(&mut chip)?;
+                }
+                session.finish()
+            });
+            (result, checksum(&chip))
+        });
+
+        let mut chip = pong();
+        let stream = listener.accept().unwrap().0;
+        let result = Session::host(stream, &mut chip, options, 7).and_then(|mut session| {
+            for frame in 0..frames {
+                chip.key = held(1, 4, frame);
+                session.run_frame(&mut chip)?;
+            }
+            session.finish()
+        });
+        [(result, checksum(&chip)), guest.join().unwrap()]
+    }
+
+    #[test]
+    fn plays_in_lockstep() {
+        let options = Options { delay: 3, ..Options::default() };
+        let [host, guest] = play(300, options, |_, _| {});
+        assert_eq!(host.0, Ok(()));
+        assert_eq!(guest.0, Ok(()));
+
+        // Both players' keys take effect 3 frames after they are pressed
+        let mut chip = pong();
+        chip.set_seed(7);
+        for frame in 0..300 {
+            if frame >= 3 {
+                let left = held(1, 4, frame - 3);
+                let right = held(0xC, 0xD, frame - 3);
+                for key in 0..16 {
+                    chip.key[key] = left[key] | right[key];
+                }
+            }
+            chip.emulate_frame(10);
+        }
+        assert_eq!(host.1, checksum(&chip));
+        assert_eq!(guest.1, checksum(&chip));
+    }
+
+    #[test]
+    fn reports_desyncs() {
+        let [host, guest] = play(300, Options::default(), |frame, chip| {
+            if frame == 100 {
+                chip.write_memory(0xE00, &[0xFF]).unwrap();
+            }
+        });
+        assert_eq!(host.0, Err(Error::Desync { frame: 120 }));
+        assert_eq!(guest.0, Err(Error::Desync { frame: 120 }));
+    }
+
+    #[test]
+    fn rejects_long_delays() {
+        let options = Options { delay: MAX_DELAY + 1, ..Options::default() };
+        let [host, guest] = play(10, options, |_, _| {});
+        let why = "An input delay of 61 frames is more than the 60 allowed".to_string();
+        assert_eq!(host.0, Err(Error::Mismatch(why.clone())));
+        assert_eq!(guest.0, Err(Error::Mismatch(why)));
+    }
+
+    #[test]
+    fn rejects_mismatches() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let guest = thread::spawn(move || {
+            let mut chip = Chip8::default();
+            chip.load_rom(&PONG[..100], PROGRAM_START).unwrap();
+            Session::join(TcpStream::connect(address).unwrap(), &mut chip, Options::default())
+                .err()
+        });
+        let stream = listener.accept().unwrap().0;
+        let host = Session::host(stream, &mut pong(), Options::default(), 0).err();
+        let mismatch = "The other player has a different game or settings".to_string();
+        assert_eq!(host, Some(Error::Mismatch(mismatch.clone())));
+        assert_eq!(guest.join().unwrap(), Some(Error::Mismatch(mismatch)));
+
+        let guest = thread::spawn(move || {
+            Session::join(TcpStream::connect(address).unwrap(), &mut pong(), Options::default())
+                .err()
+        });
+        let stream = listener.accept().unwrap().0;
+        let host = Session::host(stream, &mut pong(), Options::default(), 0).err();
+        let both = "Both players want keys 0, 1, 2, 3, 4, 5, 6, 7".to_string();
+        assert_eq!(host, Some(Error::Mismatch(both.clone())));
+        assert_eq!(guest.join().unwrap(), Some(Error::Mismatch(both)));
+    }
+
+    #[test]
+    fn rejects_different_speeds_and_quirks() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let guest = thread::spawn(move || {
+            let options = Options { keys: 0xF000, ipf: 15, ..Options::default() };
+            Session::join(TcpStream::connect(address).unwrap(), &mut pong(), options).err()
+        });
+        let stream = listener.accept().unwrap().0;
+        let host = Session::host(stream, &mut pong(), Options::default(), 0).err();
+        assert_eq!(host, Some(Error::Mismatch(
+            "The other player runs 15 instructions per frame, not 10".to_string())));
+        assert_eq!(guest.join().unwrap(), Some(Error::Mismatch(
+            "The other player runs 10 instructions per frame, not 15".to_string())));
+
+        let guest = thread::spawn(move || {
+            let mut chip = pong();
+            chip.quirks.apply("shift,wrap").unwrap();
+            let options = Options { keys: 0xF000, ..Options::default() };
+            Session::join(TcpStream::connect(address).unwrap(), &mut chip, options).err()
+        });
+        let stream = listener.accept().unwrap().0;
+        let host = Session::host(stream, &mut pong(), Options::default(), 0).err();
+        assert_eq!(host, Some(Error::Mismatch(
+            "The other player has quirks shift, wrap, not none".to_string())));
+        assert_eq!(guest.join().unwrap(), Some(Error::Mismatch(
+            "The other player has quirks none, not shift, wrap".to_string())));
+    }
+
+    #[test]
+    fn parses_keys() {
+        assert_eq!(parse_keys("1,4"), Ok(0x0012));
+        assert_eq!(parse_keys("c, D"), Ok(0x3000));
+        assert_eq!(parse_keys("1,G"), Err("Bad key G".to_string()));
+    }
+}
@@ -0,0 +1,1007 @@
+//! An assembler for Octo, the language Octo cartridges carry their programs
+//! in.
+//!
+//! It covers the core of the language: every CHIP-8, SUPER-CHIP and XO-CHIP
+//! instruction, `if`, `loop` and `while` with all of Octo's comparisons,
+//! labels, and the `:const`, `:alias`, `:macro`, `:calc`, `:byte`, `:org`,
+//! `:next`, `:unpack` and `:call` directives.  Other directives, such as
+//! `:stringmode` and `:assert`, are reported as errors.
+
+use std::collections::HashMap;
+
+/// The address programs are assembled for.
+const START: usize = 0x200;
+
+/// The most macros a program may expand, which stops macros that call
+/// themselves from expanding forever.
+const MAX_EXPANSIONS: usize = 100_000;
+
+/// The operators that can follow a register at the start of a statement.
+const OPERATORS: [&str; 9] = [":=", "+=", "-=", "|=", "&=", "^=", ">>=", "=-", "<<="];
+
+/// Assembles Octo source into a ROM to be loaded at 0x200.  Errors give the
+/// line of the mistake.
+pub fn assemble(source: &str) -> Result<Vec<u8>, String> {
+    let mut tokens = Vec::new();
+    for (i, line) in source.lines().enumerate() {
+        let code = line.split('#').next().unwrap();
+        tokens.extend(code.split_whitespace().map(|text| Token { text: text, line: i + 1 }));
+    }
+    // The tokens are a stack, so that macros can push their bodies
+    tokens.reverse();
+
+    let mut assembler = Assembler {
+        tokens: tokens,
+        line: 1,
+        rom: Vec::new(),
+        here: START,
+        labels: HashMap::new(),
+        constants: HashMap::new(),
+        aliases: HashMap::new(),
+        macros: HashMap::new(),
+        fixups: Vec::new(),
+        blocks: Vec::new(),
+        jump_to_main: true,
+        expansions: 0,
+    };
+    assembler.run()
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Token<'a> {
+    text: &'a str,
+    line: usize,
+}
+
+#[derive(Clone, Debug)]
+struct Macro<'a> {
+    params: Vec<&'a str>,
+    body: Vec<Token<'a>>,
+}
+
+/// A block that is still open, with the address of the jump to patch when
+/// it closes.
+#[derive(Debug)]
+enum Block {
+    If(usize),
+    Else(usize),
+    /// A loop's start, and the jumps out of it made by `while`.
+    Loop(usize, Vec<usize>),
+}
+
+/// How a label's address goes into an instruction that uses it.
+#[derive(Clone, Copy, Debug)]
+enum Use {
+    /// The low 12 bits of the instruction, as in `jump` and `i :=`.
+    Address,
+    /// The 16 bit word after `i := long`.
+    Long,
+    /// The two `v0 :=` and `v1 :=` instructions of `:unpack`.
+    Unpack,
+}
+
+/// A use of a label that isn't defined yet.
+#[derive(Debug)]
+struct Fixup<'a> {
+    at: usize,
+    name: &'a str,
+    kind: Use,
+    line: usize,
+}
+
+/// A condition, as in `if v0 == 5 then`.
+#[derive(Clone, Copy, Debug)]
+struct Condition<'a> {
+    register: u8,
+    op: &'a str,
+    operand: Operand,
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Operand {
+    None,
+    Register(u8),
+    Byte(u8),
+}
+
+struct Assembler<'a> {
+    tokens: Vec<Token<'a>>,
+    /// The line of the last token read.
+    line: usize,
+    rom: Vec<u8>,
+    /// The address of the next byte.
+    here: usize,
+    labels: HashMap<&'a str, usize>,
+    constants: HashMap<&'a str, i64>,
+    aliases: HashMap<&'a str, u8>,
+    macros: HashMap<&'a str, Macro<'a>>,
+    fixups: Vec<Fixup<'a>>,
+    blocks: Vec<(Block, usize)>,
+    /// Whether the program starts with a jump to `main`, which it doesn't
+    /// need when it starts with `main`.
+    jump_to_main: bool,
+    expansions: usize,
+}
+
+impl<'a> Assembler<'a> {
+    fn run(&mut self) -> Result<Vec<u8>, String> {
+        // Leave room for the jump to main
+        self.instruction(0x00, 0x00)?;
+        while let Some(token) = self.tokens.pop() {
+            self.line = token.line;
+            self.statement(token.text)?;
+        }
+
+        if let Some(&(ref block, line)) = self.blocks.last() {
+            let name = match *block {
+                Block::If(_) | Block::Else(_) => "begin",
+                Block::Loop(..) => "loop",
+            };
+            return Err(format!("Line {}: This {} is never closed", line, name));
+        }
+        let main = *self.labels.get("main").ok_or("The program has no main label")?;
+        if self.jump_to_main {
+            self.patch(START, main)?;
+            self.rom[0] |= 0x10;
+        }
+
+        let fixups: Vec<Fixup> = self.fixups.drain(..).collect();
+        for fixup in fixups {
+            self.line = fixup.line;
+            let address = match self.labels.get(fixup.name) {
+                Some(&address) => address,
+                None => return Err(self.fail(&format!("Unknown name {}", fixup.name))),
+            };
+            let at = fixup.at - START;
+            match fixup.kind {
+                Use::Address => self.patch(fixup.at, address)?,
+                Use::Long => {
+                    self.rom[at + 2] = (address >> 8) as u8;
+                    self.rom[at + 3] = address as u8;
+                }
+                Use::Unpack => {
+                    self.check_reach(address)?;
+                    self.rom[at + 1] |= (address >> 8) as u8;
+                    self.rom[at + 3] = address as u8;
+                }
+            }
+        }
+        Ok(self.rom.clone())
+    }
+
+    fn statement(&mut self, text: &'a str) -> Result<(), String> {
+        match text {
+            ":" => {
+                let name = self.name()?;
+                if name == "main" && self.jump_to_main && self.rom.len() == 2 &&
+                   self.here == START + 2 && self.labels.is_empty() {
+                    // Nothing comes before main, so there's no need to jump
+                    self.rom.clear();
+                    self.here = START;
+                    self.jump_to_main = false;
+                }
+                let here = self.here;
+                self.define(name, here)
+            }
+            ":next" => {
+                let name = self.name()?;
+                let here = self.here;
+                self.define(name, here + 1)
+            }
+            ":const" => {
+                let name = self.name()?;
+                let value = self.value()?;
+                self.constants.insert(name, value);
+                Ok(())
+            }
+            ":alias" => {
+                let name = self.name()?;
+                let register = self.register()?;
+                self.aliases.insert(name, register);
+                Ok(())
+            }
+            ":macro" => self.define_macro(),
+            ":calc" => {
+                let name = self.name()?;
+                self.expect("{")?;
+                let value = self.calc()?;
+                self.constants.insert(name, value);
+                Ok(())
+            }
+            ":byte" => {
+                let value = if self.peek() == Some("{") {
+                    self.next()?;
+                    self.calc()?
+                } else {
+                    self.value()?
+                };
+                let byte = self.to_byte(value)?;
+                self.emit(byte)
+            }
+            ":org" => {
+                let address = self.value()?;
+                if address < START as i64 || address > 0xFFFF {
+                    return Err(self.fail(&format!("Can't put the program at {:#X}", address)));
+                }
+                self.here = address as usize;
+                Ok(())
+            }
+            ":unpack" => {
+                let nibble = self.nibble()?;
+                let address = self.address(Use::Unpack)?;
+                self.instruction(0x60, nibble << 4 | (address >> 8) as u8)?;
+                self.instruction(0x61, address as u8)
+            }
+            ":call" => {
+                let address = self.address(Use::Address)?;
+                self.instruction(0x20 | (address >> 8) as u8, address as u8)
+            }
+            ":breakpoint" => self.next().map(|_| ()),
+            ":monitor" => self.next().and_then(|_| self.next()).map(|_| ()),
+            ";" | "return" => self.instruction(0x00, 0xEE),
+            "clear" => self.instruction(0x00, 0xE0),
+            "hires" => self.instruction(0x00, 0xFF),
+            "lores" => self.instruction(0x00, 0xFE),
+            "scroll-left" => self.instruction(0x00, 0xFC),
+            "scroll-right" => self.instruction(0x00, 0xFB),
+            "exit" => self.instruction(0x00, 0xFD),
+            "scroll-down" => {
+                let n = self.nibble()?;
+                self.instruction(0x00, 0xC0 | n)
+            }
+            "scroll-up" => {
+                let n = self.nibble()?;
+                self.instruction(0x00, 0xD0 | n)
+            }
+            "bcd" => self.register_instruction(0x33),
+            "saveflags" => self.register_instruction(0x75),
+            "loadflags" => self.register_instruction(0x85),
+            "save" | "load" => {
+                let x = self.register()?;
+                if self.peek() == Some("-") {
+                    self.next()?;
+                    let y = self.register()?;
+                    let kind = if text == "save" { 0x2 } else { 0x3 };
+                    self.instruction(0x50 | x, y << 4 | kind)
+                } else {
+                    self.instruction(0xF0 | x, if text == "save" { 0x55 } else { 0x65 })
+                }
+            }
+            "sprite" => {
+                let x = self.register()?;
+                let y = self.register()?;
+                let n = self.nibble()?;
+                self.instruction(0xD0 | x, y << 4 | n)
+            }
+            "jump" | "jump0" | "native" => {
+                let address = self.address(Use::Address)?;
+                let op = match text {
+                    "jump" => 0x10,
+                    "jump0" => 0xB0,
+                    _ => 0x00,
+                };
+                self.instruction(op | (address >> 8) as u8, address as u8)
+            }
+            "delay" | "buzzer" | "pitch" => {
+                self.expect(":=")?;
+                self.register_instruction(match text {
+                    "delay" => 0x15,
+                    "buzzer" => 0x18,
+                    _ => 0x3A,
+                })
+            }
+            "plane" => {
+                let n = self.nibble()?;
+                self.instruction(0xF0 | n, 0x01)
+            }
+            "audio" => self.instruction(0xF0, 0x02),
+            "i" => self.index(),
+            "if" => {
+                let condition = self.condition()?;
+                match self.next()? {
+                    "then" => self.skip_unless(condition, false),
+                    "begin" => {
+                        self.skip_unless(condition, true)?;
+                        let (here, line) = (self.here, self.line);
+                        self.blocks.push((Block::If(here), line));
+                        self.instruction(0x10, 0x00)
+                    }
+                    other => Err(self.fail(&format!("Expected then or begin, got {}", other))),
+                }
+            }
+            "else" => {
+                match self.blocks.pop() {
+                    Some((Block::If(jump), line)) => {
+                        let here = self.here;
+                        self.blocks.push((Block::Else(here), line));
+                        self.instruction(0x10, 0x00)?;
+                        let here = self.here;
+                        self.patch(jump, here)
+                    }
+                    _ => Err(self.fail("This else has no if")),
+                }
+            }
+            "end" => {
+                match self.blocks.pop() {
+                    Some((Block::If(jump), _)) | Some((Block::Else(jump), _)) => {
+                        let here = self.here;
+                        self.patch(jump, here)
+                    }
+                    _ => Err(self.fail("This end has no begin")),
+                }
+            }
+            "loop" => {
+                let (here, line) = (self.here, self.line);
+                self.blocks.push((Block::Loop(here, Vec::new()), line));
+                Ok(())
+            }
+            "while" => {
+                let condition = self.condition()?;
+                self.skip_unless(condition, true)?;
+                let here = self.here;
+                match self.blocks.iter_mut().rev().find_map(|&mut (ref mut block, _)| match *block {
+                    Block::Loop(_, ref mut exits) => Some(exits),
+                    _ => None,
+                }) {
+                    Some(exits) => exits.push(here),
+                    None => return Err(self.fail("This while isn't in a loop")),
+                }
+                self.instruction(0x10, 0x00)
+            }
+            "again" => {
+                match self.blocks.pop() {
+                    Some((Block::Loop(start, exits), _)) => {
+                        self.instruction(0x10 | (start >> 8) as u8, start as u8)?;
+                        self.check_reach(start)?;
+                        let here = self.here;
+                        for exit in exits {
+                            self.patch(exit, here)?;
+                        }
+                        Ok(())
+                    }
+                    _ => Err(self.fail("This again has no loop")),
+                }
+            }
+            "then" | "begin" => Err(self.fail(&format!("This {} has no if", text))),
+            _ if self.macros.contains_key(text) => self.expand(text),
+            _ if self.is_register(text) || self.peek().is_some_and(|op| OPERATORS.contains(&op)) => {
+                let x = self.register_of(text)?;
+                self.assignment(x)
+            }
+            _ if text.starts_with(':') => Err(self.fail(&format!("{} isn't supported", text))),
+            _ => {
+                if let Some(value) = self.number(text) {
+                    // Labels are called, and other numbers are data
+                    if !self.labels.contains_key(text) {
+                        let byte = self.to_byte(value)?;
+                        return self.emit(byte);
+                    }
+                }
+                // Calls to labels that may not be defined yet
+                self.fixups.push(Fixup {
+                    at: self.here,
+                    name: text,
+                    kind: Use::Address,
+                    line: self.line,
+                });
+                self.instruction(0x20, 0x00)
+            }
+        }
+    }
+
+    /// Assembles the rest of a statement starting with `i`.
+    fn index(&mut self) -> Result<(), String> {
+        match self.next()? {
+            ":=" => match self.peek() {
+                Some("hex") => {
+                    self.next()?;
+                    self.register_instruction(0x29)
+                }
+                Some("bighex") => {
+                    self.next()?;
+                    self.register_instruction(0x30)
+                }
+                Some("long") => {
+                    self.next()?;
+                    let address = self.address(Use::Long)?;
+                    self.instruction(0xF0, 0x00)?;
+                    self.instruction((address >> 8) as u8, address as u8)
+                }
+                _ => {
+                    let address = self.address(Use::Address)?;
+                    self.instruction(0xA0 | (address >> 8) as u8, address as u8)
+                }
+            },
+            "+=" => self.register_instruction(0x1E),
+            other => Err(self.fail(&format!("Expected := or += after i, got {}", other))),
+        }
+    }
+
+    /// Assembles the rest of a statement starting with register X.
+    fn assignment(&mut self, x: u8) -> Result<(), String> {
+        let op = self.next()?;
+        let peek_register = self.peek().is_some_and(|text| self.is_register(text));
+        match op {
+            ":=" => match self.peek() {
+                Some("key") => {
+                    self.next()?;
+                    self.instruction(0xF0 | x, 0x0A)
+                }
+                Some("delay") => {
+                    self.next()?;
+                    self.instruction(0xF0 | x, 0x07)
+                }
+                Some("random") => {
+                    self.next()?;
+                    let mask = self.byte()?;
+                    self.instruction(0xC0 | x, mask)
+                }
+                _ if peek_register => {
+                    let y = self.register()?;
+                    self.instruction(0x80 | x, y << 4)
+                }
+                _ => {
+                    let n = self.byte()?;
+                    self.instruction(0x60 | x, n)
+                }
+            },
+            "+=" if !peek_register => {
+                let n = self.byte()?;
+                self.instruction(0x70 | x, n)
+            }
+            "-=" if !peek_register => {
+                let n = self.byte()?;
+                self.instruction(0x70 | x, n.wrapping_neg())
+            }
+            "|=" | "&=" | "^=" | "+=" | "-=" | ">>=" | "=-" | "<<=" => {
+                let y = self.register()?;
+                let kind = match op {
+                    "|=" => 0x1,
+                    "&=" => 0x2,
+                    "^=" => 0x3,
+                    "+=" => 0x4,
+                    "-=" => 0x5,
+                    ">>=" => 0x6,
+                    "=-" => 0x7,
+                    _ => 0xE,
+                };
+                self.instruction(0x80 | x, y << 4 | kind)
+            }
+            other => Err(self.fail(&format!("Expected an operator, got {}", other))),
+        }
+    }
+
+    /// Reads a condition, such as `v0 == 5` or `v1 key`.
+    fn condition(&mut self) -> Result<Condition<'a>, String> {
+        let register = self.register()?;
+        let op = self.next()?;
+        let operand = match op {
+            "key" | "-key" => Operand::None,
+            "==" | "!=" | "<" | ">" | "<=" | ">=" => {
+                if self.peek().is_some_and(|text| self.is_register(text)) {
+                    Operand::Register(self.register()?)
+                } else {
+                    Operand::Byte(self.byte()?)
+                }
+            }
+            other => return Err(self.fail(&format!("Expected a comparison, got {}", other))),
+        };
+        Ok(Condition {
+            register: register,
+            op: op,
+            operand: operand,
+        })
+    }
+
+    /// Assembles a condition so that the next instruction only runs when it
+    /// holds, or when it doesn't if `negated`.  Comparisons other than `==`
+    /// and `!=` use VF.
+    fn skip_unless(&mut self, condition: Condition, negated: bool) -> Result<(), String> {
+        let x = condition.register;
+        let op = if negated {
+            match condition.op {
+                "==" => "!=",
+                "!=" => "==",
+                "key" => "-key",
+                "-key" => "key",
+                "<" => ">=",
+                ">" => "<=",
+                ">=" => "<",
+                _ => ">",
+            }
+        } else {
+            condition.op
+        };
+        match (op, condition.operand) {
+            ("==", Operand::Register(y)) => self.instruction(0x90 | x, y << 4),
+            ("==", Operand::Byte(n)) => self.instruction(0x40 | x, n),
+            ("!=", Operand::Register(y)) => self.instruction(0x50 | x, y << 4),
+            ("!=", Operand::Byte(n)) => self.instruction(0x30 | x, n),
+            ("key", _) => self.instruction(0xE0 | x, 0xA1),
+            ("-key", _) => self.instruction(0xE0 | x, 0x9E),
+            (_, operand) => {
+                // VF takes the operand, then the flag of a subtraction says
+                // which is larger
+                match operand {
+                    Operand::Register(y) => self.instruction(0x8F, y << 4)?,
+                    _ => {
+                        let n = match operand {
+                            Operand::Byte(n) => n,
+                            _ => 0,
+                        };
+                        self.instruction(0x6F, n)?
+                    }
+                }
+                let (subtract, skip) = match op {
+                    ">" => (0x5, 0x3F),
+                    "<" => (0x7, 0x3F),
+                    ">=" => (0x7, 0x4F),
+                    _ => (0x5, 0x4F),
+                };
+                self.instruction(0x8F, x << 4 | subtract)?;
+                self.instruction(skip, 0x01)
+            }
+        }
+    }
+
+    fn define_macro(&mut self) -> Result<(), String> {
+        let name = self.name()?;
+        let mut params = Vec::new();
+        loop {
+            match self.next()? {
+                "{" => break,
+                param => params.push(param),
+            }
+        }
+        let body = self.braces()?;
+        self.macros.insert(name, Macro {
+            params: params,
+            body: body,
+        });
+        Ok(())
+    }
+
+    /// Pushes a macro's body in place of its use, with its arguments in
+    /// place of its parameters.
+    fn expand(&mut self, name: &str) -> Result<(), String> {
+        self.expansions += 1;
+        if self.expansions > MAX_EXPANSIONS {
+            return Err(self.fail(&format!("The macro {} never stops expanding", name)));
+        }
+        let definition = self.macros[name].clone();
+        let mut args = Vec::new();
+        for _ in 0..definition.params.len() {
+            args.push(self.next()?);
+        }
+        let line = self.line;
+        for token in definition.body.iter().rev() {
+            let text = definition.params.iter()
+                .position(|param| *param == token.text)
+                .map_or(token.text, |i| args[i]);
+            self.tokens.push(Token { text: text, line: line });
+        }
+        Ok(())
+    }
+
+    /// Reads the tokens up to the `}` matching a `{` just read.
+    fn braces(&mut self) -> Result<Vec<Token<'a>>, String> {
+        let mut depth = 1;
+        let mut tokens = Vec::new();
+        loop {
+            let token = self.next_token()?;
+            match token.text {
+                "{" => depth += 1,
+                "}" => {
+                    depth -= 1;
+                    if depth == 0 {
+                        return Ok(tokens);
+                    }
+                }
+                _ => {}
+            }
+            tokens.push(token);
+        }
+    }
+
+    /// Evaluates the expression in braces after `:calc`, just after the `{`.
+    /// Like Octo, operators have no precedence and work from right to left.
+    fn calc(&mut self) -> Result<i64, String> {
+        let tokens = self.braces()?;
+        let mut pos = 0;
+        let value = self.expression(&tokens, &mut pos)?;
+        match tokens.get(pos) {
+            Some(token) => Err(self.fail(&format!("Unexpected {} in expression", token.text))),
+            None => Ok(value),
+        }
+    }
+
+    fn expression(&self, tokens: &[Token], pos: &mut usize) -> Result<i64, String> {
+        let left = self.term(tokens, pos)?;
+        let op = match tokens.get(*pos) {
+            Some(token) if token.text != ")" => token.text,
+            _ => return Ok(left),
+        };
+        *pos += 1;
+        let right = self.expression(tokens, pos)?;
+        match op {
+            "+" => Ok(left.wrapping_add(right)),
+            "-" => Ok(left.wrapping_sub(right)),
+            "*" => Ok(left.wrapping_mul(right)),
+            "/" | "%" if right == 0 => Err(self.fail("Division by zero")),
+            "/" => Ok(left.wrapping_div(right)),
+            "%" => Ok(left.wrapping_rem(right)),
+            "&" => Ok(left & right),
+            "|" => Ok(left | right),
+            "^" => Ok(left ^ right),
+            "<<" => Ok(left.checked_shl(right as u32).unwrap_or(0)),
+            ">>" => Ok(left.checked_shr(right as u32).unwrap_or(0)),
+            "min" => Ok(left.min(right)),
+            "max" => Ok(left.max(right)),
+            _ => Err(self.fail(&format!("Unknown operator {}", op))),
+        }
+    }
+
+    fn term(&self, tokens: &[Token], pos: &mut usize) -> Result<i64, String> {
+        let text = tokens.get(*pos).ok_or_else(|| self.fail("The expression ends early"))?.text;
+        *pos += 1;
+        match text {
+            "(" => {
+                let value = self.expression(tokens, pos)?;
+                match tokens.get(*pos) {
+                    Some(token) if token.text == ")" => {
+                        *pos += 1;
+                        Ok(value)
+                    }
+                    _ => Err(self.fail("Expected )")),
+                }
+            }
+            "-" => self.term(tokens, pos).map(|value| value.wrapping_neg()),
+            "~" => self.term(tokens, pos).map(|value| !value),
+            "HERE" => Ok(self.here as i64),
+            _ => self.number(text).ok_or_else(|| self.fail(&format!("Unknown name {}", text))),
+        }
+    }
+
+    /// The value of a number, constant or label that is already defined.
+    fn number(&self, text: &str) -> Option<i64> {
+        let (negative, digits) = match text.strip_prefix('-') {
+            Some(digits) => (true, digits),
+            None => (false, text),
+        };
+        let literal = if let Some(hex) = digits.strip_prefix("0x").or_else(|| digits.strip_prefix("0X")) {
+            i64::from_str_radix(hex, 16).ok()
+        } else if let Some(binary) = digits.strip_prefix("0b").or_else(|| digits.strip_prefix("0B")) {
+            i64::from_str_radix(binary, 2).ok()
+        } else if digits.starts_with(|c: char| c.is_ascii_digit()) {
+            digits.parse().ok()
+        } else {
+            None
+        };
+        match literal {
+            Some(value) if negative => Some(-value),
+            Some(value) => Some(value),
+            None => {
+                self.constants.get(text).cloned()
+                    .or_else(|| self.labels.get(text).map(|&address| address as i64))
+            }
+        }
+    }
+
+    fn value(&mut self) -> Result<i64, String> {
+        let text = self.next()?;
+        self.number(text).ok_or_else(|| self.fail(&format!("Expected a number, got {}", text)))
+    }
+
+    fn to_byte(&self, value: i64) -> Result<u8, String> {
+        if (-128..=255).contains(&value) {
+            Ok(value as u8)
+        } else {
+            Err(self.fail(&format!("{} doesn't fit in a byte", value)))
+        }
+    }
+
+    fn byte(&mut self) -> Result<u8, String> {
+        let value = self.value()?;
+        self.to_byte(value)
+    }
+
+    fn nibble(&mut self) -> Result<u8, String> {
+        let value = self.value()?;
+        if (0..16).contains(&value) {
+            Ok(value as u8)
+        } else {
+            Err(self.fail(&format!("{} isn't between 0 and 15", value)))
+        }
+    }
+
+    /// Reads the address of an instruction about to be assembled, which
+    /// may be a label that is defined later.
+    fn address(&mut self, kind: Use) -> Result<usize, String> {
+        let text = self.next()?;
+        match self.number(text) {
+            Some(value) => {
+                let limit = match kind {
+                    Use::Long => 0xFFFF,
+                    _ => 0xFFF,
+                };
+                if value < 0 || value > limit {
+                    return Err(self.fail(&format!("{} is out of reach", text)));
+                }
+                Ok(value as usize)
+            }
+            None if self.is_name(text) => {
+                self.fixups.push(Fixup {
+                    at: self.here,
+                    name: text,
+                    kind: kind,
+                    line: self.line,
+                });
+                Ok(0)
+            }
+            None => Err(self.fail(&format!("Expected an address, got {}", text))),
+        }
+    }
+
+    fn is_register(&self, text: &str) -> bool {
+        self.register_of(text).is_ok()
+    }
+
+    fn register_of(&self, text: &str) -> Result<u8, String> {
+        if let Some(&register) = self.aliases.get(text) {
+            return Ok(register);
+        }
+        match text.strip_prefix('v').or_else(|| text.strip_prefix('V')) {
+            Some(digit) if digit.len() == 1 => {
+                u8::from_str_radix(digit, 16)
+                    .map_err(|_| self.fail(&format!("Expected a register, got {}", text)))
+            }
+            _ => Err(self.fail(&format!("Expected a register, got {}", text))),
+        }
+    }
+
+    fn register(&mut self) -> Result<u8, String> {
+        let text = self.next()?;
+        self.register_of(text)
+    }
+
+    /// Assembles an `FXNN` instruction for the register that comes next.
+    fn register_instruction(&mut self, nn: u8) -> Result<(), String> {
+        let x = self.register()?;
+        self.instruction(0xF0 | x, nn)
+    }
+
+    fn is_name(&self, text: &str) -> bool {
+        !text.starts_with(|c: char| c.is_ascii_digit() || c == '-' || c == ':') &&
+            !self.is_register(text)
+    }
+
+    fn name(&mut self) -> Result<&'a str, String> {
+        let text = self.next()?;
+        if self.is_name(text) {
+            Ok(text)
+        } else {
+            Err(self.fail(&format!("{} can't be a name", text)))
+        }
+    }
+
+    fn define(&mut self, name: &'a str, address: usize) -> Result<(), String> {
+        if self.labels.contains_key(name) || self.constants.contains_key(name) {
+            return Err(self.fail(&format!("{} is already defined", name)));
+        }
+        self.labels.insert(name, address);
+        Ok(())
+    }
+
+    fn next_token(&mut self) -> Result<Token<'a>, String> {
+        match self.tokens.pop() {
+            Some(token) => {
+                self.line = token.line;
+                Ok(token)
+            }
+            None => Err(self.fail("The program ends in the middle of a statement")),
+        }
+    }
+
+    fn next(&mut self) -> Result<&'a str, String> {
+        self.next_token().map(|token| token.text)
+    }
+
+    fn peek(&self) -> Option<&'a str> {
+        self.tokens.last().map(|token| token.text)
+    }
+
+    fn expect(&mut self, expected: &str) -> Result<(), String> {
+        match self.next()? {
+            text if text == expected => Ok(()),
+            text => Err(self.fail(&format!("Expected {}, got {}", expected, text))),
+        }
+    }
+
+    fn emit(&mut self, byte: u8) -> Result<(), String> {
+        if self.here > 0xFFFF {
+            return Err(self.fail("The program doesn't fit in memory"));
+        }
+        let at = self.here - START;
+        if at >= self.rom.len() {
+            self.rom.resize(at + 1, 0);
+        }
+        self.rom[at] = byte;
+        self.here += 1;
+        Ok(())
+    }
+
+    fn instruction(&mut self, high: u8, low: u8) -> Result<(), String> {
+        self.emit(high)?;
+        self.emit(low)
+    }
+
+    fn check_reach(&self, address: usize) -> Result<(), String> {
+        if address > 0xFFF {
+            Err(self.fail(&format!("{:#X} is out of reach of jumps", address)))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Points the instruction at the given address to the target.
+    fn patch(&mut self, at: usize, target: usize) -> Result<(), String> {
+        self.check_reach(target)?;
+        let at = at - START;
+        self.rom[at] = self.rom[at] & 0xF0 | (target >> 8) as u8;
+        self.rom[at + 1] = target as u8;
+        Ok(())
+    }
+
+    fn fail(&self, message: &str) -> String {
+        format!("Line {}: {}", self.line, message)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::assemble;
+    use Chip8;
+
+    /// Assembles a program that starts with main, as 16 bit words.
+    fn words(code: &str) -> Vec<u16> {
+        let rom = assemble(&format!(": main\n{}", code)).unwrap();
+        rom.chunks(2).map(|pair| (pair[0] as u16) << 8 | pair[1] as u16).collect()
+    }
+
+    #[test]
+    fn bytes() {
+        assert_eq!(assemble(": main # start here\n  0x6A 0b101 -1\n\n255 # done").unwrap(),
+                   vec![0x6A, 5, 0xFF, 0xFF]);
+        // Programs that don't start with main jump to it
+        assert_eq!(assemble("1 2\n: main\nclear").unwrap(), vec![0x12, 0x04, 1, 2, 0x00, 0xE0]);
+    }
+
+    #[test]
+    fn instructions() {
+        let code = "clear return ; hires lores scroll-down 3 scroll-up 2 scroll-left \
+                    scroll-right exit
+                    bcd v1 save v2 load v3 save v1 - v4 load v5 - v6 saveflags v7 loadflags v8
+                    sprite v1 v2 5 jump 0x300 jump0 0x400 native 0x123 :call 0x500
+                    delay := v3 buzzer := v4 pitch := v5 plane 3 audio
+                    i := 0x234 i += v6 i := hex v7 i := bighex v8 i := long 0x1234
+                    v1 := key v2 := delay v3 := random 0x0F v4 := v5 v6 := 7
+                    v7 += 2 v8 -= 1 va += vb vb -= vc vc |= vd vd &= ve ve ^= vf
+                    v0 >>= v1 v2 =- v3 v4 <<= v5";
+        assert_eq!(words(code),
+                   vec![0x00E0, 0x00EE, 0x00EE, 0x00FF, 0x00FE, 0x00C3, 0x00D2, 0x00FC,
+                        0x00FB, 0x00FD,
+                        0xF133, 0xF255, 0xF365, 0x5142, 0x5563, 0xF775, 0xF885,
+                        0xD125, 0x1300, 0xB400, 0x0123, 0x2500,
+                        0xF315, 0xF418, 0xF53A, 0xF301, 0xF002,
+                        0xA234, 0xF61E, 0xF729, 0xF830, 0xF000, 0x1234,
+                        0xF10A, 0xF207, 0xC30F, 0x8450, 0x6607,
+                        0x7702, 0x78FF, 0x8AB4, 0x8BC5, 0x8CD1, 0x8DE2, 0x8EF3,
+                        0x8016, 0x8237, 0x845E]);
+    }
+
+    #[test]
+    fn labels() {
+        // Labels can be used before they are defined
+        let code = "i := sprite
+                    loop
+                      draw
+                    again
+                    : draw
+                      return
+                    : sprite
+                      0xF0 0x00";
+        assert_eq!(words(code), vec![0xA208, 0x2206, 0x1202, 0x00EE, 0xF000]);
+        assert_eq!(assemble(": main\n  jump nowhere\n").unwrap_err(), "Line 2: Unknown name nowhere");
+        assert_eq!(assemble(": main\n: main\n").unwrap_err(), "Line 2: main is already defined");
+        assert_eq!(assemble(": start\n").unwrap_err(), "The program has no main label");
+    }
+
+    #[test]
+    fn control_flow() {
+        assert_eq!(words("if v1 == 2 then v3 := 4"), vec![0x4102, 0x6304]);
+        assert_eq!(words("if v1 != v2 then v3 := 4"), vec![0x5120, 0x6304]);
+        assert_eq!(words("if v1 key then v3 := 4"), vec![0xE1A1, 0x6304]);
+        assert_eq!(words("if v1 > 2 then v3 := 4"), vec![0x6F02, 0x8F15, 0x3F01, 0x6304]);
+        assert_eq!(words("if v1 == 2 begin v3 := 4 else v3 := 5 end"),
+                   vec![0x3102, 0x1208, 0x6304, 0x120A, 0x6305]);
+        assert_eq!(words("loop while v1 -key v1 += 1 again"),
+                   vec![0xE1A1, 0x1208, 0x7101, 0x1200]);
+
+        assert_eq!(assemble(": main\n  loop\n").unwrap_err(), "Line 2: This loop is never closed");
+        assert_eq!(assemble(": main\n  end\n").unwrap_err(), "Line 2: This end has no begin");
+        assert_eq!(assemble(": main\n  while v0 == 1\n").unwrap_err(),
+                   "Line 2: This while isn't in a loop");
+    }
+
+    #[test]
+    fn comparisons() {
+        // Counts V1 up in twos while V0 counts to 10, then checks each
+        // comparison against V0, setting a bit of V2 for each that holds
+        let rom = assemble("
+            : main
+              v0 := 0
+              loop
+                while v0 < 10
+                v0 += 1
+                v1 += 2
+              again
+              v3 := 10
+              v4 := 1
+              if v0 == v3 then v2 |= v4
+              if v0 >= 10 begin v4 := 2 v2 |= v4 end
+              if v0 <= 10 begin v4 := 4 v2 |= v4 end
+              if v0 > 9 begin v4 := 8 v2 |= v4 end
+              if v0 < 10 begin v4 := 16 v2 |= v4 else v4 := 32 v2 |= v4 end
+              if v0 != 10 then v2 := 0
+            : halt
+              jump halt").unwrap();
+        let mut chip = Chip8::default();
+        chip.load_rom(&rom, 0x200).unwrap();
+        for _ in 0..200 {
+            chip.emulate_cycle();
+        }
+        assert_eq!(chip.registers()[0], 10);
+        assert_eq!(chip.registers()[1], 20);
+        assert_eq!(chip.registers()[2], 1 | 2 | 4 | 8 | 32);
+    }
+
+    #[test]
+    fn directives() {
+        let code = ":const speed 3
+                    :alias x v5
+                    :macro twice op reg n { reg op n reg op n }
+                    :calc double { speed * 2 }
+                    x := speed
+                    twice += x double
+                    :next target v1 := 0
+                    :unpack 0xA target
+                    :byte { 256 - 1 }
+                    :org 0x300 7";
+        let rom = assemble(&format!(": main\n{}", code)).unwrap();
+        assert_eq!(&rom[..16], &[0x65, 0x03, 0x75, 0x06, 0x75, 0x06, 0x61, 0x00, 0x60, 0xA2,
+                                 0x61, 0x07, 0xFF, 0, 0, 0]);
+        assert_eq!(rom.len(), 0x101);
+        assert_eq!(rom[0x100], 7);
+
+        assert_eq!(assemble(": main\n  :assert 1\n").unwrap_err(),
+                   "Line 2: :assert isn't supported");
+        assert_eq!(assemble(":macro forever { forever }\n: main forever").unwrap_err(),
+                   "Line 2: The macro forever never stops expanding");
+    }
+
+    #[test]
+    fn mistakes() {
+        assert_eq!(assemble(": main\n  vg := 1\n").unwrap_err(),
+                   "Line 2: Expected a register, got vg");
+        assert_eq!(assemble(": main\n  v0 := vg\n").unwrap_err(),
+                   "Line 2: Expected a number, got vg");
+        assert_eq!(assemble(": main\n  v0 := 256\n").unwrap_err(),
+                   "Line 2: 256 doesn't fit in a byte");
+        assert_eq!(assemble(": main\n  sprite v0 v1 16\n").unwrap_err(),
+                   "Line 2: 16 isn't between 0 and 15");
+        assert_eq!(assemble(": main\n  jump 0x1000\n").unwrap_err(),
+                   "Line 2: 0x1000 is out of reach");
+        assert_eq!(assemble(": main\n  if v0 == 1\n").unwrap_err(),
+                   "Line 2: The program ends in the middle of a statement");
+    }
+}
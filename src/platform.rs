@@ -0,0 +1,209 @@
+/// Switches for the places where Chip8 interpreters disagree on what an
+/// opcode does.  Games written for one interpreter often misbehave on
+/// another, so these let the chip act like the one a game expects.
+///
+/// The default leaves every quirk off.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Quirks {
+    /// 0x8XY6 and 0x8XYE shift regY into regX, instead of shifting regX.
+    pub shift_uses_vy: bool,
+    /// 0xFX55 and 0xFX65 leave the index pointing past the last register
+    /// stored or loaded.
+    pub load_store_increments_index: bool,
+    /// 0xBNNN jumps to NNN + regX, where X is the high nibble of NNN, instead
+    /// of NNN + reg0.
+    pub jump_uses_vx: bool,
+    /// 0x8XY1, 0x8XY2 and 0x8XY3 reset regF to 0.
+    pub logic_resets_vf: bool,
+    /// Sprites drawn past the edge of the display wrap around to the other
+    /// side, instead of being clipped.
+    pub wrap_sprites: bool,
+    /// 0xDXYN waits for the next frame, so at most one sprite is drawn per
+    /// frame.
+    pub display_wait: bool,
+}
+
+/// The names of each quirk, as used by `Quirks::apply`.
+pub const QUIRK_NAMES: [&str; 6] = [
+    "shift",
+    "load-store",
+    "jump",
+    "vf-reset",
+    "wrap",
+    "display-wait",
+];
+
+impl Quirks {
+    /// Returns a mutable reference to the quirk with the given name.
+    #[cfg(feature = "std")]
+    fn named(&mut self, name: &str) -> Option<&mut bool> {
+        match name {
+            "shift" => Some(&mut self.shift_uses_vy),
+            "load-store" => Some(&mut self.load_store_increments_index),
+            "jump" => Some(&mut self.jump_uses_vx),
+            "vf-reset" => Some(&mut self.logic_resets_vf),
+            "wrap" => Some(&mut self.wrap_sprites),
+            "display-wait" => Some(&mut self.display_wait),
+            _ => None,
+        }
+    }
+
+    /// Turns quirks on or off from a comma separated list of overrides, such
+    /// as `shift=on,jump=off`.  A quirk named without a value is turned on.
+    #[cfg(feature = "std")]
+    pub fn apply(&mut self, overrides: &str) -> Result<(), String> {
+        for item in overrides.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+            let mut parts = item.splitn(2, '=');
+            let name = parts.next().unwrap().trim();
+            let enabled = match parts.next().map(str::trim) {
+                None | Some("on") | Some("true") | Some("1") => true,
+                Some("off") | Some("false") | Some("0") => false,
+                Some(value) => return Err(format!("Bad value for quirk {}: {}", name, value)),
+            };
+            match self.named(name) {
+                Some(quirk) => *quirk = enabled,
+                None => {
+                    return Err(format!("Unknown quirk {} (expected one of: {})",
+                                       name,
+                                       QUIRK_NAMES.join(", ")))
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The Chip8 interpreters whose behavior can be imitated.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Platform {
+    /// The original interpreter on the COSMAC VIP.
+    Chip8,
+    /// SUPER-CHIP 1.1 on the HP 48 calculators.
+    SuperChip,
+    /// The XO-CHIP extension used by Octo.
+    XoChip,
+}
+
+/// Every platform, in the order they are listed in help messages.
+pub const PLATFORMS: [Platform; 3] = [
+    Platform::Chip8,
+    Platform::SuperChip,
+    Platform::XoChip,
+];
+
+impl Platform {
+    /// The platform's name, as used on the command line.
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Platform::Chip8 => "chip8",
+            Platform::SuperChip => "schip",
+            Platform::XoChip => "xochip",
+        }
+    }
+
+    /// Finds the platform with the given name, ignoring case.
+    pub fn from_name(name: &str) -> Option<Platform> {
+        PLATFORMS.iter().cloned().find(|p| p.name().eq_ignore_ascii_case(name))
+    }
+
+    /// The address just past the last byte of memory programs can use.  The
+    /// COSMAC VIP kept its stack and display at the top of its 4K.
+    pub fn memory_end(&self) -> usize {
+        match *self {
+            Platform::Chip8 => 0xEA0,
+            Platform::SuperChip => 0x1000,
+            Platform::XoChip => 0x10000,
+        }
+    }
+
+    /// The size of the platform's memory, including any parts programs
+    /// can't use.
+    pub fn memory_size(&self) -> usize {
+        match *self {
+            Platform::Chip8 | Platform::SuperChip => 0x1000,
+            Platform::XoChip => 0x10000,
+        }
+    }
+
+    /// Checks that a ROM of the given size loaded at the given address fits
+    /// in the memory programs can use on the platform.
+    #[cfg(feature = "std")]
+    pub fn check_rom(&self, size: usize, address: u16) -> Result<(), String> {
+        let end = address as usize + size;
+        if end > self.memory_end() {
+            return Err(format!("A {} byte ROM loaded at {:#X} is {} bytes too big for {}",
+                               size, address, end - self.memory_end(), self.name()));
+        }
+        Ok(())
+    }
+
+    /// The quirks of the platform's interpreter.
+    pub fn quirks(&self) -> Quirks {
+        match *self {
+            Platform::Chip8 => Quirks {
+                shift_uses_vy: true,
+                load_store_increments_index: true,
+                jump_uses_vx: false,
+                logic_resets_vf: true,
+                wrap_sprites: false,
+                display_wait: true,
+            },
+            Platform::SuperChip => Quirks {
+                shift_uses_vy: false,
+                load_store_increments_index: false,
+                jump_uses_vx: true,
+                logic_resets_vf: false,
+                wrap_sprites: false,
+                display_wait: false,
+            },
+            Platform::XoChip => Quirks {
+                shift_uses_vy: true,
+                load_store_increments_index: true,
+                jump_uses_vx: false,
+                logic_resets_vf: false,
+                wrap_sprites: true,
+                display_wait: false,
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Platform, Quirks, PLATFORMS};
+
+    #[test]
+    fn platform_names() {
+        for platform in PLATFORMS.iter() {
+            assert_eq!(Platform::from_name(platform.name()), Some(*platform));
+        }
+        assert_eq!(Platform::from_name("SCHIP"), Some(Platform::SuperChip));
+        assert_eq!(Platform::from_name("megachip"), None);
+    }
+
+    #[test]
+    fn check_rom_size() {
+        assert!(Platform::Chip8.check_rom(0xCA0, 0x200).is_ok());
+        assert!(Platform::Chip8.check_rom(0xCA1, 0x200).is_err());
+        assert!(Platform::SuperChip.check_rom(0xCA1, 0x200).is_ok());
+        assert!(Platform::SuperChip.check_rom(0xA01, 0x600).is_err());
+    }
+
+    #[test]
+    fn apply_overrides() {
+        let mut quirks = Platform::Chip8.quirks();
+        quirks.apply("shift=off, wrap, jump=on").unwrap();
+        assert!(!quirks.shift_uses_vy);
+        assert!(quirks.wrap_sprites);
+        assert!(quirks.jump_uses_vx);
+        assert!(quirks.load_store_increments_index);
+    }
+
+    #[test]
+    fn apply_rejects_bad_overrides() {
+        let mut quirks = Quirks::default();
+        assert!(quirks.apply("sideways=on").is_err());
+        assert!(quirks.apply("shift=maybe").is_err());
+        assert_eq!(quirks, Quirks::default());
+    }
+}
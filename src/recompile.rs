@@ -0,0 +1,324 @@
+//! Runs the chip a basic block at a time.  Each block is a run of decoded
+//! opcodes that goes straight through, ending with the first opcode that
+//! can jump, wait, draw or write memory, so the block's opcodes are run one
+//! after another without looking anything up in between.  Skips don't end
+//! blocks, since most of them aren't taken: when one is, the rest of the
+//! block is left for the block at the address skipped to.
+//!
+//! Blocks are compiled the first time the program counter reaches them.
+//! When the program writes to an address that is part of a block, the blocks
+//! covering it are thrown away and compiled again as they are reached, which
+//! keeps self-modifying code working without recompiling the rest.  The
+//! rewritten opcodes themselves are left out of blocks from then on and
+//! decoded every time they run, since programs tend to rewrite them again.
+
+use super::Chip8;
+use decode::{self, Decoded};
+use std::cmp;
+
+/// The most opcodes in a block, so that long runs of straight code don't
+/// make huge blocks.
+const MAX_BLOCK: u32 = 64;
+
+/// Where a block's opcodes are in the list of compiled opcodes.
+#[derive(Clone, Copy, Debug)]
+struct Block {
+    first: u32,
+    len: u32,
+    /// Whether any of the opcodes can skip, so that the block has to check
+    /// where the program counter went after each one.
+    skips: bool,
+}
+
+/// The compiled blocks, by the address they start at.
+#[derive(Clone, Debug, Default)]
+pub struct Blocks {
+    /// The opcodes of every block compiled since the blocks were last
+    /// cleared, including those of blocks thrown away since.
+    ops: Vec<Decoded>,
+    starts: Vec<Option<Block>>,
+    /// Whether each address may be part of a compiled block.  Addresses stay
+    /// marked after their blocks are thrown away, unless they are written.
+    code: Vec<bool>,
+    /// Whether the program has written to each address after compiling it.
+    /// Opcodes there aren't compiled again, but decoded every time they run.
+    modified: Vec<bool>,
+}
+
+impl Blocks {
+    /// Throws away every block, keeping the space they used, for a memory of
+    /// the given size.
+    pub fn clear(&mut self, size: usize) {
+        self.ops.clear();
+        self.starts.clear();
+        self.starts.resize(size, None);
+        self.code.clear();
+        self.code.resize(size, false);
+        self.modified.clear();
+        self.modified.resize(size, false);
+    }
+
+    /// Throws away the blocks that cover any of the given number of bytes
+    /// written at the given address.
+    pub fn invalidate(&mut self, address: u16, count: usize) {
+        let size = self.code.len();
+        if size == 0 {
+            return;
+        }
+        let mask = size - 1;
+        let written = (0..count).any(|i| self.code[(address as usize + i) & mask]);
+        if !written {
+            return;
+        }
+        if count >= size {
+            self.clear(size);
+            return;
+        }
+
+        // No block will be compiled over the written bytes again, so once the
+        // blocks covering them are gone, writing to them again costs nothing
+        for i in 0..count {
+            let byte = (address as usize + i) & mask;
+            self.modified[byte] |= self.code[byte];
+            self.code[byte] = false;
+        }
+        // Blocks don't wrap around the end of memory, but the write can, so
+        // each end is looked at on its own
+        let first = address as usize & mask;
+        let last = first + count;
+        self.drop_blocks(first, cmp::min(last, size));
+        if last > size {
+            self.drop_blocks(0, last - size);
+        }
+    }
+
+    /// Throws away the blocks that cover any of the addresses from `first`
+    /// up to `last`, which are no further apart than the size of memory.
+    fn drop_blocks(&mut self, first: usize, last: usize) {
+        // A block can start at most its longest length before the addresses
+        let earliest = first.saturating_sub(2 * MAX_BLOCK as usize - 1);
+        for start in earliest..last {
+            if let Some(block) = self.starts[start] {
+                if start + 2 * block.len as usize > first {
+                    self.starts[start] = None;
+                }
+            }
+        }
+    }
+
+    /// Whether the program has rewritten either byte of the opcode at the
+    /// given address.
+    fn is_modified(&self, address: usize) -> bool {
+        let mask = self.modified.len() - 1;
+        self.modified[address & mask] || self.modified[(address + 1) & mask]
+    }
+}
+
+/// Whether the given opcode ends a block, because it can do something other
+/// than move on to the next opcode or skip it, or could change the block by
+/// writing memory, or can end a frame by drawing.  Bad opcodes end blocks
+/// too, so that they panic in the same place the interpreter would.
+fn ends_block(opcode: u16) -> bool {
+    match opcode & 0xF000 {
+        0x0000 => opcode != 0x00E0,
+        0x3000 | 0x4000 | 0x5000 | 0x6000 | 0x7000 | 0x9000 | 0xA000 | 0xC000 => false,
+        0x8000 => opcode & 0x000F > 0x7 && opcode & 0x000F != 0xE,
+        0xE000 => opcode & 0x00FF != 0x9E && opcode & 0x00FF != 0xA1,
+        0xF000 => ![0x07, 0x15, 0x18, 0x1E, 0x29, 0x65].contains(&(opcode & 0x00FF)),
+        _ => true,
+    }
+}
+
+/// Whether the given opcode skips the next one under some condition.
+fn can_skip(opcode: u16) -> bool {
+    [0x3000, 0x4000, 0x5000, 0x9000, 0xE000].contains(&(opcode & 0xF000))
+}
+
+/// Compiles the block starting at the given address, which the program
+/// hasn't rewritten.  The block stops short of opcodes that it has.
+fn compile(chip: &mut Chip8, start: u16) -> Block {
+    let size = chip.blocks.code.len();
+    // Thrown away blocks leave their opcodes behind, so once there are more
+    // opcodes than memory could hold, every block starts over
+    if chip.blocks.ops.len() + MAX_BLOCK as usize > size {
+        chip.blocks.clear(size);
+    }
+    let first = chip.blocks.ops.len() as u32;
+    let mut address = start as usize & (size - 1);
+    let mut len = 0;
+    let mut skips = false;
+    loop {
+        let opcode = chip.memory.read_word(address as u16);
+        chip.blocks.ops.push(decode::decode(opcode));
+        chip.blocks.code[address] = true;
+        chip.blocks.code[(address + 1) & (size - 1)] = true;
+        skips |= can_skip(opcode);
+        len += 1;
+        address += 2;
+        if ends_block(opcode) || len == MAX_BLOCK || address + 1 >= size ||
+           chip.blocks.is_modified(address) {
+            break;
+        }
+    }
+    Block {
+        first: first,
+        len: len,
+        skips: skips,
+    }
+}
+
+/// Runs the given number of opcodes a block at a time, stopping early after
+/// drawing a sprite with the display wait quirk, like `Chip8::emulate_frame`.
+/// Returns the number of opcodes run.
+pub fn run(chip: &mut Chip8, cycles: u32) -> u32 {
+    let mut left = cycles;
+    while left > 0 {
+        let start = chip.pc as usize & (chip.blocks.starts.len() - 1);
+        match chip.blocks.starts[start] {
+            Some(block) => left -= run_block(chip, block, left),
+            None if chip.blocks.is_modified(start) => {
+                let decoded = decode::decode(chip.memory.read_word(chip.pc));
+                chip.opcode = decoded.opcode;
+                (decoded.handler)(chip, &decoded);
+                left -= 1;
+            }
+            None => {
+                let pc = chip.pc;
+                let block = compile(chip, pc);
+                chip.blocks.starts[start] = Some(block);
+                left -= run_block(chip, block, left);
+            }
+        }
+
+        if chip.quirks.display_wait && chip.opcode & 0xF000 == 0xD000 {
+            break;
+        }
+    }
+    cycles - left
+}
+
+/// Runs up to the given number of the block's opcodes, returning how many
+/// were run.  Only the last opcode can throw the blocks away, so the rest can
+/// be run without looking the block up again, until one skips.
+#[inline]
+fn run_block(chip: &mut Chip8, block: Block, cycles: u32) -> u32 {
+    let count = cmp::min(block.len, cycles);
+    let ops = block.first..block.first + count;
+    if !block.skips {
+        for i in ops {
+            let decoded = chip.blocks.ops[i as usize];
+            chip.opcode = decoded.opcode;
+            (decoded.handler)(chip, &decoded);
+        }
+        return count;
+    }
+
+    let mut next = chip.pc;
+    for i in ops {
+        let decoded = chip.blocks.ops[i as usize];
+        chip.opcode = decoded.opcode;
+        (decoded.handler)(chip, &decoded);
+        next = next.wrapping_add(2);
+        if chip.pc != next {
+            return i - block.first + 1;
+        }
+    }
+    count
+}
+
+#[cfg(test)]
+mod test {
+    use super::ends_block;
+    use decode::test::{run_both, EVERY_OPCODE, SELF_MODIFYING};
+    use platform::Platform;
+    use {Chip8, Engine};
+
+    #[test]
+    fn block_ends() {
+        assert!(!ends_block(0x00E0));
+        assert!(!ends_block(0x8124));
+        assert!(!ends_block(0xF265));
+        assert!(ends_block(0x00EE));
+        assert!(ends_block(0x8128));
+        assert!(ends_block(0xD125));
+        assert!(ends_block(0xF255));
+        assert!(!ends_block(0x3100));
+        assert!(!ends_block(0xE19E));
+        assert!(ends_block(0xE1FF));
+        assert!(ends_block(0x1200));
+    }
+
+    #[test]
+    fn matches_interpreter() {
+        let pong = include_bytes!("../PONG");
+        for &platform in [None, Some(Platform::Chip8), Some(Platform::SuperChip)].iter() {
+            // An odd number of cycles per frame stops partway through blocks
+            for &cycles in [1, 7, 15].iter() {
+                run_both(Engine::Recompiler, pong, platform, 300, cycles);
+                run_both(Engine::Recompiler, &EVERY_OPCODE, platform, 300, cycles);
+            }
+        }
+    }
+
+    #[test]
+    fn waits_for_keys_and_timers() {
+        // Waits for a key, then sets the delay timer and spins until it runs
+        // out, reading it back every time around
+        let program = [0xF1, 0x0A, 0x70, 0x01, 0xF0, 0x15, 0xF2, 0x07,
+                       0x32, 0x00, 0x12, 0x06, 0x12, 0x00];
+        for &cycles in [1, 4, 15].iter() {
+            let chip = run_both(Engine::Recompiler, &program, None, 120, cycles);
+            assert!(chip.registers()[0] > 1);
+        }
+    }
+
+    #[test]
+    fn sees_self_modifying_code() {
+        let chip = run_both(Engine::Recompiler, &SELF_MODIFYING, None, 2, 15);
+        assert_eq!(chip.registers()[2], 7);
+        assert_eq!(chip.pc(), 0x210);
+    }
+
+    #[test]
+    fn keeps_blocks_away_from_writes() {
+        // Rewrites the operand of its own 6XNN every time around the loop,
+        // then does arithmetic that is never written to
+        let program = [0xA2, 0x03, 0x60, 0x00, 0x70, 0x01, 0xF0, 0x55,
+                       0x81, 0x04, 0x82, 0x14, 0x12, 0x02];
+        let chip = run_both(Engine::Recompiler, &program, None, 20, 15);
+        assert!(chip.registers()[0] > 20);
+        assert!(chip.blocks.starts[0x204].is_some());
+        assert!(chip.blocks.starts[0x208].is_some());
+        assert!(chip.blocks.starts[0x200].is_none());
+        assert!(chip.blocks.starts[0x202].is_none());
+
+        // Long enough for the thrown away opcodes to fill up memory's worth
+        let chip = run_both(Engine::Recompiler, &program, None, 600, 15);
+        assert!(chip.blocks.ops.len() <= chip.memory().len());
+    }
+
+    #[test]
+    fn sees_outside_writes() {
+        let mut chip = Chip8::with_engine(Engine::Recompiler);
+        chip.load_hex(&[0x60, 0x01, 0x12, 0x00]);
+        chip.emulate_frame(2);
+        chip.write_memory(0x200, &[0x60, 0x02]).unwrap();
+        chip.emulate_frame(2);
+        assert_eq!(chip.registers()[0], 2);
+
+        // Read hooks fall back to the interpreter
+        chip.memory_bus().add_read_hook(0x201, 0x201, |_, _| 0x04);
+        chip.emulate_frame(2);
+        assert_eq!(chip.registers()[0], 4);
+    }
+
+    #[test]
+    #[should_panic(expected = "Opcode 0x8128 is bad")]
+    fn bad_opcodes_panic() {
+        let mut chip = Chip8::with_engine(Engine::Recompiler);
+        chip.load_hex(&[0x60, 0x01, 0x81, 0x28]);
+        chip.emulate_frame(1);
+        assert_eq!(chip.registers()[0], 1);
+        chip.emulate_frame(1);
+    }
+}
@@ -0,0 +1,263 @@
+use filter::Filter;
+use gif;
+use png;
+use render::{self, Palette};
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use super::{HEIGHT, NPIXELS, TIMER_RATE, WIDTH};
+
+/// The most 60Hz frames a single recorded image may be shown for.  Longer
+/// stretches are split so their delays fit in the formats' 16-bit fields.
+const MAX_REPEAT: u32 = 30000;
+
+/// The largest scale a GIF can be recorded at, as its width and height are
+/// 16-bit.
+pub const MAX_GIF_SCALE: u32 = 0xFFFF / WIDTH;
+
+/// The animated image formats a recording can be saved as.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    /// An animated GIF.
+    Gif,
+    /// An animated PNG.
+    Apng,
+}
+
+impl Format {
+    /// Picks the format matching the extension of the given path, if any.
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Option<Format> {
+        let ext = match path.as_ref().extension().and_then(|e| e.to_str()) {
+            Some(ext) => ext.to_lowercase(),
+            None => return None,
+        };
+        match ext.as_str() {
+            "gif" => Some(Format::Gif),
+            "png" | "apng" => Some(Format::Apng),
+            _ => None,
+        }
+    }
+}
+
+/// A recorded image along with how many 60Hz frames it stayed on screen.
+struct Frame {
+    bitmap: Box<[u8; NPIXELS]>,
+    repeat: u32,
+}
+
+/// Records the chip's display once per 60Hz frame so it can be saved as an
+/// animated image.
+///
+/// Consecutive identical frames are merged into a single longer frame, which
+/// keeps recordings of mostly static games small.
+pub struct Recorder {
+    format: Format,
+    palette: Palette,
+    scale: u32,
+    filter: Filter,
+    frames: Vec<Frame>,
+}
+
+impl Recorder {
+    /// Constructs a Recorder that will save in the given format, using the
+    /// given palette, integer scaling factor and filter.
+    ///
+    /// Without a filter, frames are saved with a two color palette.  Filtered
+    /// frames have many more colors, so GIFs need each frame quantized down to
+    /// 256 colors, which is slower and makes for larger files.  Fails if the
+    /// scale is too large for the format.
+    pub fn new(format: Format,
+               palette: Palette,
+               scale: u32,
+               filter: Filter) -> Result<Self, String> {
+        if format == Format::Gif && scale > MAX_GIF_SCALE {
+            return Err(format!("GIFs can only be recorded at a scale of up to {}", MAX_GIF_SCALE));
+        }
+        Ok(Recorder {
+            format: format,
+            palette: palette,
+            scale: scale,
+            filter: filter,
+            frames: Vec::new(),
+        })
+    }
+
+    /// Adds the given bitmap as the next 60Hz frame of the recording.
+    pub fn capture(&mut self, bitmap: &[u8; NPIXELS]) {
+        if let Some(last) = self.frames.last_mut() {
+            if last.repeat < MAX_REPEAT && last.bitmap[..] == bitmap[..] {
+                last.repeat += 1;
+                return;
+            }
+        }
+        self.frames.push(Frame {
+            bitmap: Box::new(*bitmap),
+            repeat: 1,
+        });
+    }
+
+    /// The number of distinct images recorded so far.
+    pub fn len(&self) -> usize {
+        self.frames.len()
+    }
+
+    /// Returns true if nothing has been recorded yet.
+    pub fn is_empty(&self) -> bool {
+        self.frames.is_empty()
+    }
+
+    /// Encodes the recording and saves it to the given path.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let file = File::create(path)?;
+        self.write(BufWriter::new(file))
+    }
+
+    /// Encodes the recording to the given writer.
+    pub fn write<W: Write>(&self, w: W) -> io::Result<()> {
+        match self.format {
+            Format::Gif => self.write_gif(w),
+            Format::Apng => self.write_apng(w),
+        }
+    }
+
+    fn write_gif<W: Write>(&self, w: W) -> io::Result<()> {
+        let width = (WIDTH * self.scale) as u16;
+        let height = (HEIGHT * self.scale) as u16;
+        let table = self.palette.to_rgb_table();
+        let mut encoder = gif::Encoder::new(w, width, height, &table)
+            .map_err(gif_error)?;
+        encoder.set_repeat(gif::Repeat::Infinite).map_err(gif_error)?;
+
+        // GIF delays are in hundredths of a second, which don't divide 60Hz
+        // evenly, so round the running total to keep the playback in sync.
+        let mut elapsed = 0;
+        let mut shown = 0;
+        for frame in &self.frames {
+            elapsed += frame.repeat;
+            let end = (elapsed * 100 + TIMER_RATE / 2) / TIMER_RATE;
+            let mut image = if self.filter == Filter::None {
+                gif::Frame::from_indexed_pixels(
+                    width, height, render::upscale(&frame.bitmap, self.scale), None)
+            } else {
+                let mut rendered = self.filter.render(&frame.bitmap, &self.palette, self.scale);
+                gif::Frame::from_rgba_speed(width, height, &mut rendered.pixels, 10)
+            };
+            image.delay = (end - shown) as u16;
+            shown = end;
+            encoder.write_frame(&image).map_err(gif_error)?;
+        }
+        Ok(())
+    }
+
+    fn write_apng<W: Write>(&self, w: W) -> io::Result<()> {
+        let mut encoder = png::Encoder::new(w, WIDTH * self.scale, HEIGHT * self.scale);
+        encoder.set_depth(png::BitDepth::Eight);
+        if self.filter == Filter::None {
+            encoder.set_color(png::ColorType::Indexed);
+            encoder.set_palette(self.palette.to_rgb_table());
+        } else {
+            encoder.set_color(png::ColorType::Rgba);
+        }
+        encoder.set_animated(self.frames.len() as u32, 0)?;
+        let mut writer = encoder.write_header()?;
+        for frame in &self.frames {
+            writer.set_frame_delay(frame.repeat as u16, TIMER_RATE as u16)?;
+            if self.filter == Filter::None {
+                writer.write_image_data(&render::upscale(&frame.bitmap, self.scale))?;
+            } else {
+                let rendered = self.filter.render(&frame.bitmap, &self.palette, self.scale);
+                writer.write_image_data(&rendered.pixels)?;
+            }
+        }
+        writer.finish()?;
+        Ok(())
+    }
+}
+
+/// Converts a GIF encoding error into an I/O error.
+fn gif_error(e: gif::EncodingError) -> io::Error {
+    io::Error::other(e)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Format, Recorder};
+    use filter::Filter;
+    use render::Palette;
+    use NPIXELS;
+
+    #[test]
+    fn format_from_path() {
+        assert_eq!(Format::from_path("out.gif"), Some(Format::Gif));
+        assert_eq!(Format::from_path("out.PNG"), Some(Format::Apng));
+        assert_eq!(Format::from_path("out.apng"), Some(Format::Apng));
+        assert_eq!(Format::from_path("out.avi"), None);
+        assert_eq!(Format::from_path("out"), None);
+    }
+
+    #[test]
+    fn capture_merges_duplicates() {
+        let mut recorder = Recorder::new(Format::Gif, Palette::default(), 1, Filter::None).unwrap();
+        let mut bitmap = [0; NPIXELS];
+        recorder.capture(&bitmap);
+        recorder.capture(&bitmap);
+        bitmap[5] = 1;
+        recorder.capture(&bitmap);
+        recorder.capture(&bitmap);
+        recorder.capture(&bitmap);
+        assert_eq!(recorder.len(), 2);
+        assert_eq!(recorder.frames[0].repeat, 2);
+        assert_eq!(recorder.frames[1].repeat, 3);
+    }
+
+    #[test]
+    fn write_gif() {
+        let mut recorder = Recorder::new(Format::Gif, Palette::default(), 2, Filter::None).unwrap();
+        let mut bitmap = [0; NPIXELS];
+        recorder.capture(&bitmap);
+        bitmap[0] = 1;
+        recorder.capture(&bitmap);
+
+        let mut out = Vec::new();
+        recorder.write(&mut out).unwrap();
+        assert_eq!(&out[0..6], b"GIF89a");
+        assert_eq!(&out[6..10], &[128, 0, 64, 0]);
+    }
+
+    #[test]
+    fn rejects_gifs_too_big() {
+        let too_big = Recorder::new(Format::Gif, Palette::default(), 1024, Filter::None);
+        assert!(too_big.is_err());
+        assert!(Recorder::new(Format::Gif, Palette::default(), 1023, Filter::None).is_ok());
+        assert!(Recorder::new(Format::Apng, Palette::default(), 1024, Filter::None).is_ok());
+    }
+
+    #[test]
+    fn write_apng() {
+        let mut recorder = Recorder::new(Format::Apng, Palette::default(), 1, Filter::None).unwrap();
+        let mut bitmap = [0; NPIXELS];
+        recorder.capture(&bitmap);
+        bitmap[0] = 1;
+        recorder.capture(&bitmap);
+
+        let mut out = Vec::new();
+        recorder.write(&mut out).unwrap();
+        assert_eq!(&out[1..4], b"PNG");
+        assert!(out.windows(4).any(|w| w == b"acTL"));
+        assert_eq!(out.windows(4).filter(|w| *w == b"fcTL").count(), 2);
+    }
+
+    #[test]
+    fn write_filtered() {
+        for format in &[Format::Gif, Format::Apng] {
+            let mut recorder = Recorder::new(*format, Palette::default(), 2, Filter::Crt).unwrap();
+            let mut bitmap = [0; NPIXELS];
+            bitmap[0] = 1;
+            recorder.capture(&bitmap);
+
+            let mut out = Vec::new();
+            recorder.write(&mut out).unwrap();
+            assert!(!out.is_empty());
+        }
+    }
+}
@@ -0,0 +1,215 @@
+#[cfg(feature = "png")]
+use png;
+#[cfg(feature = "png")]
+use std::fs::File;
+#[cfg(feature = "png")]
+use std::io::{self, BufWriter, Write};
+#[cfg(feature = "png")]
+use std::path::Path;
+use std::str::FromStr;
+use super::{HEIGHT, NPIXELS, WIDTH};
+
+/// The colors used to turn the chip's monochrome display into an image.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Palette {
+    /// The RGB color of pixels that are off.
+    pub background: [u8; 3],
+    /// The RGB color of pixels that are on.
+    pub foreground: [u8; 3],
+}
+
+impl Default for Palette {
+    /// Constructs the yellow on black palette used by the SDL window.
+    fn default() -> Self {
+        Palette {
+            background: [0x00, 0x00, 0x00],
+            foreground: [0xFF, 0xFF, 0x00],
+        }
+    }
+}
+
+impl Palette {
+    /// Returns the color for the given pixel value, which is the background
+    /// for 0 and the foreground for anything else.
+    pub fn color(&self, index: u8) -> [u8; 3] {
+        if 0 == index { self.background } else { self.foreground }
+    }
+
+    /// Returns the palette as a flat list of RGB triplets, with the background
+    /// at index 0 and the foreground at index 1.  This is the layout expected
+    /// by indexed image formats such as GIF and PNG.
+    pub fn to_rgb_table(&self) -> Vec<u8> {
+        let mut table = Vec::with_capacity(6);
+        table.extend_from_slice(&self.background);
+        table.extend_from_slice(&self.foreground);
+        table
+    }
+}
+
+impl FromStr for Palette {
+    type Err = String;
+
+    /// Parses a palette from two hex colors separated by a comma, the
+    /// background then the foreground, such as `000000,ffff00`.  Colors may
+    /// start with a `#`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let colors: Vec<&str> = s.split(',').map(str::trim).collect();
+        if colors.len() != 2 {
+            return Err(format!("Expected two comma separated colors, got {}", s));
+        }
+        Ok(Palette {
+            background: parse_color(colors[0])?,
+            foreground: parse_color(colors[1])?,
+        })
+    }
+}
+
+/// Parses a color written as six hex digits, optionally starting with a `#`.
+fn parse_color(s: &str) -> Result<[u8; 3], String> {
+    let hex = s.trim_start_matches('#');
+    if hex.len() != 6 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(format!("Expected a color like ff8800, got {}", s));
+    }
+    let channel = |i: usize| u8::from_str_radix(&hex[i..i + 2], 16).unwrap();
+    Ok([channel(0), channel(2), channel(4)])
+}
+
+/// Scales the given bitmap up by the given integer factor using nearest
+/// neighbor sampling.
+///
+/// The result holds one byte per pixel, 0 for off and 1 for on, so it can be
+/// used directly as indices into `Palette::to_rgb_table`.
+pub fn upscale(bitmap: &[u8; NPIXELS], scale: u32) -> Vec<u8> {
+    let scale = scale as usize;
+    let width = WIDTH as usize;
+    let height = HEIGHT as usize;
+    let mut out = Vec::with_capacity(NPIXELS * scale * scale);
+    for y in 0..height {
+        let row_start = out.len();
+        for x in 0..width {
+            let value = if 0 != bitmap[y * width + x] { 1 } else { 0 };
+            for _ in 0..scale {
+                out.push(value);
+            }
+        }
+        // Repeat the finished row for the rest of the scaled row's height
+        for _ in 1..scale {
+            out.extend_from_within(row_start..row_start + width * scale);
+        }
+    }
+    out
+}
+
+/// An RGBA image, stored row by row with four bytes per pixel.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Image {
+    /// The width of the image (in pixels).
+    pub width: u32,
+    /// The height of the image (in pixels).
+    pub height: u32,
+    /// The pixel data, `width * height * 4` bytes long.
+    pub pixels: Vec<u8>,
+}
+
+impl Image {
+    /// Renders the given bitmap with the given palette, scaled up by the given
+    /// integer factor.
+    pub fn from_bitmap(bitmap: &[u8; NPIXELS], palette: &Palette, scale: u32) -> Self {
+        let indices = upscale(bitmap, scale);
+        let mut pixels = Vec::with_capacity(indices.len() * 4);
+        for index in indices {
+            pixels.extend_from_slice(&palette.color(index));
+            pixels.push(0xFF);
+        }
+        Image {
+            width: WIDTH * scale,
+            height: HEIGHT * scale,
+            pixels: pixels,
+        }
+    }
+
+    /// Encodes the image as a PNG and saves it to the given path.
+    #[cfg(feature = "png")]
+    pub fn save_png<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let file = File::create(path)?;
+        self.write_png(BufWriter::new(file))
+    }
+
+    /// Encodes the image as a PNG to the given writer.
+    #[cfg(feature = "png")]
+    pub fn write_png<W: Write>(&self, w: W) -> io::Result<()> {
+        let mut encoder = png::Encoder::new(w, self.width, self.height);
+        encoder.set_color(png::ColorType::Rgba);
+        encoder.set_depth(png::BitDepth::Eight);
+        let mut writer = encoder.write_header()?;
+        writer.write_image_data(&self.pixels)?;
+        writer.finish()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{upscale, Image, Palette};
+    use NPIXELS;
+
+    #[test]
+    fn upscale_by_two() {
+        let mut bitmap = [0; NPIXELS];
+        bitmap[1] = 1;
+        bitmap[64] = 1;
+
+        let scaled = upscale(&bitmap, 2);
+        assert_eq!(scaled.len(), NPIXELS * 4);
+        assert_eq!(&scaled[0..4], &[0, 0, 1, 1]);
+        assert_eq!(&scaled[128..132], &[0, 0, 1, 1]);
+        assert_eq!(&scaled[256..260], &[1, 1, 0, 0]);
+        assert_eq!(&scaled[384..388], &[1, 1, 0, 0]);
+    }
+
+    #[test]
+    fn rgb_table() {
+        let palette = Palette::default();
+        assert_eq!(palette.to_rgb_table(), vec![0, 0, 0, 0xFF, 0xFF, 0x00]);
+    }
+
+    #[test]
+    fn parse_palette() {
+        let palette: Palette = "#102030, A0b0C0".parse().unwrap();
+        assert_eq!(palette.background, [0x10, 0x20, 0x30]);
+        assert_eq!(palette.foreground, [0xA0, 0xB0, 0xC0]);
+
+        assert!("102030".parse::<Palette>().is_err());
+        assert!("102030,a0b0".parse::<Palette>().is_err());
+        assert!("102030,a0b0cg".parse::<Palette>().is_err());
+    }
+
+    #[test]
+    fn image_from_bitmap() {
+        let mut bitmap = [0; NPIXELS];
+        bitmap[1] = 1;
+        let palette = Palette {
+            background: [1, 2, 3],
+            foreground: [4, 5, 6],
+        };
+
+        let image = Image::from_bitmap(&bitmap, &palette, 3);
+        assert_eq!(image.width, 192);
+        assert_eq!(image.height, 96);
+        assert_eq!(image.pixels.len(), 192 * 96 * 4);
+        assert_eq!(&image.pixels[0..4], &[1, 2, 3, 0xFF]);
+        assert_eq!(&image.pixels[12..16], &[4, 5, 6, 0xFF]);
+        assert_eq!(&image.pixels[(2 * 192 + 5) * 4..(2 * 192 + 6) * 4], &[4, 5, 6, 0xFF]);
+        assert_eq!(&image.pixels[(2 * 192 + 6) * 4..(2 * 192 + 7) * 4], &[1, 2, 3, 0xFF]);
+    }
+
+    #[test]
+    #[cfg(feature = "png")]
+    fn write_png() {
+        let image = Image::from_bitmap(&[0; NPIXELS], &Palette::default(), 1);
+        let mut out = Vec::new();
+        image.write_png(&mut out).unwrap();
+        assert_eq!(&out[0..8], b"\x89PNG\r\n\x1a\n");
+        assert_eq!(&out[16..24], &[0, 0, 0, 64, 0, 0, 0, 32]);
+    }
+}
@@ -0,0 +1,800 @@
+//! A JSON-RPC 2.0 server for driving the emulator from scripts, started with
+//! `--rpc`.  It listens on a port on localhost, or on a Unix socket, and
+//! takes a request per line, answering each with a response on a line of
+//! its own.
+//!
+//! Connections are read and written on threads of their own, and calls are
+//! handled on the emulator's thread between frames, so they always see the
+//! chip between whole frames, or stopped at a breakpoint.  Each client has a
+//! queue of lines to send it, and a client that lets its queue fill up misses
+//! events, or is hung up on if a response doesn't fit, so slow clients never
+//! hold up the emulator.  Calls still on their way from a client that was
+//! hung up on are ignored.  Clients that
+//! subscribe to events are sent them as notifications, such as
+//! `{"jsonrpc":"2.0","method":"beep","params":{"frame":120}}`.
+//!
+//! The methods are:
+//!
+//! * `load {path}`: plays another ROM, with its settings.
+//! * `pause`, `resume`, `step {frames}`: pause, resume, or pause and run a
+//!   number of frames (1 by default).
+//! * `press {key}`, `release {key}`: hold a key down or let it go, on top of
+//!   the keyboard.
+//! * `read_memory {address, length}`, `write_memory {address, bytes}`.
+//! * `registers`, `set_registers {v, i, pc, delay, sound}`, where every
+//!   parameter of `set_registers` is optional.
+//! * `screenshot {path}`: saves a PNG, or without a path returns the display
+//!   as rows with a digit per pixel.
+//! * `save_state {path}`, `load_state {path}` or `load_state {state}`: states
+//!   go to and from files, or are passed as hex without a path.
+//! * `set_breakpoint {address}`, `clear_breakpoint {address}`, `breakpoints`.
+//! * `subscribe {events}`, `unsubscribe {events}`, with events from `frame`,
+//!   `beep` and `breakpoint`.
+//! * `status`, and `quit`.
+//!
+//! Files are only read and written in the directory given by `--rpc-dir`,
+//! or the current directory, and paths are relative to it, so that clients
+//! can't reach other files.
+//!
+//! In netplay, the methods that change the game, `load`, `write_memory`,
+//! `set_registers` and `load_state`, fail, since they would only change this
+//! player's game.
+
+use chip8::Chip8;
+use clap::ArgMatches;
+use config::Settings;
+use serde_json::{self, Map, Value};
+use std::fs;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{Shutdown, TcpListener};
+#[cfg(unix)]
+use std::os::unix::fs::FileTypeExt;
+#[cfg(unix)]
+use std::os::unix::net::UnixListener;
+use std::path::{Component, Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// The events clients can subscribe to.
+pub const EVENTS: [&str; 3] = ["frame", "beep", "breakpoint"];
+
+/// The methods that are turned off in netplay.
+const CHANGES: [&str; 4] = ["load", "write_memory", "set_registers", "load_state"];
+
+/// The JSON-RPC error codes used.
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+const FAILED: i64 = -32000;
+
+/// How many lines can wait to be sent to each client, and how many messages
+/// can wait for the emulator's thread before connections stop reading.
+const QUEUE: usize = 256;
+
+/// How long quitting waits for the last lines to be sent to clients.
+const GOODBYE: Duration = Duration::from_secs(1);
+
+/// Shuts a connection down, which ends its threads even if they are stuck
+/// reading or writing.
+type Hangup = Box<dyn Fn() + Send>;
+
+/// What connections tell the emulator's thread.
+enum Message {
+    /// A client connected, and is sent lines through the sender by the
+    /// thread.
+    Connected(usize, SyncSender<String>, JoinHandle<()>, Hangup),
+    /// A client made a call.
+    Call(usize, Value),
+    /// A client sent a line that isn't JSON.
+    Unreadable(usize, String),
+    /// A client hung up.
+    Disconnected(usize),
+}
+
+/// A connected client.
+struct Client {
+    id: usize,
+    lines: SyncSender<String>,
+    writer: JoinHandle<()>,
+    hang_up: Hangup,
+    /// Whether the client was hung up on, and is only waiting for its
+    /// threads to notice.
+    hung_up: bool,
+    /// The events the client subscribed to.
+    events: Vec<String>,
+}
+
+/// Why a call failed, as a JSON-RPC error code and message.
+struct Fault {
+    code: i64,
+    message: String,
+}
+
+impl Fault {
+    fn new(code: i64, message: String) -> Self {
+        Fault {
+            code: code,
+            message: message,
+        }
+    }
+}
+
+impl From<String> for Fault {
+    fn from(message: String) -> Self {
+        Fault::new(FAILED, message)
+    }
+}
+
+/// The server, which is polled by the frontend between frames.
+pub struct Server {
+    messages: Receiver<Message>,
+    clients: Vec<Client>,
+    /// The Unix socket listened on, which is removed when the server is.
+    #[cfg(unix)]
+    socket: Option<PathBuf>,
+    /// The directory calls can read and write files in.
+    dir: PathBuf,
+    /// The keys held down by calls.
+    keys: [u8; 16],
+    breakpoints: Vec<u16>,
+    /// The opcodes left in the frame stopped at a breakpoint, if it is.
+    stopped: Option<u32>,
+    /// Whether to go on from the breakpoint in the next frame.
+    resume: bool,
+    /// Frames to run while paused, from `step`.
+    steps: u64,
+    /// The number of frames run.
+    frame: u64,
+    quit: bool,
+}
+
+impl Server {
+    /// Starts listening on the given address, which is a port on localhost,
+    /// or elsewhere the path of a Unix socket, with calls reading and writing
+    /// files in the given directory.
+    pub fn listen(address: &str, dir: &str) -> Result<Server, String> {
+        let dir = fs::canonicalize(dir).map_err(|why| format!("Couldn't find {}: {}", dir, why))?;
+        let (sender, receiver) = mpsc::sync_channel(QUEUE);
+        let mut server = Server {
+            messages: receiver,
+            clients: Vec::new(),
+            #[cfg(unix)]
+            socket: None,
+            dir: dir,
+            keys: [0; 16],
+            breakpoints: Vec::new(),
+            stopped: None,
+            resume: false,
+            steps: 0,
+            frame: 0,
+            quit: false,
+        };
+
+        if let Ok(port) = address.parse::<u16>() {
+            let listener = TcpListener::bind(("127.0.0.1", port))
+                .map_err(|why| format!("Couldn't listen on port {}: {}", port, why))?;
+            println!("Listening for JSON-RPC calls on {}", listener.local_addr().unwrap());
+            thread::spawn(move || {
+                for (id, stream) in listener.incoming().enumerate() {
+                    if let Ok(stream) = stream {
+                        let _ = stream.set_nodelay(true);
+                        if let (Ok(writer), Ok(closer)) = (stream.try_clone(), stream.try_clone()) {
+                            let hang_up = move || {
+                                let _ = closer.shutdown(Shutdown::Both);
+                            };
+                            serve(id, stream, writer, Box::new(hang_up), sender.clone());
+                        }
+                    }
+                }
+            });
+            return Ok(server);
+        }
+        server.listen_unix(address, sender)?;
+        Ok(server)
+    }
+
+    /// Listens on a Unix socket, replacing an old one left at the path.
+    #[cfg(unix)]
+    fn listen_unix(&mut self, path: &str, sender: SyncSender<Message>) -> Result<(), String> {
+        if fs::metadata(path).map(|metadata| metadata.file_type().is_socket()).unwrap_or(false) {
+            fs::remove_file(path).map_err(|why| format!("Couldn't remove {}: {}", path, why))?;
+        }
+        let listener = UnixListener::bind(path)
+            .map_err(|why| format!("Couldn't listen on {}: {}", path, why))?;
+        println!("Listening for JSON-RPC calls on {}", path);
+        self.socket = Some(PathBuf::from(path));
+        thread::spawn(move || {
+            for (id, stream) in listener.incoming().enumerate() {
+                if let Ok(stream) = stream {
+                    if let (Ok(writer), Ok(closer)) = (stream.try_clone(), stream.try_clone()) {
+                        let hang_up = move || {
+                            let _ = closer.shutdown(Shutdown::Both);
+                        };
+                        serve(id, stream, writer, Box::new(hang_up), sender.clone());
+                    }
+                }
+            }
+        });
+        Ok(())
+    }
+
+    #[cfg(not(unix))]
+    fn listen_unix(&mut self, address: &str, _: SyncSender<Message>) -> Result<(), String> {
+        Err(format!("Expected a port for --rpc, not {}", address))
+    }
+
+    /// Handles the calls that have come in since the last frame, then holds
+    /// down the keys calls have pressed on top of the chip's keys.  Returns
+    /// whether to keep going, which is until a call asks to quit.
+    pub fn poll(&mut self,
+                chip: &mut Chip8,
+                settings: &mut Settings,
+                game: &mut Vec<u8>,
+                matches: &ArgMatches,
+                paused: &mut bool,
+                netplay: bool) -> bool {
+        while let Ok(message) = self.messages.try_recv() {
+            match message {
+                Message::Connected(id, lines, writer, hang_up) => {
+                    self.clients.push(Client {
+                        id: id,
+                        lines: lines,
+                        writer: writer,
+                        hang_up: hang_up,
+                        hung_up: false,
+                        events: Vec::new(),
+                    })
+                }
+                Message::Unreadable(id, why) => {
+                    self.send(id, &response(Value::Null, Err(Fault::new(PARSE_ERROR, why))));
+                }
+                Message::Disconnected(id) => self.clients.retain(|client| client.id != id),
+                Message::Call(client, _) if self.is_hung_up(client) => {}
+                Message::Call(client, request) => {
+                    let method = match request["method"].as_str() {
+                        Some(method) if request["jsonrpc"] == "2.0" => method,
+                        _ => {
+                            // Invalid requests are answered even without ids
+                            let id = request.get("id").cloned().unwrap_or(Value::Null);
+                            let fault = Fault::new(INVALID_REQUEST,
+                                                   "Expected a JSON-RPC 2.0 request".to_string());
+                            self.send(client, &response(id, Err(fault)));
+                            continue;
+                        }
+                    };
+                    let result = if netplay && CHANGES.contains(&method) {
+                        Err(Fault::from(format!("{} is turned off in netplay", method)))
+                    } else {
+                        let params = &request["params"];
+                        self.dispatch(client, method, params, chip, settings, game, matches, paused)
+                    };
+                    // Notifications, without ids, aren't answered
+                    if let Some(id) = request.get("id") {
+                        self.send(client, &response(id.clone(), result));
+                    }
+                }
+            }
+        }
+        for (key, &held) in chip.key.iter_mut().zip(self.keys.iter()) {
+            *key |= held;
+        }
+        !self.quit
+    }
+
+    /// Whether a call has asked for a frame to be run while paused, which
+    /// it is then counted as.
+    pub fn take_step(&mut self) -> bool {
+        if self.steps == 0 {
+            return false;
+        }
+        self.steps -= 1;
+        true
+    }
+
+    /// Runs a frame, or the rest of one stopped at a breakpoint once a call
+    /// says to go on, stopping at any breakpoints.  Returns whether the frame
+    /// finished.
+    pub fn run_frame(&mut self, chip: &mut Chip8, ipf: u32) -> bool {
+        let mut cycles = match self.stopped {
+            Some(_) if !self.resume => return false,
+            Some(cycles) => cycles,
+            None if self.breakpoints.is_empty() => {
+                chip.emulate_frame(ipf);
+                self.finish_frame(chip);
+                return true;
+            }
+            None => ipf,
+        };
+        let resume = self.resume;
+        self.resume = false;
+        if chip.emulate_frame_until(&mut cycles, &self.breakpoints, resume) {
+            self.stopped = None;
+            self.finish_frame(chip);
+            true
+        } else {
+            self.stopped = Some(cycles);
+            let mut params = Map::new();
+            params.insert("frame".to_string(), Value::from(self.frame));
+            params.insert("address".to_string(), Value::from(chip.pc()));
+            self.notify("breakpoint", params);
+            false
+        }
+    }
+
+    /// Counts a frame that was run, and tells subscribers about it.
+    pub fn finish_frame(&mut self, chip: &Chip8) {
+        self.frame += 1;
+        let mut params = Map::new();
+        params.insert("frame".to_string(), Value::from(self.frame));
+        if chip.make_sound {
+            self.notify("beep", params.clone());
+        }
+        self.notify("frame", params);
+    }
+
+    /// Sends an event to the clients that subscribed to it, except those
+    /// whose queues are full.
+    fn notify(&self, event: &str, params: Map<String, Value>) {
+        let mut notification = Map::new();
+        notification.insert("jsonrpc".to_string(), Value::from("2.0"));
+        notification.insert("method".to_string(), Value::from(event));
+        notification.insert("params".to_string(), Value::Object(params));
+        let line = Value::Object(notification).to_string();
+        for client in &self.clients {
+            if !client.hung_up && client.events.iter().any(|subscribed| subscribed == event) {
+                let _ = client.lines.try_send(line.clone());
+            }
+        }
+    }
+
+    /// Sends a line to a client, if it is still connected, hanging up on it
+    /// if its queue is full.
+    fn send(&mut self, id: usize, response: &Value) {
+        let client = match self.clients.iter_mut().find(|client| client.id == id && !client.hung_up) {
+            Some(client) => client,
+            None => return,
+        };
+        if let Err(TrySendError::Full(_)) = client.lines.try_send(response.to_string()) {
+            (client.hang_up)();
+            client.hung_up = true;
+        }
+    }
+
+    /// Whether the given client has been hung up on, or has gone.
+    fn is_hung_up(&self, id: usize) -> bool {
+        !self.clients.iter().any(|client| client.id == id && !client.hung_up)
+    }
+
+    /// Runs a method.
+    #[allow(clippy::too_many_arguments)]
+    fn dispatch(&mut self,
+                client: usize,
+                method: &str,
+                params: &Value,
+                chip: &mut Chip8,
+                settings: &mut Settings,
+                game: &mut Vec<u8>,
+                matches: &ArgMatches,
+                paused: &mut bool) -> Result<Value, Fault> {
+        match method {
+            "load" => {
+                let path = file(&self.dir, params, "path")?;
+                let (new_settings, new_game, new_chip) = ::start(matches, &path.to_string_lossy())?;
+                *settings = new_settings;
+                *game = new_game;
+                *chip = new_chip;
+                self.stopped = None;
+                self.resume = false;
+            }
+            "pause" => *paused = true,
+            "resume" => {
+                *paused = false;
+                self.resume = self.stopped.is_some();
+            }
+            "step" => {
+                *paused = true;
+                self.steps += optional_number(params, "frames")?.unwrap_or(1);
+                self.resume = self.stopped.is_some();
+            }
+            "press" | "release" => {
+                let key = number(params, "key")?;
+                if key > 0xF {
+                    return Err(Fault::new(INVALID_PARAMS, format!("Bad key {}", key)));
+                }
+                self.keys[key as usize] = (method == "press") as u8;
+            }
+            "read_memory" => {
+                let address = at_most(params, "address", 0xFFFF)? as usize;
+                let length = number(params, "length")? as usize;
+                let memory = chip.memory();
+                if length > memory.len() {
+                    return Err(Fault::new(INVALID_PARAMS,
+                                          format!("The memory is only {} bytes", memory.len())));
+                }
+                let bytes: Vec<u8> =
+                    (0..length).map(|i| memory[(address + i) % memory.len()]).collect();
+                return Ok(object(vec![("bytes", Value::from(bytes))]));
+            }
+            "write_memory" => {
+                let address = at_most(params, "address", 0xFFFF)?;
+                let bytes = bytes(params, "bytes")?;
+                chip.write_memory(address as u16, &bytes).map_err(|why| why.to_string())?;
+            }
+            "registers" => return Ok(registers(chip)),
+            "set_registers" => {
+                let mut cpu = chip.cpu_state();
+                if !params["v"].is_null() {
+                    let v = bytes(params, "v")?;
+                    if v.len() != 16 {
+                        return Err(Fault::new(INVALID_PARAMS,
+                                              "Expected 16 registers in v".to_string()));
+                    }
+                    cpu.registers.copy_from_slice(&v);
+                }
+                if let Some(index) = optional_at_most(params, "i", 0xFFFF)? {
+                    cpu.index = index as u16;
+                }
+                if let Some(pc) = optional_at_most(params, "pc", 0xFFFF)? {
+                    cpu.pc = pc as u16;
+                }
+                if let Some(delay) = optional_at_most(params, "delay", 0xFF)? {
+                    cpu.delay_timer = delay as u8;
+                }
+                if let Some(sound) = optional_at_most(params, "sound", 0xFF)? {
+                    cpu.sound_timer = sound as u8;
+                }
+                chip.set_cpu_state(&cpu).map_err(|why| why.to_string())?;
+                self.stopped = None;
+            }
+            "screenshot" => {
+                if params["path"].is_null() {
+                    return Ok(display(chip));
+                }
+                let path = file(&self.dir, params, "path")?;
+                chip.screenshot(&settings.palette, settings.scale, settings.filter)
+                    .save_png(&path)
+                    .map_err(|why| format!("Couldn't save {}: {}", path.display(), why))?;
+            }
+            "save_state" => {
+                let mut state = vec![0; chip.state_size()];
+                chip.save_state(&mut state).map_err(|why| why.to_string())?;
+                if params["path"].is_null() {
+                    return Ok(object(vec![("state", Value::from(to_hex(&state)))]));
+                }
+                let path = file(&self.dir, params, "path")?;
+                fs::write(&path, &state)
+                    .map_err(|why| format!("Couldn't save {}: {}", path.display(), why))?;
+            }
+            "load_state" => {
+                let state = if params["path"].is_null() {
+                    from_hex(string(params, "state")?)
+                        .ok_or_else(|| Fault::new(INVALID_PARAMS, "Bad hex in state".to_string()))?
+                } else {
+                    let path = file(&self.dir, params, "path")?;
+                    fs::read(&path)
+                        .map_err(|why| format!("Couldn't read {}: {}", path.display(), why))?
+                };
+                chip.load_state(&state).map_err(|why| why.to_string())?;
+                self.stopped = None;
+                self.resume = false;
+            }
+            "set_breakpoint" => {
+                let address = at_most(params, "address", 0xFFFF)? as u16;
+                if !self.breakpoints.contains(&address) {
+                    self.breakpoints.push(address);
+                }
+            }
+            "clear_breakpoint" => {
+                let address = at_most(params, "address", 0xFFFF)? as u16;
+                self.breakpoints.retain(|&breakpoint| breakpoint != address);
+            }
+            "breakpoints" => return Ok(Value::from(self.breakpoints.clone())),
+            "subscribe" | "unsubscribe" => {
+                let events = match params["events"].as_array() {
+                    Some(events) => events,
+                    None => return Err(invalid("events")),
+                };
+                let mut names = Vec::new();
+                for event in events {
+                    match event.as_str().filter(|event| EVENTS.contains(event)) {
+                        Some(event) => names.push(event.to_string()),
+                        None => {
+                            return Err(Fault::new(INVALID_PARAMS,
+                                                  format!("Unknown event {}", event)))
+                        }
+                    }
+                }
+                let client = match self.clients.iter_mut().find(|c| c.id == client) {
+                    Some(client) => client,
+                    None => return Err(Fault::from("The client has hung up".to_string())),
+                };
+                client.events.retain(|event| !names.contains(event));
+                if method == "subscribe" {
+                    client.events.extend(names);
+                }
+            }
+            "status" => {
+                let mut status = Map::new();
+                status.insert("paused".to_string(), Value::from(*paused || self.stopped.is_some()));
+                status.insert("frame".to_string(), Value::from(self.frame));
+                status.insert("pc".to_string(), Value::from(chip.pc()));
+                status.insert("breakpoint".to_string(), match self.stopped {
+                    Some(_) => Value::from(chip.pc()),
+                    None => Value::Null,
+                });
+                return Ok(Value::Object(status));
+            }
+            "quit" => self.quit = true,
+            _ => return Err(Fault::new(METHOD_NOT_FOUND, format!("Unknown method {}", method))),
+        }
+        Ok(Value::Null)
+    }
+}
+
+impl Drop for Server {
+    /// Gives clients a moment to be sent the rest of their lines, so that they
+    /// get the answer to `quit`, and removes the Unix socket.  Clients that
+    /// aren't reading are hung up on.
+    fn drop(&mut self) {
+        let clients: Vec<Client> = self.clients.drain(..).collect();
+        let deadline = Instant::now() + GOODBYE;
+        while clients.iter().any(|client| !client.writer.is_finished()) &&
+              Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+        }
+        for client in clients {
+            (client.hang_up)();
+        }
+        #[cfg(unix)]
+        {
+            if let Some(ref socket) = self.socket {
+                let _ = fs::remove_file(socket);
+            }
+        }
+    }
+}
+
+/// Reads and writes a connection on threads of their own, passing what is
+/// read on to the emulator's thread.
+fn serve<R, W>(id: usize, reader: R, mut writer: W, hang_up: Hangup, messages: SyncSender<Message>)
+    where R: Read + Send + 'static,
+          W: Write + Send + 'static
+{
+    let (lines, outgoing) = mpsc::sync_channel::<String>(QUEUE);
+    let writer = thread::spawn(move || {
+        for mut line in outgoing {
+            // In one write, so that the line isn't held back waiting for an ACK
+            line.push('\n');
+            if writer.write_all(line.as_bytes()).and_then(|_| writer.flush()).is_err() {
+                break;
+            }
+        }
+    });
+    if messages.send(Message::Connected(id, lines, writer, hang_up)).is_err() {
+        return;
+    }
+    thread::spawn(move || {
+        for line in BufReader::new(reader).lines() {
+            let line = match line {
+                Ok(line) => line,
+                Err(_) => break,
+            };
+            if line.trim().is_empty() {
+                continue;
+            }
+            let message = match serde_json::from_str(&line) {
+                Ok(request) => Message::Call(id, request),
+                Err(why) => Message::Unreadable(id, why.to_string()),
+            };
+            if messages.send(message).is_err() {
+                break;
+            }
+        }
+        let _ = messages.send(Message::Disconnected(id));
+    });
+}
+
+/// A response to a request with the given id.
+fn response(id: Value, result: Result<Value, Fault>) -> Value {
+    let mut response = Map::new();
+    response.insert("jsonrpc".to_string(), Value::from("2.0"));
+    match result {
+        Ok(result) => {
+            response.insert("result".to_string(), result);
+        }
+        Err(fault) => {
+            let mut error = Map::new();
+            error.insert("code".to_string(), Value::from(fault.code));
+            error.insert("message".to_string(), Value::from(fault.message));
+            response.insert("error".to_string(), Value::Object(error));
+        }
+    }
+    response.insert("id".to_string(), id);
+    Value::Object(response)
+}
+
+/// An object with the given fields.
+fn object(fields: Vec<(&str, Value)>) -> Value {
+    Value::Object(fields.into_iter().map(|(name, value)| (name.to_string(), value)).collect())
+}
+
+/// The chip's registers, stack and timers.
+fn registers(chip: &Chip8) -> Value {
+    let cpu = chip.cpu_state();
+    object(vec![
+        ("v", Value::from(cpu.registers.to_vec())),
+        ("i", Value::from(cpu.index)),
+        ("pc", Value::from(cpu.pc)),
+        ("stack", Value::from(cpu.stack[..cpu.sp as usize].to_vec())),
+        ("delay", Value::from(cpu.delay_timer)),
+        ("sound", Value::from(cpu.sound_timer)),
+    ])
+}
+
+/// The display, as rows with a digit per pixel.
+fn display(chip: &Chip8) -> Value {
+    let graphics = &chip.graphics;
+    let rows: Vec<Value> = (0..graphics.height())
+        .map(|y| Value::from(graphics.row(y).map(|pixel| (b'0' + pixel) as char).collect::<String>()))
+        .collect();
+    object(vec![
+        ("width", Value::from(graphics.width())),
+        ("height", Value::from(graphics.height())),
+        ("display", Value::from(rows)),
+    ])
+}
+
+/// The fault for a missing or mistyped parameter.
+fn invalid(name: &str) -> Fault {
+    Fault::new(INVALID_PARAMS, format!("Expected {} in the params", name))
+}
+
+/// A parameter that is a number.
+fn number(params: &Value, name: &str) -> Result<u64, Fault> {
+    params[name].as_u64().ok_or_else(|| invalid(name))
+}
+
+/// A parameter that is a number, if it is given.
+fn optional_number(params: &Value, name: &str) -> Result<Option<u64>, Fault> {
+    if params[name].is_null() {
+        Ok(None)
+    } else {
+        number(params, name).map(Some)
+    }
+}
+
+/// A parameter that is a number no bigger than the given one.
+fn at_most(params: &Value, name: &str, max: u64) -> Result<u64, Fault> {
+    let value = number(params, name)?;
+    if value > max {
+        return Err(Fault::new(INVALID_PARAMS, format!("{} must be at most {:#X}", name, max)));
+    }
+    Ok(value)
+}
+
+/// A parameter that is a number no bigger than the given one, if it is given.
+fn optional_at_most(params: &Value, name: &str, max: u64) -> Result<Option<u64>, Fault> {
+    if params[name].is_null() {
+        Ok(None)
+    } else {
+        at_most(params, name, max).map(Some)
+    }
+}
+
+/// A parameter that is a string.
+fn string<'a>(params: &'a Value, name: &str) -> Result<&'a str, Fault> {
+    params[name].as_str().ok_or_else(|| invalid(name))
+}
+
+/// A parameter that is the path of a file in the given directory, which
+/// can't lead outside it, even through symbolic links.
+fn file(dir: &Path, params: &Value, name: &str) -> Result<PathBuf, Fault> {
+    let path = Path::new(string(params, name)?);
+    let outside = || Fault::new(INVALID_PARAMS, format!("{} isn't in {}", path.display(), dir.display()));
+    if path.has_root() || path.components().any(|part| part == Component::ParentDir) {
+        return Err(outside());
+    }
+    // Files that don't exist yet are looked for through their directory
+    let file = dir.join(path);
+    let real = match fs::canonicalize(&file) {
+        Ok(real) => real,
+        Err(_) => {
+            let parent = file.parent().and_then(|parent| fs::canonicalize(parent).ok());
+            match (parent, file.file_name()) {
+                (Some(parent), Some(name)) => parent.join(name),
+                _ => return Ok(file),
+            }
+        }
+    };
+    if real.starts_with(dir) {
+        Ok(real)
+    } else {
+        Err(outside())
+    }
+}
+
+/// A parameter that is a list of bytes.
+fn bytes(params: &Value, name: &str) -> Result<Vec<u8>, Fault> {
+    let list = params[name].as_array().ok_or_else(|| invalid(name))?;
+    list.iter()
+        .map(|byte| byte.as_u64().filter(|&byte| byte <= 0xFF).map(|byte| byte as u8))
+        .collect::<Option<Vec<u8>>>()
+        .ok_or_else(|| invalid(name))
+}
+
+/// Writes bytes as hex.
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Reads bytes written as hex.
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if hex.len() % 2 != 0 || !hex.is_ascii() {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Client, Server, QUEUE};
+    use serde_json::Value;
+    use chip8::Chip8;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn leaves_stuck_clients_behind() {
+        let mut server = Server::listen("0", ".").unwrap();
+        // A client whose connection never takes another byte
+        let (lines, outgoing) = mpsc::sync_channel(QUEUE);
+        let writer = thread::spawn(move || {
+            let _outgoing = outgoing;
+            thread::sleep(Duration::from_secs(3600));
+        });
+        let closed = Arc::new(AtomicBool::new(false));
+        let hang_up = {
+            let closed = closed.clone();
+            move || closed.store(true, Ordering::SeqCst)
+        };
+        server.clients.push(Client {
+            id: 0,
+            lines: lines,
+            writer: writer,
+            hang_up: Box::new(hang_up),
+            hung_up: false,
+            events: vec!["frame".to_string()],
+        });
+
+        let chip = Chip8::default();
+        for _ in 0..QUEUE * 2 {
+            server.finish_frame(&chip);
+        }
+        assert!(!server.is_hung_up(0));
+        server.send(0, &Value::from("response"));
+        assert!(closed.load(Ordering::SeqCst));
+        assert!(server.is_hung_up(0));
+        assert!(server.is_hung_up(2));
+        server.clients.clear();
+
+        let started = Instant::now();
+        server.clients.push(Client {
+            id: 1,
+            lines: mpsc::sync_channel(QUEUE).0,
+            writer: thread::spawn(|| thread::sleep(Duration::from_secs(3600))),
+            hang_up: Box::new(|| ()),
+            hung_up: false,
+            events: Vec::new(),
+        });
+        drop(server);
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+}
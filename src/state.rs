@@ -0,0 +1,290 @@
+//! Save states, which hold everything a chip needs to carry on from where it
+//! was saved: its registers, stack, timers, display, memory and random
+//! number generator's state.  The quirks, engine, random number generator
+//! and memory hooks are settings rather than state, so they are left alone
+//! when a state is loaded.
+//!
+//! A state is a magic number and a version, followed by the chip's state in
+//! a fixed order with numbers in little-endian.  The display is always
+//! stored at the highest resolution, so a state's size only depends on the
+//! size of the chip's memory.
+
+use framebuffer::{Framebuffer, Resolution, MAX_HEIGHT, MAX_WIDTH};
+use memory::CAPACITY;
+use {Chip8, Error, NREG};
+
+/// The bytes every save state starts with.
+pub const MAGIC: [u8; 4] = *b"C8ST";
+
+/// The version of the save state format, which is changed whenever the
+/// format is.
+pub const VERSION: u8 = 3;
+
+/// The size of a save state without the chip's memory.
+const HEADER_SIZE: usize = 4 + 1 + 2 + NREG + 2 * 4 + 2 * 16 + 2 + 1 + 8 * 2 + 2 +
+                           (MAX_WIDTH * MAX_HEIGHT) as usize + 4;
+
+/// The size of a save state for a chip with the given size of memory.
+pub fn size(memory: usize) -> usize {
+    HEADER_SIZE + memory
+}
+
+/// Writes the chip's state at the start of the given buffer, returning the
+/// number of bytes written.
+pub fn save(chip: &Chip8, out: &mut [u8]) -> Result<usize, Error> {
+    let needed = size(chip.memory.len());
+    if out.len() < needed {
+        return Err(Error::BufferTooSmall { size: out.len(), needed: needed });
+    }
+
+    let mut writer = Writer { out: out, position: 0 };
+    writer.bytes(&MAGIC);
+    writer.bytes(&[VERSION]);
+    writer.u16(chip.opcode);
+    writer.bytes(&chip.reg);
+    writer.u16(chip.index);
+    writer.u16(chip.pc);
+    writer.u16(chip.start);
+    writer.u16(chip.sp);
+    for &address in chip.stack.iter() {
+        writer.u16(address);
+    }
+    writer.bytes(&[chip.timer_delay, chip.timer_sound, chip.make_sound as u8]);
+    writer.u64(chip.rng_state);
+    writer.u64(chip.cycles);
+
+    let resolution = match chip.graphics.resolution() {
+        Resolution::Low => 0,
+        Resolution::High => 1,
+    };
+    writer.bytes(&[resolution, chip.graphics.selected_planes()]);
+    for y in 0..MAX_HEIGHT {
+        for x in 0..MAX_WIDTH {
+            writer.bytes(&[chip.graphics.pixel(x, y)]);
+        }
+    }
+
+    writer.u32(chip.memory.len() as u32);
+    writer.bytes(chip.memory.bytes());
+    Ok(writer.position)
+}
+
+/// Puts the chip back to a saved state.  Fails without changing the chip if
+/// the state is damaged or was saved with another version of the format.
+pub fn load(chip: &mut Chip8, state: &[u8]) -> Result<(), Error> {
+    let mut reader = Reader { state: state, position: 0 };
+    if reader.bytes(4)? != MAGIC || reader.u8()? != VERSION {
+        return Err(Error::BadState);
+    }
+
+    let opcode = reader.u16()?;
+    let mut reg = [0; NREG];
+    reg.copy_from_slice(reader.bytes(NREG)?);
+    let index = reader.u16()?;
+    let pc = reader.u16()?;
+    let start = reader.u16()?;
+    let sp = reader.u16()?;
+    let mut stack = [0; 16];
+    for address in stack.iter_mut() {
+        *address = reader.u16()?;
+    }
+    if sp as usize > stack.len() {
+        return Err(Error::BadState);
+    }
+    let timer_delay = reader.u8()?;
+    let timer_sound = reader.u8()?;
+    let make_sound = reader.u8()? != 0;
+    let rng_state = reader.u64()?;
+    let cycles = reader.u64()?;
+
+    let mut graphics = Framebuffer::default();
+    match reader.u8()? {
+        0 => {}
+        1 => graphics.set_resolution(Resolution::High),
+        _ => return Err(Error::BadState),
+    }
+    graphics.select_planes(reader.u8()?);
+    let pixels = reader.bytes((MAX_WIDTH * MAX_HEIGHT) as usize)?;
+    for (i, &pixel) in pixels.iter().enumerate() {
+        let i = i as u32;
+        graphics.set_pixel(i % MAX_WIDTH, i / MAX_WIDTH, pixel);
+    }
+
+    let memory_size = reader.u32()? as usize;
+    if !memory_size.is_power_of_two() || memory_size > CAPACITY {
+        return Err(Error::BadState);
+    }
+    let memory = reader.bytes(memory_size)?;
+    if reader.position != state.len() {
+        return Err(Error::BadState);
+    }
+
+    chip.memory.resize(memory_size);
+    chip.memory.bytes_mut().copy_from_slice(memory);
+    chip.flush_cache();
+    chip.draw_flag = true;
+    chip.opcode = opcode;
+    chip.reg = reg;
+    chip.index = index;
+    chip.pc = pc;
+    chip.start = start;
+    chip.sp = sp;
+    chip.stack = stack;
+    chip.timer_delay = timer_delay;
+    chip.timer_sound = timer_sound;
+    chip.make_sound = make_sound;
+    chip.rng_state = rng_state;
+    chip.cycles = cycles;
+    chip.graphics = graphics;
+    Ok(())
+}
+
+/// Writes numbers into a buffer that is known to be big enough.
+struct Writer<'a> {
+    out: &'a mut [u8],
+    position: usize,
+}
+
+impl<'a> Writer<'a> {
+    fn bytes(&mut self, bytes: &[u8]) {
+        self.out[self.position..self.position + bytes.len()].copy_from_slice(bytes);
+        self.position += bytes.len();
+    }
+
+    fn u16(&mut self, value: u16) {
+        self.bytes(&value.to_le_bytes());
+    }
+
+    fn u32(&mut self, value: u32) {
+        self.bytes(&value.to_le_bytes());
+    }
+
+    fn u64(&mut self, value: u64) {
+        self.bytes(&value.to_le_bytes());
+    }
+}
+
+/// Reads numbers from a state, failing if it ends too soon.
+struct Reader<'a> {
+    state: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, count: usize) -> Result<&'a [u8], Error> {
+        let state = self.state;
+        let bytes = state.get(self.position..self.position + count).ok_or(Error::BadState)?;
+        self.position += count;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, Error> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, Error> {
+        let mut bytes = [0; 2];
+        bytes.copy_from_slice(self.bytes(2)?);
+        Ok(u16::from_le_bytes(bytes))
+    }
+
+    fn u32(&mut self) -> Result<u32, Error> {
+        let mut bytes = [0; 4];
+        bytes.copy_from_slice(self.bytes(4)?);
+        Ok(u32::from_le_bytes(bytes))
+    }
+
+    fn u64(&mut self) -> Result<u64, Error> {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(self.bytes(8)?);
+        Ok(u64::from_le_bytes(bytes))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use framebuffer::Resolution;
+    use platform::Platform;
+    use {Chip8, Error, Engine};
+
+    /// Draws random sprites forever, so that the display, the index, the
+    /// registers and the random numbers all keep changing.
+    const PROGRAM: [u8; 10] = [0xC0, 0x3F, 0xC1, 0x1F, 0xA0, 0x50, 0xD0, 0x15, 0x12, 0x00];
+
+    fn running(frames: u32) -> Chip8 {
+        let mut chip = Chip8::default();
+        chip.set_seed(7);
+        chip.load_hex(&PROGRAM);
+        for _ in 0..frames {
+            chip.emulate_frame(10);
+        }
+        chip
+    }
+
+    #[test]
+    fn saves_and_loads() {
+        let mut chip = running(10);
+        let mut state = vec![0; chip.state_size()];
+        assert_eq!(chip.save_state(&mut state), Ok(state.len()));
+
+        // Carrying on from a loaded state matches carrying on from the save
+        let mut loaded = Chip8::with_engine(Engine::Recompiler);
+        loaded.load_state(&state).unwrap();
+        for _ in 0..30 {
+            chip.emulate_frame(10);
+            loaded.emulate_frame(10);
+        }
+        assert_eq!(loaded.cpu_state(), chip.cpu_state());
+        assert_eq!(loaded.graphics, chip.graphics);
+        assert_eq!(loaded.memory(), chip.memory());
+        assert_eq!(loaded.cycles(), chip.cycles());
+    }
+
+    #[test]
+    fn keeps_the_resolution_and_planes() {
+        let mut chip = Chip8::default();
+        chip.graphics.set_resolution(Resolution::High);
+        chip.graphics.select_planes(0b11);
+        chip.graphics.set_pixel(127, 63, 2);
+        let mut state = vec![0; chip.state_size()];
+        chip.save_state(&mut state).unwrap();
+
+        let mut loaded = Chip8::default();
+        loaded.load_state(&state).unwrap();
+        assert_eq!(loaded.graphics.resolution(), Resolution::High);
+        assert_eq!(loaded.graphics.selected_planes(), 0b11);
+        assert_eq!(loaded.graphics.pixel(127, 63), 2);
+    }
+
+    #[test]
+    fn keeps_the_memory_size() {
+        let mut chip = Chip8::default();
+        chip.set_memory_size(Platform::XoChip.memory_size());
+        chip.write_memory(0xFFFF, &[0xAB]).unwrap();
+        let mut state = vec![0; chip.state_size()];
+        chip.save_state(&mut state).unwrap();
+
+        let mut loaded = Chip8::default();
+        loaded.load_state(&state).unwrap();
+        assert_eq!(loaded.memory().len(), 0x10000);
+        assert_eq!(loaded.memory()[0xFFFF], 0xAB);
+    }
+
+    #[test]
+    fn rejects_bad_states() {
+        let chip = running(5);
+        let mut small = vec![0; 100];
+        assert_eq!(chip.save_state(&mut small),
+                   Err(Error::BufferTooSmall { size: 100, needed: chip.state_size() }));
+
+        let mut state = vec![0; chip.state_size()];
+        chip.save_state(&mut state).unwrap();
+        let mut other = running(1);
+        let before = other.cpu_state();
+        assert_eq!(other.load_state(&state[..state.len() - 1]), Err(Error::BadState));
+        let mut wrong_version = state.clone();
+        wrong_version[4] += 1;
+        assert_eq!(other.load_state(&wrong_version), Err(Error::BadState));
+        assert_eq!(other.cpu_state(), before);
+    }
+}
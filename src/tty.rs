@@ -0,0 +1,173 @@
+//! Plays games in a terminal, for machines without a display or SDL.  The
+//! display is drawn with half-block characters in the palette's colors, two
+//! pixels to a character, and the keyboard is read a byte at a time.
+//!
+//! Terminals only say when a key is typed, not when it is let go, so each
+//! chip key stays pressed for a few frames after its keyboard key is typed,
+//! which the keyboard's repeat keeps topping up while the key is held.
+
+extern crate libc;
+
+use chip8::framebuffer::{Framebuffer, Rect};
+use chip8::render::Palette;
+use std::io::{self, Write};
+use std::mem;
+
+/// How many frames a chip key stays pressed after its keyboard key is typed.
+const HOLD_FRAMES: u8 = 8;
+
+/// The keyboard keys for the chip's keys 0 through F with the hex keymap.
+const HEX_KEYS: &[u8; 16] = b"0123456789abcdef";
+
+/// The keyboard keys for the chip's keys 0 through F with the qwerty keymap,
+/// which is laid out like `input::Keymap::qwerty`.
+const QWERTY_KEYS: &[u8; 16] = b"x123qweasdzc4rfv";
+
+/// The escape key, which quits.
+const ESCAPE: u8 = 0x1B;
+
+/// Control-C, which quits since signals are turned off.
+const INTERRUPT: u8 = 0x03;
+
+/// A terminal in raw mode, which is put back the way it was when dropped.
+pub struct Terminal {
+    original: libc::termios,
+    keys: &'static [u8; 16],
+    held: [u8; 16],
+    out: io::Stdout,
+}
+
+impl Terminal {
+    /// Puts the terminal into raw mode, reading the chip's keys with the
+    /// given keymap, which is either `hex` or `qwerty`.  Fails if standard
+    /// input isn't a terminal.
+    pub fn new(keymap: &str) -> Result<Self, String> {
+        let keys = match keymap {
+            "hex" => HEX_KEYS,
+            "qwerty" => QWERTY_KEYS,
+            _ => return Err(format!("The terminal only has the hex and qwerty keymaps, not {}",
+                                    keymap)),
+        };
+
+        let original = unsafe {
+            if libc::isatty(libc::STDIN_FILENO) == 0 {
+                return Err("Standard input isn't a terminal".to_string());
+            }
+            let mut original: libc::termios = mem::zeroed();
+            if libc::tcgetattr(libc::STDIN_FILENO, &mut original) != 0 {
+                return Err(format!("Couldn't read the terminal's settings: {}",
+                                   io::Error::last_os_error()));
+            }
+
+            // Read keys as they are typed, without echoing them or waiting
+            let mut raw = original;
+            raw.c_lflag &= !(libc::ICANON | libc::ECHO | libc::ISIG);
+            raw.c_cc[libc::VMIN] = 0;
+            raw.c_cc[libc::VTIME] = 0;
+            if libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &raw) != 0 {
+                return Err(format!("Couldn't put the terminal in raw mode: {}",
+                                   io::Error::last_os_error()));
+            }
+            original
+        };
+
+        let mut terminal = Terminal {
+            original: original,
+            keys: keys,
+            held: [0; 16],
+            out: io::stdout(),
+        };
+        // Clear the screen and hide the cursor
+        terminal.write(b"\x1b[2J\x1b[?25l");
+        Ok(terminal)
+    }
+
+    /// Reads the keys typed since the last check, marking the chip's keys
+    /// as pressed in the given key state array.  Returns false if the user
+    /// asked to quit with Escape or Control-C.
+    pub fn check(&mut self, keys: &mut [u8; 16]) -> bool {
+        for held in self.held.iter_mut() {
+            *held = held.saturating_sub(1);
+        }
+
+        let mut buffer = [0u8; 64];
+        loop {
+            let count = unsafe {
+                libc::read(libc::STDIN_FILENO, buffer.as_mut_ptr() as *mut libc::c_void,
+                           buffer.len())
+            };
+            if count <= 0 {
+                break;
+            }
+            let typed = &buffer[..count as usize];
+            for (i, &byte) in typed.iter().enumerate() {
+                match byte {
+                    INTERRUPT => return false,
+                    // A lone escape is the key, rather than the start of a
+                    // sequence for an arrow or function key
+                    ESCAPE if i + 1 == typed.len() => return false,
+                    ESCAPE => break,
+                    _ => {
+                        let byte = byte.to_ascii_lowercase();
+                        if let Some(key) = self.keys.iter().position(|&k| k == byte) {
+                            self.held[key] = HOLD_FRAMES;
+                        }
+                    }
+                }
+            }
+        }
+
+        for (key, &held) in keys.iter_mut().zip(self.held.iter()) {
+            *key = (held > 0) as u8;
+        }
+        true
+    }
+
+    /// Draws the lines of the display that the given rectangle covers, in
+    /// the given colors at the top left of the terminal, with each
+    /// character showing a pixel above another.
+    pub fn draw(&mut self, framebuffer: &Framebuffer, palette: &Palette, dirty: Rect) {
+        let mut text = String::new();
+        for line in dirty.y / 2..(dirty.y + dirty.height).div_ceil(2) {
+            text.push_str(&format!("\x1b[{};1H", line + 1));
+            let y = line * 2;
+            // Colors are only sent when they change
+            let mut colors = None;
+            for x in 0..framebuffer.width() {
+                let top = palette.color(framebuffer.pixel(x, y));
+                let bottom = palette.color(framebuffer.pixel(x, y + 1));
+                if colors != Some((top, bottom)) {
+                    colors = Some((top, bottom));
+                    text.push_str(&format!("\x1b[38;2;{};{};{}m\x1b[48;2;{};{};{}m",
+                                           top[0], top[1], top[2],
+                                           bottom[0], bottom[1], bottom[2]));
+                }
+                text.push('\u{2580}');
+            }
+            text.push_str("\x1b[0m\x1b[K");
+        }
+        // Leave the cursor below the display, clearing anything under it
+        text.push_str(&format!("\x1b[{};1H\x1b[J", framebuffer.height().div_ceil(2) + 1));
+        self.write(text.as_bytes());
+    }
+
+    /// Rings the terminal's bell.
+    pub fn beep(&mut self) {
+        self.write(b"\x07");
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        // There's nowhere to report a broken terminal, so carry on regardless
+        let _ = self.out.write_all(bytes).and_then(|_| self.out.flush());
+    }
+}
+
+impl Drop for Terminal {
+    fn drop(&mut self) {
+        // Show the cursor again below the display
+        self.write(b"\x1b[0m\x1b[?25h\r\n");
+        unsafe {
+            libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &self.original);
+        }
+    }
+}
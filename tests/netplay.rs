@@ -0,0 +1,116 @@
+//! Plays `PONG` over netplay between two emulators on this machine, each
+//! pressing its own paddle's keys from an input script, and checks that they
+//! end in the same state.
+
+use std::env;
+use std::fs;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::path::PathBuf;
+use std::process::{self, Child, Command, Stdio};
+
+/// How many frames each emulator runs.
+const FRAMES: u64 = 600;
+
+/// Writes an input script to a temporary file, returning its path.
+fn script(name: &str, text: &str) -> PathBuf {
+    let path = env::temp_dir().join(format!("chip8-netplay-{}-{}.txt", process::id(), name));
+    fs::write(&path, text).unwrap();
+    path
+}
+
+/// Starts the emulator headlessly on `PONG` with the given script and extra
+/// arguments.
+fn emulator(input: &PathBuf, arguments: &[&str]) -> Child {
+    Command::new(env!("CARGO_BIN_EXE_chip8"))
+        .arg("--headless")
+        .arg("--frames")
+        .arg(FRAMES.to_string())
+        .arg("--input")
+        .arg(input)
+        .args(arguments)
+        .arg(concat!(env!("CARGO_MANIFEST_DIR"), "/PONG"))
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap()
+}
+
+/// Reads an emulator's output until a line starting with the given prefix,
+/// returning the rest of that line.
+fn read_until<R: BufRead>(output: &mut R, prefix: &str) -> String {
+    loop {
+        let mut line = String::new();
+        assert!(output.read_line(&mut line).unwrap() > 0, "The emulator stopped");
+        if let Some(rest) = line.trim().strip_prefix(prefix) {
+            return rest.to_string();
+        }
+    }
+}
+
+/// The line an emulator printed about how netplay ended.
+fn ending(output: &str) -> &str {
+    output.lines().find(|line| line.starts_with("Netplay ended")).unwrap_or("")
+}
+
+#[test]
+fn plays_over_localhost() {
+    // The host moves the left paddle up and down, and the guest the right
+    let left = script("left", "0 1 down\n100 1 up\n100 4 down\n300 4 up\n");
+    let right = script("right", "50 D down\n250 D up\n250 C down\n400 C up\n");
+
+    let mut host = emulator(&left, &["--host", "0", "--seed", "3"]);
+    let mut stdout = BufReader::new(host.stdout.take().unwrap());
+    let mut port = None;
+    let mut host_output = String::new();
+    while port.is_none() {
+        let mut line = String::new();
+        assert!(stdout.read_line(&mut line).unwrap() > 0, "The host stopped: {}", host_output);
+        port = line.trim().strip_prefix("Waiting for the other player on port ")
+            .map(|port| port.to_string());
+        host_output.push_str(&line);
+    }
+
+    let address = format!("127.0.0.1:{}", port.unwrap());
+    let guest = emulator(&right, &["--connect", &address]).wait_with_output().unwrap();
+    stdout.read_to_string(&mut host_output).unwrap();
+    let host_status = host.wait().unwrap();
+    let guest_output = String::from_utf8(guest.stdout).unwrap();
+    fs::remove_file(left).unwrap();
+    fs::remove_file(right).unwrap();
+
+    assert!(host_status.success(), "{}", host_output);
+    assert!(guest.status.success(), "{}", guest_output);
+    let ended = format!("Netplay ended in sync after {} frames, with checksum ", FRAMES);
+    assert!(ending(&host_output).starts_with(&ended), "{}", host_output);
+    assert_eq!(ending(&host_output), ending(&guest_output));
+}
+
+#[test]
+fn turns_off_changes_over_rpc() {
+    let none = script("none", "");
+    let mut host = emulator(&none, &["--host", "0", "--rpc", "0", "--paused"]);
+    let mut stdout = BufReader::new(host.stdout.take().unwrap());
+    let address = read_until(&mut stdout, "Listening for JSON-RPC calls on ");
+    let port = read_until(&mut stdout, "Waiting for the other player on port ");
+    let mut guest = emulator(&none, &["--connect", &format!("127.0.0.1:{}", port)]);
+
+    let stream = TcpStream::connect(address).unwrap();
+    let mut reader = BufReader::new(stream.try_clone().unwrap());
+    let mut call = |request: &str| {
+        (&stream).write_all(format!("{}\n", request).as_bytes()).unwrap();
+        let mut response = String::new();
+        reader.read_line(&mut response).unwrap();
+        response
+    };
+    let response = call(r#"{"jsonrpc":"2.0","id":1,"method":"write_memory","params":{"address":512,"bytes":[0]}}"#);
+    assert!(response.contains("write_memory is turned off in netplay"), "{}", response);
+    let response = call(r#"{"jsonrpc":"2.0","id":2,"method":"read_memory","params":{"address":512,"length":1}}"#);
+    assert!(response.contains("\"result\""), "{}", response);
+    call(r#"{"jsonrpc":"2.0","id":3,"method":"quit"}"#);
+
+    // The guest is still waiting for the host's first frame
+    guest.kill().unwrap();
+    guest.wait().unwrap();
+    host.wait().unwrap();
+    fs::remove_file(none).unwrap();
+}
@@ -0,0 +1,59 @@
+//! Checks that the library builds without the standard library, for a
+//! bare-metal target, which must be installed, such as with
+//! `rustup target add thumbv7em-none-eabihf`.  In CI a missing target fails
+//! the test.  Elsewhere the test says loudly that it is skipping the
+//! bare-metal build, and builds without the `std` feature for this machine
+//! instead, which still catches most uses of `std`.
+
+use std::env;
+use std::io::{self, Write};
+use std::path::Path;
+use std::process::Command;
+
+/// The bare-metal target to build for, a Cortex-M4F microcontroller.
+const TARGET: &str = "thumbv7em-none-eabihf";
+
+/// Whether the standard library for the given target is installed.
+fn installed(target: &str) -> bool {
+    let output = Command::new("rustc").args(["--print", "sysroot"]).output();
+    match output {
+        Ok(output) => {
+            let sysroot = String::from_utf8_lossy(&output.stdout).trim().to_string();
+            Path::new(&sysroot).join("lib/rustlib").join(target).exists()
+        }
+        Err(_) => false,
+    }
+}
+
+#[test]
+fn builds_without_std() {
+    let bare_metal = installed(TARGET);
+    if !bare_metal {
+        assert!(env::var_os("CI").is_none(),
+                "{} isn't installed, so the library can't be checked without std; \
+                 install it with `rustup target add {}`", TARGET, TARGET);
+        // Straight to stderr, since the test harness hides what tests print
+        // unless they fail
+        let warning = format!("\n*** SKIPPED: {} isn't installed, so the library is only built \
+                               without std for this machine.  Install it with \
+                               `rustup target add {}`. ***\n", TARGET, TARGET);
+        io::stderr().write_all(warning.as_bytes()).unwrap();
+    }
+
+    // Both with the usual 4K of memory and with room for XO-CHIP's
+    let manifest = env!("CARGO_MANIFEST_DIR");
+    let target_dir = Path::new(manifest).join("target").join("no_std");
+    for features in ["", "xo-chip-memory"].iter() {
+        let mut command = Command::new(env::var("CARGO").unwrap_or_else(|_| "cargo".to_string()));
+        command.current_dir(manifest)
+            .args(["build", "--lib", "--no-default-features", "--features", features])
+            .arg("--target-dir")
+            .arg(&target_dir);
+        if bare_metal {
+            command.args(["--target", TARGET]);
+        }
+
+        let status = command.status().expect("Couldn't run cargo");
+        assert!(status.success(), "The library didn't build without std (features: {:?})", features);
+    }
+}
@@ -0,0 +1,228 @@
+//! Drives a headless emulator over its JSON-RPC socket, the way a script
+//! would.
+
+extern crate serde_json;
+
+use serde_json::Value;
+use std::collections::VecDeque;
+use std::env;
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpStream;
+use std::process::{self, Child, Command, Stdio};
+use std::thread;
+use std::time::Duration;
+
+/// A connection to the emulator, which keeps the notifications that arrive
+/// while waiting for responses.
+struct Client {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+    notifications: VecDeque<Value>,
+    next_id: u64,
+}
+
+impl Client {
+    fn connect(address: &str) -> Client {
+        let stream = TcpStream::connect(address).unwrap();
+        stream.set_nodelay(true).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+        Client {
+            reader: BufReader::new(stream.try_clone().unwrap()),
+            writer: stream,
+            notifications: VecDeque::new(),
+            next_id: 1,
+        }
+    }
+
+    /// Sends a line, returning the response to it.
+    fn send(&mut self, line: &str) -> Value {
+        self.writer.write_all(format!("{}\n", line).as_bytes()).unwrap();
+        loop {
+            let message = self.read();
+            if message.get("method").is_some() {
+                self.notifications.push_back(message);
+            } else {
+                return message;
+            }
+        }
+    }
+
+    /// Calls a method, returning the whole response.
+    fn request(&mut self, method: &str, params: &str) -> Value {
+        let id = self.next_id;
+        self.next_id += 1;
+        let response = self.send(&format!(r#"{{"jsonrpc":"2.0","id":{},"method":"{}","params":{}}}"#,
+                                          id, method, params));
+        assert_eq!(response["id"], id);
+        response
+    }
+
+    /// Calls a method that should succeed, returning its result.
+    fn call(&mut self, method: &str, params: &str) -> Value {
+        let response = self.request(method, params);
+        assert!(response.get("error").is_none(), "{} failed: {}", method, response);
+        response["result"].clone()
+    }
+
+    /// Waits for the next notification of the given event.
+    fn event(&mut self, event: &str) -> Value {
+        loop {
+            let message = match self.notifications.pop_front() {
+                Some(message) => message,
+                None => self.read(),
+            };
+            if message["method"] == event {
+                return message["params"].clone();
+            }
+        }
+    }
+
+    fn read(&mut self) -> Value {
+        let mut line = String::new();
+        assert!(self.reader.read_line(&mut line).unwrap() > 0, "The emulator hung up");
+        serde_json::from_str(&line).unwrap()
+    }
+}
+
+/// Starts the emulator on `PONG`, paused and listening on a free port, with
+/// files in the temporary directory.
+fn emulator() -> Child {
+    Command::new(env!("CARGO_BIN_EXE_chip8"))
+        .args(["--headless", "--paused", "--seed", "1", "--rpc", "0", "--rpc-dir"])
+        .arg(env::temp_dir())
+        .arg(concat!(env!("CARGO_MANIFEST_DIR"), "/PONG"))
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap()
+}
+
+/// Reads the address the emulator listens on from its output.
+fn address(emulator: &mut Child) -> String {
+    let mut stdout = BufReader::new(emulator.stdout.take().unwrap());
+    loop {
+        let mut line = String::new();
+        assert!(stdout.read_line(&mut line).unwrap() > 0, "The emulator stopped");
+        if let Some(address) = line.trim().strip_prefix("Listening for JSON-RPC calls on ") {
+            let address = address.to_string();
+            // Keep reading the output so that the emulator never blocks on it
+            thread::spawn(move || for _ in stdout.lines() {});
+            return address;
+        }
+    }
+}
+
+/// Waits until the emulator has run the given number of frames.
+fn wait_for_frame(client: &mut Client, frame: u64) {
+    while client.call("status", "{}")["frame"] != frame {
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+#[test]
+fn controls_the_emulator() {
+    let mut child = emulator();
+    let mut client = Client::connect(&address(&mut child));
+
+    // It starts paused, and steps a frame at a time
+    let status = client.call("status", "{}");
+    assert_eq!(status["paused"], true);
+    assert_eq!(status["frame"], 0);
+    client.call("step", r#"{"frames":3}"#);
+    wait_for_frame(&mut client, 3);
+    thread::sleep(Duration::from_millis(100));
+    assert_eq!(client.call("status", "{}")["frame"], 3);
+
+    // Memory can be read and written
+    let rom = fs::read(concat!(env!("CARGO_MANIFEST_DIR"), "/PONG")).unwrap();
+    let bytes = client.call("read_memory", r#"{"address":512,"length":4}"#);
+    assert_eq!(bytes["bytes"], Value::from(rom[..4].to_vec()));
+    client.call("write_memory", r#"{"address":3840,"bytes":[1,2,3]}"#);
+    let bytes = client.call("read_memory", r#"{"address":3840,"length":3}"#);
+    assert_eq!(bytes["bytes"], Value::from(vec![1, 2, 3]));
+
+    // So can registers, and states save and load them
+    client.call("set_registers", r#"{"v":[1,2,3,4,5,6,7,8,9,10,11,12,13,14,15,16],"i":3840}"#);
+    let state = client.call("save_state", "{}")["state"].clone();
+    client.call("set_registers", r#"{"v":[0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0]}"#);
+    assert_eq!(client.call("registers", "{}")["v"][15], 0);
+    client.call("load_state", &format!(r#"{{"state":{}}}"#, state));
+    let registers = client.call("registers", "{}");
+    assert_eq!(registers["v"][15], 16);
+    assert_eq!(registers["i"], 3840);
+
+    // Or files, but only in the directory the emulator was given
+    let name = format!("chip8-rpc-{}.state", process::id());
+    client.call("save_state", &format!(r#"{{"path":"{}"}}"#, name));
+    client.call("load_state", &format!(r#"{{"path":"./{}"}}"#, name));
+    fs::remove_file(env::temp_dir().join(name)).unwrap();
+    for path in ["/etc/passwd", "../passwd", "a/../../passwd"].iter() {
+        let params = format!(r#"{{"path":"{}"}}"#, path);
+        assert_eq!(client.request("load_state", &params)["error"]["code"], -32602);
+        assert_eq!(client.request("save_state", &params)["error"]["code"], -32602);
+        assert_eq!(client.request("load", &params)["error"]["code"], -32602);
+    }
+
+    // The display comes back as rows of digits
+    let display = client.call("screenshot", "{}");
+    assert_eq!(display["width"], 64);
+    assert_eq!(display["display"].as_array().unwrap().len(), 32);
+    assert!(display["display"][0].as_str().unwrap().chars().all(|c| c == '0' || c == '1'));
+
+    // Breakpoints stop the chip, in a loop of V1 = 0; V1 += 1; jump back
+    client.call("write_memory", r#"{"address":768,"bytes":[97,0,113,1,19,2]}"#);
+    client.call("set_registers", r#"{"pc":768}"#);
+    client.call("subscribe", r#"{"events":["breakpoint","frame"]}"#);
+    client.call("set_breakpoint", r#"{"address":770}"#);
+    assert_eq!(client.call("breakpoints", "{}"), Value::from(vec![770]));
+    client.call("resume", "{}");
+    assert_eq!(client.event("breakpoint")["address"], 770);
+    let status = client.call("status", "{}");
+    assert_eq!(status["paused"], true);
+    assert_eq!(status["breakpoint"], 770);
+    assert_eq!(client.call("registers", "{}")["v"][1], 0);
+
+    // Stepping from a breakpoint goes round the loop once
+    client.call("step", "{}");
+    assert_eq!(client.event("breakpoint")["address"], 770);
+    assert_eq!(client.call("registers", "{}")["v"][1], 1);
+
+    // Without the breakpoint, frames carry on
+    client.call("clear_breakpoint", r#"{"address":770}"#);
+    client.call("resume", "{}");
+    let frame = client.event("frame")["frame"].as_u64().unwrap();
+    assert!(client.event("frame")["frame"].as_u64().unwrap() > frame);
+    client.call("unsubscribe", r#"{"events":["frame","breakpoint"]}"#);
+    client.call("press", r#"{"key":1}"#);
+    client.call("release", r#"{"key":1}"#);
+
+    // Mistakes get JSON-RPC errors
+    assert_eq!(client.request("warp", "{}")["error"]["code"], -32601);
+    assert_eq!(client.request("press", r#"{"key":16}"#)["error"]["code"], -32602);
+    assert_eq!(client.request("read_memory", "{}")["error"]["code"], -32602);
+    let out_of_range = [
+        ("read_memory", r#"{"address":18446744073709551615,"length":2}"#),
+        ("write_memory", r#"{"address":65536,"bytes":[1]}"#),
+        ("set_breakpoint", r#"{"address":65536}"#),
+        ("set_registers", r#"{"pc":65536}"#),
+        ("set_registers", r#"{"i":65536}"#),
+        ("set_registers", r#"{"delay":256}"#),
+        ("set_registers", r#"{"sound":256}"#),
+    ];
+    for &(method, params) in out_of_range.iter() {
+        assert_eq!(client.request(method, params)["error"]["code"], -32602, "{}", params);
+    }
+    assert_eq!(client.call("breakpoints", "{}"), Value::from(Vec::<u16>::new()));
+    assert_eq!(client.send("{oops")["error"]["code"], -32700);
+    assert_eq!(client.send(r#"{"id":1}"#)["error"]["code"], -32600);
+    let invalid = ["[1,2]", "7", r#"{"method":"status"}"#, r#"{"jsonrpc":"1.0","method":"quit"}"#];
+    for line in invalid.iter() {
+        let response = client.send(line);
+        assert_eq!(response["error"]["code"], -32600, "{}", line);
+        assert_eq!(response["id"], Value::Null, "{}", line);
+    }
+    assert_eq!(client.request("load", r#"{"path":"no-such-rom"}"#)["error"]["code"], -32000);
+
+    client.call("quit", "{}");
+    assert!(child.wait().unwrap().success());
+}